@@ -0,0 +1,147 @@
+#[macro_use]
+extern crate criterion;
+extern crate hyper;
+extern crate serde_json;
+extern crate teatime;
+
+use criterion::Criterion;
+use hyper::{Chunk,Method,Response,Uri};
+use hyper::client::FutureResponse;
+use serde_json::Value;
+use teatime::*;
+use teatime::testing::{Fixture,FixtureServer};
+
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// A GitLab style listing of `count` projects
+fn listing(count: usize) -> String {
+    let projects: Vec<Value> = (0..count).map(|i| {
+        serde_json::from_str(&format!(
+            r#"{{"id": {0}, "name": "project-{0}", "path_with_namespace": "group/project-{0}",
+                "description": "A project used for benchmarking JSON parsing", "star_count": {0},
+                "tag_list": ["bench", "json"], "namespace": {{"id": 1, "kind": "group"}}}}"#, i
+        )).unwrap()
+    }).collect();
+    Value::from(projects).to_string()
+}
+
+fn chunks(body: &str) -> Vec<Chunk> {
+    body.as_bytes().chunks(CHUNK_SIZE).map(|c| Chunk::from(c.to_vec())).collect()
+}
+
+struct BenchClient {
+    base_uri: Uri,
+    client: SimpleHttpClient,
+}
+
+impl ApiClient<SimpleHttpClient> for BenchClient {
+    fn base_uri(&self) -> &Uri {
+        &self.base_uri
+    }
+
+    fn http_client(&self) -> &SimpleHttpClient {
+        &self.client
+    }
+
+    fn http_client_mut(&mut self) -> &mut SimpleHttpClient {
+        &mut self.client
+    }
+
+    fn login(&mut self, _creds: &ApiCredentials) -> Result<()> {
+        Ok(())
+    }
+
+    fn request_future<B>(&mut self, method: Method, uri: Uri, body: Option<B>)
+            -> Option<FutureResponse> where B: ToString {
+        let full_uri = self.full_uri(uri).ok()?;
+        let client = self.http_client_mut();
+        client.start_request(method, full_uri);
+        if let Some(b) = body {
+            client.add_body_bytes(b.to_string());
+        }
+        client.make_request().future()
+    }
+}
+
+impl JsonApiClient<SimpleHttpClient> for BenchClient {
+    fn next_page_uri(&mut self, _response: &Response) -> Result<Option<Uri>> {
+        Ok(None)
+    }
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let body = listing(10_000);
+    let mut group = c.benchmark_group("parse");
+    group.bench_function("concat", |b| b.iter(|| {
+        let concatenated: Vec<u8> = chunks(&body).iter().flat_map(|c| c.iter().cloned()).collect();
+        serde_json::from_slice::<Value>(&concatenated).unwrap()
+    }));
+    group.bench_function("chunk_reader", |b| b.iter(|| {
+        let reader = std::io::BufReader::new(ChunkReader::new(chunks(&body)));
+        serde_json::from_reader::<_, Value>(reader).unwrap()
+    }));
+    group.finish();
+}
+
+fn bench_request(c: &mut Criterion) {
+    let server = FixtureServer::builder()
+        .route(Method::Get, "/projects", Fixture::json(200, listing(10_000)))
+        .route(Method::Get, "/project", Fixture::json(200, listing(1)))
+        .route(Method::Post, "/projects", Fixture::new(201))
+        .start().unwrap();
+    let mut client = BenchClient {
+        base_uri: server.base_uri().parse().unwrap(),
+        client: SimpleHttpClient::new().unwrap(),
+    };
+    let mut group = c.benchmark_group("request");
+    group.sample_size(20);
+    group.bench_function("response_to_json", |b| b.iter(|| {
+        client.request_json(Method::Get, "/projects".parse().unwrap(), None::<Value>).unwrap()
+    }));
+    group.bench_function("response_to_json_streaming", |b| b.iter(|| {
+        let response = client.request(Method::Get, "/projects".parse().unwrap(), None::<String>).unwrap();
+        client.response_to_json_streaming::<Value>(response).unwrap()
+    }));
+    group.finish();
+
+    // Small responses at a high rate where per-request allocations matter most
+    let mut group = c.benchmark_group("small_request");
+    client.http_client_mut().set_buffer_pool(BufferPool::new(0, 0));
+    group.bench_function("unpooled_buffers", |b| b.iter(|| {
+        client.request_json(Method::Get, "/project".parse().unwrap(), None::<Value>).unwrap()
+    }));
+    client.http_client_mut().set_buffer_pool(BufferPool::default());
+    group.bench_function("pooled_buffers", |b| b.iter(|| {
+        client.request_json(Method::Get, "/project".parse().unwrap(), None::<Value>).unwrap()
+    }));
+    group.finish();
+
+    // Large request bodies, moved into the request by `add_body_bytes` rather than copied by
+    // `add_body`
+    let body = listing(10_000);
+    let uri: Uri = server.uri("/projects").parse().unwrap();
+    // The server records every request, so forget them to keep memory flat
+    let recorder = server.recorder();
+    let mut group = c.benchmark_group("request_body");
+    group.sample_size(20);
+    group.bench_function("add_body_copied", |b| b.iter(|| {
+        let body = body.clone();
+        let http = client.http_client_mut();
+        http.start_request(Method::Post, uri.clone());
+        let response = http.add_body(&body).make_request().response().unwrap();
+        recorder.clear();
+        response
+    }));
+    group.bench_function("add_body_moved", |b| b.iter(|| {
+        let body = body.clone();
+        let http = client.http_client_mut();
+        http.start_request(Method::Post, uri.clone());
+        let response = http.add_body_bytes(body).make_request().response().unwrap();
+        recorder.clear();
+        response
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse, bench_request);
+criterion_main!(benches);
@@ -0,0 +1,160 @@
+use std::collections::VecDeque;
+use std::collections::vec_deque::Iter;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+use *;
+
+/// One request recorded by an `AuditTrail`
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct AuditEntry {
+    /// When the request was sent
+    pub time: SystemTime,
+    /// Request method
+    pub method: Method,
+    /// Request URI with sensitive query parameters masked
+    pub uri: String,
+    /// Response status, or `None` if no response was received
+    pub status: Option<StatusCode>,
+    /// Time until the response headers were received or the request failed
+    pub duration: Duration,
+    /// Tag naming the operation that made the request, if one was set
+    pub tag: Option<String>,
+}
+
+impl AuditEntry {
+    /// JSON object describing the request
+    pub fn to_json(&self) -> Value {
+        let mut entry = serde_json::Map::new();
+        let time = self.time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        entry.insert("time".to_string(), Value::from(time));
+        entry.insert("method".to_string(), Value::from(self.method.to_string()));
+        entry.insert("uri".to_string(), Value::from(self.uri.clone()));
+        entry.insert("status".to_string(), self.status.map(|s| Value::from(u16::from(s))).unwrap_or(Value::Null));
+        entry.insert("duration_ms".to_string(), Value::from(self.duration.as_millis() as u64));
+        entry.insert("tag".to_string(), self.tag.clone().map(Value::from).unwrap_or(Value::Null));
+        Value::Object(entry)
+    }
+}
+
+/// Mask the values of the query parameters in `uri` masked by the default `RedactionPolicy`
+pub fn mask_uri(uri: &Uri) -> String {
+    RedactionPolicy::default().uri(uri)
+}
+
+/// Bounded in-memory record of the most recent requests made by an HTTP client
+///
+/// Once `capacity` entries are held the oldest is dropped for each new one. Requests are
+/// recorded when their response is awaited, so futures resolved together with
+/// `evaluate_future` are not included.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct AuditTrail {
+    entries: VecDeque<AuditEntry>,
+    capacity: usize,
+}
+
+impl AuditTrail {
+    /// Keep the last `capacity` requests
+    pub fn new(capacity: usize) -> Self {
+        AuditTrail { entries: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    /// Add an entry, dropping the oldest if the trail is full
+    pub fn record(&mut self, entry: AuditEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Recorded requests, oldest first
+    pub fn entries(&self) -> Iter<'_, AuditEntry> {
+        self.entries.iter()
+    }
+
+    /// Number of requests held
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// True if no requests are held
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Forget every recorded request
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// JSON array of the recorded requests, oldest first
+    pub fn to_json(&self) -> Value {
+        Value::Array(self.entries.iter().map(AuditEntry::to_json).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testing::{Fixture,FixtureServer,client_for};
+
+    fn entry(uri: &str) -> AuditEntry {
+        AuditEntry {
+            time: UNIX_EPOCH + Duration::from_secs(60),
+            method: Method::Get,
+            uri: uri.to_string(),
+            status: Some(StatusCode::Ok),
+            duration: Duration::from_millis(12),
+            tag: None,
+        }
+    }
+
+    #[test]
+    fn test_audit_trail_ring_buffer() {
+        let mut trail = AuditTrail::new(2);
+        trail.record(entry("/a"));
+        trail.record(entry("/b"));
+        trail.record(entry("/c"));
+        assert_eq!(trail.entries().map(|e| e.uri.as_str()).collect::<Vec<_>>(), vec!["/b", "/c"]);
+        assert_eq!(trail.to_json()[1], serde_json::from_str::<Value>(
+            r#"{"time": 60, "method": "GET", "uri": "/c", "status": 200, "duration_ms": 12, "tag": null}"#
+        ).unwrap());
+        trail.clear();
+        assert!(trail.is_empty());
+
+        let uri = "https://gitlab.example.com/api/v4/user?private_token=abc&page=2".parse().unwrap();
+        assert_eq!(mask_uri(&uri), "https://gitlab.example.com/api/v4/user?private_token=%5BREDACTED%5D&page=2");
+        assert_eq!(mask_uri(&"/user".parse().unwrap()), "/user");
+    }
+
+    #[test]
+    fn test_audit_trail() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/user", Fixture::json(200, "{}"))
+            .route(Method::Delete, "/user", Fixture::new(204).delay(Duration::from_millis(50)))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        client.request(Method::Get, "/user".parse().unwrap(), None::<String>).unwrap();
+        assert!(client.http_client().audit_trail().is_none());
+
+        client.http_client_mut().set_audit_trail(Some(AuditTrail::new(10)));
+        client.http_client_mut().set_audit_tag(Some("cleanup"));
+        client.request(Method::Get, "/user?private_token=abc".parse().unwrap(), None::<String>).unwrap();
+        client.request(Method::Delete, "/user".parse().unwrap(), None::<String>).unwrap();
+        client.http_client_mut().set_audit_tag(None::<String>);
+        client.request(Method::Post, "/missing".parse().unwrap(), Some("{}")).unwrap();
+
+        let trail = client.http_client().audit_trail().unwrap();
+        assert_eq!(trail.len(), 3);
+        let entries: Vec<_> = trail.entries().collect();
+        assert_eq!(entries[0].uri, format!("{}/user?private_token=%5BREDACTED%5D", server.base_uri()));
+        assert_eq!(entries[1].method, Method::Delete);
+        assert_eq!(entries[1].status, Some(StatusCode::NoContent));
+        assert!(entries[1].duration >= Duration::from_millis(50));
+        assert_eq!(entries[1].tag, Some("cleanup".to_string()));
+        assert_eq!(trail.to_json()[2]["status"], 404);
+        assert_eq!(trail.to_json()[2]["tag"], Value::Null);
+    }
+}
@@ -0,0 +1,941 @@
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::{Arc,Condvar,Mutex,MutexGuard};
+use std::thread::{self,ThreadId};
+use std::time::{Duration,Instant};
+
+use hyper::StatusCode;
+
+use *;
+use clock::{Clock,SystemClock};
+use state::{StateStore,TOKENS};
+
+struct TokenState<T> {
+    token: Option<T>,
+    expires_at: Option<Instant>,
+    generation: u64,
+    refresher: Option<ThreadId>,
+    expiry_reported: Option<u64>,
+}
+
+/// Changes in the life of a `SharedToken`, reported to listeners added with
+/// `SharedToken::on_event` so applications can log or alert on credential problems before
+/// requests start failing
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum TokenEvent {
+    /// A token was obtained where there was none
+    Acquired {
+        /// Generation of the new token
+        generation: u64,
+        /// Lifetime of the new token, if known
+        ttl: Option<Duration>,
+    },
+    /// The token was replaced with a new one
+    Refreshed {
+        /// Generation of the new token
+        generation: u64,
+        /// Lifetime of the new token, if known
+        ttl: Option<Duration>,
+    },
+    /// The token expires soon - reported once per token by `SharedToken::check_expiring`
+    Expiring {
+        /// Time left before the token expires
+        remaining: Duration,
+    },
+    /// The token was removed, for example after it was revoked
+    Revoked,
+    /// A login or refresh failed and the token was left as it was
+    RefreshFailed {
+        /// Description of the failure
+        error: String,
+    },
+}
+
+type TokenListener = Box<dyn Fn(&TokenEvent) + Send + Sync>;
+
+/// Token storage that can be shared between clients and guarantees that only one
+/// login or token refresh is in flight at a time
+///
+/// Every successful refresh bumps a generation counter. A caller that saw generation `n`
+/// fail with an authentication error passes `n` to `refresh`; if another caller has already
+/// refreshed past `n`, the new token is returned without logging in again. Callers arriving
+/// while a refresh is running block until it completes.
+pub struct SharedToken<T> {
+    inner: Arc<(Mutex<TokenState<T>>, Condvar)>,
+    clock: Arc<dyn Clock>,
+    listeners: Arc<Mutex<Vec<TokenListener>>>,
+}
+
+impl<T> Clone for SharedToken<T> {
+    fn clone(&self) -> Self {
+        SharedToken {
+            inner: Arc::clone(&self.inner),
+            clock: Arc::clone(&self.clock),
+            listeners: Arc::clone(&self.listeners),
+        }
+    }
+}
+
+fn replaced_event(had_token: bool, has_token: bool, generation: u64, ttl: Option<Duration>) -> Option<TokenEvent> {
+    match (had_token, has_token) {
+        (false, true) => Some(TokenEvent::Acquired { generation, ttl }),
+        (true, true) => Some(TokenEvent::Refreshed { generation, ttl }),
+        (true, false) => Some(TokenEvent::Revoked),
+        (false, false) => None,
+    }
+}
+
+impl<T> SharedToken<T> where T: Clone {
+    /// Create new shared token storage with an optional initial token
+    pub fn new(token: Option<T>) -> Self {
+        SharedToken::with_clock(token, SystemClock)
+    }
+
+    /// Create new shared token storage that tracks token lifetimes using `clock`
+    pub fn with_clock<C>(token: Option<T>, clock: C) -> Self where C: Clock + 'static {
+        SharedToken {
+            inner: Arc::new((Mutex::new(TokenState {
+                token,
+                expires_at: None,
+                generation: 0,
+                refresher: None,
+                expiry_reported: None,
+            }), Condvar::new())),
+            clock: Arc::new(clock),
+            listeners: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Call `listener` with every `TokenEvent` of this token and its clones
+    ///
+    /// Listeners run on the thread that caused the event, after the token lock is released.
+    pub fn on_event<F>(&self, listener: F) where F: Fn(&TokenEvent) + Send + Sync + 'static {
+        self.listeners.lock().unwrap_or_else(|e| e.into_inner()).push(Box::new(listener));
+    }
+
+    fn emit(&self, event: Option<TokenEvent>) {
+        if let Some(event) = event {
+            for listener in self.listeners.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+                listener(&event);
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TokenState<T>> {
+        // A panic while holding the lock cannot leave the state half updated
+        self.inner.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Get the current token
+    pub fn get(&self) -> Option<T> {
+        self.lock().token.clone()
+    }
+
+    /// Get the current token along with the generation it belongs to
+    pub fn get_with_generation(&self) -> (Option<T>, u64) {
+        let state = self.lock();
+        (state.token.clone(), state.generation)
+    }
+
+    /// Current token generation
+    pub fn generation(&self) -> u64 {
+        self.lock().generation
+    }
+
+    /// Replace the token unconditionally, waking anyone waiting on a refresh
+    pub fn set(&self, token: Option<T>) {
+        self.set_with_ttl(token, None);
+    }
+
+    /// Replace the token unconditionally, recording that it expires after `ttl`
+    pub fn set_with_ttl(&self, token: Option<T>, ttl: Option<Duration>) {
+        let mut state = self.lock();
+        let had_token = state.token.is_some();
+        state.token = token;
+        state.expires_at = ttl.map(|ttl| self.clock.now() + ttl);
+        state.generation += 1;
+        let event = replaced_event(had_token, state.token.is_some(), state.generation, ttl);
+        self.inner.1.notify_all();
+        drop(state);
+        self.emit(event);
+    }
+
+    /// Remove the token, for example after revoking it on the server
+    pub fn revoke(&self) {
+        self.set(None);
+    }
+
+    /// When the current token expires, if known
+    pub fn expires_at(&self) -> Option<Instant> {
+        self.lock().expires_at
+    }
+
+    /// True if there is no token or the token expires within `window`
+    pub fn expires_within(&self, window: Duration) -> bool {
+        let state = self.lock();
+        match (state.token.is_some(), state.expires_at) {
+            (false, _) => true,
+            (true, Some(expires_at)) => expires_at <= self.clock.now() + window,
+            (true, None) => false,
+        }
+    }
+
+    /// Report `TokenEvent::Expiring` if the token expires within `window`, once per token,
+    /// returning the time it has left
+    pub fn check_expiring(&self, window: Duration) -> Option<Duration> {
+        let mut state = self.lock();
+        let now = self.clock.now();
+        let remaining = match (state.token.is_some(), state.expires_at) {
+            (true, Some(expires_at)) if expires_at <= now + window => {
+                if expires_at > now { expires_at - now } else { Duration::from_secs(0) }
+            },
+            _ => return None,
+        };
+        let report = state.expiry_reported != Some(state.generation);
+        state.expiry_reported = Some(state.generation);
+        drop(state);
+        if report {
+            self.emit(Some(TokenEvent::Expiring { remaining }));
+        }
+        Some(remaining)
+    }
+
+    /// Run `refresh_fn` to obtain a new token unless the token has already been refreshed
+    /// since generation `seen`
+    ///
+    /// If a refresh is already running, this blocks until it finishes. A failed refresh leaves
+    /// the token untouched so that the next caller may try again. `refresh_fn` may itself
+    /// refresh the token, for example by logging in through a client sharing it, in which case
+    /// the token it stored is returned.
+    pub fn refresh<F>(&self, seen: u64, refresh_fn: F) -> Result<Option<T>>
+            where F: FnOnce() -> Result<Option<T>> {
+        self.refresh_inner(seen, || refresh_fn().map(|token| (token, None)))
+    }
+
+    /// Like `refresh` but for tokens with a known lifetime
+    pub fn refresh_minted<F>(&self, seen: u64, mint_fn: F) -> Result<Option<T>>
+            where F: FnOnce() -> Result<MintedToken<T>> {
+        self.refresh_inner(seen, || mint_fn().map(|minted| (Some(minted.token), minted.ttl)))
+    }
+
+    fn refresh_inner<F>(&self, seen: u64, refresh_fn: F) -> Result<Option<T>>
+            where F: FnOnce() -> Result<(Option<T>, Option<Duration>)> {
+        let current = thread::current().id();
+        let nested = {
+            let mut state = self.lock();
+            while state.refresher.is_some() && state.refresher != Some(current) {
+                state = self.inner.1.wait(state).unwrap_or_else(|e| e.into_inner());
+            }
+            if state.generation != seen {
+                return Ok(state.token.clone());
+            }
+            let nested = state.refresher.is_some();
+            state.refresher = Some(current);
+            nested
+        };
+
+        let guard = if nested { None } else { Some(RefreshGuard { token: self }) };
+        let result = refresh_fn();
+        let mut state = self.lock();
+        if state.generation != seen {
+            let token = state.token.clone();
+            drop(state);
+            drop(guard);
+            return result.map(|_| token);
+        }
+        let had_token = state.token.is_some();
+        let mut event = None;
+        let result = result.map(|(token, ttl)| {
+            state.token = token.clone();
+            state.expires_at = ttl.map(|ttl| self.clock.now() + ttl);
+            state.generation += 1;
+            event = replaced_event(had_token, token.is_some(), state.generation, ttl);
+            token
+        });
+        drop(state);
+        drop(guard);
+        match result {
+            Ok(_) => self.emit(event),
+            Err(ref e) => self.emit(Some(TokenEvent::RefreshFailed { error: e.to_string() })),
+        }
+        result
+    }
+}
+
+/// A freshly minted token and how long it remains valid
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct MintedToken<T> {
+    /// The token
+    pub token: T,
+    /// Lifetime of the token - `None` if it does not expire
+    pub ttl: Option<Duration>,
+}
+
+impl<T> MintedToken<T> {
+    /// Create a minted token
+    pub fn new(token: T, ttl: Option<Duration>) -> Self {
+        MintedToken { token, ttl }
+    }
+}
+
+/// Hook that mints a fresh token from a user supplied closure whenever the current token is
+/// missing or close to expiry, for example by reading a service account token from Vault
+pub struct TokenRotation<T> {
+    mint: Box<dyn FnMut() -> Result<MintedToken<T>>>,
+    refresh_before: Duration,
+}
+
+impl<T> TokenRotation<T> where T: Clone {
+    /// Rotate using `mint`, one minute before the current token expires
+    pub fn new<F>(mint: F) -> Self where F: FnMut() -> Result<MintedToken<T>> + 'static {
+        TokenRotation { mint: Box::new(mint), refresh_before: Duration::from_secs(60) }
+    }
+
+    /// Set how long before expiry the token is rotated
+    pub fn refresh_before(mut self, window: Duration) -> Self {
+        self.refresh_before = window;
+        self
+    }
+
+    /// Mint a new token into `token` if it is missing or expires within the rotation window,
+    /// returning true if this call performed the rotation
+    pub fn rotate_if_needed(&mut self, token: &SharedToken<T>) -> Result<bool> {
+        let seen = token.generation();
+        if !token.expires_within(self.refresh_before) {
+            return Ok(false);
+        }
+        token.check_expiring(self.refresh_before);
+        token.refresh_minted(seen, &mut self.mint)?;
+        Ok(token.generation() == seen + 1)
+    }
+}
+
+/// Clears the refreshing thread even if the refresh closure panics
+struct RefreshGuard<'a, T: 'a> {
+    token: &'a SharedToken<T>,
+}
+
+impl<'a, T> Drop for RefreshGuard<'a, T> {
+    fn drop(&mut self) {
+        let mut state = self.token.inner.0.lock().unwrap_or_else(|e| e.into_inner());
+        state.refresher = None;
+        self.token.inner.1.notify_all();
+    }
+}
+
+/// Source of credentials for logging in, asked again whenever the current credentials are
+/// rejected
+pub trait CredentialsProvider {
+    /// Get credentials to log in with
+    fn get(&mut self) -> Result<ApiCredentials>;
+}
+
+impl<F> CredentialsProvider for F where F: FnMut() -> Result<ApiCredentials> {
+    fn get(&mut self) -> Result<ApiCredentials> {
+        self()
+    }
+}
+
+/// Credentials fixed at construction time
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct StaticCredentials(pub ApiCredentials);
+
+impl CredentialsProvider for StaticCredentials {
+    fn get(&mut self) -> Result<ApiCredentials> {
+        Ok(self.0.clone())
+    }
+}
+
+fn env_var(name: &str) -> Result<String> {
+    env::var(name).map_err(|_| {
+        ClientError::new(format!("Environment variable {} is not set or not valid UTF-8", name))
+    })
+}
+
+/// Credentials read from environment variables each time they are requested
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum EnvCredentials {
+    /// API key in the named variable
+    ApiKey(String),
+    /// Username, password and optional two factor code in the named variables
+    UserPass {
+        /// Variable holding the username
+        username: String,
+        /// Variable holding the password
+        password: String,
+        /// Variable holding the two factor code
+        two_factor: Option<String>,
+    },
+}
+
+impl EnvCredentials {
+    /// Read an API key from `var`
+    pub fn api_key<S>(var: S) -> Self where S: Into<String> {
+        EnvCredentials::ApiKey(var.into())
+    }
+
+    /// Read a username and password from `username_var` and `password_var`
+    pub fn user_pass<S, T>(username_var: S, password_var: T) -> Self
+            where S: Into<String>, T: Into<String> {
+        EnvCredentials::UserPass {
+            username: username_var.into(),
+            password: password_var.into(),
+            two_factor: None,
+        }
+    }
+
+    /// Also read a two factor code from `var` - has no effect on API key credentials
+    pub fn two_factor<S>(self, var: S) -> Self where S: Into<String> {
+        match self {
+            EnvCredentials::UserPass { username, password, .. } => {
+                EnvCredentials::UserPass { username, password, two_factor: Some(var.into()) }
+            },
+            api_key => api_key,
+        }
+    }
+}
+
+impl CredentialsProvider for EnvCredentials {
+    fn get(&mut self) -> Result<ApiCredentials> {
+        match *self {
+            EnvCredentials::ApiKey(ref var) => Ok(ApiCredentials::ApiKey(env_var(var)?.into())),
+            EnvCredentials::UserPass { ref username, ref password, two_factor: None } => {
+                Ok(ApiCredentials::UserPass(env_var(username)?, env_var(password)?.into()))
+            },
+            EnvCredentials::UserPass { ref username, ref password, two_factor: Some(ref otp) } => {
+                Ok(ApiCredentials::UserPassTwoFactor(env_var(username)?, env_var(password)?.into(),
+                                                     env_var(otp)?.into()))
+            },
+        }
+    }
+}
+
+/// Credentials prompted for interactively in the terminal each time they are requested
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct PromptCredentials {
+    need_2fa: bool,
+    masked: bool,
+}
+
+impl PromptCredentials {
+    /// Prompt for a username and password, and a two factor code if `need_2fa` is set
+    pub fn new(need_2fa: bool) -> Self {
+        PromptCredentials { need_2fa, masked: false }
+    }
+
+    /// Echo `*` for each character of the password rather than hiding it, so operators can
+    /// see that a paste worked
+    pub fn masked(mut self, masked: bool) -> Self {
+        self.masked = masked;
+        self
+    }
+}
+
+impl CredentialsProvider for PromptCredentials {
+    fn get(&mut self) -> Result<ApiCredentials> {
+        ApiCredentials::interactive_get_with(self.need_2fa, self.masked).map_err(|e| {
+            ClientError::new(format!("Failed to read credentials: {}", e))
+        })
+    }
+}
+
+/// Credentials read from a file each time they are requested
+///
+/// The file may hold a JSON object with either a `token` field or `username` and `password`
+/// fields plus an optional `passcode` field. Any other content is treated as an API key with
+/// surrounding whitespace removed.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct FileCredentials {
+    path: PathBuf,
+}
+
+impl FileCredentials {
+    /// Read credentials from `path`
+    pub fn new<P>(path: P) -> Self where P: Into<PathBuf> {
+        FileCredentials { path: path.into() }
+    }
+
+    /// Parse the contents of a credentials file
+    pub fn parse(contents: &str) -> Result<ApiCredentials> {
+        let json = match serde_json::from_str::<Value>(contents) {
+            Ok(Value::Object(map)) => map,
+            _ => {
+                let key = contents.trim();
+                if key.is_empty() {
+                    return Err(ClientError::new("Credentials file is empty"));
+                }
+                return Ok(ApiCredentials::ApiKey(key.into()));
+            },
+        };
+        let field = |name: &str| json.get(name).and_then(|v| v.as_str()).map(|s| s.to_string());
+        match (field("token"), field("username"), field("password"), field("passcode")) {
+            (Some(token), _, _, _) => Ok(ApiCredentials::ApiKey(token.into())),
+            (None, Some(u), Some(p), Some(y)) => Ok(ApiCredentials::UserPassTwoFactor(u, p.into(), y.into())),
+            (None, Some(u), Some(p), None) => Ok(ApiCredentials::UserPass(u, p.into())),
+            _ => Err(ClientError::new("Credentials file has neither a token nor a username and password")),
+        }
+    }
+}
+
+impl CredentialsProvider for FileCredentials {
+    fn get(&mut self) -> Result<ApiCredentials> {
+        let mut contents = String::new();
+        File::open(&self.path).and_then(|mut f| f.read_to_string(&mut contents)).map_err(|e| {
+            ClientError::new(format!("Failed to read credentials from {}: {}", self.path.display(), e))
+        })?;
+        FileCredentials::parse(&contents)
+    }
+}
+
+/// Credentials stored in the operating system keyring
+#[cfg(feature = "keyring")]
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct KeyringCredentials {
+    service: String,
+    account: String,
+    user_pass: bool,
+}
+
+#[cfg(feature = "keyring")]
+impl KeyringCredentials {
+    /// Use the secret stored for `account` under `service` as an API key
+    pub fn api_key<S, A>(service: S, account: A) -> Self where S: Into<String>, A: Into<String> {
+        KeyringCredentials { service: service.into(), account: account.into(), user_pass: false }
+    }
+
+    /// Use `username` with the password stored for it under `service`
+    pub fn user_pass<S, U>(service: S, username: U) -> Self where S: Into<String>, U: Into<String> {
+        KeyringCredentials { service: service.into(), account: username.into(), user_pass: true }
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl CredentialsProvider for KeyringCredentials {
+    fn get(&mut self) -> Result<ApiCredentials> {
+        let secret = keyring::Entry::new(&self.service, &self.account)
+            .and_then(|entry| entry.get_password())
+            .map_err(|e| {
+                ClientError::new(format!("Failed to read {} from keyring: {}", self.service, e))
+            })?;
+        if self.user_pass {
+            Ok(ApiCredentials::UserPass(self.account.clone(), secret.into()))
+        } else {
+            Ok(ApiCredentials::ApiKey(secret.into()))
+        }
+    }
+}
+
+/// Token saved in a `StateStore` so that a login can be reused by later runs instead of asking
+/// for credentials again
+///
+/// Clients given a token cache start with the saved token if they have no other and save the
+/// token from every successful login.
+#[derive(Clone)]
+pub struct TokenCache {
+    store: Arc<dyn StateStore>,
+    key: String,
+}
+
+impl fmt::Debug for TokenCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TokenCache").field("key", &self.key).finish()
+    }
+}
+
+impl TokenCache {
+    /// Save the token under `key`, for example the server address, in `store`
+    pub fn new<S>(store: Arc<dyn StateStore>, key: S) -> Self where S: Into<String> {
+        TokenCache { store, key: key.into() }
+    }
+
+    /// The saved token, if any
+    pub fn load(&self) -> Result<Option<Secret>> {
+        match self.store.get(TOKENS, &self.key)? {
+            Some(bytes) => String::from_utf8(bytes).map(|t| Some(Secret::from(t))).map_err(|_| {
+                ClientError::new(format!("Cached token for {} is not valid UTF-8", self.key))
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Save `token`, replacing any earlier one
+    pub fn save(&self, token: &Secret) -> Result<()> {
+        self.store.put(TOKENS, &self.key, token.expose().as_bytes())
+    }
+
+    /// Forget the saved token
+    pub fn clear(&self) -> Result<()> {
+        self.store.delete(TOKENS, &self.key)
+    }
+}
+
+/// Classify an HTTP status as an authentication or authorization failure
+pub fn auth_failure(status: StatusCode) -> Option<ErrorKind> {
+    match status {
+        StatusCode::Unauthorized => Some(ErrorKind::AuthenticationFailed),
+        StatusCode::Forbidden => Some(ErrorKind::PermissionDenied),
+        _ => None,
+    }
+}
+
+/// Limits on how authentication failures are handled
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct AuthPolicy {
+    max_credential_attempts: usize,
+}
+
+impl Default for AuthPolicy {
+    fn default() -> Self {
+        AuthPolicy { max_credential_attempts: 3 }
+    }
+}
+
+impl AuthPolicy {
+    /// Ask for fresh credentials at most three times
+    pub fn new() -> Self {
+        AuthPolicy::default()
+    }
+
+    /// Set how many times fresh credentials are requested before giving up
+    pub fn max_credential_attempts(mut self, attempts: usize) -> Self {
+        self.max_credential_attempts = attempts;
+        self
+    }
+
+    /// How many times fresh credentials are requested before giving up
+    pub fn credential_attempts(&self) -> usize {
+        self.max_credential_attempts
+    }
+}
+
+/// Request flow that distinguishes authentication failures from authorization failures -
+/// implemented for every `ApiClient`
+pub trait AuthApiClient<HTTP>: ApiClient<HTTP> where HTTP: HttpClient {
+    /// Log in with credentials obtained from `provider`
+    fn login_with<P>(&mut self, provider: &mut P) -> Result<()> where P: CredentialsProvider {
+        let creds = provider.get()?;
+        self.login(&creds)
+    }
+
+    /// Make an API request, logging in again with fresh credentials from `provider` whenever
+    /// the server responds with `401 Unauthorized`
+    ///
+    /// A `403 Forbidden` response means re-authenticating will not help and fails immediately
+    /// with `ErrorKind::PermissionDenied`. Once the policy's credential attempts are used up,
+    /// the request fails with `ErrorKind::AuthenticationFailed`, naming the last login error.
+    fn request_authenticated<B, P>(&mut self, provider: &mut P, policy: &AuthPolicy,
+                                   method: Method, uri: Uri, body: Option<B>) -> Result<Response>
+            where B: ToString, P: CredentialsProvider {
+        authenticated(self, None::<&SharedToken<()>>, provider, policy, method, uri, body)
+    }
+
+    /// Like `request_authenticated` for a client whose token is `token`, logging in only once
+    /// when several clients sharing the token are rejected at the same time
+    ///
+    /// A request rejected after another client has already logged in is retried with the new
+    /// token without asking `provider` again.
+    fn request_authenticated_shared<B, P, T>(&mut self, token: &SharedToken<T>, provider: &mut P,
+                                             policy: &AuthPolicy, method: Method, uri: Uri,
+                                             body: Option<B>) -> Result<Response>
+            where B: ToString, P: CredentialsProvider, T: Clone {
+        authenticated(self, Some(token), provider, policy, method, uri, body)
+    }
+}
+
+fn authenticated<C, HTTP, B, P, T>(client: &mut C, token: Option<&SharedToken<T>>, provider: &mut P,
+                                   policy: &AuthPolicy, method: Method, uri: Uri, body: Option<B>)
+        -> Result<Response>
+        where C: ?Sized + ApiClient<HTTP>, HTTP: HttpClient, B: ToString, P: CredentialsProvider, T: Clone {
+    let body = body.map(|b| b.to_string());
+    let mut attempts = 0;
+    let mut last_error = None;
+    loop {
+        let seen = token.map(SharedToken::generation);
+        let response = client.request(method.clone(), uri.clone(), body.clone())?;
+        match auth_failure(response.status()) {
+            Some(ErrorKind::AuthenticationFailed) => (),
+            Some(kind) => {
+                return Err(ClientError::with_kind(kind, format!(
+                    "Permission denied for {} {}", method, uri
+                )));
+            },
+            None => { return Ok(response); },
+        }
+        loop {
+            if attempts >= policy.max_credential_attempts {
+                let message = format!("Authentication failed for {} {} after {} credential attempts",
+                                      method, uri, attempts);
+                return Err(ClientError::with_kind(ErrorKind::AuthenticationFailed, match last_error {
+                    Some(e) => format!("{}: {}", message, e),
+                    None => message,
+                }));
+            }
+            attempts += 1;
+            let mut provider_failed = false;
+            let mut log_in = || {
+                let creds = provider.get().inspect_err(|_| provider_failed = true)?;
+                client.login(&creds)
+            };
+            let login = match (token, seen) {
+                (Some(token), Some(seen)) => token.refresh(seen, || log_in().map(|_| token.get())).map(|_| ()),
+                _ => log_in(),
+            };
+            match login {
+                Ok(()) => break,
+                Err(e) => {
+                    if provider_failed {
+                        return Err(e);
+                    }
+                    last_error = Some(e);
+                },
+            }
+        }
+    }
+}
+
+impl<HTTP, C> AuthApiClient<HTTP> for C where HTTP: HttpClient, C: ApiClient<HTTP> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clock::MockClock;
+    use hyper::client::FutureResponse;
+    use std::sync::atomic::{AtomicUsize,Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use testing::{Fixture,FixtureServer};
+
+    /// Client that is rejected until its token is "new", logging in through its shared token
+    struct TokenClient {
+        base_uri: Uri,
+        client: SimpleHttpClient,
+        token: SharedToken<String>,
+        logins: usize,
+        fail_login: bool,
+        refresh_elsewhere: bool,
+    }
+
+    impl TokenClient {
+        fn new(server: &FixtureServer) -> Self {
+            TokenClient {
+                base_uri: server.base_uri().parse().unwrap(),
+                client: SimpleHttpClient::new().unwrap(),
+                token: SharedToken::new(Some("old".to_string())),
+                logins: 0,
+                fail_login: false,
+                refresh_elsewhere: false,
+            }
+        }
+    }
+
+    impl ApiClient<SimpleHttpClient> for TokenClient {
+        fn base_uri(&self) -> &Uri {
+            &self.base_uri
+        }
+
+        fn http_client(&self) -> &SimpleHttpClient {
+            &self.client
+        }
+
+        fn http_client_mut(&mut self) -> &mut SimpleHttpClient {
+            &mut self.client
+        }
+
+        fn login(&mut self, _creds: &ApiCredentials) -> Result<()> {
+            self.logins += 1;
+            if self.fail_login {
+                return Err(ClientError::new("Wrong password"));
+            }
+            let seen = self.token.generation();
+            self.token.refresh(seen, || Ok(Some("new".to_string()))).map(|_| ())
+        }
+
+        fn request_future<B>(&mut self, method: Method, _uri: Uri, _body: Option<B>)
+                -> Option<FutureResponse> where B: ToString {
+            let path = if self.token.get().as_deref() == Some("new") { "/items" } else { "/expired" };
+            if self.refresh_elsewhere {
+                self.token.set(Some("new".to_string()));
+            }
+            let full_uri = self.full_uri(path.parse().unwrap()).ok()?;
+            self.http_client_mut().start_request(method, full_uri).make_request().future()
+        }
+    }
+
+    fn auth_server() -> FixtureServer {
+        FixtureServer::builder()
+            .route(Method::Get, "/expired", Fixture::new(401))
+            .route(Method::Get, "/items", Fixture::new(200))
+            .start().unwrap()
+    }
+
+    #[test]
+    fn test_refresh_single_flight() {
+        let token = SharedToken::new(Some("old".to_string()));
+        let logins = Arc::new(AtomicUsize::new(0));
+        let seen = token.generation();
+        let handles: Vec<_> = (0..8).map(|_| {
+            let token = token.clone();
+            let logins = Arc::clone(&logins);
+            thread::spawn(move || {
+                token.refresh(seen, || {
+                    logins.fetch_add(1, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                    Ok(Some("new".to_string()))
+                }).unwrap()
+            })
+        }).collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), Some("new".to_string()));
+        }
+        assert_eq!(logins.load(Ordering::SeqCst), 1);
+        assert_eq!(token.generation(), seen + 1);
+    }
+
+    #[test]
+    fn test_request_authenticated_shared() {
+        let server = auth_server();
+        let mut asked = 0;
+        let mut provider = || { asked += 1; Ok(ApiCredentials::ApiKey("key".into())) };
+        let mut client = TokenClient::new(&server);
+        let token = client.token.clone();
+        let response = client.request_authenticated_shared(&token, &mut provider, &AuthPolicy::new(),
+                                                           Method::Get, "/".parse().unwrap(), None::<String>);
+        assert_eq!(response.unwrap().status(), StatusCode::Ok);
+        assert_eq!(client.logins, 1);
+        assert_eq!(token.generation(), 1);
+
+        let mut client = TokenClient::new(&server);
+        client.refresh_elsewhere = true;
+        let token = client.token.clone();
+        let response = client.request_authenticated_shared(&token, &mut provider, &AuthPolicy::new(),
+                                                           Method::Get, "/".parse().unwrap(), None::<String>);
+        assert_eq!(response.unwrap().status(), StatusCode::Ok);
+        assert_eq!(client.logins, 0);
+        assert_eq!(asked, 1);
+    }
+
+    #[test]
+    fn test_request_authenticated_login_error() {
+        let server = auth_server();
+        let mut client = TokenClient::new(&server);
+        client.fail_login = true;
+        let mut provider = || Ok(ApiCredentials::ApiKey("key".into()));
+        let err = client.request_authenticated(&mut provider, &AuthPolicy::new().max_credential_attempts(2),
+                                               Method::Get, "/".parse().unwrap(), None::<String>).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::AuthenticationFailed);
+        assert_eq!(err.to_string(), "Authentication failed for GET / after 2 credential attempts: Wrong password");
+        assert_eq!(client.logins, 2);
+    }
+
+    #[test]
+    fn test_auth_failure_classification() {
+        assert_eq!(auth_failure(StatusCode::Unauthorized), Some(ErrorKind::AuthenticationFailed));
+        assert_eq!(auth_failure(StatusCode::Forbidden), Some(ErrorKind::PermissionDenied));
+        assert_eq!(auth_failure(StatusCode::NotFound), None);
+    }
+
+    #[test]
+    fn test_closure_credentials_provider() {
+        let mut calls = 0;
+        let mut provider = || {
+            calls += 1;
+            Ok(ApiCredentials::ApiKey("key".into()))
+        };
+        assert_eq!(provider.get().unwrap(), ApiCredentials::ApiKey("key".into()));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_env_credentials() {
+        env::set_var("TEATIME_TEST_USER", "me");
+        env::set_var("TEATIME_TEST_PASS", "secret");
+        let mut provider = EnvCredentials::user_pass("TEATIME_TEST_USER", "TEATIME_TEST_PASS");
+        assert_eq!(provider.get().unwrap(),
+                   ApiCredentials::UserPass("me".to_string(), "secret".into()));
+        let mut missing = provider.two_factor("TEATIME_TEST_UNSET_OTP");
+        assert!(missing.get().is_err());
+    }
+
+    #[test]
+    fn test_file_credentials_parse() {
+        assert_eq!(FileCredentials::parse("  abc123\n").unwrap(),
+                   ApiCredentials::ApiKey("abc123".into()));
+        assert_eq!(FileCredentials::parse(r#"{"token": "abc123"}"#).unwrap(),
+                   ApiCredentials::ApiKey("abc123".into()));
+        assert_eq!(FileCredentials::parse(r#"{"username": "me", "password": "pw", "passcode": "123"}"#).unwrap(),
+                   ApiCredentials::UserPassTwoFactor("me".to_string(), "pw".into(), "123".into()));
+        assert!(FileCredentials::parse(r#"{"username": "me"}"#).is_err());
+        assert!(FileCredentials::parse("\n").is_err());
+    }
+
+    #[test]
+    fn test_token_rotation() {
+        let token = SharedToken::new(None);
+        let mut minted = 0;
+        let mut rotation = TokenRotation::new(move || {
+            minted += 1;
+            Ok(MintedToken::new(format!("token-{}", minted), Some(Duration::from_secs(30))))
+        });
+        assert!(rotation.rotate_if_needed(&token).unwrap());
+        assert_eq!(token.get(), Some("token-1".to_string()));
+        // Expires in 30 seconds which is inside the default one minute window
+        assert!(rotation.rotate_if_needed(&token).unwrap());
+        assert_eq!(token.get(), Some("token-2".to_string()));
+
+        let mut rotation = rotation.refresh_before(Duration::from_secs(5));
+        assert!(!rotation.rotate_if_needed(&token).unwrap());
+        token.set(Some("static".to_string()));
+        assert!(!token.expires_within(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_token_expiry_with_mock_clock() {
+        let clock = MockClock::new();
+        let token = SharedToken::with_clock(None, clock.clone());
+        token.set_with_ttl(Some("t".to_string()), Some(Duration::from_secs(300)));
+        assert!(!token.expires_within(Duration::from_secs(60)));
+        clock.advance(Duration::from_secs(240));
+        assert!(token.expires_within(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_failed_refresh_keeps_token() {
+        let token = SharedToken::new(Some("old".to_string()));
+        let seen = token.generation();
+        assert!(token.refresh(seen, || Err(ClientError::new("denied"))).is_err());
+        assert_eq!(token.get_with_generation(), (Some("old".to_string()), seen));
+        assert_eq!(token.refresh(seen, || Ok(Some("new".to_string()))).unwrap(),
+                   Some("new".to_string()));
+    }
+
+    #[test]
+    fn test_token_events() {
+        let clock = MockClock::new();
+        let token = SharedToken::with_clock(None, clock.clone());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        token.clone().on_event(move |e| sink.lock().unwrap().push(e.clone()));
+
+        let ttl = Some(Duration::from_secs(300));
+        token.set_with_ttl(Some("t1".to_string()), ttl);
+        assert_eq!(token.check_expiring(Duration::from_secs(60)), None);
+        clock.advance(Duration::from_secs(270));
+        assert_eq!(token.check_expiring(Duration::from_secs(60)), Some(Duration::from_secs(30)));
+        assert!(token.check_expiring(Duration::from_secs(60)).is_some());
+        let seen = token.generation();
+        assert!(token.refresh(seen, || Err(ClientError::new("denied"))).is_err());
+        token.refresh_minted(seen, || Ok(MintedToken::new("t2".to_string(), ttl))).unwrap();
+        token.revoke();
+        token.revoke();
+
+        assert_eq!(*events.lock().unwrap(), vec![
+            TokenEvent::Acquired { generation: 1, ttl },
+            TokenEvent::Expiring { remaining: Duration::from_secs(30) },
+            TokenEvent::RefreshFailed { error: "denied".to_string() },
+            TokenEvent::Refreshed { generation: 2, ttl },
+            TokenEvent::Revoked,
+        ]);
+    }
+}
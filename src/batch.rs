@@ -0,0 +1,196 @@
+use futures::future::join_all;
+
+use *;
+use auth::auth_failure;
+
+/// How a bulk write is split up and retried
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct BatchOptions {
+    concurrency: usize,
+    retries: u32,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions { concurrency: 1, retries: 0 }
+    }
+}
+
+impl BatchOptions {
+    /// One batch at a time with no retries
+    pub fn new() -> Self {
+        BatchOptions::default()
+    }
+
+    /// Send up to `concurrency` batches at once
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Resend a failed batch up to `retries` more times - authentication and permission
+    /// failures are never retried
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+}
+
+/// Outcome of a bulk write for each item, in the order the items were given
+#[derive(Debug)]
+pub struct BatchReport {
+    results: Vec<Result<()>>,
+    requests: usize,
+}
+
+impl BatchReport {
+    /// Result for each item
+    pub fn results(&self) -> &[Result<()>] {
+        &self.results
+    }
+
+    /// Number of items that were written
+    pub fn succeeded(&self) -> usize {
+        self.results.iter().filter(|r| r.is_ok()).count()
+    }
+
+    /// Indices of the items that could not be written along with the errors
+    pub fn failed(&self) -> Vec<(usize, &ClientError)> {
+        self.results.iter().enumerate().filter_map(|(i, r)| r.as_ref().err().map(|e| (i, e))).collect()
+    }
+
+    /// True if every item was written
+    pub fn is_success(&self) -> bool {
+        self.results.iter().all(|r| r.is_ok())
+    }
+
+    /// Number of requests sent, including retries
+    pub fn requests(&self) -> usize {
+        self.requests
+    }
+}
+
+fn is_retryable(error: &ClientError) -> bool {
+    !matches!(*error.kind(), ErrorKind::AuthenticationFailed | ErrorKind::PermissionDenied)
+}
+
+/// Bulk writes split into batches - implemented for every `JsonApiClient`
+pub trait BatchApiClient<HTTP>: JsonApiClient<HTTP> where HTTP: HttpClient {
+    /// `POST` `items` to `uri` as JSON arrays of at most `batch_size` items, one batch at a
+    /// time - see `post_batched_with`
+    fn post_batched(&mut self, uri: Uri, items: Vec<Value>, batch_size: usize) -> Result<BatchReport> {
+        self.post_batched_with(uri, items, batch_size, &BatchOptions::new())
+    }
+
+    /// `POST` `items` to `uri` as JSON arrays of at most `batch_size` items
+    ///
+    /// A batch succeeds or fails as a whole, so every item in a batch gets the batch's result.
+    /// Only the failed batches are resent on retry. An error is returned only if the batching
+    /// itself is invalid; failed writes are reported per item.
+    fn post_batched_with(&mut self, uri: Uri, items: Vec<Value>, batch_size: usize,
+                         options: &BatchOptions) -> Result<BatchReport> {
+        if batch_size == 0 {
+            return Err(ClientError::new("Batch size must be greater than zero"));
+        }
+        let mut batches = Vec::new();
+        let mut items = items.into_iter().peekable();
+        while items.peek().is_some() {
+            let batch: Vec<Value> = items.by_ref().take(batch_size).collect();
+            batches.push((batch.len(), Value::Array(batch).to_string()));
+        }
+
+        let mut outcomes: Vec<Result<()>> = (0..batches.len()).map(|_| Ok(())).collect();
+        let mut pending: Vec<usize> = (0..batches.len()).collect();
+        let mut requests = 0;
+        for _ in 0..options.retries + 1 {
+            let mut failed = Vec::new();
+            for group in pending.chunks(options.concurrency) {
+                let mut futures = Vec::new();
+                for &i in group {
+                    let future = self.request_future(Method::Post, uri.clone(), Some(batches[i].1.clone()))
+                        .ok_or(ClientError::new("No request made"))?;
+                    futures.push(self.http_client().limit_concurrency(future).then(Ok::<_, ()>));
+                }
+                requests += group.len();
+                let responses = self.http_client_mut().evaluate_future(join_all(futures))
+                    .map_err(|_| ClientError::new("Failed to send batches"))?;
+                for (&i, response) in group.iter().zip(responses) {
+                    let outcome = match response {
+                        Ok(ref r) if r.status().is_success() => Ok(()),
+                        Ok(r) => {
+                            let status = r.status();
+                            let kind = auth_failure(status).unwrap_or(ErrorKind::Other);
+                            let message = match self.response_to_json(r) {
+                                Ok(body) => format!("Batch of {} items failed with status {}: {}", batches[i].0, status, body),
+                                Err(_) => format!("Batch of {} items failed with status {}", batches[i].0, status),
+                            };
+                            Err(ClientError::with_kind(kind, message))
+                        },
+                        Err(e) => Err(ClientError::from(e)),
+                    };
+                    if outcome.as_ref().err().is_some_and(is_retryable) {
+                        failed.push(i);
+                    }
+                    outcomes[i] = outcome;
+                }
+            }
+            if failed.is_empty() {
+                break;
+            }
+            pending = failed;
+        }
+
+        let mut results = Vec::new();
+        for ((len, _), outcome) in batches.iter().zip(outcomes) {
+            for _ in 0..*len {
+                results.push(match outcome {
+                    Ok(()) => Ok(()),
+                    Err(ref e) => Err(ClientError::with_kind(e.kind().clone(), e.to_string())),
+                });
+            }
+        }
+        Ok(BatchReport { results, requests })
+    }
+}
+
+impl<HTTP, C> BatchApiClient<HTTP> for C where HTTP: HttpClient, C: JsonApiClient<HTTP> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testing::{Fixture,FixtureServer,client_for};
+
+    #[test]
+    fn test_post_batched() {
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/bulk", Fixture::new(201))
+            .route(Method::Post, "/bulk", Fixture::json(500, r#"{"error": "busy"}"#))
+            .route(Method::Post, "/bulk", Fixture::new(201))
+            .route(Method::Post, "/flaky", Fixture::new(502))
+            .route(Method::Post, "/flaky", Fixture::new(201))
+            .route(Method::Post, "/denied", Fixture::json(403, r#"{"error": "forbidden"}"#))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let items: Vec<Value> = (0..5).map(Value::from).collect();
+        let report = client.post_batched("/bulk".parse().unwrap(), items.clone(), 2).unwrap();
+        assert_eq!(report.succeeded(), 3);
+        assert_eq!(report.failed().iter().map(|f| f.0).collect::<Vec<_>>(), vec![2, 3]);
+        assert!(report.failed()[0].1.to_string().contains("busy"));
+        assert_eq!(server.requests()[2].body, b"[4]".to_vec());
+
+        let report = client.post_batched_with("/flaky".parse().unwrap(), items.clone(), 2,
+                                              &BatchOptions::new().retries(2)).unwrap();
+        assert!(report.is_success());
+        assert_eq!(report.requests(), 4);
+        assert_eq!(server.requests()[6].body, b"[0,1]".to_vec());
+
+        let report = client.post_batched_with("/denied".parse().unwrap(), items, 2,
+                                              &BatchOptions::new().concurrency(3).retries(3)).unwrap();
+        assert_eq!(report.requests(), 3);
+        assert_eq!(report.failed().len(), 5);
+        assert_eq!(report.results()[4].as_ref().unwrap_err().kind(), &ErrorKind::PermissionDenied);
+
+        assert!(client.post_batched("/bulk".parse().unwrap(), Vec::new(), 2).unwrap().is_success());
+        assert!(client.post_batched("/bulk".parse().unwrap(), Vec::new(), 0).is_err());
+    }
+}
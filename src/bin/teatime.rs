@@ -0,0 +1,348 @@
+//! Command line client for the Gitlab, Vault and Sensu APIs
+//!
+//! ```text
+//! teatime gitlab projects list [--all] [--format text|json|table] [--select SELECTOR]
+//! teatime vault read PATH [--field NAME] [--format text|json|table] [--select SELECTOR]
+//! teatime sensu silence CLIENT [--expire SECONDS] [--reason TEXT]
+//! teatime complete gitlab|vault|sensu [PREFIX]
+//! ```
+//!
+//! `complete` prints Gitlab project paths, Vault secret paths or Sensu client names starting
+//! with `PREFIX` for use in shell completion scripts. Listings are cached for a minute under
+//! `$XDG_CACHE_HOME/teatime` or `~/.cache/teatime`.
+//!
+//! Servers and credentials are taken from the environment:
+//!
+//! * `GITLAB_URL` (default `https://gitlab.com/api/v4/`) and either `GITLAB_TOKEN` or
+//!   `GITLAB_USERNAME` and `GITLAB_PASSWORD`
+//! * `VAULT_ADDR` and either `VAULT_TOKEN`, `~/.vault-token` or `VAULT_USERNAME` and
+//!   `VAULT_PASSWORD` for LDAP login
+//! * `SENSU_URL`
+extern crate hyper;
+extern crate serde_json;
+extern crate teatime;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+use hyper::{Method,Uri};
+use serde_json::Value;
+
+use teatime::{ApiClient,ClientError,JsonApiClient,Result};
+use teatime::auth::{CredentialsProvider,EnvCredentials};
+use teatime::completion::NameCache;
+use teatime::gitlab::{GitlabClient,TokenType};
+use teatime::output::{self,OutputFormat,Table};
+use teatime::sensu::SensuClient;
+use teatime::vault::VaultClient;
+
+const USAGE: &str = "Usage:
+    teatime gitlab projects list [--all] [--format text|json|table] [--select SELECTOR]
+    teatime vault read PATH [--field NAME] [--format text|json|table] [--select SELECTOR]
+    teatime sensu silence CLIENT [--expire SECONDS] [--reason TEXT]
+    teatime complete gitlab|vault|sensu [PREFIX]";
+
+const DEFAULT_GITLAB_URL: &str = "https://gitlab.com/api/v4/";
+
+const COMPLETION_TTL: Duration = Duration::from_secs(60);
+
+/// Output format chosen with `--format`
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+enum Format {
+    Text,
+    Json,
+    Table,
+}
+
+/// Command line arguments split into positional arguments and `--name value` options
+#[derive(Debug,Default)]
+struct Args {
+    positional: Vec<String>,
+    options: Vec<(String, Option<String>)>,
+}
+
+/// Options that take no value
+const FLAGS: &[&str] = &["all", "help"];
+
+impl Args {
+    fn parse<I>(args: I) -> std::result::Result<Self, String> where I: IntoIterator<Item=String> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "-h" {
+                parsed.options.push(("help".to_string(), None));
+            } else if let Some(name) = arg.strip_prefix("--") {
+                let (name, value) = match name.find('=') {
+                    Some(i) => (name[..i].to_string(), Some(name[i + 1..].to_string())),
+                    None if FLAGS.contains(&name) => (name.to_string(), None),
+                    None => {
+                        let value = args.next().ok_or_else(|| format!("Option --{} needs a value", name))?;
+                        (name.to_string(), Some(value))
+                    },
+                };
+                parsed.options.push((name, value));
+            } else {
+                parsed.positional.push(arg);
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.options.iter().any(|o| o.0 == name)
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.options.iter().rev().find(|o| o.0 == name).and_then(|o| o.1.as_deref())
+    }
+
+    /// Fail on any option not in `known`
+    fn check_options(&self, known: &[&str]) -> std::result::Result<(), String> {
+        match self.options.iter().find(|o| !known.contains(&o.0.as_str())) {
+            Some(option) => Err(format!("Unknown option --{}", option.0)),
+            None => Ok(()),
+        }
+    }
+
+    fn format(&self) -> std::result::Result<Format, String> {
+        match self.value("format") {
+            None | Some("text") => Ok(Format::Text),
+            Some("json") => Ok(Format::Json),
+            Some("table") => Ok(Format::Table),
+            Some(other) => Err(format!("Unknown format {}", other)),
+        }
+    }
+}
+
+/// Why a command failed
+#[derive(Debug)]
+enum Failure {
+    /// The command line was invalid
+    Usage(String),
+    /// The API request failed
+    Api(ClientError),
+}
+
+impl From<ClientError> for Failure {
+    fn from(e: ClientError) -> Self {
+        Failure::Api(e)
+    }
+}
+
+impl From<String> for Failure {
+    fn from(e: String) -> Self {
+        Failure::Usage(e)
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+fn required_env_var(name: &str) -> Result<String> {
+    env_var(name).ok_or_else(|| ClientError::new(format!("Environment variable {} must be set", name)))
+}
+
+fn gitlab_client() -> Result<GitlabClient> {
+    let url = env_var("GITLAB_URL").unwrap_or_else(|| DEFAULT_GITLAB_URL.to_string());
+    let mut builder = GitlabClient::builder(url);
+    if let Some(token) = env_var("GITLAB_TOKEN") {
+        builder = builder.token(TokenType::PersonalAccess(token.into()));
+    }
+    let mut client = builder.build()?;
+    if env_var("GITLAB_TOKEN").is_none() && env_var("GITLAB_USERNAME").is_some() {
+        let creds = EnvCredentials::user_pass("GITLAB_USERNAME", "GITLAB_PASSWORD").get()?;
+        client.login(&creds)?;
+    }
+    Ok(client)
+}
+
+fn vault_token_file() -> Option<String> {
+    let home = env::var_os("HOME")?;
+    let token = fs::read_to_string(PathBuf::from(home).join(".vault-token")).ok()?;
+    Some(token.trim().to_string()).filter(|t| !t.is_empty())
+}
+
+fn vault_client() -> Result<VaultClient> {
+    let mut builder = VaultClient::builder(required_env_var("VAULT_ADDR")?);
+    let token = env_var("VAULT_TOKEN").or_else(vault_token_file);
+    let login = token.is_none() && env_var("VAULT_USERNAME").is_some();
+    if let Some(token) = token {
+        builder = builder.token(token);
+    }
+    let mut client = builder.build()?;
+    if login {
+        let creds = EnvCredentials::user_pass("VAULT_USERNAME", "VAULT_PASSWORD").get()?;
+        client.login(&creds)?;
+    }
+    Ok(client)
+}
+
+fn sensu_client() -> Result<SensuClient> {
+    SensuClient::builder(required_env_var("SENSU_URL")?).build()
+}
+
+/// Print `value` in the format chosen on the command line, narrowed by `--select` if given
+///
+/// `text` prints the values at `lines` one per line, or indented JSON if there is no default
+/// selection, and `table` shows `columns`.
+fn print_value(value: &Value, args: &Args, lines: Option<&str>, columns: &[&str])
+        -> std::result::Result<(), Failure> {
+    let selector = args.value("select");
+    let (format, selector) = match args.format()? {
+        Format::Json => (OutputFormat::Json, selector),
+        Format::Table => (OutputFormat::Table(Table::pointers(columns)), selector),
+        Format::Text if lines.is_none() && selector.is_none() => (OutputFormat::Json, None),
+        Format::Text => (OutputFormat::Lines, selector.or(lines)),
+    };
+    print!("{}", output::render(value, &format, selector)?);
+    Ok(())
+}
+
+fn gitlab_projects_list(args: &Args) -> std::result::Result<(), Failure> {
+    args.check_options(&["all", "format", "select"])?;
+    args.format()?;
+    let uri = if args.flag("all") { "/projects?per_page=100" } else { "/projects?membership=true&per_page=100" };
+    let uri = uri.parse::<Uri>().map_err(ClientError::from)?;
+    let mut client = gitlab_client()?;
+    let projects = client.autopagination(Method::Get, uri, None::<Value>)?;
+    print_value(&projects, args, Some("$[*].path_with_namespace"), &["/id", "/path_with_namespace", "/visibility"])
+}
+
+fn vault_read(args: &Args) -> std::result::Result<(), Failure> {
+    args.check_options(&["field", "format", "select"])?;
+    args.format()?;
+    let path = match args.positional.get(2) {
+        Some(path) if args.positional.len() == 3 => path,
+        _ => return Err(Failure::Usage("vault read takes exactly one PATH".to_string())),
+    };
+    let mut client = vault_client()?;
+    let secret = client.read_secret(path)?;
+    let data = secret.get("data").cloned().unwrap_or(Value::Null);
+    match args.value("field") {
+        Some(field) => match data.get(field) {
+            Some(Value::String(s)) => println!("{}", s),
+            Some(value) => println!("{}", value),
+            None => return Err(ClientError::new(format!("Secret {} has no field {}", path, field)).into()),
+        },
+        None if args.format()? == Format::Table => {
+            let rows = data.as_object().map(|m| m.iter().map(|(k, v)| {
+                let mut row = serde_json::Map::new();
+                row.insert("key".to_string(), Value::from(k.clone()));
+                row.insert("value".to_string(), v.clone());
+                Value::Object(row)
+            }).collect()).unwrap_or_default();
+            print_value(&Value::Array(rows), args, None, &["/key", "/value"])?;
+        },
+        None => print_value(&data, args, None, &[])?,
+    }
+    Ok(())
+}
+
+fn sensu_silence(args: &Args) -> std::result::Result<(), Failure> {
+    args.check_options(&["expire", "reason"])?;
+    let client_name = match args.positional.get(2) {
+        Some(name) if args.positional.len() == 3 => name,
+        _ => return Err(Failure::Usage("sensu silence takes exactly one CLIENT".to_string())),
+    };
+    let expire = match args.value("expire") {
+        Some(secs) => Some(Duration::from_secs(secs.parse().map_err(|_| {
+            format!("Invalid --expire value {}", secs)
+        })?)),
+        None => None,
+    };
+    let mut client = sensu_client()?;
+    client.silence_client(client_name, expire, args.value("reason"))?;
+    println!("Silenced {}", client_name);
+    Ok(())
+}
+
+fn completion_cache() -> Result<NameCache> {
+    let dir = env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .ok_or_else(|| ClientError::new("Neither XDG_CACHE_HOME nor HOME is set"))?;
+    NameCache::new(COMPLETION_TTL).persist_to(dir.join("teatime"))
+}
+
+fn complete(args: &Args) -> std::result::Result<(), Failure> {
+    args.check_options(&[])?;
+    if args.positional.len() > 3 {
+        return Err(Failure::Usage("complete takes at most one PREFIX".to_string()));
+    }
+    let prefix = args.positional.get(2).map(|p| p.as_str()).unwrap_or("");
+    let mut cache = completion_cache()?;
+    let names = match args.positional[1].as_str() {
+        "gitlab" => gitlab_client()?.complete_project_paths(&mut cache, prefix)?,
+        "vault" => vault_client()?.complete_secret_paths(&mut cache, prefix)?,
+        "sensu" => sensu_client()?.complete_client_names(&mut cache, prefix)?,
+        other => return Err(Failure::Usage(format!("Cannot complete {}", other))),
+    };
+    for name in names {
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+fn run(args: &Args) -> std::result::Result<(), Failure> {
+    let command: Vec<&str> = args.positional.iter().map(|s| s.as_str()).collect();
+    match command.as_slice() {
+        ["gitlab", "projects", "list"] => gitlab_projects_list(args),
+        ["vault", "read", ..] => vault_read(args),
+        ["sensu", "silence", ..] => sensu_silence(args),
+        ["complete", _, ..] => complete(args),
+        _ => Err(Failure::Usage("Unknown command".to_string())),
+    }
+}
+
+fn main() {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        },
+    };
+    if args.flag("help") {
+        println!("{}", USAGE);
+        return;
+    }
+    match run(&args) {
+        Ok(()) => (),
+        Err(Failure::Usage(e)) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        },
+        Err(Failure::Api(e)) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(line: &str) -> std::result::Result<Args, String> {
+        Args::parse(line.split_whitespace().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let parsed = args("sensu silence web-1 --expire 60 --reason=deploy").unwrap();
+        assert_eq!(parsed.positional, vec!["sensu", "silence", "web-1"]);
+        assert_eq!(parsed.value("expire"), Some("60"));
+        assert_eq!(parsed.value("reason"), Some("deploy"));
+        assert!(parsed.check_options(&["expire", "reason"]).is_ok());
+        assert!(parsed.check_options(&["expire"]).is_err());
+
+        let parsed = args("gitlab projects list --all --format json").unwrap();
+        assert!(parsed.flag("all"));
+        assert_eq!(parsed.format(), Ok(Format::Json));
+        assert!(args("gitlab projects list --format yaml").unwrap().format().is_err());
+        assert!(args("vault read secret/foo --field").is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use url::form_urlencoded;
+
+use *;
+use state::{FileStateStore,StateStore,RESPONSES};
+
+/// Whether a `ResponseCache` records responses or replays them
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum CacheMode {
+    /// Send every request and save its JSON response, replacing any earlier recording
+    Record,
+    /// Never touch the network - serve recorded responses and fail on a cache miss
+    Offline,
+}
+
+/// Recorded JSON responses keyed by normalized request, for running without network access
+///
+/// Requests are normalized before lookup: the method and scheme are compared case
+/// insensitively, query parameters and JSON object keys in the body are sorted, so equivalent
+/// requests share a recording. Only `request_json` consults the cache. Recordings are kept in
+/// the `responses` namespace of a `StateStore`.
+#[derive(Clone)]
+pub struct ResponseCache {
+    store: Arc<dyn StateStore>,
+    mode: CacheMode,
+}
+
+impl fmt::Debug for ResponseCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResponseCache").field("mode", &self.mode).finish()
+    }
+}
+
+/// FNV-1a, chosen over `DefaultHasher` because file names must not change between Rust
+/// releases
+pub(crate) fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Normalized form of a request used as its cache key
+pub fn cache_key(method: &Method, uri: &Uri, body: Option<&str>) -> String {
+    let mut key = method.to_string().to_uppercase();
+    key.push(' ');
+    if let Some(scheme) = uri.scheme() {
+        key.push_str(&scheme.to_lowercase());
+        key.push_str("://");
+    }
+    if let Some(authority) = uri.authority() {
+        key.push_str(&authority.to_lowercase());
+    }
+    key.push_str(uri.path());
+    if let Some(query) = uri.query() {
+        let mut pairs: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        pairs.sort();
+        key.push('?');
+        key.push_str(&form_urlencoded::Serializer::new(String::new()).extend_pairs(pairs).finish());
+    }
+    if let Some(body) = body {
+        key.push('\n');
+        match serde_json::from_str::<Value>(body) {
+            Ok(json) => key.push_str(&json.to_string()),
+            Err(_) => key.push_str(body),
+        }
+    }
+    key
+}
+
+impl ResponseCache {
+    /// Record responses into `dir`, creating it if needed
+    pub fn record<P>(dir: P) -> Result<Self> where P: AsRef<Path> {
+        fs::create_dir_all(dir.as_ref()).map_err(|e| {
+            ClientError::new(format!("Failed to create cache directory {}: {}", dir.as_ref().display(), e))
+        })?;
+        Ok(ResponseCache::with_store(Arc::new(FileStateStore::new(dir)), CacheMode::Record))
+    }
+
+    /// Serve responses recorded in `dir` without making requests
+    pub fn offline<P>(dir: P) -> Result<Self> where P: AsRef<Path> {
+        if !dir.as_ref().is_dir() {
+            return Err(ClientError::new(format!("Cache directory {} does not exist", dir.as_ref().display())));
+        }
+        Ok(ResponseCache::with_store(Arc::new(FileStateStore::new(dir)), CacheMode::Offline))
+    }
+
+    /// Record responses into or serve them from `store`
+    pub fn with_store(store: Arc<dyn StateStore>, mode: CacheMode) -> Self {
+        ResponseCache { store, mode }
+    }
+
+    /// Whether responses are recorded or replayed
+    pub fn mode(&self) -> CacheMode {
+        self.mode
+    }
+
+    /// In offline mode, the recorded response for a request, failing if there is none - always
+    /// `None` when recording
+    pub fn lookup(&self, method: &Method, uri: &Uri, body: Option<&str>) -> Result<Option<Value>> {
+        if self.mode == CacheMode::Record {
+            return Ok(None);
+        }
+        let key = cache_key(method, uri, body);
+        let recorded = self.store.get(RESPONSES, &key)?.ok_or_else(|| {
+            ClientError::new(format!("No recorded response for {} in offline mode", key.lines().next().unwrap_or("")))
+        })?;
+        Ok(Some(serde_json::from_slice(&recorded)?))
+    }
+
+    /// When recording, save `response` as the recording for a request
+    pub fn store(&self, method: &Method, uri: &Uri, body: Option<&str>, response: &Value) -> Result<()> {
+        if self.mode == CacheMode::Offline {
+            return Ok(());
+        }
+        self.store.put(RESPONSES, &cache_key(method, uri, body), response.to_string().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testing::{Fixture,FixtureServer,client_for};
+
+    #[test]
+    fn test_cache_key_normalization() {
+        let a = cache_key(&Method::Get, &"HTTPS://GitLab.example.com/api/v4/projects?b=2&a=1".parse().unwrap(),
+                          Some(r#"{"y": 1, "x": [2, 3]}"#));
+        let b = cache_key(&Method::Get, &"https://gitlab.example.com/api/v4/projects?a=1&b=2".parse().unwrap(),
+                          Some(r#"{"x":[2,3],"y":1}"#));
+        assert_eq!(a, b);
+        assert_ne!(a, cache_key(&Method::Post, &"https://gitlab.example.com/api/v4/projects?a=1&b=2".parse().unwrap(),
+                                Some(r#"{"x":[2,3],"y":1}"#)));
+        assert_ne!(cache_key(&Method::Get, &"/Projects".parse().unwrap(), None),
+                   cache_key(&Method::Get, &"/projects".parse().unwrap(), None));
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_offline_response_cache() {
+        use std::{env,fs,process};
+
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/projects", Fixture::json(200, r#"[{"id": 1}]"#))
+            .route(Method::Post, "/search", Fixture::json(200, r#"{"hits": 3}"#))
+            .start().unwrap();
+        let dir = env::temp_dir().join(format!("teatime-cache-{}", process::id()));
+        let mut http = SimpleHttpClient::new().unwrap();
+        http.set_response_cache(Some(ResponseCache::record(&dir).unwrap()));
+        let mut client = client_for(&server).with_http(http);
+        client.request_json(Method::Get, "/projects?b=2&a=1".parse().unwrap(), None::<Value>).unwrap();
+        client.request_json(Method::Post, "/search".parse().unwrap(), Some(RawBody(r#"{"q": "x", "n": 1}"#.to_string())))
+            .unwrap();
+        assert_eq!(server.requests().len(), 2);
+
+        client.http_client_mut().set_response_cache(Some(ResponseCache::offline(&dir).unwrap()));
+        let projects = client.request_json(Method::Get, "/projects?a=1&b=2".parse().unwrap(), None::<Value>).unwrap();
+        assert_eq!(projects[0]["id"], 1);
+        let search = client.request_json(Method::Post, "/search".parse().unwrap(),
+                                         Some(RawBody(r#"{"n":1,"q":"x"}"#.to_string()))).unwrap();
+        assert_eq!(search["hits"], 3);
+        let err = client.request_json(Method::Get, "/groups".parse().unwrap(), None::<Value>).unwrap_err();
+        assert!(err.to_string().contains("No recorded response for GET"));
+        assert_eq!(server.requests().len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(ResponseCache::offline(&dir).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+use std::borrow::Cow;
+
+use encoding_rs::{Encoding,UTF_8};
+use hyper::Response;
+use hyper::header::ContentType;
+use hyper::mime::{self,Mime};
+
+use *;
+
+/// Look up the encoding named by the `charset` parameter of a `Content-Type`, defaulting to
+/// UTF-8 when there is none
+pub fn encoding_for(content_type: Option<&Mime>) -> Result<&'static Encoding> {
+    match content_type.and_then(|m| m.get_param(mime::CHARSET)) {
+        Some(label) => Encoding::for_label(label.as_str().as_bytes()).ok_or_else(|| {
+            ClientError::new(format!("Unsupported response charset {}", label))
+        }),
+        None => Ok(UTF_8),
+    }
+}
+
+/// Decode a body in the charset given by its `Content-Type`
+pub fn decode<'a>(bytes: &'a [u8], content_type: Option<&Mime>) -> Result<Cow<'a, str>> {
+    let encoding = encoding_for(content_type)?;
+    encoding.decode_without_bom_handling_and_without_replacement(bytes).ok_or_else(|| {
+        ClientError::new(format!("Response body is not valid {}", encoding.name()))
+    })
+}
+
+/// Transcode a body to UTF-8, borrowing it unchanged if it is already UTF-8
+///
+/// Bodies claiming to be UTF-8 are passed through without validation so that the caller can
+/// report invalid data itself.
+pub fn to_utf8<'a>(bytes: &'a [u8], content_type: Option<&Mime>) -> Result<Cow<'a, [u8]>> {
+    let encoding = encoding_for(content_type)?;
+    if encoding == UTF_8 {
+        return Ok(Cow::Borrowed(bytes));
+    }
+    Ok(match decode(bytes, content_type)? {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    })
+}
+
+/// Text responses in any charset - implemented for every `ApiClient`
+///
+/// `JsonApiClient::response_to_json` also transcodes bodies when this feature is enabled.
+pub trait CharsetApiClient<HTTP>: ApiClient<HTTP> where HTTP: HttpClient {
+    /// Decode a response body to a string using the charset from its `Content-Type`
+    fn response_to_text(&mut self, response: Response) -> Result<String> {
+        let content_type = response.headers().get::<ContentType>().map(|c| c.0.clone());
+        let chunk = self.http_client_mut().evaluate_future(response.body().concat2())?;
+        decode(&chunk, content_type.as_ref()).map(Cow::into_owned)
+    }
+}
+
+impl<HTTP, C> CharsetApiClient<HTTP> for C where HTTP: HttpClient, C: ApiClient<HTTP> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transcode_latin1() {
+        let latin1: Mime = "application/json; charset=ISO-8859-1".parse().unwrap();
+        let body = b"{\"name\": \"Jos\xe9\"}";
+        assert_eq!(decode(body, Some(&latin1)).unwrap(), "{\"name\": \"Jos\u{e9}\"}");
+        let utf8 = to_utf8(body, Some(&latin1)).unwrap();
+        assert_eq!(serde_json::from_slice::<Value>(&utf8).unwrap()["name"], "Jos\u{e9}");
+
+        let plain = mime::APPLICATION_JSON;
+        assert!(matches!(to_utf8(b"{}", Some(&plain)).unwrap(), Cow::Borrowed(_)));
+        assert!(decode(b"\xff", None).is_err());
+        let unknown: Mime = "text/plain; charset=x-unknown".parse().unwrap();
+        assert!(encoding_for(Some(&unknown)).is_err());
+    }
+}
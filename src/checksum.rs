@@ -0,0 +1,462 @@
+use std::io::{Read,Seek,SeekFrom,Write};
+use std::sync::{Arc,Mutex};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hyper::{Response,StatusCode};
+use hyper::header::{ETag,Headers};
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest,Sha256};
+
+use *;
+use download::DownloadApiClient;
+
+/// Hash algorithms supported for integrity checks
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum ChecksumAlgorithm {
+    /// MD5 - only suitable for detecting corruption
+    Md5,
+    /// SHA1
+    Sha1,
+    /// SHA256
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    /// Name of the algorithm as used in error messages
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ChecksumAlgorithm::Md5 => "MD5",
+            ChecksumAlgorithm::Sha1 => "SHA1",
+            ChecksumAlgorithm::Sha256 => "SHA256",
+        }
+    }
+}
+
+/// An expected digest of some data
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Checksum {
+    algorithm: ChecksumAlgorithm,
+    hex: String,
+}
+
+impl Checksum {
+    /// Create a checksum from a hex encoded digest
+    pub fn new<S>(algorithm: ChecksumAlgorithm, hex: S) -> Self where S: AsRef<str> {
+        Checksum { algorithm, hex: hex.as_ref().trim().to_lowercase() }
+    }
+
+    /// Expected MD5 digest in hex
+    pub fn md5<S>(hex: S) -> Self where S: AsRef<str> {
+        Checksum::new(ChecksumAlgorithm::Md5, hex)
+    }
+
+    /// Expected SHA1 digest in hex
+    pub fn sha1<S>(hex: S) -> Self where S: AsRef<str> {
+        Checksum::new(ChecksumAlgorithm::Sha1, hex)
+    }
+
+    /// Expected SHA256 digest in hex
+    pub fn sha256<S>(hex: S) -> Self where S: AsRef<str> {
+        Checksum::new(ChecksumAlgorithm::Sha256, hex)
+    }
+
+    /// Algorithm of the digest
+    pub fn algorithm(&self) -> ChecksumAlgorithm {
+        self.algorithm
+    }
+
+    /// Hex encoded digest
+    pub fn hex(&self) -> &str {
+        &self.hex
+    }
+
+    /// Find the checksum a server advertised for a response body
+    ///
+    /// `Content-MD5` is preferred. Otherwise a strong `ETag` that is exactly an MD5 hex digest,
+    /// as returned by S3-compatible stores for single part uploads, is used.
+    pub fn from_headers(headers: &Headers) -> Option<Self> {
+        let content_md5 = headers.get_raw("Content-MD5").and_then(|raw| raw.one())
+            .and_then(|bytes| BASE64.decode(bytes).ok())
+            .filter(|digest| digest.len() == 16);
+        if let Some(digest) = content_md5 {
+            return Some(Checksum::md5(to_hex(&digest)));
+        }
+        headers.get::<ETag>().filter(|etag| !etag.0.weak)
+            .map(|etag| etag.0.tag())
+            .filter(|tag| tag.len() == 32 && tag.chars().all(|c| c.is_ascii_hexdigit()))
+            .map(Checksum::md5)
+    }
+
+    /// Compare against the hex digest of the data received
+    pub fn verify(&self, actual: &str) -> Result<()> {
+        if self.hex == actual {
+            Ok(())
+        } else {
+            Err(ClientError::with_kind(
+                ErrorKind::ChecksumMismatch {
+                    algorithm: self.algorithm.name().to_string(),
+                    expected: self.hex.clone(),
+                    actual: actual.to_string(),
+                },
+                format!("{} checksum mismatch: expected {} but received {}",
+                        self.algorithm.name(), self.hex, actual)
+            ))
+        }
+    }
+}
+
+enum HasherInner {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+/// Incremental hasher for one of the supported algorithms
+pub struct Hasher(HasherInner);
+
+impl Hasher {
+    /// Create a new hasher
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        Hasher(match algorithm {
+            ChecksumAlgorithm::Md5 => HasherInner::Md5(Md5::new()),
+            ChecksumAlgorithm::Sha1 => HasherInner::Sha1(Sha1::new()),
+            ChecksumAlgorithm::Sha256 => HasherInner::Sha256(Sha256::new()),
+        })
+    }
+
+    /// Add data to the digest
+    pub fn update(&mut self, data: &[u8]) {
+        match self.0 {
+            HasherInner::Md5(ref mut h) => h.update(data),
+            HasherInner::Sha1(ref mut h) => h.update(data),
+            HasherInner::Sha256(ref mut h) => h.update(data),
+        }
+    }
+
+    /// Hex encoded digest of all data added
+    pub fn finish(self) -> String {
+        match self.0 {
+            HasherInner::Md5(h) => to_hex(&h.finalize()),
+            HasherInner::Sha1(h) => to_hex(&h.finalize()),
+            HasherInner::Sha256(h) => to_hex(&h.finalize()),
+        }
+    }
+}
+
+/// Writer that hashes everything written through it
+pub struct ChecksumWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W> ChecksumWriter<W> where W: Write {
+    /// Wrap a writer
+    pub fn new(inner: W, algorithm: ChecksumAlgorithm) -> Self {
+        ChecksumWriter { inner, hasher: Hasher::new(algorithm) }
+    }
+
+    /// Return the wrapped writer and the hex digest of the data written
+    pub fn finish(self) -> (W, String) {
+        (self.inner, self.hasher.finish())
+    }
+}
+
+impl<W> Write for ChecksumWriter<W> where W: Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// MD5, SHA1 and SHA256 digests of the same data
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Checksums {
+    /// Hex encoded MD5 digest
+    pub md5: String,
+    /// Hex encoded SHA1 digest
+    pub sha1: String,
+    /// Hex encoded SHA256 digest
+    pub sha256: String,
+}
+
+impl Checksums {
+    /// Read all data from `reader`, computing every digest in a single pass without keeping the
+    /// data, and return the number of bytes read along with the digests
+    pub fn compute<R>(mut reader: R) -> io::Result<(u64, Self)> where R: Read {
+        let mut md5 = Hasher::new(ChecksumAlgorithm::Md5);
+        let mut sha1 = Hasher::new(ChecksumAlgorithm::Sha1);
+        let mut sha256 = Hasher::new(ChecksumAlgorithm::Sha256);
+        let mut total = 0;
+        let mut buf = [0u8; 8192];
+        loop {
+            let len = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            md5.update(&buf[..len]);
+            sha1.update(&buf[..len]);
+            sha256.update(&buf[..len]);
+            total += len as u64;
+        }
+        Ok((total, Checksums { md5: md5.finish(), sha1: sha1.finish(), sha256: sha256.finish() }))
+    }
+
+    /// Set the `X-Checksum-*` headers used by artifact stores to verify uploads
+    pub fn set_headers(&self, overrides: &mut RequestOverrides) {
+        overrides.set_raw_header("X-Checksum-Md5", self.md5.clone())
+                 .set_raw_header("X-Checksum-Sha1", self.sha1.clone())
+                 .set_raw_header("X-Checksum-Sha256", self.sha256.clone());
+    }
+}
+
+/// True if a response is a `409 Conflict` whose JSON error names a checksum mismatch
+///
+/// Only the `message`, `error` and `code` fields of the body or of its `errors` are checked,
+/// as sent by Artifactory and Nexus, so a conflict over a path or version that merely contains
+/// "checksum" is not mistaken for one.
+pub fn is_checksum_conflict(status: StatusCode, body: &[u8]) -> bool {
+    if status != StatusCode::Conflict {
+        return false;
+    }
+    let error: Value = match serde_json::from_slice(body) {
+        Ok(error) => error,
+        Err(_) => return false,
+    };
+    let names_checksum = |error: &Value| ["message", "error", "code"].iter().any(|field| {
+        error.get(field).and_then(Value::as_str).is_some_and(|s| s.to_lowercase().contains("checksum"))
+    });
+    names_checksum(&error) || error.get("errors").and_then(Value::as_array)
+        .is_some_and(|errors| errors.iter().any(names_checksum))
+}
+
+/// One attempt at reading an upload shared between attempts
+///
+/// Starting a new attempt rewinds the reader, after which reads of earlier attempts fail
+/// rather than steal data from it.
+struct UploadAttempt<R> {
+    shared: Arc<Mutex<(R, u64)>>,
+    attempt: u64,
+}
+
+impl<R> UploadAttempt<R> where R: Seek {
+    fn next(shared: &Arc<Mutex<(R, u64)>>, start: u64) -> io::Result<Self> {
+        let mut guard = shared.lock().map_err(|_| io::Error::other("Upload reader poisoned"))?;
+        guard.0.seek(SeekFrom::Start(start))?;
+        guard.1 += 1;
+        Ok(UploadAttempt { shared: shared.clone(), attempt: guard.1 })
+    }
+}
+
+impl<R> Read for UploadAttempt<R> where R: Read {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut guard = self.shared.lock().map_err(|_| io::Error::other("Upload reader poisoned"))?;
+        if guard.1 != self.attempt {
+            return Err(io::Error::other("Upload attempt superseded"));
+        }
+        guard.0.read(buf)
+    }
+}
+
+/// Download helpers that verify the integrity of the data received - implemented for every
+/// `ApiClient`
+pub trait ChecksumApiClient<HTTP>: DownloadApiClient<HTTP> where HTTP: HttpClient {
+    /// Download the body of a `GET` request into `writer` and verify it against `expected`, or
+    /// against the checksum advertised in the response headers if `expected` is `None`
+    ///
+    /// Data is written as it arrives, so on a mismatch the caller should discard what was
+    /// written. Fails with `ErrorKind::ChecksumMismatch` if the digests differ.
+    fn download_verified<W>(&mut self, uri: Uri, writer: &mut W, expected: Option<Checksum>)
+            -> Result<u64> where W: Write {
+        let response = self.request(Method::Get, uri, None::<String>)?;
+        if !response.status().is_success() {
+            return Err(ClientError::new(format!("Download failed with status {}", response.status())));
+        }
+        let expected = match expected.or_else(|| Checksum::from_headers(response.headers())) {
+            Some(c) => c,
+            None => { return Err(ClientError::new("No checksum available to verify download")); },
+        };
+        let mut checksum_writer = ChecksumWriter::new(writer, expected.algorithm());
+        let written = self.write_body(response, &mut checksum_writer)?;
+        let (_, actual) = checksum_writer.finish();
+        expected.verify(&actual)?;
+        Ok(written)
+    }
+
+    /// Download a file in chunks using `Range` requests and verify the assembled data
+    /// against `expected`
+    fn download_ranged_verified<W>(&mut self, uri: Uri, writer: &mut W, chunk_size: u64,
+                                   expected: &Checksum) -> Result<u64> where W: Write {
+        let mut checksum_writer = ChecksumWriter::new(writer, expected.algorithm());
+        let written = self.download_ranged(uri, &mut checksum_writer, chunk_size)?;
+        let (_, actual) = checksum_writer.finish();
+        expected.verify(&actual)?;
+        Ok(written)
+    }
+
+    /// Upload the data read from `reader` with checksum headers set, retrying up to
+    /// `max_attempts` times in total if the server rejects the upload with a checksum
+    /// mismatch `409 Conflict`
+    ///
+    /// The digests are computed in a first pass over `reader`, which is then rewound and
+    /// streamed for each attempt, so the upload is never held in memory. Any other response
+    /// is returned to the caller as is.
+    fn upload_with_checksums<R>(&mut self, method: Method, uri: Uri, mut reader: R, max_attempts: usize)
+            -> Result<Response> where R: Read + Seek + Send + 'static {
+        let read_error = |e: io::Error| ClientError::new(format!("Failed to read upload: {}", e));
+        let start = reader.stream_position().map_err(read_error)?;
+        let (len, checksums) = Checksums::compute(&mut reader).map_err(read_error)?;
+        let shared = Arc::new(Mutex::new((reader, 0)));
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let mut overrides = RequestOverrides::new();
+            checksums.set_headers(&mut overrides);
+            overrides.set_stream_body(UploadAttempt::next(&shared, start).map_err(read_error)?, Some(len));
+            let future = self.request_future_with_overrides(method.clone(), uri.clone(), overrides)?;
+            let response = self.response_future(future)?;
+            if response.status() != StatusCode::Conflict {
+                return Ok(response);
+            }
+            let status = response.status();
+            let body = self.http_client_mut().evaluate_future(response.body().concat2())?;
+            if !is_checksum_conflict(status, &body) {
+                return Err(ClientError::new(format!(
+                    "Upload failed with conflict: {}", String::from_utf8_lossy(&body)
+                )));
+            }
+            if attempt >= max_attempts {
+                return Err(ClientError::new(format!(
+                    "Upload checksum mismatch after {} attempts: {}", attempt,
+                    String::from_utf8_lossy(&body)
+                )));
+            }
+        }
+    }
+}
+
+impl<HTTP, C> ChecksumApiClient<HTTP> for C where HTTP: HttpClient, C: ApiClient<HTTP> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use hyper::header::EntityTag;
+    use testing::{Fixture,FixtureServer,client_for};
+
+    #[test]
+    fn test_checksum_writer() {
+        let mut writer = ChecksumWriter::new(Vec::new(), ChecksumAlgorithm::Sha256);
+        writer.write_all(b"tea").unwrap();
+        writer.write_all(b"time").unwrap();
+        let (data, digest) = writer.finish();
+        assert_eq!(data, b"teatime".to_vec());
+        let mut hasher = Hasher::new(ChecksumAlgorithm::Sha256);
+        hasher.update(b"teatime");
+        assert_eq!(digest, hasher.finish());
+        assert!(Checksum::sha256(digest.to_uppercase()).verify(&digest).is_ok());
+    }
+
+    #[test]
+    fn test_upload_checksums() {
+        let (len, checksums) = Checksums::compute(&b"teatime"[..]).unwrap();
+        assert_eq!(len, 7);
+        let mut md5 = Hasher::new(ChecksumAlgorithm::Md5);
+        md5.update(b"teatime");
+        assert_eq!(checksums.md5, md5.finish());
+        assert_eq!(checksums.sha1.len(), 40);
+        assert_eq!(checksums.sha256.len(), 64);
+
+        let mut overrides = RequestOverrides::new();
+        checksums.set_headers(&mut overrides);
+        assert!(overrides.headers().get_raw("X-Checksum-Sha256").is_some());
+        assert!(is_checksum_conflict(StatusCode::Conflict, b"{\"errors\":[{\"message\":\"Checksum error\"}]}"));
+        assert!(!is_checksum_conflict(StatusCode::Conflict, b"Checksum error"));
+        assert!(!is_checksum_conflict(StatusCode::Conflict,
+                                      b"{\"path\":\"libs/checksum-tool.jar\",\"message\":\"Version already exists\"}"));
+        assert!(!is_checksum_conflict(StatusCode::BadRequest, b"{\"message\":\"Checksum error\"}"));
+    }
+
+    #[test]
+    fn test_upload_with_checksums_conflict() {
+        let server = FixtureServer::builder()
+            .route(Method::Put, "/mismatch", Fixture::json(409, r#"{"errors":[{"status":409,"message":"Checksum error"}]}"#))
+            .route(Method::Put, "/exists", Fixture::json(409, r#"{"path":"libs/checksum-tool.jar","message":"Version already exists"}"#))
+            .route(Method::Put, "/ok", Fixture::new(201))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let (_, checksums) = Checksums::compute(&b"teatime"[..]).unwrap();
+
+        let response = client.upload_with_checksums(Method::Put, "/ok".parse().unwrap(),
+                                                    Cursor::new(b"teatime".to_vec()), 3).unwrap();
+        assert_eq!(response.status(), StatusCode::Created);
+        server.assert_sent().put("/ok").header("X-Checksum-Sha256", &checksums.sha256).body("teatime");
+
+        let err = client.upload_with_checksums(Method::Put, "/mismatch".parse().unwrap(),
+                                               Cursor::new(b"teatime".to_vec()), 3).unwrap_err();
+        assert!(err.to_string().starts_with("Upload checksum mismatch after 3 attempts"));
+        let attempts = server.requests().into_iter().filter(|r| r.path == "/mismatch")
+            .map(|r| r.body).collect::<Vec<_>>();
+        assert_eq!(attempts, vec![b"teatime".to_vec(); 3]);
+
+        let err = client.upload_with_checksums(Method::Put, "/exists".parse().unwrap(),
+                                               Cursor::new(b"teatime".to_vec()), 3).unwrap_err();
+        assert!(err.to_string().starts_with("Upload failed with conflict"));
+        server.assert_sent().count(5);
+    }
+
+    #[test]
+    fn test_download_ranged_verified() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/file", Fixture::new(206).body("01234").header("Content-Range", "bytes 0-4/10"))
+            .route(Method::Get, "/file", Fixture::new(206).body("56789").header("Content-Range", "bytes 5-9/10"))
+            .route(Method::Get, "/file", Fixture::new(206).body("0123X").header("Content-Range", "bytes 0-4/10"))
+            .route(Method::Get, "/file", Fixture::new(206).body("56789").header("Content-Range", "bytes 5-9/10"))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let expected = Checksum::sha256("84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882");
+        let mut data = Vec::new();
+        assert_eq!(client.download_ranged_verified("/file".parse().unwrap(), &mut data, 5, &expected).unwrap(), 10);
+        assert_eq!(data, b"0123456789".to_vec());
+
+        let err = client.download_ranged_verified("/file".parse().unwrap(), &mut Vec::new(), 5, &expected)
+            .unwrap_err();
+        match *err.kind() {
+            ErrorKind::ChecksumMismatch { ref algorithm, .. } => assert_eq!(algorithm, "SHA256"),
+            ref other => panic!("Unexpected error kind {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_verify_mismatch_kind() {
+        let err = Checksum::md5("00").verify("ff").unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::ChecksumMismatch {
+            algorithm: "MD5".to_string(),
+            expected: "00".to_string(),
+            actual: "ff".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_checksum_from_headers() {
+        let mut headers = Headers::new();
+        headers.set(ETag(EntityTag::strong("9e107d9d372bb6826bd81d3542a419d6".to_string())));
+        assert_eq!(Checksum::from_headers(&headers),
+                   Some(Checksum::md5("9e107d9d372bb6826bd81d3542a419d6")));
+        headers.set_raw("Content-MD5", "nhB9nTcrtoJr2B01QqQZ1g==");
+        assert_eq!(Checksum::from_headers(&headers),
+                   Some(Checksum::md5("9e107d9d372bb6826bd81d3542a419d6")));
+
+        let mut multipart = Headers::new();
+        multipart.set(ETag(EntityTag::strong("9e107d9d372bb6826bd81d3542a419d6-2".to_string())));
+        assert_eq!(Checksum::from_headers(&multipart), None);
+    }
+}
@@ -0,0 +1,134 @@
+use std::sync::{Arc,Mutex,MutexGuard};
+use std::thread;
+use std::time::{Duration,Instant,SystemTime};
+
+/// Source of the current time for features that depend on it, such as token lifetimes,
+/// retries and rate limiting
+pub trait Clock: Send + Sync {
+    /// Current monotonic time, used for measuring intervals
+    fn now(&self) -> Instant;
+    /// Current wall clock time, used for timestamps sent to servers
+    fn system_now(&self) -> SystemTime;
+}
+
+/// Waits for a period of time, for example between retries
+pub trait Sleeper: Send + Sync {
+    /// Block for `duration`
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real clock and `thread::sleep`
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+impl Sleeper for SystemClock {
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+#[derive(Debug)]
+struct MockState {
+    elapsed: Duration,
+    sleeps: Vec<Duration>,
+}
+
+/// Clock that only moves when told to, so tests of time based logic run instantly and
+/// deterministically
+///
+/// Sleeping advances the clock by the requested duration without blocking and records the
+/// duration so tests can check backoff schedules. Clones share the same time.
+#[derive(Clone,Debug)]
+pub struct MockClock {
+    start: Instant,
+    system_start: SystemTime,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::at(SystemTime::now())
+    }
+}
+
+impl MockClock {
+    /// Create a clock frozen at the current time
+    pub fn new() -> Self {
+        MockClock::default()
+    }
+
+    /// Create a clock frozen at wall clock time `system_start`
+    pub fn at(system_start: SystemTime) -> Self {
+        MockClock {
+            start: Instant::now(),
+            system_start,
+            state: Arc::new(Mutex::new(MockState { elapsed: Duration::from_secs(0), sleeps: Vec::new() })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Move the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        self.lock().elapsed += duration;
+    }
+
+    /// Total time the clock has moved since it was created
+    pub fn elapsed(&self) -> Duration {
+        self.lock().elapsed
+    }
+
+    /// Durations passed to `sleep`, in order
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.lock().sleeps.clone()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.system_start + self.elapsed()
+    }
+}
+
+impl Sleeper for MockClock {
+    fn sleep(&self, duration: Duration) {
+        let mut state = self.lock();
+        state.elapsed += duration;
+        state.sleeps.push(duration);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let system_start = SystemTime::now();
+        let clock = MockClock::at(system_start);
+        let start = clock.now();
+        let shared = clock.clone();
+        shared.advance(Duration::from_secs(10));
+        clock.sleep(Duration::from_millis(250));
+        clock.sleep(Duration::from_millis(500));
+        assert_eq!(clock.now() - start, Duration::from_millis(10750));
+        assert_eq!(clock.system_now(), system_start + Duration::from_millis(10750));
+        assert_eq!(shared.sleeps(), vec![Duration::from_millis(250), Duration::from_millis(500)]);
+    }
+}
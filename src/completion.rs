@@ -0,0 +1,160 @@
+use std::fs;
+use std::path::{Path,PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use *;
+use cache::fnv1a;
+use clock::{Clock,SystemClock};
+
+/// Names that start with `prefix`, sorted and without duplicates
+pub fn complete(names: &[String], prefix: &str) -> Vec<String> {
+    let mut matches = names.iter().filter(|n| n.starts_with(prefix)).cloned().collect::<Vec<_>>();
+    matches.sort();
+    matches.dedup();
+    matches
+}
+
+/// The string `field` of every object in the JSON array `list`
+pub fn names_at(list: &Value, field: &str) -> Vec<String> {
+    list.as_array().map(|items| {
+        items.iter().filter_map(|item| item.get(field).and_then(Value::as_str).map(str::to_string)).collect()
+    }).unwrap_or_default()
+}
+
+/// Short lived cache of name listings so shell completion does not wait on the API for every
+/// key press
+///
+/// Listings are kept in memory and, if `persist_to` is used, in a directory so that they
+/// survive between the separate processes a completion script starts. Persisting is best
+/// effort: unreadable or unwritable cache files are treated as a cache miss.
+pub struct NameCache {
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    entries: HashMap<String, (SystemTime, Vec<String>)>,
+    dir: Option<PathBuf>,
+}
+
+impl NameCache {
+    /// Keep listings for `ttl`
+    pub fn new(ttl: Duration) -> Self {
+        NameCache::with_clock(ttl, SystemClock)
+    }
+
+    /// Keep listings for `ttl` as measured by `clock`
+    pub fn with_clock<C>(ttl: Duration, clock: C) -> Self where C: Clock + 'static {
+        NameCache { ttl, clock: Arc::new(clock), entries: HashMap::new(), dir: None }
+    }
+
+    /// Also store listings as files in `dir`, creating it if needed
+    pub fn persist_to<P>(mut self, dir: P) -> Result<Self> where P: AsRef<Path> {
+        fs::create_dir_all(dir.as_ref()).map_err(|e| {
+            ClientError::new(format!("Failed to create completion cache {}: {}", dir.as_ref().display(), e))
+        })?;
+        self.dir = Some(dir.as_ref().to_path_buf());
+        Ok(self)
+    }
+
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(format!("{:016x}.json", fnv1a(key.as_bytes()))))
+    }
+
+    fn is_fresh(&self, fetched: SystemTime) -> bool {
+        self.clock.system_now().duration_since(fetched).is_ok_and(|age| age < self.ttl)
+    }
+
+    fn read_file(&self, key: &str) -> Option<(SystemTime, Vec<String>)> {
+        let text = fs::read_to_string(self.path(key)?).ok()?;
+        let json = serde_json::from_str::<Value>(&text).ok()?;
+        if json.get("key").and_then(Value::as_str) != Some(key) {
+            return None;
+        }
+        let fetched = UNIX_EPOCH + Duration::from_secs(json.get("fetched")?.as_u64()?);
+        let names = json.get("names")?.as_array()?.iter().filter_map(Value::as_str).map(str::to_string).collect();
+        Some((fetched, names))
+    }
+
+    fn write_file(&self, key: &str, fetched: SystemTime, names: &[String]) {
+        if let Some(path) = self.path(key) {
+            let mut json = serde_json::Map::new();
+            json.insert("key".to_string(), Value::from(key));
+            json.insert("fetched".to_string(),
+                        Value::from(fetched.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)));
+            json.insert("names".to_string(), Value::from(names.to_vec()));
+            let _ = fs::write(path, Value::Object(json).to_string());
+        }
+    }
+
+    /// The cached listing for `key` if it is younger than the TTL, otherwise the result of
+    /// `fetch`, which is cached on success
+    pub fn get_or_fetch<F>(&mut self, key: &str, fetch: F) -> Result<Vec<String>>
+            where F: FnOnce() -> Result<Vec<String>> {
+        if let Some(&(fetched, ref names)) = self.entries.get(key) {
+            if self.is_fresh(fetched) {
+                return Ok(names.clone());
+            }
+        }
+        if let Some((fetched, names)) = self.read_file(key) {
+            if self.is_fresh(fetched) {
+                self.entries.insert(key.to_string(), (fetched, names.clone()));
+                return Ok(names);
+            }
+        }
+        let names = fetch()?;
+        let now = self.clock.system_now();
+        self.write_file(key, now, &names);
+        self.entries.insert(key.to_string(), (now, names.clone()));
+        Ok(names)
+    }
+
+    /// Forget the listing for `key`
+    pub fn invalidate(&mut self, key: &str) {
+        self.entries.remove(key);
+        if let Some(path) = self.path(key) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use clock::MockClock;
+
+    #[test]
+    fn test_name_cache() {
+        let clock = MockClock::at(UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let dir = env::temp_dir().join(format!("teatime-completion-{}", process::id()));
+        let mut cache = NameCache::with_clock(Duration::from_secs(30), clock.clone()).persist_to(&dir).unwrap();
+        let fetches = Cell::new(0);
+        let fetch = || {
+            fetches.set(fetches.get() + 1);
+            Ok(vec!["web-2".to_string(), "db-1".to_string(), "web-1".to_string()])
+        };
+        let names = cache.get_or_fetch("clients", fetch).unwrap();
+        assert_eq!(complete(&names, "web"), vec!["web-1", "web-2"]);
+        clock.advance(Duration::from_secs(10));
+        cache.get_or_fetch("clients", fetch).unwrap();
+        assert_eq!(fetches.get(), 1);
+
+        // A new process sees the persisted listing until it expires
+        let mut other = NameCache::with_clock(Duration::from_secs(30), clock.clone()).persist_to(&dir).unwrap();
+        assert_eq!(other.get_or_fetch("clients", || Err(ClientError::new("offline"))).unwrap().len(), 3);
+        clock.advance(Duration::from_secs(30));
+        assert!(other.get_or_fetch("clients", || Err(ClientError::new("offline"))).is_err());
+        cache.get_or_fetch("clients", fetch).unwrap();
+        assert_eq!(fetches.get(), 2);
+
+        cache.invalidate("clients");
+        cache.get_or_fetch("clients", fetch).unwrap();
+        assert_eq!(fetches.get(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let list = serde_json::from_str(r#"[{"name": "a"}, {"name": 1}, {"id": 2}, {"name": "b"}]"#).unwrap();
+        assert_eq!(names_at(&list, "name"), vec!["a", "b"]);
+    }
+}
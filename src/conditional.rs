@@ -0,0 +1,191 @@
+use hyper::header::{ETag,EntityTag,IfMatch};
+
+use *;
+
+/// How a write proves the resource has not changed since it was read
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Precondition {
+    /// Send the `ETag` of the read back in `If-Match` - the server answers `412` if it changed
+    IfMatch,
+    /// Copy the top level version `field` of the read into the written body - the server
+    /// answers `409` or `412` if it changed
+    VersionField(String),
+}
+
+impl Precondition {
+    /// Version field strategy for `field`
+    pub fn version_field<S>(field: S) -> Self where S: Into<String> {
+        Precondition::VersionField(field.into())
+    }
+}
+
+/// A resource read along with what is needed to write it back conditionally
+#[derive(Clone,Debug,PartialEq)]
+pub struct Versioned {
+    /// Resource body
+    pub value: Value,
+    /// `ETag` of the response, if the server sent one
+    pub etag: Option<EntityTag>,
+}
+
+impl Versioned {
+    fn condition(&self, precondition: &Precondition, mut body: Value) -> Result<(Value, Headers)> {
+        let mut headers = Headers::new();
+        match *precondition {
+            Precondition::IfMatch => {
+                let etag = self.etag.clone().ok_or_else(|| {
+                    ClientError::new("Response has no ETag to make a conditional write with")
+                })?;
+                headers.set(IfMatch::Items(vec![etag]));
+            },
+            Precondition::VersionField(ref field) => {
+                let version = self.value.get(field).cloned().ok_or_else(|| {
+                    ClientError::new(format!("Response has no {} field to make a conditional write with", field))
+                })?;
+                match body {
+                    Value::Object(ref mut map) => { map.insert(field.clone(), version); },
+                    _ => return Err(ClientError::new("Conditional write body must be a JSON object")),
+                }
+            },
+        }
+        Ok((body, headers))
+    }
+}
+
+/// Read-modify-write helpers using `If-Match` or version fields - implemented for every
+/// `JsonApiClient`
+pub trait ConditionalApiClient<HTTP>: JsonApiClient<HTTP> where HTTP: HttpClient {
+    /// `GET` a resource, keeping its `ETag` for a later conditional write
+    fn get_versioned(&mut self, uri: Uri) -> Result<Versioned> {
+        let response = self.request(Method::Get, uri, None::<String>)?;
+        let status = response.status();
+        let etag = response.headers().get::<ETag>().map(|e| e.0.clone());
+        let value = self.response_to_json(response)?;
+        if !status.is_success() {
+            let kind = auth::auth_failure(status).unwrap_or(ErrorKind::Other);
+            return Err(ClientError::with_kind(kind, format!("Read failed with status {}: {}", status, value)));
+        }
+        Ok(Versioned { value, etag })
+    }
+
+    /// Write `body` to `uri` with `method` only if the resource is still as `read`
+    ///
+    /// Fails with `ErrorKind::PreconditionFailed` if the server reports that the resource has
+    /// changed, in which case it should be read again and the change reapplied.
+    fn write_if_unchanged(&mut self, method: Method, uri: Uri, read: &Versioned, body: Value,
+                          precondition: &Precondition) -> Result<Value> {
+        let (body, headers) = read.condition(precondition, body)?;
+        let response = self.request_with_headers(method, uri, Some(body.to_string()), &headers)?;
+        let status = response.status();
+        let value = self.response_to_json(response).unwrap_or(Value::Null);
+        match status {
+            StatusCode::PreconditionFailed => {},
+            StatusCode::Conflict if *precondition != Precondition::IfMatch => {},
+            s if s.is_success() => return Ok(value),
+            s => {
+                let kind = auth::auth_failure(s).unwrap_or(ErrorKind::Other);
+                return Err(ClientError::with_kind(kind, format!("Write failed with status {}: {}", s, value)));
+            },
+        }
+        Err(ClientError::with_kind(ErrorKind::PreconditionFailed,
+                                   format!("Resource changed since it was read: {}", value)))
+    }
+
+    /// Read the resource at `uri`, let `modify` change it and write it back with `method`
+    /// unless it changed in between
+    fn modify<F>(&mut self, method: Method, uri: Uri, precondition: &Precondition, modify: F) -> Result<Value>
+            where F: FnOnce(Value) -> Value {
+        let read = self.get_versioned(uri.clone())?;
+        let body = modify(read.value.clone());
+        self.write_if_unchanged(method, uri, &read, body, precondition)
+    }
+
+    /// Read `get_uri`, apply `mutate` and `PUT` the result to `put_uri`, reading again and
+    /// reapplying `mutate` whenever the write fails with `ErrorKind::PreconditionFailed`
+    ///
+    /// Gives up with the last conflict after `max_attempts` writes; any other error is
+    /// returned immediately.
+    fn update_with_retry<F>(&mut self, get_uri: Uri, mut mutate: F, put_uri: Uri, precondition: &Precondition,
+                            max_attempts: usize) -> Result<Value> where F: FnMut(Value) -> Value {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let read = self.get_versioned(get_uri.clone())?;
+            let body = mutate(read.value.clone());
+            match self.write_if_unchanged(Method::Put, put_uri.clone(), &read, body, precondition) {
+                Err(ref e) if *e.kind() == ErrorKind::PreconditionFailed && attempt < max_attempts => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<HTTP, C> ConditionalApiClient<HTTP> for C where HTTP: HttpClient, C: JsonApiClient<HTTP> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testing::{Fixture,FixtureServer,client_for};
+
+    #[test]
+    fn test_conditional_writes() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/settings", Fixture::json(200, r#"{"name": "a", "version": 7}"#).header("ETag", "\"v7\""))
+            .route(Method::Put, "/settings", Fixture::json(200, r#"{"name": "b", "version": 8}"#))
+            .route(Method::Put, "/settings", Fixture::json(412, r#"{"message": "stale"}"#))
+            .route(Method::Patch, "/settings", Fixture::json(409, r#"{"message": "version mismatch"}"#))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let updated = client.modify(Method::Put, "/settings".parse().unwrap(), &Precondition::IfMatch, |mut v| {
+            v["name"] = Value::from("b");
+            v
+        }).unwrap();
+        assert_eq!(updated["version"], 8);
+        assert_eq!(server.requests()[1].header("If-Match"), Some("\"v7\""));
+
+        let read = client.get_versioned("/settings".parse().unwrap()).unwrap();
+        let err = client.write_if_unchanged(Method::Put, "/settings".parse().unwrap(), &read,
+                                            serde_json::from_str(r#"{"name": "c"}"#).unwrap(),
+                                            &Precondition::IfMatch).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::PreconditionFailed);
+
+        let err = client.write_if_unchanged(Method::Patch, "/settings".parse().unwrap(), &read,
+                                            serde_json::from_str(r#"{"name": "c"}"#).unwrap(),
+                                            &Precondition::version_field("version")).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::PreconditionFailed);
+        let sent: Value = serde_json::from_slice(&server.requests()[4].body).unwrap();
+        assert_eq!(sent["version"], 7);
+        assert!(server.requests()[4].header("If-Match").is_none());
+
+        assert!(client.write_if_unchanged(Method::Put, "/settings".parse().unwrap(), &read, Value::from(1),
+                                          &Precondition::version_field("version")).is_err());
+        assert_eq!(server.requests().len(), 5);
+    }
+
+    #[test]
+    fn test_update_with_retry() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/kv", Fixture::json(200, r#"{"count": 1}"#).header("ETag", "\"1\""))
+            .route(Method::Get, "/kv", Fixture::json(200, r#"{"count": 2}"#).header("ETag", "\"2\""))
+            .route(Method::Put, "/kv", Fixture::json(412, "{}"))
+            .route(Method::Put, "/kv", Fixture::json(200, r#"{"count": 3}"#))
+            .route(Method::Get, "/busy", Fixture::json(200, "{}").header("ETag", "\"1\""))
+            .route(Method::Put, "/busy", Fixture::json(412, "{}"))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let mut seen = Vec::new();
+        let result = client.update_with_retry("/kv".parse().unwrap(), |mut v| {
+            seen.push(v["count"].as_u64().unwrap());
+            v["count"] = Value::from(v["count"].as_u64().unwrap() + 1);
+            v
+        }, "/kv".parse().unwrap(), &Precondition::IfMatch, 3).unwrap();
+        assert_eq!(result["count"], 3);
+        assert_eq!(seen, vec![1, 2]);
+        assert_eq!(server.requests()[3].header("If-Match"), Some("\"2\""));
+
+        let err = client.update_with_retry("/busy".parse().unwrap(), |v| v, "/busy".parse().unwrap(),
+                                           &Precondition::IfMatch, 2).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::PreconditionFailed);
+        assert_eq!(server.requests().len(), 8);
+    }
+}
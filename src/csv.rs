@@ -0,0 +1,136 @@
+use hyper::Response;
+use hyper::header::{Accept,qitem};
+use hyper::mime::Mime;
+use serde::de::DeserializeOwned;
+use csv_crate::{self,ReaderBuilder};
+
+use *;
+
+pub use csv_crate::StringRecord;
+
+fn csv_mime() -> Mime {
+    "text/csv".parse().expect("CSV MIME type is valid")
+}
+
+/// Options controlling how CSV response bodies are parsed
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct CsvOptions {
+    delimiter: u8,
+    has_headers: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions { delimiter: b',', has_headers: true }
+    }
+}
+
+impl CsvOptions {
+    /// Comma delimited with a header row
+    pub fn new() -> Self {
+        CsvOptions::default()
+    }
+
+    /// Set the field delimiter
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Set whether the first row is a header row - typed rows are matched to struct fields
+    /// by header name when this is set and by position otherwise
+    pub fn has_headers(mut self, has_headers: bool) -> Self {
+        self.has_headers = has_headers;
+        self
+    }
+
+    fn reader<'a>(&self, bytes: &'a [u8]) -> csv_crate::Reader<&'a [u8]> {
+        ReaderBuilder::new().delimiter(self.delimiter).has_headers(self.has_headers)
+            .from_reader(bytes)
+    }
+
+    /// Parse CSV into untyped records
+    pub fn parse_records(&self, bytes: &[u8]) -> Result<Vec<StringRecord>> {
+        self.reader(bytes).records().collect::<result::Result<Vec<_>, _>>().map_err(csv_error)
+    }
+
+    /// Parse CSV into typed rows
+    pub fn parse_rows<T>(&self, bytes: &[u8]) -> Result<Vec<T>> where T: DeserializeOwned {
+        self.reader(bytes).deserialize().collect::<result::Result<Vec<_>, _>>().map_err(csv_error)
+    }
+}
+
+fn csv_error(e: csv_crate::Error) -> ClientError {
+    ClientError::new(format!("Failed to parse CSV: {}", e))
+}
+
+/// CSV response flows for export and admin endpoints - implemented for every `ApiClient`
+///
+/// Requests are sent with `Accept: text/csv`. Any request body is sent as JSON.
+pub trait CsvApiClient<HTTP>: ApiClient<HTTP> where HTTP: HttpClient {
+    /// Make an API request and parse the CSV response into records
+    fn request_csv<B>(&mut self, method: Method, uri: Uri, body: Option<B>, options: &CsvOptions)
+            -> Result<Vec<StringRecord>> where B: JsonBody {
+        let future = self.request_future_csv(method, uri, body)?;
+        let response = self.response_future(future)?;
+        self.response_to_csv(response, options)
+    }
+
+    /// Make an API request and parse the CSV response into typed rows
+    fn request_csv_typed<B, T>(&mut self, method: Method, uri: Uri, body: Option<B>,
+                               options: &CsvOptions) -> Result<Vec<T>>
+            where B: JsonBody, T: DeserializeOwned {
+        let future = self.request_future_csv(method, uri, body)?;
+        let response = self.response_future(future)?;
+        self.response_to_csv_typed(response, options)
+    }
+
+    /// Make an API request accepting CSV and return the future
+    fn request_future_csv<B>(&mut self, method: Method, uri: Uri, body: Option<B>)
+            -> Result<FutureResponse> where B: JsonBody {
+        let mut overrides = RequestOverrides::new();
+        overrides.set_header(Accept(vec![qitem(csv_mime())]));
+        if let Some(b) = encode_json_body(body)? {
+            overrides.set_body(b);
+        }
+        self.request_future_with_overrides(method, uri, overrides)
+    }
+
+    /// Parse a response body as CSV records
+    fn response_to_csv(&mut self, response: Response, options: &CsvOptions)
+            -> Result<Vec<StringRecord>> {
+        let chunk = self.http_client_mut().evaluate_future(response.body().concat2())?;
+        options.parse_records(&chunk)
+    }
+
+    /// Parse a response body as typed CSV rows
+    fn response_to_csv_typed<T>(&mut self, response: Response, options: &CsvOptions)
+            -> Result<Vec<T>> where T: DeserializeOwned {
+        let chunk = self.http_client_mut().evaluate_future(response.body().concat2())?;
+        options.parse_rows(&chunk)
+    }
+}
+
+impl<HTTP, C> CsvApiClient<HTTP> for C where HTTP: HttpClient, C: ApiClient<HTTP> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_parse_records_and_rows() {
+        let body = b"name;status\ndisk;2\nload;0\n";
+        let options = CsvOptions::new().delimiter(b';');
+        let records = options.parse_records(body).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(&records[0][0], "disk");
+
+        let rows: Vec<HashMap<String, String>> = options.parse_rows(body).unwrap();
+        assert_eq!(rows[1]["status"], "0");
+
+        let headerless: Vec<(String, u8)> = options.has_headers(false)
+            .parse_rows(b"disk;2\n").unwrap();
+        assert_eq!(headerless, vec![("disk".to_string(), 2)]);
+    }
+}
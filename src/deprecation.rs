@@ -0,0 +1,193 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use hyper::header::HttpDate;
+
+use *;
+use latency::endpoint_template;
+
+/// Warning that an endpoint is deprecated or will be removed, from the `Deprecation`,
+/// `Sunset` and `Warning` headers of a response
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct DeprecationNotice {
+    /// Request method
+    pub method: Method,
+    /// Path of the endpoint with identifiers replaced by `{id}`
+    pub endpoint: String,
+    /// Value of the `Deprecation` header - a date such as `@1688169599` or `true`
+    pub deprecation: Option<String>,
+    /// Time from the `Sunset` header after which the endpoint may stop responding
+    pub sunset: Option<SystemTime>,
+    /// Texts of the `Warning` headers
+    pub warnings: Vec<String>,
+}
+
+impl fmt::Display for DeprecationNotice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.method, self.endpoint)?;
+        match self.deprecation.as_deref() {
+            Some("true") | Some("?1") => write!(f, " is deprecated")?,
+            Some(date) => write!(f, " is deprecated as of {}", date)?,
+            None => write!(f, " returned warnings")?,
+        }
+        if let Some(sunset) = self.sunset {
+            write!(f, " and will be removed after {}", HttpDate::from(sunset))?;
+        }
+        for warning in &self.warnings {
+            write!(f, ": {}", warning)?;
+        }
+        Ok(())
+    }
+}
+
+/// Texts of a `Warning` header value such as
+/// `299 - "Deprecated API" "Wed, 21 Oct 2015 07:28:00 GMT"`, skipping the optional dates
+fn warning_texts(value: &str) -> Vec<String> {
+    let mut texts = Vec::new();
+    let mut quoted = 0;
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ',' => quoted = 0,
+            '"' => {
+                let mut text = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => text.extend(chars.next()),
+                        '"' => break,
+                        c => text.push(c),
+                    }
+                }
+                if quoted == 0 {
+                    texts.push(text);
+                }
+                quoted += 1;
+            },
+            _ => {},
+        }
+    }
+    texts
+}
+
+type NoticeFn = Arc<dyn Fn(&DeprecationNotice) + Send + Sync>;
+
+/// Watches responses for deprecation notices and reports each endpoint once, so long-lived
+/// automation hears about endpoints going away before they do
+///
+/// Endpoints are named as by `latency::endpoint_template`. A `Deprecation: false` header is
+/// ignored.
+#[derive(Clone,Default)]
+pub struct DeprecationMonitor {
+    seen: HashSet<String>,
+    notices: Vec<DeprecationNotice>,
+    on_notice: Option<NoticeFn>,
+}
+
+impl fmt::Debug for DeprecationMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DeprecationMonitor").field("notices", &self.notices).finish()
+    }
+}
+
+impl DeprecationMonitor {
+    /// Monitor that only collects notices
+    pub fn new() -> Self {
+        DeprecationMonitor::default()
+    }
+
+    /// Call `warn` with the first notice for each endpoint
+    pub fn on_notice<F>(mut self, warn: F) -> Self where F: Fn(&DeprecationNotice) + Send + Sync + 'static {
+        self.on_notice = Some(Arc::new(warn));
+        self
+    }
+
+    /// Look for a notice in the `headers` of a response to `method` and `path`, returning it
+    /// if it is the first for the endpoint
+    pub fn check(&mut self, method: &Method, path: &str, headers: &Headers) -> Option<&DeprecationNotice> {
+        let raw = |name: &str| headers.get_raw(name).into_iter()
+            .flat_map(|values| values.iter().filter_map(|v| str::from_utf8(v).ok()).map(str::trim).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let deprecation = raw("Deprecation").into_iter().find(|v| !v.eq_ignore_ascii_case("false") && *v != "?0")
+            .map(str::to_string);
+        let sunset = raw("Sunset").into_iter().filter_map(|v| v.parse::<HttpDate>().ok()).map(SystemTime::from).next();
+        let warnings = raw("Warning").into_iter().flat_map(warning_texts).collect::<Vec<_>>();
+        if deprecation.is_none() && sunset.is_none() && warnings.is_empty() {
+            return None;
+        }
+
+        let endpoint = endpoint_template(path);
+        if !self.seen.insert(format!("{} {}", method, endpoint)) {
+            return None;
+        }
+        let notice = DeprecationNotice { method: method.clone(), endpoint, deprecation, sunset, warnings };
+        if let Some(ref warn) = self.on_notice {
+            warn(&notice);
+        }
+        self.notices.push(notice);
+        self.notices.last()
+    }
+
+    /// Notices reported so far, one per endpoint
+    pub fn notices(&self) -> &[DeprecationNotice] {
+        &self.notices
+    }
+
+    /// Forget the notices so each endpoint is reported again
+    pub fn clear(&mut self) {
+        self.seen.clear();
+        self.notices.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+    use testing::{Fixture,FixtureServer,client_for};
+
+    #[test]
+    fn test_deprecation_monitor() {
+        assert_eq!(warning_texts(r#"299 - "Deprecated API" "Wed, 21 Oct 2015 07:28:00 GMT", 199 proxy "say \"hi\"""#),
+                   vec!["Deprecated API".to_string(), "say \"hi\"".to_string()]);
+
+        let mut monitor = DeprecationMonitor::new();
+        let mut headers = Headers::new();
+        assert!(monitor.check(&Method::Get, "/v1/users/3", &headers).is_none());
+        headers.set_raw("Deprecation", "false");
+        assert!(monitor.check(&Method::Get, "/v1/users/3", &headers).is_none());
+
+        headers.set_raw("Deprecation", "@1688169599");
+        headers.set_raw("Sunset", "Wed, 11 Nov 2026 23:59:59 GMT");
+        headers.set_raw("Warning", r#"299 - "Use /v2/users instead""#);
+        assert_eq!(monitor.check(&Method::Get, "/v1/users/3?full=true", &headers).unwrap().to_string(),
+                   "GET /v1/users/{id} is deprecated as of @1688169599 and will be removed after \
+                    Wed, 11 Nov 2026 23:59:59 GMT: Use /v2/users instead");
+        assert!(monitor.check(&Method::Get, "/v1/users/4", &headers).is_none());
+        assert!(monitor.check(&Method::Delete, "/v1/users/4", &headers).is_some());
+        assert_eq!(monitor.notices().len(), 2);
+    }
+
+    #[test]
+    fn test_deprecation_notices() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/v1/projects/7", Fixture::json(200, "{}")
+                   .header("Deprecation", "true").header("Sunset", "Wed, 11 Nov 2026 23:59:59 GMT"))
+            .route(Method::Get, "/v2/projects/7", Fixture::json(200, "{}"))
+            .start().unwrap();
+        let notices = Arc::new(Mutex::new(Vec::new()));
+        let recorded = notices.clone();
+        let monitor = DeprecationMonitor::new().on_notice(move |n| recorded.lock().unwrap().push(n.to_string()));
+        let http = SimpleHttpClientBuilder::new().deprecation_monitor(monitor)
+            .build_for(&server.base_uri().parse().unwrap()).unwrap();
+        let mut client = client_for(&server).with_http(http);
+        for path in &["/v1/projects/7", "/v1/projects/7", "/v2/projects/7"] {
+            client.request(Method::Get, path.parse().unwrap(), None::<String>).unwrap();
+        }
+
+        assert_eq!(*notices.lock().unwrap(), vec![
+            "GET /v1/projects/{id} is deprecated and will be removed after Wed, 11 Nov 2026 23:59:59 GMT".to_string(),
+        ]);
+        assert_eq!(client.http_client().deprecation_monitor().unwrap().notices().len(), 1);
+    }
+}
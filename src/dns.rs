@@ -0,0 +1,341 @@
+use std::net::{IpAddr,SocketAddr,ToSocketAddrs};
+use std::sync::{Arc,Mutex};
+
+use futures::future;
+use futures::sync::oneshot;
+use hyper::client::{HttpConnector,Service};
+use tokio_core::net::TcpStream;
+
+use *;
+use clock::{Clock,SystemClock};
+use proxy::{tunnel,ProxyConfig,ProxyServer};
+use timeout::{connect_within,Timeouts,TimeoutStream};
+
+/// How long lookups are kept by `DnsCache::new` - the system resolver does not report the TTL
+/// of the records it returns
+pub const DEFAULT_DNS_TTL: Duration = Duration::from_secs(30);
+
+struct DnsEntry {
+    addrs: Vec<SocketAddr>,
+    expires: Instant,
+}
+
+struct CacheState {
+    ttl: Option<Duration>,
+    entries: HashMap<(String, u16), DnsEntry>,
+}
+
+/// In-process cache of resolved host addresses used by the connectors of a `SimpleHttpClient`,
+/// so bursts of short requests to the same hosts do not wait for a lookup each
+///
+/// Lookups are kept for the cache's TTL, since the system resolver does not report record
+/// TTLs; set a TTL no longer than that of the records, or `flush` after a DNS change. Failed
+/// lookups are not cached, and an entry is dropped when none of its addresses accept a
+/// connection. Clones share the same entries. A disabled cache passes every lookup through.
+#[derive(Clone)]
+pub struct DnsCache {
+    state: Arc<Mutex<CacheState>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl fmt::Debug for DnsCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("DnsCache").field("ttl", &state.ttl).field("entries", &state.entries.len()).finish()
+    }
+}
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        DnsCache::new()
+    }
+}
+
+impl DnsCache {
+    /// Cache keeping lookups for `DEFAULT_DNS_TTL`
+    pub fn new() -> Self {
+        DnsCache::with_ttl(Some(DEFAULT_DNS_TTL))
+    }
+
+    /// Cache that resolves every connection afresh until a TTL is set
+    pub fn disabled() -> Self {
+        DnsCache::with_ttl(None)
+    }
+
+    /// Cache keeping lookups for `ttl` - `None` disables it
+    pub fn with_ttl(ttl: Option<Duration>) -> Self {
+        DnsCache {
+            state: Arc::new(Mutex::new(CacheState { ttl, entries: HashMap::new() })),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Read the time from `clock`
+    pub fn clock<C>(mut self, clock: C) -> Self where C: Clock + 'static {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Keep lookups for `ttl` from now on - `None` disables the cache and drops its entries
+    pub fn set_ttl(&self, ttl: Option<Duration>) {
+        let mut state = self.lock();
+        state.ttl = ttl;
+        if ttl.is_none() {
+            state.entries.clear();
+        }
+    }
+
+    /// How long lookups are kept, or `None` if the cache is disabled
+    pub fn ttl(&self) -> Option<Duration> {
+        self.lock().ttl
+    }
+
+    /// Drop every cached lookup
+    pub fn flush(&self) {
+        self.lock().entries.clear();
+    }
+
+    /// Drop the cached lookups for `host`
+    pub fn flush_host(&self, host: &str) {
+        let host = host.to_lowercase();
+        self.lock().entries.retain(|key, _| key.0 != host);
+    }
+
+    /// Number of lookups cached and not yet expired
+    pub fn len(&self) -> usize {
+        let now = self.clock.now();
+        self.lock().entries.values().filter(|e| e.expires > now).count()
+    }
+
+    /// True if no unexpired lookups are cached
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The cached addresses of `host` and `port`, if a lookup has not expired
+    pub fn get(&self, host: &str, port: u16) -> Option<Vec<SocketAddr>> {
+        let now = self.clock.now();
+        let mut state = self.lock();
+        let key = (host.to_lowercase(), port);
+        match state.entries.get(&key) {
+            Some(entry) if entry.expires > now => Some(entry.addrs.clone()),
+            Some(_) => {
+                state.entries.remove(&key);
+                None
+            },
+            None => None,
+        }
+    }
+
+    /// Cache `addrs` as the result of looking up `host` and `port`, if the cache is enabled
+    pub fn insert(&self, host: &str, port: u16, addrs: Vec<SocketAddr>) {
+        let now = self.clock.now();
+        let mut state = self.lock();
+        if let Some(ttl) = state.ttl {
+            state.entries.insert((host.to_lowercase(), port), DnsEntry { addrs, expires: now + ttl });
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.lock().ttl.is_some()
+    }
+}
+
+type Connecting = Box<dyn Future<Item=TcpStream, Error=io::Error>>;
+
+/// Look up `host` on a new thread so the event loop is not blocked
+fn resolve(host: String, port: u16) -> Box<dyn Future<Item=Vec<SocketAddr>, Error=io::Error>> {
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = sender.send((host.as_str(), port).to_socket_addrs().map(|addrs| addrs.collect::<Vec<_>>()));
+    });
+    Box::new(receiver.then(|result| match result {
+        Ok(addrs) => addrs,
+        Err(_) => Err(io::Error::other("DNS lookup thread stopped")),
+    }))
+}
+
+/// Connect to each of `addrs` in turn until one accepts
+fn connect_any(http: &HttpConnector, scheme: &str, addrs: Vec<SocketAddr>) -> Connecting {
+    let none: Connecting = Box::new(future::err(io::Error::new(io::ErrorKind::NotFound, "Host has no addresses")));
+    addrs.into_iter().fold(none, |previous, addr| {
+        let http = http.clone();
+        let uri = format!("{}://{}/", scheme, addr);
+        Box::new(previous.or_else(move |_| -> Connecting {
+            match uri.parse::<Uri>() {
+                Ok(uri) => Box::new(http.call(uri)),
+                Err(e) => Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, e))),
+            }
+        }))
+    })
+}
+
+/// HTTP connector resolving hosts through a `DnsCache`
+///
+/// Connections are made by hyper's `HttpConnector`, which is given the resolved addresses; the
+/// TLS layer above still sees the original host for server name indication and verification.
+/// The connect and read limits of its `Timeouts` apply to every connection it makes. Hosts
+/// behind a proxy are reached through it, tunneled with `CONNECT` for `https` URIs.
+#[derive(Clone)]
+pub struct CachingConnector {
+    http: HttpConnector,
+    cache: DnsCache,
+    handle: Handle,
+    timeouts: Timeouts,
+    proxy: ProxyConfig,
+}
+
+impl fmt::Debug for CachingConnector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CachingConnector").field("cache", &self.cache).field("timeouts", &self.timeouts)
+            .field("proxy", &self.proxy).finish()
+    }
+}
+
+impl CachingConnector {
+    /// Connector resolving through `cache`, with `threads` lookup threads for while it is
+    /// disabled
+    pub fn new(threads: usize, handle: &Handle, cache: DnsCache) -> Self {
+        let mut http = HttpConnector::new(threads, handle);
+        http.enforce_http(false);
+        CachingConnector { http, cache, handle: handle.clone(), timeouts: Timeouts::default(),
+                           proxy: ProxyConfig::none() }
+    }
+
+    /// Apply the connect and read limits of `timeouts` to new connections
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Connect through the proxies of `proxy`
+    pub fn proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// The cache lookups go through
+    pub fn cache(&self) -> &DnsCache {
+        &self.cache
+    }
+}
+
+impl Service for CachingConnector {
+    type Request = Uri;
+    type Response = TimeoutStream;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item=TimeoutStream, Error=io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        let connecting = match self.proxy.proxy_for(&uri).cloned() {
+            Some(proxy) => self.connect_through(proxy, uri),
+            None => self.connect(uri),
+        };
+        let connecting = match self.timeouts.connect {
+            Some(timeout) => connect_within(connecting, timeout, &self.handle),
+            None => connecting,
+        };
+        let (read, handle) = (self.timeouts.read, self.handle.clone());
+        Box::new(connecting.map(move |stream| TimeoutStream::new(stream, read, &handle)))
+    }
+}
+
+impl CachingConnector {
+    /// Connect to `proxy`, then open a tunnel to the host of `uri` unless it is plain `http`
+    fn connect_through(&self, proxy: ProxyServer, uri: Uri) -> Connecting {
+        let connecting = match format!("http://{}/", proxy.authority()).parse::<Uri>() {
+            Ok(proxy_uri) => self.connect(proxy_uri),
+            Err(e) => return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, e))),
+        };
+        if uri.scheme() != Some("https") {
+            return connecting;
+        }
+        let host = uri.host().unwrap_or("");
+        let host = if host.contains(':') && !host.starts_with('[') { format!("[{}]", host) } else { host.to_string() };
+        let target = format!("{}:{}", host, uri.port().unwrap_or(443));
+        Box::new(connecting.and_then(move |stream| tunnel(stream, target, &proxy)))
+    }
+
+    fn connect(&self, uri: Uri) -> Connecting {
+        let (scheme, host) = match (uri.scheme(), uri.host()) {
+            (Some(scheme), Some(host)) => (scheme.to_string(), host.trim_matches(|c| c == '[' || c == ']').to_string()),
+            _ => return Box::new(self.http.call(uri)),
+        };
+        if !self.cache.is_enabled() || host.parse::<IpAddr>().is_ok() {
+            return Box::new(self.http.call(uri));
+        }
+        let port = uri.port().unwrap_or(if scheme == "https" { 443 } else { 80 });
+        let http = self.http.clone();
+        let cache = self.cache.clone();
+        let addrs: Box<dyn Future<Item=Vec<SocketAddr>, Error=io::Error>> = match cache.get(&host, port) {
+            Some(addrs) => Box::new(future::ok(addrs)),
+            None => {
+                let cache = cache.clone();
+                let lookup = host.clone();
+                Box::new(resolve(host.clone(), port).map(move |addrs| {
+                    cache.insert(&lookup, port, addrs.clone());
+                    addrs
+                }))
+            },
+        };
+        Box::new(addrs.and_then(move |addrs| connect_any(&http, &scheme, addrs).map_err(move |e| {
+            cache.flush_host(&host);
+            e
+        })))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clock::MockClock;
+
+    #[test]
+    fn test_dns_cache_ttl() {
+        let clock = MockClock::new();
+        let cache = DnsCache::with_ttl(Some(Duration::from_secs(10))).clock(clock.clone());
+        let addrs = vec!["192.0.2.1:443".parse().unwrap()];
+        cache.insert("API.example.com", 443, addrs.clone());
+        assert_eq!(cache.get("api.example.com", 443), Some(addrs.clone()));
+        assert_eq!(cache.get("api.example.com", 80), None);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.get("api.example.com", 443), None);
+        assert!(cache.is_empty());
+
+        cache.insert("api.example.com", 443, addrs.clone());
+        cache.insert("cdn.example.com", 443, addrs.clone());
+        cache.flush_host("API.example.com");
+        assert_eq!(cache.len(), 1);
+        cache.flush();
+        assert!(cache.is_empty());
+
+        cache.set_ttl(None);
+        cache.insert("api.example.com", 443, addrs);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_caching_connector() {
+        use testing::{Fixture,FixtureServer};
+
+        let server = FixtureServer::builder().route(Method::Get, "/user", Fixture::json(200, "{}")).start().unwrap();
+        let port = server.base_uri().rsplit(':').next().unwrap().parse::<u16>().unwrap();
+        let uri = format!("http://localhost:{}/user", port).parse::<Uri>().unwrap();
+        let cache = DnsCache::new();
+        let mut http = SimpleHttpClient::with_dns_cache(cache.clone()).unwrap();
+        assert_eq!(http.start_request(Method::Get, uri.clone()).make_request().response().unwrap().status(), StatusCode::Ok);
+        assert!(cache.get("localhost", port).is_some());
+
+        // A stale entry is dropped once none of its addresses accept a connection
+        cache.insert("localhost", port, vec!["127.0.0.1:1".parse().unwrap()]);
+        let mut fresh = SimpleHttpClient::with_dns_cache(cache.clone()).unwrap();
+        assert!(fresh.start_request(Method::Get, uri.clone()).make_request().response().is_err());
+        assert!(cache.get("localhost", port).is_none());
+        assert_eq!(fresh.start_request(Method::Get, uri).make_request().response().unwrap().status(), StatusCode::Ok);
+        assert!(SimpleHttpClient::new().unwrap().dns_cache().ttl().is_none());
+    }
+}
@@ -0,0 +1,229 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use hyper::{Response,StatusCode};
+use hyper::header::{ContentRange,ContentRangeSpec,Range};
+
+use *;
+
+fn write_error(e: io::Error) -> ClientError {
+    ClientError::new(format!("Failed to write download: {}", e))
+}
+
+/// Validate the `Content-Range` of a partial response against the range that was requested,
+/// returning the last byte position received and the total length if the server reported it
+pub fn check_content_range(range: Option<&ContentRange>, start: u64, end: u64)
+        -> Result<(u64, Option<u64>)> {
+    match range {
+        Some(&ContentRange(ContentRangeSpec::Bytes { range: Some((first, last)), instance_length })) => {
+            if first != start || last > end || last < first {
+                return Err(ClientError::new(format!(
+                    "Server returned bytes {}-{} when {}-{} was requested", first, last, start, end
+                )));
+            }
+            if let Some(len) = instance_length {
+                if last >= len {
+                    return Err(ClientError::new(format!(
+                        "Server returned bytes {}-{} of a {} byte file", first, last, len
+                    )));
+                }
+            }
+            Ok((last, instance_length))
+        },
+        _ => Err(ClientError::new("Partial response is missing a valid Content-Range header")),
+    }
+}
+
+/// Download helpers for large files - implemented for every `ApiClient`
+pub trait DownloadApiClient<HTTP>: ApiClient<HTTP> where HTTP: HttpClient {
+    /// Download the body of a `GET` request into `writer`, returning the number of bytes written
+    fn download<W>(&mut self, uri: Uri, writer: &mut W) -> Result<u64> where W: Write {
+        let response = self.request(Method::Get, uri, None::<String>)?;
+        if !response.status().is_success() {
+            return Err(ClientError::new(format!("Download failed with status {}", response.status())));
+        }
+        self.write_body(response, writer)
+    }
+
+    /// Download a file in chunks of `chunk_size` bytes using `Range` requests, returning the
+    /// number of bytes written
+    fn download_ranged<W>(&mut self, uri: Uri, writer: &mut W, chunk_size: u64) -> Result<u64>
+            where W: Write {
+        self.download_ranged_from(uri, writer, 0, chunk_size)
+    }
+
+    /// Download a file in chunks starting at byte `offset`, returning the number of bytes
+    /// written
+    ///
+    /// Each partial response must carry a `Content-Range` matching the requested range. A
+    /// server that ignores `Range` and returns the full body is only accepted when starting
+    /// from offset 0.
+    fn download_ranged_from<W>(&mut self, uri: Uri, writer: &mut W, offset: u64, chunk_size: u64)
+            -> Result<u64> where W: Write {
+        if chunk_size == 0 {
+            return Err(ClientError::new("Chunk size must be greater than zero"));
+        }
+        let mut start = offset;
+        let mut total: Option<u64> = None;
+        loop {
+            if let Some(len) = total {
+                if start >= len {
+                    break;
+                }
+            }
+            let end = start.saturating_add(chunk_size - 1);
+            let mut overrides = RequestOverrides::new();
+            overrides.set_header(Range::bytes(start, end));
+            let future = self.request_future_with_overrides(Method::Get, uri.clone(), overrides)?;
+            let response = self.response_future(future)?;
+            match response.status() {
+                StatusCode::PartialContent => {
+                    let (last, length) = check_content_range(response.headers().get::<ContentRange>(),
+                                                             start, end)?;
+                    let written = self.write_body(response, writer)?;
+                    if written != last - start + 1 {
+                        return Err(ClientError::new(format!(
+                            "Expected {} bytes in range but received {}", last - start + 1, written
+                        )));
+                    }
+                    start = last + 1;
+                    total = length;
+                    if total.is_none() && last < end {
+                        break;
+                    }
+                },
+                StatusCode::Ok if start == 0 => {
+                    start += self.write_body(response, writer)?;
+                    break;
+                },
+                StatusCode::Ok => {
+                    return Err(ClientError::new("Server does not support range requests"));
+                },
+                StatusCode::RangeNotSatisfiable if start > 0 => {
+                    // Requesting past the end of a file that is already fully downloaded
+                    match response.headers().get::<ContentRange>() {
+                        Some(&ContentRange(ContentRangeSpec::Bytes { range: None, instance_length: Some(len) }))
+                            if len == start => break,
+                        _ => {
+                            return Err(ClientError::new(format!(
+                                "Range starting at byte {} is not satisfiable", start
+                            )));
+                        },
+                    }
+                },
+                status => {
+                    return Err(ClientError::new(format!("Download failed with status {}", status)));
+                },
+            }
+        }
+        writer.flush().map_err(write_error)?;
+        Ok(start - offset)
+    }
+
+    /// Resume downloading into the file at `path`, continuing after any bytes already present,
+    /// and return the total size of the file
+    fn resume_download<P>(&mut self, uri: Uri, path: P, chunk_size: u64) -> Result<u64>
+            where P: AsRef<Path> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path.as_ref())
+            .map_err(write_error)?;
+        let offset = file.metadata().map_err(write_error)?.len();
+        let written = self.download_ranged_from(uri, &mut file, offset, chunk_size)?;
+        Ok(offset + written)
+    }
+
+    /// Write a response body to `writer` chunk by chunk, returning the number of bytes written
+    fn write_body<W>(&mut self, response: Response, writer: &mut W) -> Result<u64> where W: Write {
+        let mut written = 0u64;
+        let body = response.body().map_err(|e| ClientError::new(e.to_string()))
+            .for_each(|chunk| {
+                written += chunk.len() as u64;
+                writer.write_all(&chunk).map_err(write_error)
+            });
+        self.http_client_mut().evaluate_future(body)?;
+        Ok(written)
+    }
+}
+
+impl<HTTP, C> DownloadApiClient<HTTP> for C where HTTP: HttpClient, C: ApiClient<HTTP> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{env,fs,process};
+    use testing::{Fixture,FixtureServer,client_for};
+
+    fn bytes(first: u64, last: u64, len: Option<u64>) -> ContentRange {
+        ContentRange(ContentRangeSpec::Bytes { range: Some((first, last)), instance_length: len })
+    }
+
+    #[test]
+    fn test_check_content_range() {
+        assert_eq!(check_content_range(Some(&bytes(0, 99, Some(1000))), 0, 99).unwrap(),
+                   (99, Some(1000)));
+        assert_eq!(check_content_range(Some(&bytes(900, 999, Some(1000))), 900, 1099).unwrap(),
+                   (999, Some(1000)));
+        assert_eq!(check_content_range(Some(&bytes(100, 150, None)), 100, 199).unwrap(),
+                   (150, None));
+        assert!(check_content_range(Some(&bytes(50, 99, Some(1000))), 0, 99).is_err());
+        assert!(check_content_range(Some(&bytes(0, 199, Some(1000))), 0, 99).is_err());
+        assert!(check_content_range(Some(&bytes(0, 99, Some(50))), 0, 99).is_err());
+        assert!(check_content_range(None, 0, 99).is_err());
+    }
+
+    fn partial(body: &str, range: &str) -> Fixture {
+        Fixture::new(206).body(body).header("Content-Range", format!("bytes {}", range))
+    }
+
+    #[test]
+    fn test_download_ranged() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/file", partial("0123", "0-3/10"))
+            .route(Method::Get, "/file", partial("4567", "4-7/10"))
+            .route(Method::Get, "/file", partial("89", "8-9/10"))
+            .route(Method::Get, "/unsized", partial("456", "4-6/*"))
+            .route(Method::Get, "/full", Fixture::new(200).body("0123456789"))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let mut data = Vec::new();
+        assert_eq!(client.download_ranged("/file".parse().unwrap(), &mut data, 4).unwrap(), 10);
+        assert_eq!(data, b"0123456789".to_vec());
+        let ranges = server.requests().iter().map(|r| r.header("Range").unwrap().to_string()).collect::<Vec<_>>();
+        assert_eq!(ranges, vec!["bytes=0-3", "bytes=4-7", "bytes=8-11"]);
+
+        // A chunk size reaching past the largest offset requests everything that is left
+        let mut data = Vec::new();
+        assert_eq!(client.download_ranged_from("/unsized".parse().unwrap(), &mut data, 4, u64::MAX).unwrap(), 3);
+        assert_eq!(data, b"456".to_vec());
+        server.assert_sent().get("/unsized").header("Range", &format!("bytes=4-{}", u64::MAX));
+
+        let mut data = Vec::new();
+        assert_eq!(client.download_ranged("/full".parse().unwrap(), &mut data, 4).unwrap(), 10);
+        assert_eq!(data, b"0123456789".to_vec());
+        let err = client.download_ranged_from("/full".parse().unwrap(), &mut Vec::new(), 4, 4).unwrap_err();
+        assert_eq!(err.to_string(), "Server does not support range requests");
+        assert!(client.download_ranged("/full".parse().unwrap(), &mut Vec::new(), 0).is_err());
+    }
+
+    #[test]
+    fn test_resume_download() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/file", partial("456789", "4-9/10"))
+            .route(Method::Get, "/file", Fixture::new(416).header("Content-Range", "bytes */10"))
+            .route(Method::Get, "/short", Fixture::new(416).header("Content-Range", "bytes */20"))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let path = env::temp_dir().join(format!("teatime-resume-{}", process::id()));
+        fs::write(&path, "0123").unwrap();
+        assert_eq!(client.resume_download("/file".parse().unwrap(), &path, 100).unwrap(), 10);
+        assert_eq!(fs::read(&path).unwrap(), b"0123456789".to_vec());
+        server.assert_sent().get("/file").header("Range", "bytes=4-103");
+
+        // Resuming a complete file finds nothing left past its end
+        assert_eq!(client.resume_download("/file".parse().unwrap(), &path, 100).unwrap(), 10);
+        assert_eq!(fs::read(&path).unwrap(), b"0123456789".to_vec());
+        let err = client.resume_download("/short".parse().unwrap(), &path, 100).unwrap_err();
+        assert_eq!(err.to_string(), "Range starting at byte 10 is not satisfiable");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+use *;
+
+/// Object-safe core of an API client, so clients for different services can be held together
+/// as `Box<dyn DynApiClient>`
+///
+/// `ApiClient` cannot be made into a trait object because its request methods are generic over
+/// the body type. This trait takes bodies as `String` instead and is implemented for every
+/// `JsonApiClient` using `SimpleHttpClient`, which includes the reference clients. Clients on
+/// other transports implement it when wrapped in `AnyTransport`. The generic conveniences are
+/// provided for trait objects by `DynApiClientExt`.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate teatime;
+/// # use teatime::dynamic::{DynApiClient,DynApiClientExt};
+/// fn versions(clients: &mut [Box<dyn DynApiClient>]) -> Vec<String> {
+///     clients.iter_mut()
+///         .map(|c| c.send_json(hyper::Method::Get, "/version".parse().unwrap(), None::<String>)
+///              .map(|v| v.to_string()).unwrap_or_default())
+///         .collect()
+/// }
+/// # fn main() {}
+/// ```
+pub trait DynApiClient {
+    /// Base API URI to which relative request URIs are appended
+    fn dyn_base_uri(&self) -> &Uri;
+    /// Authenticate with `creds`
+    fn dyn_login(&mut self, creds: &ApiCredentials) -> Result<()>;
+    /// Make an API request with per-call `options` and resolve it to a response
+    fn send_raw(&mut self, method: Method, uri: Uri, body: Option<String>, options: &RequestOptions)
+        -> Result<Response>;
+    /// Read a response body as JSON
+    fn read_json(&mut self, response: Response) -> Result<Value>;
+    /// Make an API request and collect every page of the JSON response
+    fn send_paginated(&mut self, method: Method, uri: Uri, body: Option<String>) -> Result<Value>;
+}
+
+impl<C> DynApiClient for C where C: JsonApiClient<SimpleHttpClient> {
+    fn dyn_base_uri(&self) -> &Uri {
+        self.base_uri()
+    }
+
+    fn dyn_login(&mut self, creds: &ApiCredentials) -> Result<()> {
+        self.login(creds)
+    }
+
+    fn send_raw(&mut self, method: Method, uri: Uri, body: Option<String>, options: &RequestOptions)
+            -> Result<Response> {
+        self.request_with_options(method, uri, body, options)
+    }
+
+    fn read_json(&mut self, response: Response) -> Result<Value> {
+        self.response_to_json(response)
+    }
+
+    fn send_paginated(&mut self, method: Method, uri: Uri, body: Option<String>) -> Result<Value> {
+        self.autopagination(method, uri, body.map(RawBody))
+    }
+}
+
+/// Makes any `AnyJsonApiClient` a `DynApiClient`, for clients whose transport is not
+/// `SimpleHttpClient`
+///
+/// ```
+/// # use teatime::AnyJsonApiClient;
+/// # use teatime::dynamic::{AnyTransport,DynApiClient};
+/// fn boxed<C>(client: C) -> Box<dyn DynApiClient> where C: AnyJsonApiClient + 'static {
+///     Box::new(AnyTransport(client))
+/// }
+/// ```
+#[derive(Debug)]
+pub struct AnyTransport<C>(pub C);
+
+impl<C> DynApiClient for AnyTransport<C> where C: AnyJsonApiClient {
+    fn dyn_base_uri(&self) -> &Uri {
+        self.0.base_uri()
+    }
+
+    fn dyn_login(&mut self, creds: &ApiCredentials) -> Result<()> {
+        self.0.login(creds)
+    }
+
+    fn send_raw(&mut self, method: Method, uri: Uri, body: Option<String>, options: &RequestOptions)
+            -> Result<Response> {
+        self.0.request_with_options(method, uri, body, options)
+    }
+
+    fn read_json(&mut self, response: Response) -> Result<Value> {
+        self.0.response_to_json(response)
+    }
+
+    fn send_paginated(&mut self, method: Method, uri: Uri, body: Option<String>) -> Result<Value> {
+        self.0.autopagination(method, uri, body.map(RawBody))
+    }
+}
+
+/// Generic request helpers for any `DynApiClient`, including trait objects
+pub trait DynApiClientExt: DynApiClient {
+    /// Make an API request and resolve it to a response
+    fn send<B>(&mut self, method: Method, uri: Uri, body: Option<B>) -> Result<Response> where B: ToString {
+        self.send_raw(method, uri, body.map(|b| b.to_string()), &RequestOptions::new())
+    }
+
+    /// Make an API request with a JSON body and parse the JSON response
+    fn send_json<B>(&mut self, method: Method, uri: Uri, body: Option<B>) -> Result<Value> where B: JsonBody {
+        self.send_json_with_options(method, uri, body, &RequestOptions::new())
+    }
+
+    /// Make an API request with a JSON body and per-call `options` and parse the JSON response
+    fn send_json_with_options<B>(&mut self, method: Method, uri: Uri, body: Option<B>,
+                                 options: &RequestOptions) -> Result<Value> where B: JsonBody {
+        let body = encode_json_body(body)?;
+        let response = self.send_raw(method, uri, body, options)?;
+        self.read_json(response)
+    }
+
+    /// Make an API request with a JSON body and collect every page of the JSON response
+    fn send_json_paginated<B>(&mut self, method: Method, uri: Uri, body: Option<B>) -> Result<Value>
+            where B: JsonBody {
+        let body = encode_json_body(body)?;
+        self.send_paginated(method, uri, body)
+    }
+}
+
+impl<C> DynApiClientExt for C where C: DynApiClient + ?Sized {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testing::{Fixture,FixtureServer,client_for};
+
+    fn version_of<C>(client: &mut C) -> Result<Value> where C: AnyJsonApiClient {
+        client.request_json(Method::Get, "/version".parse().unwrap(), None::<Value>)
+    }
+
+    #[test]
+    fn test_dyn_api_client() {
+        let first = FixtureServer::builder()
+            .route(Method::Get, "/version", Fixture::json(200, r#"{"version": "1"}"#))
+            .start().unwrap();
+        let second = FixtureServer::builder()
+            .route(Method::Get, "/version", Fixture::json(200, r#"{"version": "2"}"#))
+            .route(Method::Post, "/items", Fixture::json(201, r#"{"id": 7}"#))
+            .start().unwrap();
+        let mut clients: Vec<Box<dyn DynApiClient>> = vec![
+            Box::new(client_for(&first)),
+            Box::new(AnyTransport(client_for(&second))),
+        ];
+        let versions: Vec<Value> = clients.iter_mut().map(|c| {
+            c.send_json(Method::Get, "/version".parse().unwrap(), None::<Value>).unwrap()["version"].clone()
+        }).collect();
+        assert_eq!(versions, vec![Value::from("1"), Value::from("2")]);
+        let mut client = client_for(&first);
+        assert_eq!(version_of(&mut client).unwrap()["version"], "1");
+
+        let created = clients[1].send_json(Method::Post, "/items".parse().unwrap(),
+                                           Some(serde_json::from_str::<Value>(r#"{"name": "x"}"#).unwrap()))
+            .unwrap();
+        assert_eq!(created["id"], 7);
+        assert_eq!(second.requests()[1].body, br#"{"name":"x"}"#.to_vec());
+        assert_eq!(clients[0].send(Method::Get, "/missing".parse().unwrap(), None::<String>).unwrap().status(),
+                   StatusCode::NotFound);
+        assert_eq!(clients[0].send_json_paginated(Method::Get, "/version".parse().unwrap(), None::<Value>).unwrap(),
+                   serde_json::from_str::<Value>(r#"[{"version": "1"}]"#).unwrap());
+        assert!(clients[0].dyn_base_uri().to_string().starts_with("http://127.0.0.1"));
+        clients[0].dyn_login(&ApiCredentials::NoAuth).unwrap();
+    }
+}
@@ -0,0 +1,318 @@
+use std::sync::Arc;
+
+use chacha20poly1305::{Key,KeyInit,XChaCha20Poly1305,XNonce};
+use chacha20poly1305::aead::{Aead,AeadCore,OsRng,Payload};
+use chacha20poly1305::aead::rand_core::RngCore;
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+
+use *;
+use state::StateStore;
+
+/// PBKDF2-HMAC-SHA256 iterations used to derive a key from a passphrase
+pub const PBKDF2_ROUNDS: u32 = 600_000;
+
+/// Namespace in the wrapped store holding the passphrase salt and key check value
+const ENCRYPTION_NAMESPACE: &str = "encryption";
+const SALT_KEY: &str = "salt";
+const CHECK_KEY: &str = "check";
+const CHECK_VALUE: &[u8] = b"teatime";
+
+const FORMAT_VERSION: u8 = 1;
+const NONCE_LEN: usize = 24;
+const SALT_LEN: usize = 16;
+
+/// 256 bit key encrypting state at rest
+#[derive(Clone,PartialEq,Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("EncryptionKey([REDACTED])")
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for EncryptionKey {
+    fn drop(&mut self) {
+        use zeroize::Zeroize;
+        self.0.zeroize();
+    }
+}
+
+#[cfg(any(feature = "keyring", test))]
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+impl EncryptionKey {
+    /// Use `bytes` as the key
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    /// New random key from the operating system's random number generator
+    pub fn generate() -> Self {
+        let mut bytes = [0; 32];
+        OsRng.fill_bytes(&mut bytes);
+        EncryptionKey(bytes)
+    }
+
+    /// Derive a key from `passphrase` and `salt` with PBKDF2
+    pub fn from_passphrase(passphrase: &Secret, salt: &[u8]) -> Self {
+        EncryptionKey::derive(passphrase, salt, PBKDF2_ROUNDS)
+    }
+
+    fn derive(passphrase: &Secret, salt: &[u8], rounds: u32) -> Self {
+        let mut bytes = [0; 32];
+        pbkdf2_hmac::<Sha256>(passphrase.expose().as_bytes(), salt, rounds, &mut bytes);
+        EncryptionKey(bytes)
+    }
+
+    /// Key stored for `account` under `service` in the operating system keyring, generating
+    /// and storing a new one the first time
+    #[cfg(feature = "keyring")]
+    pub fn from_keyring(service: &str, account: &str) -> Result<Self> {
+        let keyring_error = |e: keyring::Error| {
+            ClientError::new(format!("Failed to access encryption key {} in keyring: {}", service, e))
+        };
+        let entry = keyring::Entry::new(service, account).map_err(keyring_error)?;
+        match entry.get_password() {
+            Ok(text) => {
+                let bytes = from_hex(text.trim()).filter(|b| b.len() == 32).ok_or_else(|| {
+                    ClientError::new(format!("Encryption key {} in keyring is not 64 hex digits", service))
+                })?;
+                let mut key = [0; 32];
+                key.copy_from_slice(&bytes);
+                Ok(EncryptionKey(key))
+            },
+            Err(keyring::Error::NoEntry) => {
+                let key = EncryptionKey::generate();
+                entry.set_password(&to_hex(&key.0)).map_err(keyring_error)?;
+                Ok(key)
+            },
+            Err(e) => Err(keyring_error(e)),
+        }
+    }
+}
+
+/// A `StateStore` that encrypts values before handing them to another store, so that tokens
+/// cached on shared machines are not kept in plain text
+///
+/// Values are encrypted with XChaCha20-Poly1305 under a random nonce and bound to their
+/// namespace and key, so a value copied to another key or altered on disk fails to decrypt
+/// rather than being returned. Keys and namespaces themselves are not encrypted. The
+/// `encryption` namespace holds the passphrase salt and check value, so writing or deleting in
+/// it fails.
+///
+/// ```no_run
+/// # extern crate teatime;
+/// # use std::sync::Arc;
+/// # use teatime::auth::TokenCache;
+/// # use teatime::encryption::EncryptedStateStore;
+/// # use teatime::state::FileStateStore;
+/// # fn main() {
+/// let files = Arc::new(FileStateStore::new("/var/lib/deploy/state"));
+/// let store = EncryptedStateStore::prompt_passphrase(files).unwrap();
+/// let tokens = TokenCache::new(Arc::new(store), "https://vault.example.com:8200");
+/// # }
+/// ```
+pub struct EncryptedStateStore {
+    inner: Arc<dyn StateStore>,
+    cipher: XChaCha20Poly1305,
+}
+
+impl fmt::Debug for EncryptedStateStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("EncryptedStateStore")
+    }
+}
+
+fn associated_data(namespace: &str, key: &str) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(namespace.len() + key.len() + 1);
+    aad.extend_from_slice(namespace.as_bytes());
+    aad.push(0);
+    aad.extend_from_slice(key.as_bytes());
+    aad
+}
+
+impl EncryptedStateStore {
+    /// Encrypt values stored in `inner` with `key`
+    pub fn new(inner: Arc<dyn StateStore>, key: &EncryptionKey) -> Self {
+        EncryptedStateStore { inner, cipher: XChaCha20Poly1305::new(Key::from_slice(&key.0)) }
+    }
+
+    /// Encrypt values stored in `inner` with a key derived from `passphrase`
+    ///
+    /// The salt is generated and kept in `inner` the first time, along with a check value
+    /// that makes opening the store with a different passphrase fail immediately.
+    pub fn with_passphrase(inner: Arc<dyn StateStore>, passphrase: &Secret) -> Result<Self> {
+        EncryptedStateStore::open(inner, passphrase, PBKDF2_ROUNDS)
+    }
+
+    /// Prompt for the passphrase in the terminal and open the store with it - see
+    /// `with_passphrase`
+    pub fn prompt_passphrase(inner: Arc<dyn StateStore>) -> Result<Self> {
+        let passphrase = rpassword::prompt_password_stdout("State passphrase: ").map_err(|e| {
+            ClientError::new(format!("Failed to read passphrase: {}", e))
+        })?;
+        EncryptedStateStore::with_passphrase(inner, &Secret::from(passphrase))
+    }
+
+    /// Encrypt values stored in `inner` with the key kept in the operating system keyring -
+    /// see `EncryptionKey::from_keyring`
+    #[cfg(feature = "keyring")]
+    pub fn with_keyring(inner: Arc<dyn StateStore>, service: &str, account: &str) -> Result<Self> {
+        Ok(EncryptedStateStore::new(inner, &EncryptionKey::from_keyring(service, account)?))
+    }
+
+    fn open(inner: Arc<dyn StateStore>, passphrase: &Secret, rounds: u32) -> Result<Self> {
+        let salt = match inner.get(ENCRYPTION_NAMESPACE, SALT_KEY)? {
+            Some(salt) => salt,
+            None => {
+                let mut salt = vec![0; SALT_LEN];
+                OsRng.fill_bytes(&mut salt);
+                inner.put(ENCRYPTION_NAMESPACE, SALT_KEY, &salt)?;
+                salt
+            },
+        };
+        let store = EncryptedStateStore::new(inner, &EncryptionKey::derive(passphrase, &salt, rounds));
+        match store.inner.get(ENCRYPTION_NAMESPACE, CHECK_KEY)? {
+            Some(sealed) => match store.unseal(ENCRYPTION_NAMESPACE, CHECK_KEY, &sealed) {
+                Ok(ref check) if check == CHECK_VALUE => Ok(store),
+                _ => Err(ClientError::new("Wrong passphrase for encrypted state")),
+            },
+            None => {
+                store.seal(ENCRYPTION_NAMESPACE, CHECK_KEY, CHECK_VALUE)?;
+                Ok(store)
+            },
+        }
+    }
+
+    fn unseal(&self, namespace: &str, key: &str, sealed: &[u8]) -> Result<Vec<u8>> {
+        let fail = || ClientError::new(format!("Failed to decrypt {}/{} - wrong key or corrupted value", namespace, key));
+        if sealed.len() < 1 + NONCE_LEN || sealed[0] != FORMAT_VERSION {
+            return Err(fail());
+        }
+        let aad = associated_data(namespace, key);
+        let nonce = XNonce::from_slice(&sealed[1..1 + NONCE_LEN]);
+        self.cipher.decrypt(nonce, Payload { msg: &sealed[1 + NONCE_LEN..], aad: &aad }).map_err(|_| fail())
+    }
+
+    fn seal(&self, namespace: &str, key: &str, value: &[u8]) -> Result<()> {
+        let aad = associated_data(namespace, key);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher.encrypt(&nonce, Payload { msg: value, aad: &aad }).map_err(|_| {
+            ClientError::new(format!("Failed to encrypt {}/{}", namespace, key))
+        })?;
+        let mut sealed = Vec::with_capacity(1 + NONCE_LEN + ciphertext.len());
+        sealed.push(FORMAT_VERSION);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        self.inner.put(namespace, key, &sealed)
+    }
+}
+
+fn check_namespace(namespace: &str) -> Result<()> {
+    if namespace == ENCRYPTION_NAMESPACE {
+        return Err(ClientError::new(format!(
+            "The {} namespace is reserved for the passphrase salt and check value", namespace
+        )));
+    }
+    Ok(())
+}
+
+impl StateStore for EncryptedStateStore {
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.inner.get(namespace, key)? {
+            Some(sealed) => self.unseal(namespace, key, &sealed).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, namespace: &str, key: &str, value: &[u8]) -> Result<()> {
+        check_namespace(namespace)?;
+        self.seal(namespace, key, value)
+    }
+
+    fn delete(&self, namespace: &str, key: &str) -> Result<()> {
+        check_namespace(namespace)?;
+        self.inner.delete(namespace, key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use state::{MemoryStateStore,TOKENS};
+
+    #[test]
+    fn test_encrypted_state_store() {
+        let inner = Arc::new(MemoryStateStore::new());
+        let store = EncryptedStateStore::open(inner.clone(), &"hunter2".into(), 1000).unwrap();
+        store.put(TOKENS, "vault", b"s.secret-token").unwrap();
+        assert_eq!(store.get(TOKENS, "vault").unwrap(), Some(b"s.secret-token".to_vec()));
+        assert_eq!(store.get(TOKENS, "gitlab").unwrap(), None);
+
+        let sealed = inner.get(TOKENS, "vault").unwrap().unwrap();
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        store.put(TOKENS, "vault", b"s.secret-token").unwrap();
+        assert_ne!(inner.get(TOKENS, "vault").unwrap().unwrap(), sealed);
+
+        // The same passphrase reopens the store, another is rejected
+        let reopened = EncryptedStateStore::open(inner.clone(), &"hunter2".into(), 1000).unwrap();
+        assert_eq!(reopened.get(TOKENS, "vault").unwrap(), Some(b"s.secret-token".to_vec()));
+        let err = EncryptedStateStore::open(inner.clone(), &"hunter3".into(), 1000).unwrap_err();
+        assert_eq!(err.to_string(), "Wrong passphrase for encrypted state");
+        assert!(store.put(ENCRYPTION_NAMESPACE, SALT_KEY, b"salt").is_err());
+        assert!(store.delete(ENCRYPTION_NAMESPACE, CHECK_KEY).is_err());
+        assert!(reopened.get(ENCRYPTION_NAMESPACE, CHECK_KEY).is_ok());
+
+        // Values moved to another key or altered do not decrypt
+        inner.put(TOKENS, "gitlab", &sealed).unwrap();
+        assert!(store.get(TOKENS, "gitlab").is_err());
+        let mut altered = sealed.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        inner.put(TOKENS, "vault", &altered).unwrap();
+        assert!(store.get(TOKENS, "vault").is_err());
+
+        let other = EncryptedStateStore::new(inner.clone(), &EncryptionKey::generate());
+        inner.put(TOKENS, "vault", &sealed).unwrap();
+        assert!(other.get(TOKENS, "vault").is_err());
+        store.delete(TOKENS, "vault").unwrap();
+        assert_eq!(inner.get(TOKENS, "vault").unwrap(), None);
+
+        assert_eq!(from_hex(&to_hex(&[0, 15, 255])), Some(vec![0, 15, 255]));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(format!("{:?}", EncryptionKey::from_bytes([7; 32])), "EncryptionKey([REDACTED])");
+    }
+
+    /// Store that cannot read the check value, as a file store whose files became unreadable
+    struct UnreadableCheck;
+
+    impl StateStore for UnreadableCheck {
+        fn get(&self, _namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+            match key {
+                SALT_KEY => Ok(Some(vec![0; SALT_LEN])),
+                _ => Err(ClientError::new("Failed to read check: Permission denied")),
+            }
+        }
+
+        fn put(&self, _namespace: &str, _key: &str, _value: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn delete(&self, _namespace: &str, _key: &str) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_open_reports_inner_errors() {
+        let err = EncryptedStateStore::open(Arc::new(UnreadableCheck), &"hunter2".into(), 1000).unwrap_err();
+        assert_eq!(err.to_string(), "Failed to read check: Permission denied");
+    }
+}
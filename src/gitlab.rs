@@ -1,12 +1,22 @@
 use std::str;
 use std::fmt;
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read,Write};
+use std::path::Path;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
 
 use serde_json::{Value,Map};
 use hyper::{self,Response};
 use hyper::header::{self,Header,Raw,ContentType,Authorization,Bearer};
+use url::form_urlencoded;
+use url::percent_encoding::{utf8_percent_encode,PATH_SEGMENT_ENCODE_SET};
 
 use *;
+use auth::{self,SharedToken,TokenCache,TokenRotation};
+use completion::{NameCache,complete,names_at};
+use download::DownloadApiClient;
+use oauth2::{DeviceAuthorization,DeviceFlow,DeviceFlowApiClient};
 
 named!(parse_link_header<&str, HashMap<String, String> >,
     fold_many1!(
@@ -98,30 +108,1034 @@ impl Header for Link {
 }
 
 /// Support OAuth tokens and personal access tokens in Gitlab
-#[derive(Clone)]
+#[derive(Clone,Debug)]
 pub enum TokenType {
     /// OAuth token
-    Oauth(String),
+    Oauth(Secret),
     /// Personal access tokens in Gitlab
-    PersonalAccess(String),
+    PersonalAccess(Secret),
+}
+
+impl TokenType {
+    /// Form saved in a `TokenCache`, prefixed with the token type
+    fn to_cached(&self) -> Secret {
+        match *self {
+            TokenType::Oauth(ref t) => Secret::from(format!("oauth:{}", t.expose())),
+            TokenType::PersonalAccess(ref t) => Secret::from(format!("personal:{}", t.expose())),
+        }
+    }
+
+    fn from_cached(cached: Secret) -> Result<Self> {
+        let cached = cached.expose();
+        if let Some(token) = cached.strip_prefix("oauth:") {
+            Ok(TokenType::Oauth(token.into()))
+        } else if let Some(token) = cached.strip_prefix("personal:") {
+            Ok(TokenType::PersonalAccess(token.into()))
+        } else {
+            Err(ClientError::new("Cached Gitlab token has no token type"))
+        }
+    }
+}
+
+/// Encode a numeric ID or a `namespace/name` path for use as a single URI path segment
+pub fn encode_id(id: &str) -> String {
+    utf8_percent_encode(id, PATH_SEGMENT_ENCODE_SET).to_string()
+}
+
+fn endpoint(path: String) -> Result<Uri> {
+    Ok(path.parse::<Uri>()?)
+}
+
+fn endpoint_with_query(path: String, query: &[(&str, String)]) -> Result<Uri> {
+    if query.is_empty() {
+        return endpoint(path);
+    }
+    let mut serializer = form_urlencoded::Serializer::new(String::new());
+    for &(key, ref value) in query {
+        serializer.append_pair(key, value);
+    }
+    endpoint(format!("{}?{}", path, serializer.finish()))
+}
+
+/// What `GitlabClient::search` searches for
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum SearchScope {
+    /// Projects
+    Projects,
+    /// Issues
+    Issues,
+    /// Merge requests
+    MergeRequests,
+    /// File contents
+    Blobs,
+    /// Commit messages
+    Commits,
+    /// Users
+    Users,
+}
+
+impl SearchScope {
+    /// Name of the scope in the Gitlab API
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            SearchScope::Projects => "projects",
+            SearchScope::Issues => "issues",
+            SearchScope::MergeRequests => "merge_requests",
+            SearchScope::Blobs => "blobs",
+            SearchScope::Commits => "commits",
+            SearchScope::Users => "users",
+        }
+    }
+}
+
+/// Which audit events `GitlabClient::list_audit_events` lists
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum AuditScope {
+    /// Every event on the instance - requires an administrator token
+    Instance,
+    /// Events for a group, by ID or path
+    Group(String),
+    /// Events for a project, by ID or path
+    Project(String),
+}
+
+/// Filters for `GitlabClient::list_audit_events`
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct AuditEventFilter {
+    created_after: Option<String>,
+    created_before: Option<String>,
+    entity_type: Option<String>,
+    entity_id: Option<u64>,
+}
+
+impl AuditEventFilter {
+    /// Match every event
+    pub fn new() -> Self {
+        AuditEventFilter::default()
+    }
+
+    /// Only events created after `time`, an ISO 8601 date or timestamp
+    pub fn created_after<S>(mut self, time: S) -> Self where S: Into<String> {
+        self.created_after = Some(time.into());
+        self
+    }
+
+    /// Only events created before `time`, an ISO 8601 date or timestamp
+    pub fn created_before<S>(mut self, time: S) -> Self where S: Into<String> {
+        self.created_before = Some(time.into());
+        self
+    }
+
+    /// Only events about the entity `entity_id` of `entity_type` (`User`, `Group` or
+    /// `Project`) - instance scope only
+    pub fn entity<S>(mut self, entity_type: S, entity_id: u64) -> Self where S: Into<String> {
+        self.entity_type = Some(entity_type.into());
+        self.entity_id = Some(entity_id);
+        self
+    }
+
+    fn query(&self) -> Vec<(&'static str, String)> {
+        let mut query = Vec::new();
+        if let Some(ref time) = self.created_after {
+            query.push(("created_after", time.clone()));
+        }
+        if let Some(ref time) = self.created_before {
+            query.push(("created_before", time.clone()));
+        }
+        if let Some(ref entity_type) = self.entity_type {
+            query.push(("entity_type", entity_type.clone()));
+        }
+        if let Some(id) = self.entity_id {
+            query.push(("entity_id", id.to_string()));
+        }
+        query
+    }
+}
+
+/// Access levels used by protected branches
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum AccessLevel {
+    /// Nobody
+    NoAccess,
+    /// Developers and maintainers
+    Developer,
+    /// Maintainers only
+    Maintainer,
+    /// Administrators only
+    Admin,
+}
+
+impl AccessLevel {
+    /// Numeric value used by the Gitlab API
+    pub fn value(&self) -> u64 {
+        match *self {
+            AccessLevel::NoAccess => 0,
+            AccessLevel::Developer => 30,
+            AccessLevel::Maintainer => 40,
+            AccessLevel::Admin => 60,
+        }
+    }
+}
+
+/// A project level merge request approval rule
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ApprovalRule {
+    /// Rule name
+    pub name: String,
+    /// Number of approvals required
+    pub approvals_required: u64,
+    /// Users allowed to approve
+    pub user_ids: Vec<u64>,
+    /// Groups whose members are allowed to approve
+    pub group_ids: Vec<u64>,
+    /// Protected branches the rule applies to - all branches if empty
+    pub protected_branch_ids: Vec<u64>,
+}
+
+impl ApprovalRule {
+    /// Rule requiring `approvals_required` approvals from any eligible approver
+    pub fn new<S>(name: S, approvals_required: u64) -> Self where S: Into<String> {
+        ApprovalRule {
+            name: name.into(),
+            approvals_required,
+            user_ids: Vec::new(),
+            group_ids: Vec::new(),
+            protected_branch_ids: Vec::new(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut args = Map::new();
+        args.insert("name".to_string(), Value::from(self.name.clone()));
+        args.insert("approvals_required".to_string(), Value::from(self.approvals_required));
+        args.insert("user_ids".to_string(), Value::from(self.user_ids.clone()));
+        args.insert("group_ids".to_string(), Value::from(self.group_ids.clone()));
+        args.insert("protected_branch_ids".to_string(), Value::from(self.protected_branch_ids.clone()));
+        Value::from(args)
+    }
+}
+
+/// Which container registry tags to remove, used both for one off bulk deletion and for a
+/// project's scheduled expiration policy
+///
+/// Tags matching `name_regex_delete` are removed unless they match `name_regex_keep`, are among
+/// the `keep_n` most recent or are newer than `older_than`.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct TagCleanup {
+    name_regex_delete: String,
+    name_regex_keep: Option<String>,
+    keep_n: Option<u64>,
+    older_than: Option<String>,
+}
+
+impl TagCleanup {
+    /// Remove tags whose names match `name_regex_delete` - `.*` matches every tag
+    pub fn new<S>(name_regex_delete: S) -> Self where S: Into<String> {
+        TagCleanup { name_regex_delete: name_regex_delete.into(), name_regex_keep: None, keep_n: None,
+                     older_than: None }
+    }
+
+    /// Never remove tags whose names match `regex`
+    pub fn keep_matching<S>(mut self, regex: S) -> Self where S: Into<String> {
+        self.name_regex_keep = Some(regex.into());
+        self
+    }
+
+    /// Never remove the `n` most recent tags
+    pub fn keep_latest(mut self, n: u64) -> Self {
+        self.keep_n = Some(n);
+        self
+    }
+
+    /// Only remove tags older than `age`, in Gitlab's duration format such as `7d` or `1month`
+    pub fn older_than<S>(mut self, age: S) -> Self where S: Into<String> {
+        self.older_than = Some(age.into());
+        self
+    }
+
+    fn to_json(&self, delete_key: &str) -> Map<String, Value> {
+        let mut args = Map::new();
+        args.insert(delete_key.to_string(), Value::from(self.name_regex_delete.clone()));
+        if let Some(ref keep) = self.name_regex_keep {
+            args.insert("name_regex_keep".to_string(), Value::from(keep.clone()));
+        }
+        if let Some(n) = self.keep_n {
+            args.insert("keep_n".to_string(), Value::from(n));
+        }
+        if let Some(ref age) = self.older_than {
+            args.insert("older_than".to_string(), Value::from(age.clone()));
+        }
+        args
+    }
+}
+
+/// Rate limit state reported by the `RateLimit-*` headers of the latest Gitlab response
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct RateLimitStatus {
+    /// Requests allowed in the current window
+    pub limit: u64,
+    /// Requests left in the current window
+    pub remaining: u64,
+    /// When the current window ends
+    pub reset: SystemTime,
+}
+
+impl RateLimitStatus {
+    /// Parse the rate limit headers, returning `None` if any are missing or invalid
+    pub fn from_headers(headers: &Headers) -> Option<Self> {
+        fn number(headers: &Headers, name: &str) -> Option<u64> {
+            let raw = headers.get_raw(name)?.one()?;
+            str::from_utf8(raw).ok()?.trim().parse().ok()
+        }
+        Some(RateLimitStatus {
+            limit: number(headers, "RateLimit-Limit")?,
+            remaining: number(headers, "RateLimit-Remaining")?,
+            reset: UNIX_EPOCH + Duration::from_secs(number(headers, "RateLimit-Reset")?),
+        })
+    }
+
+    /// True if no requests are left in the current window
+    pub fn is_exhausted(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// Time from `now` until the current window ends
+    pub fn time_until_reset(&self, now: SystemTime) -> Duration {
+        self.reset.duration_since(now).unwrap_or_else(|_| Duration::from_secs(0))
+    }
+}
+
+/// Version of a Gitlab server as reported by `/version`, ordered by major, minor and patch
+/// version
+#[derive(Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub struct GitlabVersion {
+    /// Major version
+    pub major: u32,
+    /// Minor version
+    pub minor: u32,
+    /// Patch version
+    pub patch: u32,
+    /// Anything after the numbers, such as `ee` or `pre`
+    pub suffix: Option<String>,
+}
+
+impl GitlabVersion {
+    /// Parse a version such as `16.4.1-ee`
+    pub fn parse(version: &str) -> Result<Self> {
+        let invalid = || ClientError::new(format!("Invalid Gitlab version {}", version));
+        let mut parts = version.trim().splitn(2, '-');
+        let numbers = parts.next().unwrap_or("").split('.').map(str::parse::<u32>).collect::<result::Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        if numbers.is_empty() || numbers.len() > 3 {
+            return Err(invalid());
+        }
+        Ok(GitlabVersion {
+            major: numbers[0],
+            minor: numbers.get(1).cloned().unwrap_or(0),
+            patch: numbers.get(2).cloned().unwrap_or(0),
+            suffix: parts.next().map(str::to_string),
+        })
+    }
+
+    /// True if this is version `major.minor` or later
+    pub fn at_least(&self, major: u32, minor: u32) -> bool {
+        (self.major, self.minor) >= (major, minor)
+    }
+}
+
+impl fmt::Display for GitlabVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        match self.suffix {
+            Some(ref suffix) => write!(f, "-{}", suffix),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A personal access token created by `GitlabClient::create_personal_access_token`
+#[derive(Clone,Debug)]
+pub struct PersonalAccessToken {
+    /// Token ID
+    pub id: u64,
+    /// Token name
+    pub name: String,
+    /// Scopes granted to the token
+    pub scopes: Vec<String>,
+    /// Expiry date as `YYYY-MM-DD`, if any
+    pub expires_at: Option<String>,
+    /// The token itself - only returned when the token is created
+    pub token: Secret,
 }
 
 /// Gitlab API client
 pub struct GitlabClient {
     base_uri: Uri,
-    token: Option<TokenType>,
+    token: SharedToken<TokenType>,
+    rotation: Option<TokenRotation<TokenType>>,
+    pagination_allowlist: Vec<String>,
+    per_page: Option<u32>,
+    api_version: Option<ApiVersion>,
+    version_checks: bool,
+    server_version: Option<GitlabVersion>,
+    rate_limit: Option<RateLimitStatus>,
+    token_cache: Option<TokenCache>,
     client: SimpleHttpClient,
 }
 
 impl<'a> GitlabClient {
     /// Create a new Gitlab API client
     pub fn new(base_uri: String) -> Result<Self> {
-        Ok(GitlabClient{
+        GitlabClient::with_shared_token(base_uri, SharedToken::new(None))
+    }
+
+    /// Start building a client for the API at the absolute `base_uri` with more options than
+    /// `new` offers
+    pub fn builder<S>(base_uri: S) -> GitlabClientBuilder where S: Into<String> {
+        GitlabClientBuilder {
+            base_uri: base_uri.into(),
             token: None,
+            shared_token: None,
+            rotation: None,
+            pagination_allowlist: Vec::new(),
+            per_page: None,
+            api_version: None,
+            version_checks: false,
+            token_cache: None,
+            http: SimpleHttpClientBuilder::new(),
+        }
+    }
+
+    /// Create a new Gitlab API client using token storage shared with other clients so that
+    /// concurrent logins are only performed once
+    pub fn with_shared_token(base_uri: String, token: SharedToken<TokenType>) -> Result<Self> {
+        Ok(GitlabClient{
+            token,
+            rotation: None,
+            pagination_allowlist: Vec::new(),
+            per_page: None,
+            api_version: None,
+            version_checks: false,
+            server_version: None,
+            rate_limit: None,
+            token_cache: None,
             base_uri: base_uri.parse::<Uri>()?,
             client: SimpleHttpClient::new()?,
         })
     }
+
+    /// Get a handle to the token storage for sharing with other clients
+    pub fn shared_token(&self) -> SharedToken<TokenType> {
+        self.token.clone()
+    }
+
+    /// Mint tokens with `rotation` whenever the current token is missing or about to expire
+    pub fn set_token_rotation(&mut self, rotation: TokenRotation<TokenType>) {
+        self.rotation = Some(rotation);
+    }
+
+    /// Allow autopagination to follow next page links to `authority` (`host` or `host:port`)
+    /// in addition to the host of the base URI
+    ///
+    /// Links must use `https` or the scheme of the base URI - give `http://host` to allow
+    /// plaintext links from an `https` base URI.
+    pub fn allow_pagination_to<S>(&mut self, authority: S) where S: Into<String> {
+        self.pagination_allowlist.push(authority.into());
+    }
+
+    /// Request `per_page` elements per page when paginating, in place of any `per_page` in
+    /// the URI - Gitlab allows up to 100
+    pub fn set_per_page(&mut self, per_page: Option<u32>) {
+        self.per_page = per_page;
+    }
+
+    /// Ask for `version` of the API - `None` leaves the base URI to choose it
+    pub fn set_api_version(&mut self, version: Option<ApiVersion>) {
+        self.api_version = version;
+    }
+
+    /// Start with the token saved in `cache` if the client has no token, and save the token
+    /// from every login to it
+    pub fn set_token_cache(&mut self, cache: TokenCache) -> Result<()> {
+        if self.token.get().is_none() {
+            if let Some(token) = cache.load()? {
+                self.token.set(Some(TokenType::from_cached(token)?));
+            }
+        }
+        self.token_cache = Some(cache);
+        Ok(())
+    }
+
+    /// Rate limit reported by the latest response, or `None` if it did not include the
+    /// `RateLimit-*` headers
+    ///
+    /// Batch jobs can check this to slow down before Gitlab starts rejecting requests.
+    pub fn rate_limit_status(&self) -> Option<RateLimitStatus> {
+        self.rate_limit
+    }
+
+    /// Create a new Gitlab API client authenticating with a personal access token stored in the
+    /// `token` field of the Vault secret at `path`, re-read whenever its lease expires
+    #[cfg(feature = "vault")]
+    pub fn with_vault_secret(base_uri: String, vault: ::vault::VaultClient, path: &str)
+            -> Result<Self> {
+        let mut client = GitlabClient::new(base_uri)?;
+        let resolver = ::vault::VaultSecretResolver::new(vault, path);
+        client.set_token_rotation(resolver.into_rotation(TokenType::PersonalAccess));
+        Ok(client)
+    }
+}
+
+/// Builder for a `GitlabClient` - see `GitlabClient::builder`
+pub struct GitlabClientBuilder {
+    base_uri: String,
+    token: Option<TokenType>,
+    shared_token: Option<SharedToken<TokenType>>,
+    rotation: Option<TokenRotation<TokenType>>,
+    pagination_allowlist: Vec<String>,
+    per_page: Option<u32>,
+    api_version: Option<ApiVersion>,
+    version_checks: bool,
+    token_cache: Option<TokenCache>,
+    http: SimpleHttpClientBuilder,
+}
+
+impl GitlabClientBuilder {
+    /// Authenticate with `token` instead of logging in
+    pub fn token(mut self, token: TokenType) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Use token storage shared with other clients
+    pub fn shared_token(mut self, token: SharedToken<TokenType>) -> Self {
+        self.shared_token = Some(token);
+        self
+    }
+
+    /// Mint tokens with `rotation` whenever the current token is missing or about to expire
+    pub fn token_rotation(mut self, rotation: TokenRotation<TokenType>) -> Self {
+        self.rotation = Some(rotation);
+        self
+    }
+
+    /// Allow autopagination to follow next page links to `authority`
+    pub fn allow_pagination_to<S>(mut self, authority: S) -> Self where S: Into<String> {
+        self.pagination_allowlist.push(authority.into());
+        self
+    }
+
+    /// Request `per_page` elements per page when paginating - see `GitlabClient::set_per_page`
+    pub fn per_page(mut self, per_page: u32) -> Self {
+        self.per_page = Some(per_page);
+        self
+    }
+
+    /// Ask for `version` of the API - see `GitlabClient::set_api_version`
+    pub fn api_version(mut self, version: ApiVersion) -> Self {
+        self.api_version = Some(version);
+        self
+    }
+
+    /// Check the server version before calls that need a newer Gitlab - see
+    /// `GitlabClient::set_version_checks`
+    pub fn version_checks(mut self, enabled: bool) -> Self {
+        self.version_checks = enabled;
+        self
+    }
+
+    /// Start with the token saved in `cache` if no other is set, and save the token from
+    /// every login to it
+    pub fn token_cache(mut self, cache: TokenCache) -> Self {
+        self.token_cache = Some(cache);
+        self
+    }
+
+    /// Configure the HTTP client - headers, timeout, TLS and the other `SimpleHttpClient`
+    /// settings
+    pub fn http<F>(mut self, configure: F) -> Self
+            where F: FnOnce(SimpleHttpClientBuilder) -> SimpleHttpClientBuilder {
+        self.http = configure(self.http);
+        self
+    }
+
+    /// Check the options and create the client
+    pub fn build(self) -> Result<GitlabClient> {
+        let base_uri = parse_base_uri(&self.base_uri)?;
+        let token = match (self.token, self.shared_token) {
+            (Some(_), Some(_)) => return Err(ClientError::new("Set either a token or shared token storage, not both")),
+            (Some(TokenType::Oauth(ref t)), None) | (Some(TokenType::PersonalAccess(ref t)), None) if t.is_empty() => {
+                return Err(ClientError::new("Gitlab token is empty"));
+            },
+            (token, None) => SharedToken::new(token),
+            (None, Some(shared)) => shared,
+        };
+        let mut client = GitlabClient {
+            client: self.http.build_for(&base_uri)?,
+            base_uri,
+            token,
+            rotation: self.rotation,
+            pagination_allowlist: self.pagination_allowlist,
+            per_page: self.per_page,
+            api_version: self.api_version,
+            version_checks: self.version_checks,
+            server_version: None,
+            rate_limit: None,
+            token_cache: None,
+        };
+        if let Some(cache) = self.token_cache {
+            client.set_token_cache(cache)?;
+        }
+        Ok(client)
+    }
+}
+
+impl GitlabClient {
+    /// Log in with a username and password and mint a personal access token for that user with
+    /// `scopes` and an optional `expires_at` date (`YYYY-MM-DD`), then switch this client over
+    /// to the new token - useful for bootstrapping automation accounts
+    ///
+    /// Creating tokens for a user requires administrator access. Gitlab does not accept two
+    /// factor codes in this flow so any passcode in `creds` is ignored.
+    pub fn create_personal_access_token(&mut self, creds: &ApiCredentials, name: &str,
+                                        scopes: &[&str], expires_at: Option<&str>)
+                                        -> Result<PersonalAccessToken> {
+        match *creds {
+            ApiCredentials::UserPass(..) | ApiCredentials::UserPassTwoFactor(..) => (),
+            _ => return Err(ClientError::new("A username and password are required to create a token")),
+        }
+        self.login(creds)?;
+        let user = self.request_json(Method::Get, "/user".parse()?, None::<Value>)?;
+        let user_id = user.get("id").and_then(|id| id.as_u64())
+            .ok_or(ClientError::new("Could not retrieve the ID of the logged in user"))?;
+
+        let mut args = Map::new();
+        args.insert("name".to_string(), Value::from(name));
+        args.insert("scopes".to_string(), Value::from(scopes.to_vec()));
+        if let Some(date) = expires_at {
+            args.insert("expires_at".to_string(), Value::from(date));
+        }
+        let uri = format!("/users/{}/personal_access_tokens", user_id).parse::<Uri>()?;
+        let json = self.request_json(Method::Post, uri, Some(Value::from(args)))?;
+        let field = |name: &str| json.get(name).and_then(|v| v.as_str()).map(|s| s.to_string());
+        let token = PersonalAccessToken {
+            id: json.get("id").and_then(|id| id.as_u64())
+                .ok_or(ClientError::new("Could not create personal access token"))?,
+            name: field("name").unwrap_or_else(|| name.to_string()),
+            scopes: json.get("scopes").and_then(|s| s.as_array())
+                .map(|s| s.iter().filter_map(|v| v.as_str()).map(|v| v.to_string()).collect())
+                .unwrap_or_default(),
+            expires_at: field("expires_at"),
+            token: field("token").map(Secret::from)
+                .ok_or(ClientError::new("Gitlab did not return the new personal access token"))?,
+        };
+        let personal = TokenType::PersonalAccess(token.token.clone());
+        self.token.set(Some(personal.clone()));
+        if let Some(cache) = self.token_cache.as_ref() {
+            cache.save(&personal.to_cached())?;
+        }
+        Ok(token)
+    }
+
+    fn host_uri(&self) -> Result<String> {
+        uri_origin(&self.base_uri)
+    }
+
+    /// Device authorization flow against this Gitlab instance for the OAuth application
+    /// `client_id`, which must have the device flow enabled
+    pub fn device_flow(&self, client_id: &str) -> Result<DeviceFlow> {
+        let host_uri = self.host_uri()?;
+        Ok(DeviceFlow::new(format!("{}/oauth/authorize_device", host_uri).parse()?,
+                           format!("{}/oauth/token", host_uri).parse()?, client_id))
+    }
+
+    /// Sign in with the device authorization `flow`, showing the code with `prompt`, and
+    /// switch this client over to the OAuth token issued
+    pub fn device_login<F>(&mut self, flow: &DeviceFlow, prompt: F) -> Result<()>
+            where F: FnOnce(&DeviceAuthorization) {
+        let token = self.device_flow_login(flow, prompt)?;
+        let token = TokenType::Oauth(token.access_token);
+        self.token.set(Some(token.clone()));
+        if let Some(cache) = self.token_cache.as_ref() {
+            cache.save(&token.to_cached())?;
+        }
+        Ok(())
+    }
+}
+
+/// Server version
+impl GitlabClient {
+    /// Version of the server, read from `/version` on the first call
+    pub fn server_version(&mut self) -> Result<GitlabVersion> {
+        if let Some(ref version) = self.server_version {
+            return Ok(version.clone());
+        }
+        let json = self.request_json(Method::Get, endpoint("/version".to_string())?, None::<Value>)?;
+        let version = json.get("version").and_then(Value::as_str)
+            .ok_or(ClientError::new("Gitlab did not report its version"))
+            .and_then(GitlabVersion::parse)?;
+        self.server_version = Some(version.clone());
+        Ok(version)
+    }
+
+    /// Use `version` as the server version instead of reading it - `None` reads it again on
+    /// the next call that needs it
+    pub fn set_server_version(&mut self, version: Option<GitlabVersion>) {
+        self.server_version = version;
+    }
+
+    /// Check the server version before calls that need a newer Gitlab, so that they fail with
+    /// the version required rather than a `404` - off unless enabled, since it costs a request
+    pub fn set_version_checks(&mut self, enabled: bool) {
+        self.version_checks = enabled;
+    }
+
+    /// Fail unless the server runs Gitlab `major.minor` or later, for `feature` - does nothing
+    /// unless version checks are enabled
+    pub fn require_version(&mut self, major: u32, minor: u32, feature: &str) -> Result<()> {
+        if !self.version_checks {
+            return Ok(());
+        }
+        let version = self.server_version()?;
+        if version.at_least(major, minor) {
+            Ok(())
+        } else {
+            Err(ClientError::new(format!("{} requires GitLab >= {}.{}, but the server runs {}",
+                                         feature, major, minor, version)))
+        }
+    }
+}
+
+/// Group epics
+impl GitlabClient {
+    /// List all epics in `group`, where `group` is a numeric ID or full path
+    pub fn list_epics(&mut self, group: &str) -> Result<Value> {
+        let uri = endpoint(format!("/groups/{}/epics", encode_id(group)))?;
+        self.autopagination(Method::Get, uri, None::<Value>)
+    }
+
+    /// Create an epic in `group`
+    pub fn create_epic(&mut self, group: &str, title: &str, description: Option<&str>) -> Result<Value> {
+        let mut args = Map::new();
+        args.insert("title".to_string(), Value::from(title));
+        if let Some(d) = description {
+            args.insert("description".to_string(), Value::from(d));
+        }
+        let uri = endpoint(format!("/groups/{}/epics", encode_id(group)))?;
+        self.request_json(Method::Post, uri, Some(Value::from(args)))
+    }
+
+    /// List all issues linked to the epic `epic_iid` in `group`
+    pub fn list_epic_issues(&mut self, group: &str, epic_iid: u64) -> Result<Value> {
+        let uri = endpoint(format!("/groups/{}/epics/{}/issues", encode_id(group), epic_iid))?;
+        self.autopagination(Method::Get, uri, None::<Value>)
+    }
+
+    /// Link the issue with global ID `issue_id` to the epic `epic_iid` in `group`
+    pub fn link_issue_to_epic(&mut self, group: &str, epic_iid: u64, issue_id: u64) -> Result<Value> {
+        let uri = endpoint(format!("/groups/{}/epics/{}/issues/{}", encode_id(group), epic_iid, issue_id))?;
+        self.request_json(Method::Post, uri, None::<Value>)
+    }
+}
+
+/// Project issue boards
+impl GitlabClient {
+    /// List all issue boards in `project`, where `project` is a numeric ID or full path
+    pub fn list_boards(&mut self, project: &str) -> Result<Value> {
+        let uri = endpoint(format!("/projects/{}/boards", encode_id(project)))?;
+        self.autopagination(Method::Get, uri, None::<Value>)
+    }
+
+    /// List the lists of board `board_id` in `project`
+    pub fn list_board_lists(&mut self, project: &str, board_id: u64) -> Result<Value> {
+        let uri = endpoint(format!("/projects/{}/boards/{}/lists", encode_id(project), board_id))?;
+        self.autopagination(Method::Get, uri, None::<Value>)
+    }
+
+    /// Move issue `issue_iid` from one list of board `board_id` to another by swapping the
+    /// list labels on the issue, returning the updated issue
+    pub fn move_issue_between_lists(&mut self, project: &str, board_id: u64, issue_iid: u64,
+                                    from_list_id: u64, to_list_id: u64) -> Result<Value> {
+        let from = self.board_list_label(project, board_id, from_list_id)?;
+        let to = self.board_list_label(project, board_id, to_list_id)?;
+        let mut args = Map::new();
+        args.insert("remove_labels".to_string(), Value::from(from));
+        args.insert("add_labels".to_string(), Value::from(to));
+        let uri = endpoint(format!("/projects/{}/issues/{}", encode_id(project), issue_iid))?;
+        self.request_json(Method::Put, uri, Some(Value::from(args)))
+    }
+
+    fn board_list_label(&mut self, project: &str, board_id: u64, list_id: u64) -> Result<String> {
+        let uri = endpoint(format!("/projects/{}/boards/{}/lists/{}", encode_id(project), board_id, list_id))?;
+        let list = self.request_json(Method::Get, uri, None::<Value>)?;
+        list.get("label").and_then(|l| l.get("name")).and_then(|n| n.as_str()).map(|n| n.to_string())
+            .ok_or(ClientError::new(format!("Board list {} is not a label list", list_id)))
+    }
+}
+
+/// Protected branches and merge request approval rules
+impl GitlabClient {
+    /// Protect branch `name` (which may be a wildcard such as `release-*`) in `project`
+    pub fn protect_branch(&mut self, project: &str, name: &str, push_level: AccessLevel,
+                          merge_level: AccessLevel) -> Result<Value> {
+        let mut args = Map::new();
+        args.insert("name".to_string(), Value::from(name));
+        args.insert("push_access_level".to_string(), Value::from(push_level.value()));
+        args.insert("merge_access_level".to_string(), Value::from(merge_level.value()));
+        let uri = endpoint(format!("/projects/{}/protected_branches", encode_id(project)))?;
+        self.request_json(Method::Post, uri, Some(Value::from(args)))
+    }
+
+    /// List all protected branches in `project`
+    pub fn list_protected_branches(&mut self, project: &str) -> Result<Value> {
+        let uri = endpoint(format!("/projects/{}/protected_branches", encode_id(project)))?;
+        self.autopagination(Method::Get, uri, None::<Value>)
+    }
+
+    /// Remove protection from branch `name` in `project`
+    pub fn unprotect_branch(&mut self, project: &str, name: &str) -> Result<()> {
+        let uri = endpoint(format!("/projects/{}/protected_branches/{}", encode_id(project), encode_id(name)))?;
+        self.request_no_content(Method::Delete, uri, None)
+    }
+
+    /// List the merge request approval rules of `project` - requires Gitlab 12.3
+    pub fn list_approval_rules(&mut self, project: &str) -> Result<Value> {
+        self.require_version(12, 3, "Approval rules")?;
+        let uri = endpoint(format!("/projects/{}/approval_rules", encode_id(project)))?;
+        self.request_json(Method::Get, uri, None::<Value>)
+    }
+
+    /// Create a merge request approval rule in `project` - requires Gitlab 12.3
+    pub fn create_approval_rule(&mut self, project: &str, rule: &ApprovalRule) -> Result<Value> {
+        self.require_version(12, 3, "Approval rules")?;
+        let uri = endpoint(format!("/projects/{}/approval_rules", encode_id(project)))?;
+        self.request_json(Method::Post, uri, Some(rule.to_json()))
+    }
+
+    /// Replace the merge request approval rule `rule_id` in `project` - requires Gitlab 12.3
+    pub fn update_approval_rule(&mut self, project: &str, rule_id: u64, rule: &ApprovalRule)
+            -> Result<Value> {
+        self.require_version(12, 3, "Approval rules")?;
+        let uri = endpoint(format!("/projects/{}/approval_rules/{}", encode_id(project), rule_id))?;
+        self.request_json(Method::Put, uri, Some(rule.to_json()))
+    }
+
+    /// Delete the merge request approval rule `rule_id` in `project` - requires Gitlab 12.3
+    pub fn delete_approval_rule(&mut self, project: &str, rule_id: u64) -> Result<()> {
+        self.require_version(12, 3, "Approval rules")?;
+        let uri = endpoint(format!("/projects/{}/approval_rules/{}", encode_id(project), rule_id))?;
+        self.request_no_content(Method::Delete, uri, None)
+    }
+
+    fn request_no_content(&mut self, method: Method, uri: Uri, body: Option<Value>) -> Result<()> {
+        let response = self.request(method.clone(), uri.clone(), body.map(|b| b.to_string()))?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(ClientError::new(format!("{} {} failed with status {}", method, uri, response.status())))
+        }
+    }
+}
+
+/// Container registry
+impl GitlabClient {
+    /// List the container registry repositories of `project`
+    pub fn list_registry_repositories(&mut self, project: &str) -> Result<Value> {
+        let uri = endpoint(format!("/projects/{}/registry/repositories", encode_id(project)))?;
+        self.autopagination(Method::Get, uri, None::<Value>)
+    }
+
+    /// List the tags in registry repository `repository_id` of `project`
+    pub fn list_tags(&mut self, project: &str, repository_id: u64) -> Result<Value> {
+        let uri = endpoint(format!("/projects/{}/registry/repositories/{}/tags", encode_id(project),
+                                   repository_id))?;
+        self.autopagination(Method::Get, uri, None::<Value>)
+    }
+
+    /// Delete tag `tag` from registry repository `repository_id` of `project`
+    pub fn delete_tag(&mut self, project: &str, repository_id: u64, tag: &str) -> Result<()> {
+        let uri = endpoint(format!("/projects/{}/registry/repositories/{}/tags/{}", encode_id(project),
+                                   repository_id, encode_id(tag)))?;
+        self.request_no_content(Method::Delete, uri, None)
+    }
+
+    /// Delete every tag selected by `cleanup` from registry repository `repository_id` of
+    /// `project`
+    ///
+    /// Gitlab accepts the request and removes the tags in the background.
+    pub fn bulk_delete_tags(&mut self, project: &str, repository_id: u64, cleanup: &TagCleanup)
+            -> Result<()> {
+        let uri = endpoint(format!("/projects/{}/registry/repositories/{}/tags", encode_id(project),
+                                   repository_id))?;
+        self.request_no_content(Method::Delete, uri, Some(Value::from(cleanup.to_json("name_regex_delete"))))
+    }
+
+    /// Enable the scheduled tag expiration policy of `project`, removing the tags selected by
+    /// `cleanup` from all of its registry repositories every `cadence` such as `1d` or `7d`
+    pub fn set_tag_expiration_policy(&mut self, project: &str, cleanup: &TagCleanup, cadence: &str)
+            -> Result<Value> {
+        let mut policy = cleanup.to_json("name_regex");
+        policy.insert("enabled".to_string(), Value::from(true));
+        policy.insert("cadence".to_string(), Value::from(cadence));
+        let mut args = Map::new();
+        args.insert("container_expiration_policy_attributes".to_string(), Value::from(policy));
+        let uri = endpoint(format!("/projects/{}", encode_id(project)))?;
+        self.request_json(Method::Put, uri, Some(Value::from(args)))
+    }
+
+    /// Disable the scheduled tag expiration policy of `project`
+    pub fn disable_tag_expiration_policy(&mut self, project: &str) -> Result<Value> {
+        let mut policy = Map::new();
+        policy.insert("enabled".to_string(), Value::from(false));
+        let mut args = Map::new();
+        args.insert("container_expiration_policy_attributes".to_string(), Value::from(policy));
+        let uri = endpoint(format!("/projects/{}", encode_id(project)))?;
+        self.request_json(Method::Put, uri, Some(Value::from(args)))
+    }
+}
+
+/// Search
+impl GitlabClient {
+    /// Search the whole instance for `query` in `scope`, following every page of results
+    pub fn search(&mut self, scope: SearchScope, query: &str) -> Result<Value> {
+        let uri = endpoint_with_query("/search".to_string(), &[
+            ("scope", scope.as_str().to_string()),
+            ("search", query.to_string()),
+        ])?;
+        self.autopagination(Method::Get, uri, None::<Value>)
+    }
+}
+
+/// Audit events and instance statistics
+impl GitlabClient {
+    /// List the audit events in `scope` matching `filter`, following every page
+    ///
+    /// Keyset pagination is requested so that large event logs can be read in full without
+    /// hitting the offset pagination limit.
+    pub fn list_audit_events(&mut self, scope: &AuditScope, filter: &AuditEventFilter) -> Result<Value> {
+        let path = match *scope {
+            AuditScope::Instance => "/audit_events".to_string(),
+            AuditScope::Group(ref group) => format!("/groups/{}/audit_events", encode_id(group)),
+            AuditScope::Project(ref project) => format!("/projects/{}/audit_events", encode_id(project)),
+        };
+        let mut query = vec![("pagination", "keyset".to_string()), ("order_by", "id".to_string()),
+                             ("sort", "asc".to_string()), ("per_page", "100".to_string())];
+        query.extend(filter.query());
+        let uri = endpoint_with_query(path, &query)?;
+        self.autopagination(Method::Get, uri, None::<Value>)
+    }
+
+    /// Instance wide object counts - requires an administrator token
+    pub fn admin_statistics(&mut self) -> Result<Value> {
+        self.request_json(Method::Get, endpoint("/application/statistics".to_string())?, None::<Value>)
+    }
+}
+
+/// Shell completion
+impl GitlabClient {
+    /// Paths of the projects the user is a member of that start with `prefix`, listed at most
+    /// once per `cache` TTL
+    pub fn complete_project_paths(&mut self, cache: &mut NameCache, prefix: &str) -> Result<Vec<String>> {
+        let key = format!("gitlab {} projects", self.base_uri);
+        let paths = cache.get_or_fetch(&key, || {
+            let uri = endpoint("/projects?membership=true&simple=true&per_page=100".to_string())?;
+            Ok(names_at(&self.autopagination(Method::Get, uri, None::<Value>)?, "path_with_namespace"))
+        })?;
+        Ok(complete(&paths, prefix))
+    }
+}
+
+/// Generic package registry
+impl GitlabClient {
+    /// Upload `file` to the generic package `name` at `version` in `project`, keeping its file
+    /// name
+    ///
+    /// The file is streamed rather than read into memory. Uploading a file that already exists
+    /// in the package version adds a new copy that Gitlab serves in place of the old one.
+    pub fn upload_package<P>(&mut self, project: &str, name: &str, version: &str, file: P) -> Result<()>
+            where P: AsRef<Path> {
+        let path = file.as_ref();
+        let file_name = path.file_name().and_then(|f| f.to_str()).ok_or_else(|| {
+            ClientError::new(format!("{} does not have a valid file name", path.display()))
+        })?;
+        let file = File::open(path).map_err(|e| {
+            ClientError::new(format!("Failed to open {}: {}", path.display(), e))
+        })?;
+        let len = file.metadata().ok().map(|m| m.len());
+        self.upload_package_from(project, name, version, file_name, file, len)
+    }
+
+    /// Upload the contents of `reader` as `file_name` in the generic package `name` at
+    /// `version` in `project`
+    ///
+    /// `len` is sent as the `Content-Length` when it is known, otherwise the body is sent
+    /// chunked. Requires Gitlab 13.5.
+    pub fn upload_package_from<R>(&mut self, project: &str, name: &str, version: &str, file_name: &str,
+                                  reader: R, len: Option<u64>) -> Result<()>
+            where R: Read + Send + 'static {
+        self.require_version(13, 5, "Generic packages")?;
+        let uri = self.full_uri(package_endpoint(project, name, version, file_name)?)?;
+        self.before_request()?;
+        let token = self.token.get();
+        let future = {
+            let client = self.http_client_mut();
+            client.start_request(Method::Put, uri);
+            add_token_header(client, &token);
+            if let Some(len) = len {
+                client.add_header(ContentLength(len));
+            }
+            client.add_stream_body(reader).make_request().future()
+        }.ok_or(ClientError::new("No request made"))?;
+        let response = self.response_future(future)?;
+        package_status(response.status(), "upload", name, version, file_name)
+    }
+
+    /// Download `file_name` from the generic package `name` at `version` in `project` to
+    /// `writer`, returning the number of bytes written - requires Gitlab 13.5
+    pub fn download_package<W>(&mut self, project: &str, name: &str, version: &str, file_name: &str,
+                               writer: &mut W) -> Result<u64> where W: Write {
+        self.require_version(13, 5, "Generic packages")?;
+        let uri = package_endpoint(project, name, version, file_name)?;
+        let response = self.request(Method::Get, uri, None::<String>)?;
+        package_status(response.status(), "download", name, version, file_name)?;
+        self.write_body(response, writer)
+    }
+}
+
+fn add_token_header(client: &mut SimpleHttpClient, token: &Option<TokenType>) {
+    if let Some(TokenType::Oauth(ref t)) = *token {
+        client.add_header(Authorization(Bearer { token: t.expose().to_string() }));
+    } else if let Some(TokenType::PersonalAccess(ref t)) = *token {
+        client.add_header(PrivateToken(t.expose().to_string()));
+    }
+}
+
+fn package_endpoint(project: &str, name: &str, version: &str, file_name: &str) -> Result<Uri> {
+    endpoint(format!("/projects/{}/packages/generic/{}/{}/{}", encode_id(project), encode_id(name),
+                     encode_id(version), encode_id(file_name)))
+}
+
+fn package_status(status: StatusCode, action: &str, name: &str, version: &str, file_name: &str)
+        -> Result<()> {
+    if status.is_success() {
+        return Ok(());
+    }
+    let message = match status {
+        StatusCode::BadRequest => format!("Failed to {} {} from package {} {}: invalid package name, \
+                                           version or file name", action, file_name, name, version),
+        StatusCode::NotFound => format!("Failed to {} {} from package {} {}: project, package or file \
+                                         not found", action, file_name, name, version),
+        _ => format!("Failed to {} {} from package {} {}: status {}", action, file_name, name, version,
+                     status),
+    };
+    match auth::auth_failure(status) {
+        Some(kind) => Err(ClientError::with_kind(kind, message)),
+        None => Err(ClientError::new(message)),
+    }
+}
+
+impl Transport for GitlabClient {
+    type Http = SimpleHttpClient;
 }
 
 impl ApiClient<SimpleHttpClient> for GitlabClient {
@@ -137,28 +1151,36 @@ impl ApiClient<SimpleHttpClient> for GitlabClient {
         &mut self.client
     }
 
+    fn api_version(&self) -> Option<&ApiVersion> {
+        self.api_version.as_ref()
+    }
+
+    fn before_request(&mut self) -> Result<()> {
+        if let Some(ref mut rotation) = self.rotation {
+            rotation.rotate_if_needed(&self.token)?;
+        }
+        Ok(())
+    }
+
     fn login(&mut self, creds: &ApiCredentials) -> Result<()> {
-        let token = {
-            let mut auth = |user: &String, pass: &String| -> Result<Option<String>> {
+        let shared_token = self.token.clone();
+        let seen = shared_token.generation();
+        shared_token.refresh(seen, || {
+            let mut auth = |user: &String, pass: &Secret| -> Result<Option<Secret>> {
                 let mut json_map = Map::new();
                 json_map.insert("grant_type".to_string(), Value::from("password"));
                 json_map.insert("username".to_string(), Value::from(user.clone()));
-                json_map.insert("password".to_string(), Value::from(pass.clone()));
-                let mut host_uri = format!("{}://{}", self.base_uri.scheme().ok_or(ClientError::new("Invalid base URI"))?,
-                                           self.base_uri.authority().ok_or(ClientError::new("Invalid base URI"))?);
-                if host_uri.ends_with('/') {
-                    let _ = host_uri.pop();
-                }
-                let uri = (host_uri + "/oauth/token").parse::<Uri>()?;
+                json_map.insert("password".to_string(), Value::from(pass.expose()));
+                let uri = (self.host_uri()? + "/oauth/token").parse::<Uri>()?;
                 let json = <Self as JsonApiClient<SimpleHttpClient>>::request_json(self, Method::Post, uri,
                     Some(Value::from(json_map)))?;
                 let token_json = json.get("access_token")
                                  .ok_or(ClientError::new("Could not log in with given username and password"))?
-                                 .as_str().map(|x| { x.to_string() });
+                                 .as_str().map(Secret::from);
                 Ok(token_json)
             };
 
-            match *creds {
+            let token = match *creds {
                 ApiCredentials::NoAuth => None,
                 ApiCredentials::UserPass(ref user, ref pass) => {
                     try!(auth(user, pass)).map(TokenType::Oauth)
@@ -167,29 +1189,33 @@ impl ApiClient<SimpleHttpClient> for GitlabClient {
                     try!(auth(user, pass)).map(TokenType::Oauth)
                 },
                 ApiCredentials::ApiKey(ref key) => Some(TokenType::PersonalAccess(key.clone())),
-            }
-        };
-
-        self.token = token;
+            };
+            Ok(token)
+        })?;
+        if let (Some(cache), Some(token)) = (self.token_cache.as_ref(), self.token.get()) {
+            cache.save(&token.to_cached())?;
+        }
         Ok(())
     }
 
     fn request_future<B>(&mut self, method: Method, uri: Uri, body: Option<B>) -> Option<FutureResponse>
             where B: ToString {
-        let token = self.token.clone();
+        let token = self.token.get();
         let full_uri = self.full_uri(uri).ok()?;
         let client = self.http_client_mut();
         client.start_request(method, full_uri).add_header(ContentType::json());
-        if let Some(TokenType::Oauth(ref t)) = token {
-            client.add_header(Authorization(Bearer { token: t.clone() }));
-        } else if let Some(TokenType::PersonalAccess(ref t)) = token {
-            client.add_header(PrivateToken(t.clone()));
-        }
+        add_token_header(client, &token);
         if let Some(b) = body {
-            client.add_body(b.to_string());
+            client.add_body_bytes(b.to_string());
         }
         client.make_request().future()
     }
+
+    fn response_future(&mut self, f: FutureResponse) -> Result<Response> {
+        let response = self.http_client_mut().evaluate_response(f)?;
+        self.rate_limit = RateLimitStatus::from_headers(response.headers());
+        Ok(response)
+    }
 }
 
 impl JsonApiClient<SimpleHttpClient> for GitlabClient {
@@ -198,11 +1224,599 @@ impl JsonApiClient<SimpleHttpClient> for GitlabClient {
         let link_option = resp.headers().get::<Link>();
         Ok(link_option.next())
     }
+
+    fn page_info(&mut self, resp: &Response) -> Result<PageInfo> {
+        let mut info = PageInfo::from_headers(resp.headers());
+        if let Some(link) = resp.headers().get::<Link>() {
+            info.next = link.next.as_ref().and_then(|n| n.parse::<Uri>().ok());
+            info.prev = link.previous.as_ref().and_then(|p| p.parse::<Uri>().ok());
+        }
+        Ok(info)
+    }
+
+    fn per_page(&self) -> Option<u32> {
+        self.per_page
+    }
+
+    fn pagination_allowlist(&self) -> &[String] {
+        &self.pagination_allowlist
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use testing::{Fixture,FixtureServer};
+
+    #[test]
+    fn test_create_personal_access_token() {
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/oauth/token", Fixture::json(200, r#"{"access_token": "oauth"}"#))
+            .route(Method::Get, "/api/v4/user", Fixture::json(200, r#"{"id": 5, "username": "bot"}"#))
+            .route(Method::Post, "/api/v4/users/5/personal_access_tokens", Fixture::json(201,
+                r#"{"id": 42, "name": "ci", "scopes": ["api"], "expires_at": "2030-01-01", "token": "glpat-new"}"#))
+            .start().unwrap();
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let creds = ApiCredentials::UserPass("bot".to_string(), "pw".into());
+        let token = client.create_personal_access_token(&creds, "ci", &["api"], Some("2030-01-01")).unwrap();
+        assert_eq!(token.id, 42);
+        assert_eq!(token.scopes, vec!["api".to_string()]);
+        assert_eq!(token.token.expose(), "glpat-new");
+        match client.shared_token().get() {
+            Some(TokenType::PersonalAccess(ref t)) => assert_eq!(t.expose(), "glpat-new"),
+            other => panic!("Unexpected token {:?}", other),
+        }
+
+        server.assert_sent().post("/oauth/token")
+            .json_body_contains(serde_json::from_str(r#"{"username": "bot", "password": "pw"}"#).unwrap());
+        server.assert_sent().get("/api/v4/user").header("Authorization", "Bearer oauth");
+        server.assert_sent().post("/api/v4/users/5/personal_access_tokens")
+            .json_body_contains(serde_json::from_str(
+                r#"{"name": "ci", "scopes": ["api"], "expires_at": "2030-01-01"}"#
+            ).unwrap());
+        assert!(client.create_personal_access_token(&ApiCredentials::ApiKey("k".into()), "ci", &[], None).is_err());
+    }
+
+    #[test]
+    fn test_device_login() {
+        use clock::MockClock;
+
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/oauth/authorize_device", Fixture::json(200, r#"{"device_code": "dev",
+                "user_code": "WXYZ", "verification_uri": "https://gitlab.example.com/oauth/device", "expires_in": 300}"#))
+            .route(Method::Post, "/oauth/token", Fixture::json(400, r#"{"error": "authorization_pending"}"#))
+            .route(Method::Post, "/oauth/token", Fixture::json(200, r#"{"access_token": "device-oauth"}"#))
+            .route(Method::Get, "/api/v4/user", Fixture::json(200, r#"{"id": 5}"#))
+            .start().unwrap();
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let clock = MockClock::new();
+        let flow = client.device_flow("app-id").unwrap().scope("api").sleeper(clock.clone());
+        client.device_login(&flow, |a| assert_eq!(a.user_code, "WXYZ")).unwrap();
+        assert_eq!(clock.sleeps(), vec![Duration::from_secs(5); 2]);
+        client.request_json(Method::Get, "/user".parse().unwrap(), None::<Value>).unwrap();
+        server.assert_sent().get("/api/v4/user").header("Authorization", "Bearer device-oauth");
+    }
+
+    #[test]
+    fn test_ipv6_host_uri() {
+        let client = GitlabClient::new("https://[2001:db8::1]:8443/api/v4/".to_string()).unwrap();
+        let flow = client.device_flow("app-id").unwrap();
+        assert_eq!(flow.token_uri().to_string(), "https://[2001:db8::1]:8443/oauth/token");
+        assert_eq!(client.full_uri("/projects".parse().unwrap()).unwrap().to_string(),
+                   "https://[2001:db8::1]:8443/api/v4/projects");
+    }
+
+    #[test]
+    fn test_encode_id() {
+        assert_eq!(encode_id("42"), "42");
+        assert_eq!(encode_id("group/sub group/project"), "group%2Fsub%20group%2Fproject");
+    }
+
+    #[test]
+    fn test_epics_and_boards() {
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/api/v4/groups/ops%2Fplatform/epics", Fixture::json(201, r#"{"iid": 3}"#))
+            .route(Method::Post, "/api/v4/groups/ops%2Fplatform/epics/3/issues/77", Fixture::json(201, "{}"))
+            .route(Method::Get, "/api/v4/projects/9/boards/1/lists/10",
+                   Fixture::json(200, r#"{"id": 10, "label": {"name": "To Do"}}"#))
+            .route(Method::Get, "/api/v4/projects/9/boards/1/lists/11",
+                   Fixture::json(200, r#"{"id": 11, "label": {"name": "Doing"}}"#))
+            .route(Method::Put, "/api/v4/projects/9/issues/4", Fixture::json(200, r#"{"iid": 4}"#))
+            .start().unwrap();
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let epic = client.create_epic("ops/platform", "Q3", None).unwrap();
+        assert_eq!(epic["iid"], 3);
+        client.link_issue_to_epic("ops/platform", 3, 77).unwrap();
+        client.move_issue_between_lists("9", 1, 4, 10, 11).unwrap();
+
+        server.assert_sent().post("/api/v4/groups/ops%2Fplatform/epics")
+            .json_body_contains(serde_json::from_str(r#"{"title": "Q3"}"#).unwrap());
+        server.assert_sent().put("/api/v4/projects/9/issues/4")
+            .json_body_contains(serde_json::from_str(
+                r#"{"remove_labels": "To Do", "add_labels": "Doing"}"#
+            ).unwrap());
+    }
+
+    #[test]
+    fn test_protected_branches_and_approval_rules() {
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/api/v4/projects/9/protected_branches", Fixture::json(201, r#"{"name": "main"}"#))
+            .route(Method::Delete, "/api/v4/projects/9/protected_branches/release-*", Fixture::new(204))
+            .route(Method::Put, "/api/v4/projects/9/approval_rules/2", Fixture::json(200, r#"{"id": 2}"#))
+            .route(Method::Delete, "/api/v4/projects/9/approval_rules/3", Fixture::new(404))
+            .start().unwrap();
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        client.protect_branch("9", "main", AccessLevel::NoAccess, AccessLevel::Maintainer).unwrap();
+        client.unprotect_branch("9", "release-*").unwrap();
+        client.http_client_mut().set_method_override(true);
+        server.add_route(Method::Post, "/api/v4/projects/9/protected_branches/main", Fixture::new(204));
+        client.unprotect_branch("9", "main").unwrap();
+        client.http_client_mut().set_method_override(false);
+        server.assert_sent().post("/api/v4/projects/9/protected_branches/main")
+            .header("X-HTTP-Method-Override", "DELETE");
+        let mut rule = ApprovalRule::new("security", 2);
+        rule.group_ids.push(7);
+        client.update_approval_rule("9", 2, &rule).unwrap();
+        assert!(client.delete_approval_rule("9", 3).is_err());
+
+        server.assert_sent().post("/api/v4/projects/9/protected_branches")
+            .json_body_contains(serde_json::from_str(
+                r#"{"name": "main", "push_access_level": 0, "merge_access_level": 40}"#
+            ).unwrap());
+        server.assert_sent().put("/api/v4/projects/9/approval_rules/2")
+            .json_body_contains(serde_json::from_str(
+                r#"{"name": "security", "approvals_required": 2, "group_ids": [7], "user_ids": []}"#
+            ).unwrap());
+    }
+
+    #[test]
+    fn test_container_registry() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/projects/group%2Fapp/registry/repositories/4/tags",
+                   Fixture::json(200, r#"[{"name": "v1.0"}, {"name": "latest"}]"#))
+            .route(Method::Delete, "/api/v4/projects/group%2Fapp/registry/repositories/4/tags/v1.0",
+                   Fixture::new(200))
+            .route(Method::Delete, "/api/v4/projects/group%2Fapp/registry/repositories/4/tags",
+                   Fixture::new(202))
+            .route(Method::Put, "/api/v4/projects/group%2Fapp", Fixture::json(200, r#"{"id": 9}"#))
+            .start().unwrap();
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        assert_eq!(client.list_tags("group/app", 4).unwrap()[1]["name"], "latest");
+        client.delete_tag("group/app", 4, "v1.0").unwrap();
+        let cleanup = TagCleanup::new(".*").keep_matching("^v\\d").keep_latest(5).older_than("30d");
+        client.bulk_delete_tags("group/app", 4, &cleanup).unwrap();
+        client.set_tag_expiration_policy("group/app", &cleanup, "7d").unwrap();
+
+        server.assert_sent().delete("/api/v4/projects/group%2Fapp/registry/repositories/4/tags")
+            .json_body_contains(serde_json::from_str(
+                r#"{"name_regex_delete": ".*", "name_regex_keep": "^v\\d", "keep_n": 5, "older_than": "30d"}"#
+            ).unwrap());
+        server.assert_sent().put("/api/v4/projects/group%2Fapp")
+            .json_body_contains(serde_json::from_str(r#"{"container_expiration_policy_attributes":
+                {"enabled": true, "cadence": "7d", "name_regex": ".*", "keep_n": 5}}"#).unwrap());
+    }
+
+    #[test]
+    fn test_generic_packages() {
+        let path = "/api/v4/projects/9/packages/generic/agent/1.2.0/agent.tar.gz";
+        let server = FixtureServer::builder()
+            .route(Method::Put, path, Fixture::json(201, r#"{"message": "201 Created"}"#))
+            .route(Method::Get, path, Fixture::new(200).body("package contents"))
+            .route(Method::Get, "/api/v4/projects/9/packages/generic/agent/1.2.0/missing", Fixture::new(404))
+            .route(Method::Put, "/api/v4/projects/8/packages/generic/agent/1.2.0/agent.tar.gz", Fixture::new(403))
+            .start().unwrap();
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let contents = vec![7u8; STREAM_CHUNK_SIZE + 10];
+        client.upload_package_from("9", "agent", "1.2.0", "agent.tar.gz", io::Cursor::new(contents.clone()),
+                                   None).unwrap();
+        server.assert_sent().put(path).body(&contents);
+
+        let mut written = Vec::new();
+        assert_eq!(client.download_package("9", "agent", "1.2.0", "agent.tar.gz", &mut written).unwrap(), 16);
+        assert_eq!(written, b"package contents");
+        assert!(client.download_package("9", "agent", "1.2.0", "missing", &mut written).is_err());
+        let denied = client.upload_package_from("8", "agent", "1.2.0", "agent.tar.gz", io::empty(), Some(0));
+        assert_eq!(denied.unwrap_err().kind(), &ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_audit_events() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/application/statistics", Fixture::json(200, r#"{"users": "12"}"#))
+            .start().unwrap();
+        let next = format!(r#"<{}>; rel="next""#, server.uri("/api/v4/groups/7/audit_events?cursor=abc"));
+        server.add_route(Method::Get, "/api/v4/groups/7/audit_events?pagination=keyset&order_by=id&sort=asc\
+                                       &per_page=100&created_after=2024-01-01T00%3A00%3A00Z",
+                         Fixture::json(200, r#"[{"id": 1}]"#).header("Link", next));
+        server.add_route(Method::Get, "/api/v4/groups/7/audit_events?cursor=abc",
+                         Fixture::json(200, r#"[{"id": 2}]"#));
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let filter = AuditEventFilter::new().created_after("2024-01-01T00:00:00Z");
+        let events = client.list_audit_events(&AuditScope::Group("7".to_string()), &filter).unwrap();
+        assert_eq!(events, serde_json::from_str::<Value>(r#"[{"id": 1}, {"id": 2}]"#).unwrap());
+        assert_eq!(client.admin_statistics().unwrap()["users"], "12");
+    }
+
+    #[test]
+    fn test_search() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/search?scope=merge_requests&search=fix+%26+test",
+                   Fixture::json(200, r#"[{"iid": 3}]"#))
+            .start().unwrap();
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let results = client.search(SearchScope::MergeRequests, "fix & test").unwrap();
+        assert_eq!(results[0]["iid"], 3);
+        assert_eq!(SearchScope::Blobs.as_str(), "blobs");
+    }
+
+    #[test]
+    fn test_rate_limit_status() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/projects/1", Fixture::json(200, "{}")
+                   .header("RateLimit-Limit", "600").header("RateLimit-Remaining", "0")
+                   .header("RateLimit-Reset", "1700000060"))
+            .route(Method::Get, "/api/v4/projects/2", Fixture::json(200, "{}"))
+            .start().unwrap();
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        assert_eq!(client.rate_limit_status(), None);
+        client.request_json(Method::Get, "/projects/1".parse().unwrap(), None::<Value>).unwrap();
+        let status = client.rate_limit_status().unwrap();
+        assert_eq!((status.limit, status.remaining), (600, 0));
+        assert!(status.is_exhausted());
+        let now = UNIX_EPOCH + Duration::from_secs(1700000000);
+        assert_eq!(status.time_until_reset(now), Duration::from_secs(60));
+        assert_eq!(status.time_until_reset(now + Duration::from_secs(120)), Duration::from_secs(0));
+        client.request_json(Method::Get, "/projects/2".parse().unwrap(), None::<Value>).unwrap();
+        assert_eq!(client.rate_limit_status(), None);
+    }
+
+    #[test]
+    fn test_request_with_headers() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/user", Fixture::json(200, r#"{"username": "ops"}"#))
+            .start().unwrap();
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let mut headers = Headers::new();
+        headers.set_raw("Sudo", "ops");
+        let user = client.request_json_with_headers(Method::Get, "/user".parse().unwrap(), None::<Value>,
+                                                    &headers).unwrap();
+        assert_eq!(user["username"], "ops");
+        client.request_json(Method::Get, "/user".parse().unwrap(), None::<Value>).unwrap();
+
+        let sent = server.requests();
+        assert_eq!(sent[0].header("Sudo"), Some("ops"));
+        assert_eq!(sent[1].header("Sudo"), None);
+    }
+
+    #[test]
+    fn test_request_with_options() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/projects/9/issues?state=opened", Fixture::new(502))
+            .route(Method::Get, "/api/v4/projects/9/issues?state=opened", Fixture::json(200, r#"[{"iid": 1}]"#))
+            .route(Method::Post, "/api/v4/projects/9/issues", Fixture::new(503))
+            .route(Method::Post, "/api/v4/projects/9/issues", Fixture::new(503))
+            .route(Method::Post, "/api/v4/projects/9/issues", Fixture::json(201, r#"{"iid": 2}"#))
+            .route(Method::Get, "/api/v4/slow", Fixture::json(200, "{}").delay(Duration::from_millis(500)))
+            .start().unwrap();
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let uri = || "/projects/9/issues".parse::<Uri>().unwrap();
+
+        let options = RequestOptions::new().query("state", "opened").retries(1);
+        let issues = client.request_json_with_options(Method::Get, uri(), None::<Value>, &options).unwrap();
+        assert_eq!(issues[0]["iid"], 1);
+
+        let created = client.request_with_options(Method::Post, uri(), Some("{}"), &options).unwrap();
+        assert_eq!(created.status(), StatusCode::ServiceUnavailable);
+        let options = options.idempotency_key("create-issue-2");
+        let created = client.request_json_with_options(Method::Post, uri(), Some(Value::Null), &options).unwrap();
+        assert_eq!(created["iid"], 2);
+        let sent = server.requests();
+        assert_eq!(sent.len(), 5);
+        assert_eq!(sent[2].header("Idempotency-Key"), None);
+        assert_eq!(sent[3].header("Idempotency-Key"), Some("create-issue-2"));
+        assert_eq!(sent[4].header("Idempotency-Key"), Some("create-issue-2"));
+
+        let options = RequestOptions::new().timeout(Duration::from_millis(50));
+        let slow = client.request_with_options(Method::Get, "/slow".parse().unwrap(), None::<String>, &options);
+        assert_eq!(slow.unwrap_err().kind(), &ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_autopagination_same_origin() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/projects?page=2", Fixture::json(200, r#"[{"id": 2}]"#))
+            .route(Method::Get, "/api/v4/groups", Fixture::json(200, "[]")
+                   .header("Link", r#"<https://evil.example.com/api/v4/groups?page=2>; rel="next""#))
+            .start().unwrap();
+        let next = format!(r#"<{}>; rel="next""#, server.uri("/api/v4/projects?page=2"));
+        server.add_route(Method::Get, "/api/v4/projects", Fixture::json(200, r#"[{"id": 1}]"#).header("Link", next));
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let projects = client.autopagination(Method::Get, "projects".parse().unwrap(), None::<Value>).unwrap();
+        assert_eq!(projects, serde_json::from_str::<Value>(r#"[{"id": 1}, {"id": 2}]"#).unwrap());
+
+        let err = client.autopagination(Method::Get, "groups".parse().unwrap(), None::<Value>).unwrap_err();
+        assert!(err.to_string().contains("evil.example.com"));
+        assert_eq!(server.requests().len(), 3);
+
+        client.allow_pagination_to("evil.example.com");
+        assert!(client.check_next_page(&"https://evil.example.com/api/v4/groups?page=2".parse().unwrap()).is_ok());
+        assert!(client.check_next_page(&"https://evil.example.com.attacker.net/".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_pagination_allowlist_scheme() {
+        let mut client = GitlabClient::new("https://gitlab.example.com/api/v4/".to_string()).unwrap();
+        client.allow_pagination_to("cdn.example.com");
+        assert!(client.check_next_page(&"https://cdn.example.com/api/v4/groups?page=2".parse().unwrap()).is_ok());
+        let err = client.check_next_page(&"http://cdn.example.com/api/v4/groups?page=2".parse().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("Refusing to follow"));
+
+        client.allow_pagination_to("http://mirror.example.com:8080");
+        assert!(client.check_next_page(&"http://mirror.example.com:8080/groups".parse().unwrap()).is_ok());
+        assert!(client.check_next_page(&"https://mirror.example.com:8080/groups".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_pipe_pages() {
+        use pipeline::PipelineApiClient;
+
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/users?page=2", Fixture::json(200, r#"[{"id": 3}, {"id": 4}, {"id": 5}]"#))
+            .start().unwrap();
+        let next = format!(r#"<{}>; rel="next""#, server.uri("/api/v4/users?page=2"));
+        server.add_route(Method::Get, "/api/v4/users", Fixture::json(200, r#"[{"id": 1}, {"id": 2}]"#).header("Link", next));
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let mut batches = Vec::new();
+        let written = client.pipe_pages(Method::Get, "users".parse().unwrap(), None::<Value>, 4, |batch| {
+            batches.push(batch.iter().map(|u| u["id"].as_u64().unwrap()).collect::<Vec<_>>());
+            Ok(())
+        }).unwrap();
+        assert_eq!(written, 5);
+        assert_eq!(batches, vec![vec![1, 2, 3, 4], vec![5]]);
+
+        let mut pages = client.pages(Method::Get, "users".parse().unwrap(), None::<Value>).unwrap();
+        assert_eq!(pages.next().unwrap().unwrap().len(), 2);
+        drop(pages);
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_execute_raw() {
+        let purge = Method::Extension("PURGE".to_string());
+        let server = FixtureServer::builder()
+            .route(purge.clone(), "/api/v4/projects/1/cache", Fixture::new(202))
+            .start().unwrap();
+        let mut client = GitlabClient::builder(server.uri("/api/v4/"))
+            .token(TokenType::PersonalAccess("glpat".into())).build().unwrap();
+        let mut request = hyper::Request::new(purge.clone(), "/projects/1/cache".parse().unwrap());
+        request.headers_mut().set_raw("X-Purge-Scope", "all");
+        request.set_body("everything");
+        assert_eq!(client.execute_raw(request).unwrap().status(), StatusCode::Accepted);
+
+        let sent = server.assert_sent().count(1).request(purge, "/api/v4/projects/1/cache")
+            .header("Private-Token", "glpat")
+            .header("X-Purge-Scope", "all");
+        assert_eq!(sent.request().body, b"everything".to_vec());
+        assert!(client.http_client_mut().request_overrides().unwrap().is_empty());
+        assert!(client.client.into_inner().is_some());
+    }
+
+    #[test]
+    fn test_per_page() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/users?page=2&per_page=100", Fixture::json(200, r#"[{"id": 3}]"#))
+            .start().unwrap();
+        let next = format!(r#"<{}>; rel="next""#, server.uri("/api/v4/users?page=2&per_page=20"));
+        server.add_route(Method::Get, "/api/v4/users?per_page=100",
+                         Fixture::json(200, r#"[{"id": 1}, {"id": 2}]"#).header("Link", next));
+        let mut client = GitlabClient::builder(server.uri("/api/v4/")).per_page(100).build().unwrap();
+        let users = client.autopagination(Method::Get, "/users?per_page=20".parse().unwrap(), None::<Value>).unwrap();
+        assert_eq!(users.as_array().unwrap().len(), 3);
+        let paths = server.requests().into_iter().map(|r| r.path).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/api/v4/users?per_page=100", "/api/v4/users?page=2&per_page=100"]);
+    }
+
+    #[test]
+    fn test_server_version() {
+        let version = GitlabVersion::parse("16.4.1-ee").unwrap();
+        assert_eq!(version.to_string(), "16.4.1-ee");
+        assert!(version.at_least(16, 4) && !version.at_least(16, 5));
+        assert!(GitlabVersion::parse("12.10").unwrap() > GitlabVersion::parse("12.9.8").unwrap());
+        assert!(GitlabVersion::parse("twelve").is_err());
+
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/version", Fixture::json(200, r#"{"version": "12.10.3-ee", "revision": "a1b2"}"#))
+            .route(Method::Get, "/api/v4/projects/9/approval_rules", Fixture::json(200, "[]"))
+            .start().unwrap();
+        let mut client = GitlabClient::builder(server.uri("/api/v4/")).version_checks(true).build().unwrap();
+        assert_eq!(client.list_approval_rules("9").unwrap(), Value::Array(Vec::new()));
+        let mut sink = Vec::new();
+        let err = client.download_package("9", "tool", "1.0", "tool.tgz", &mut sink).unwrap_err();
+        assert_eq!(err.to_string(), "Generic packages requires GitLab >= 13.5, but the server runs 12.10.3-ee");
+        client.set_version_checks(false);
+        client.set_server_version(None);
+        assert!(client.list_approval_rules("9").is_ok());
+        let paths = server.requests().into_iter().map(|r| r.path).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/api/v4/version", "/api/v4/projects/9/approval_rules", "/api/v4/projects/9/approval_rules"]);
+    }
+
+    #[test]
+    fn test_api_version() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/user", Fixture::json(200, r#"{"id": 4}"#))
+            .route(Method::Get, "/api/v5/user", Fixture::json(200, r#"{"id": 5}"#))
+            .start().unwrap();
+        let mut version = ApiVersion::new("4").path_prefix("api/v{version}/")
+            .accept("application/vnd.gitlab+json; version={version}");
+        let mut client = GitlabClient::builder(server.uri("/")).api_version(version.clone()).build().unwrap();
+        client.request_json(Method::Get, "/user".parse().unwrap(), None::<Value>).unwrap();
+        server.assert_sent().get("/api/v4/user").header("Accept", "application/vnd.gitlab+json; version=4");
+
+        version.set_version("5");
+        client.set_api_version(Some(version));
+        let options = RequestOptions::new().raw_header("Accept", "application/json");
+        let user = client.request_json_with_options(Method::Get, "/user".parse().unwrap(), None::<Value>, &options).unwrap();
+        assert_eq!(user["id"], 5);
+        server.assert_sent().get("/api/v5/user").header("Accept", "application/json");
+    }
+
+    #[test]
+    fn test_page_info() {
+        use pipeline::PipelineApiClient;
+
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/users?page=3", Fixture::json(200, r#"[{"id": 5}]"#)
+                   .header("X-Page", "3").header("X-Per-Page", "2").header("X-Total", "5"))
+            .start().unwrap();
+        let links = format!(r#"<{}>; rel="prev", <{}>; rel="next""#,
+                            server.uri("/api/v4/users?page=1"), server.uri("/api/v4/users?page=3"));
+        server.add_route(Method::Get, "/api/v4/users?page=2", Fixture::json(200, r#"[{"id": 3}, {"id": 4}]"#)
+                         .header("Link", links).header("X-Page", "2").header("X-Per-Page", "2")
+                         .header("X-Total", "5").header("X-Total-Pages", "3"));
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let mut pages = client.pages(Method::Get, "/users?page=2".parse().unwrap(), None::<Value>).unwrap();
+
+        let page = pages.next_page().unwrap().unwrap();
+        assert_eq!(page.items.len(), 2);
+        assert_eq!((page.info.page, page.info.per_page, page.info.total), (Some(2), Some(2), Some(5)));
+        assert_eq!(page.info.to_string(), "page 2 of 3");
+        assert_eq!(page.info.prev.map(|u| u.to_string()), Some(server.uri("/api/v4/users?page=1")));
+        assert_eq!(page.info.next.map(|u| u.to_string()), Some(server.uri("/api/v4/users?page=3")));
+
+        let last = pages.next_page().unwrap().unwrap().info;
+        assert_eq!(last.to_string(), "page 3 of 3");
+        assert!(last.is_last());
+        assert!(pages.next_page().is_none());
+    }
+
+    #[test]
+    fn test_pipe_pages_resumable() {
+        use std::sync::Arc;
+        use pipeline::{Checkpoint,PipelineApiClient};
+        use state::MemoryStateStore;
+
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/users?page=2", Fixture::json(200, r#"[{"id": 3}]"#))
+            .start().unwrap();
+        let next = format!(r#"<{}>; rel="next""#, server.uri("/api/v4/users?page=2"));
+        server.add_route(Method::Get, "/api/v4/users", Fixture::json(200, r#"[{"id": 1}, {"id": 2}]"#).header("Link", next));
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let checkpoint = Checkpoint::new(Arc::new(MemoryStateStore::new()), "users");
+        let mut written = Vec::new();
+        let users = "/users".parse::<Uri>().unwrap();
+        let err = client.pipe_pages_resumable(Method::Get, users.clone(), None::<Value>, 10, &checkpoint, |batch| {
+            if batch[0]["id"] == 3 {
+                return Err(ClientError::new("sink unavailable"));
+            }
+            written.extend(batch);
+            Ok(())
+        });
+        assert!(err.is_err());
+        assert_eq!(written.len(), 2);
+        assert_eq!(checkpoint.load().unwrap().map(|u| u.to_string()), Some(server.uri("/api/v4/users?page=2")));
+
+        let count = client.pipe_pages_resumable(Method::Get, users, None::<Value>, 10, &checkpoint, |batch| {
+            written.extend(batch);
+            Ok(())
+        });
+        assert_eq!(count.unwrap(), 1);
+        assert_eq!(written.iter().map(|u| u["id"].as_u64().unwrap()).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(checkpoint.load().unwrap(), None);
+        server.assert_sent().count(3);
+    }
+
+    #[test]
+    fn test_reconcile_labels() {
+        use reconcile::{ReconcileApiClient,Reconciler};
+
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/projects/1/labels", Fixture::json(200, r##"[
+                {"id": 10, "name": "bug", "color": "#ff0000"},
+                {"id": 11, "name": "stale", "color": "#cccccc"},
+                {"id": 12, "name": "docs", "color": "#0000ff"}]"##))
+            .route(Method::Post, "/api/v4/projects/1/labels", Fixture::json(201, "{}"))
+            .route(Method::Put, "/api/v4/projects/1/labels/12", Fixture::json(200, "{}"))
+            .route(Method::Delete, "/api/v4/projects/1/labels/11", Fixture::new(204))
+            .start().unwrap();
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let desired = serde_json::from_str::<Vec<Value>>(r##"[
+            {"name": "bug", "color": "#ff0000"},
+            {"name": "docs", "color": "#00ff00"},
+            {"name": "feature", "color": "#00ffff"}]"##).unwrap();
+        let plan = client.plan("/projects/1/labels".parse().unwrap(), desired, &Reconciler::by_field("name")).unwrap();
+        assert_eq!((plan.create.len(), plan.update.len(), plan.delete.len(), plan.unchanged), (1, 1, 1, 1));
+
+        let applied = client.apply_plan("/projects/1/labels".parse().unwrap(), &plan, Method::Put, |label| {
+            Ok(format!("/projects/1/labels/{}", label["id"]).parse()?)
+        }).unwrap();
+        assert_eq!(applied, 3);
+        server.assert_sent().post("/api/v4/projects/1/labels").json_body_contains(
+            serde_json::from_str(r#"{"name": "feature"}"#).unwrap());
+        server.assert_sent().put("/api/v4/projects/1/labels/12").json_body_contains(
+            serde_json::from_str(r##"{"color": "#00ff00"}"##).unwrap());
+        server.assert_sent().delete("/api/v4/projects/1/labels/11");
+
+        let err = client.apply_plan("/projects/1/labels".parse().unwrap(), &plan, Method::Patch, |label| {
+            Ok(format!("/projects/1/labels/{}", label["id"]).parse()?)
+        }).unwrap_err();
+        assert!(err.to_string().contains("404"));
+    }
+
+    #[test]
+    fn test_complete_project_paths() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/projects", Fixture::json(200,
+                   r#"[{"path_with_namespace": "ops/teatime"}, {"path_with_namespace": "web/site"}]"#))
+            .start().unwrap();
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let mut cache = NameCache::new(Duration::from_secs(30));
+        assert_eq!(client.complete_project_paths(&mut cache, "ops/").unwrap(), vec!["ops/teatime"]);
+        assert_eq!(client.complete_project_paths(&mut cache, "").unwrap(), vec!["ops/teatime", "web/site"]);
+        server.assert_sent().count(1).get("/api/v4/projects");
+        assert_eq!(server.requests()[0].path, "/api/v4/projects?membership=true&simple=true&per_page=100");
+    }
+
+    #[test]
+    fn test_client_builder() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v4/user", Fixture::json(200, r#"{"id": 1}"#))
+            .route(Method::Get, "/api/v4/slow", Fixture::json(200, "{}").delay(Duration::from_millis(300)))
+            .route(Method::Post, "/oauth/token", Fixture::json(200, r#"{"access_token": "session"}"#))
+            .route(Method::Post, "/api/v4/users/1/personal_access_tokens", Fixture::json(201,
+                r#"{"id": 7, "name": "ci", "scopes": ["api"], "token": "glpat-minted"}"#))
+            .start().unwrap();
+        let mut client = GitlabClient::builder(server.uri("/api/v4/"))
+            .token(TokenType::PersonalAccess("glpat".into()))
+            .allow_pagination_to("cdn.example.com")
+            .http(|h| h.raw_header("X-Team", "ops").timeout(Duration::from_millis(100)))
+            .build().unwrap();
+        client.request_json(Method::Get, "/user".parse().unwrap(), None::<Value>).unwrap();
+        server.assert_sent().get("/api/v4/user").header("Private-Token", "glpat").header("X-Team", "ops");
+        let err = client.request_json(Method::Get, "/slow".parse().unwrap(), None::<Value>).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::TimedOut);
+        assert!(client.check_next_page(&"https://cdn.example.com/api/v4/user?page=2".parse().unwrap()).is_ok());
+
+        assert!(GitlabClient::builder("/api/v4/").build().is_err());
+        assert!(GitlabClient::builder("ftp://gitlab.example.com/").build().is_err());
+        assert!(GitlabClient::builder("https://gitlab.example.com/api/v4/")
+                .token(TokenType::Oauth("".into())).build().is_err());
+        assert!(GitlabClient::builder("https://gitlab.example.com/api/v4/")
+                .token(TokenType::Oauth("a".into())).shared_token(SharedToken::new(None)).build().is_err());
+
+        let store = ::std::sync::Arc::new(::state::MemoryStateStore::new());
+        let cache = TokenCache::new(store, "gitlab");
+        cache.save(&"oauth:saved".into()).unwrap();
+        let mut client = GitlabClient::builder(server.uri("/api/v4/")).token_cache(cache.clone()).build().unwrap();
+        client.request_json(Method::Get, "/user".parse().unwrap(), None::<Value>).unwrap();
+        assert_eq!(server.requests().last().unwrap().header("Authorization"), Some("Bearer saved"));
+        client.login(&ApiCredentials::ApiKey("glpat-new".into())).unwrap();
+        assert_eq!(cache.load().unwrap(), Some(Secret::from("personal:glpat-new")));
+        let creds = ApiCredentials::UserPass("bot".to_string(), "pw".into());
+        client.create_personal_access_token(&creds, "ci", &["api"], None).unwrap();
+        assert_eq!(cache.load().unwrap(), Some(Secret::from("personal:glpat-minted")));
+        cache.save(&"untyped".into()).unwrap();
+        assert!(GitlabClient::builder(server.uri("/api/v4/")).token_cache(cache).build().is_err());
+    }
 
     #[test]
     fn test_parsing_macro() {
@@ -0,0 +1,313 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map::Iter;
+use std::sync::Arc;
+
+use *;
+
+/// Upper bounds in milliseconds of the buckets of a `LatencyHistogram` - slower requests fall
+/// in a final unbounded bucket
+pub const LATENCY_BUCKETS_MS: &[u64] = &[10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Distribution of the response times of one endpoint
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    total: Duration,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    /// Add one response time
+    pub fn record(&mut self, duration: Duration) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; LATENCY_BUCKETS_MS.len() + 1];
+        }
+        let ms = duration.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS.iter().position(|&bound| ms <= bound).unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.counts[bucket] += 1;
+        self.total += duration;
+        self.max = self.max.max(duration);
+    }
+
+    /// Number of response times recorded
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Mean response time, or `None` if none were recorded
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count() {
+            0 => None,
+            count => Some(self.total / count as u32),
+        }
+    }
+
+    /// Slowest response time recorded
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Upper bound of the bucket holding the `percentile` (0 to 100) response time, or the
+    /// slowest time if that is in the unbounded bucket
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((percentile.clamp(0.0, 100.0) / 100.0) * count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(LATENCY_BUCKETS_MS.get(i).map(|&ms| Duration::from_millis(ms)).unwrap_or(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// Number of response times in each bucket, by upper bound - `None` for the last bucket
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        (0..=LATENCY_BUCKETS_MS.len()).map(|i| {
+            (LATENCY_BUCKETS_MS.get(i).map(|&ms| Duration::from_millis(ms)), self.counts.get(i).cloned().unwrap_or(0))
+        }).collect()
+    }
+
+    /// JSON object with the count, mean, 50th, 95th and 99th percentiles and maximum in
+    /// milliseconds
+    pub fn to_json(&self) -> Value {
+        let ms = |d: Option<Duration>| d.map(|d| Value::from(d.as_millis() as u64)).unwrap_or(Value::Null);
+        let mut summary = serde_json::Map::new();
+        summary.insert("count".to_string(), Value::from(self.count()));
+        summary.insert("mean_ms".to_string(), ms(self.mean()));
+        summary.insert("p50_ms".to_string(), ms(self.percentile(50.0)));
+        summary.insert("p95_ms".to_string(), ms(self.percentile(95.0)));
+        summary.insert("p99_ms".to_string(), ms(self.percentile(99.0)));
+        summary.insert("max_ms".to_string(), Value::from(self.max.as_millis() as u64));
+        Value::Object(summary)
+    }
+}
+
+/// A request that took longer than its slow request threshold
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct SlowRequest {
+    /// Request method
+    pub method: Method,
+    /// Path template of the endpoint, such as `/api/v4/projects/{id}/issues`
+    pub endpoint: String,
+    /// Time until the response headers were received or the request failed
+    pub duration: Duration,
+    /// Threshold the request exceeded
+    pub threshold: Duration,
+    /// Response status, or `None` if no response was received
+    pub status: Option<StatusCode>,
+}
+
+impl fmt::Display for SlowRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Slow request: {} {} took {}ms, over the {}ms threshold", self.method, self.endpoint,
+               self.duration.as_millis(), self.threshold.as_millis())?;
+        match self.status {
+            Some(status) => write!(f, " ({})", status),
+            None => write!(f, " (no response)"),
+        }
+    }
+}
+
+type SlowRequestFn = Arc<dyn Fn(&SlowRequest) + Send + Sync>;
+
+/// True if a path segment looks like an identifier rather than part of the endpoint
+fn is_identifier(segment: &str) -> bool {
+    let hex = segment.chars().filter(|c| c.is_ascii_hexdigit()).count();
+    (!segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit()))
+        || (segment.len() >= 16 && hex + segment.matches('-').count() == segment.len())
+        || segment.contains('%')
+}
+
+/// `path` without its query string and with numeric, UUID-like and percent-encoded segments
+/// replaced by `{id}`, for grouping requests by endpoint
+pub fn endpoint_template(path: &str) -> String {
+    let path = path.split('?').next().unwrap_or("");
+    path.split('/').map(|s| if is_identifier(s) { "{id}" } else { s }).collect::<Vec<_>>().join("/")
+}
+
+fn matches_template(template: &str, path: &str) -> bool {
+    let template = template.trim_end_matches('/').split('/');
+    let path = path.trim_end_matches('/').split('/');
+    template.clone().count() == path.clone().count() && template.zip(path).all(|(t, p)| {
+        (t.starts_with('{') && t.ends_with('}')) || t == p
+    })
+}
+
+/// Response times per endpoint with warnings for requests slower than a threshold, so tools
+/// can report APIs that are degrading
+///
+/// Endpoints are named by the first registered path template that matches the request path,
+/// otherwise by the path with numeric, UUID-like and percent-encoded segments replaced by
+/// `{id}`. Query strings are ignored.
+#[derive(Clone,Default)]
+pub struct LatencyTracker {
+    templates: Vec<String>,
+    histograms: BTreeMap<String, LatencyHistogram>,
+    threshold: Option<Duration>,
+    thresholds: Vec<(String, Duration)>,
+    on_slow: Option<SlowRequestFn>,
+    slow: u64,
+}
+
+impl fmt::Debug for LatencyTracker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LatencyTracker")
+            .field("endpoints", &self.histograms.len())
+            .field("threshold", &self.threshold)
+            .field("slow", &self.slow)
+            .finish()
+    }
+}
+
+impl LatencyTracker {
+    /// Tracker with no threshold
+    pub fn new() -> Self {
+        LatencyTracker::default()
+    }
+
+    /// Name requests whose path matches `template`, such as `/api/v4/projects/{id}/issues`,
+    /// after it
+    pub fn template<S>(mut self, template: S) -> Self where S: Into<String> {
+        self.templates.push(template.into());
+        self
+    }
+
+    /// Warn about requests to any endpoint that take longer than `threshold`
+    pub fn slow_threshold(mut self, threshold: Duration) -> Self {
+        self.threshold = Some(threshold);
+        self
+    }
+
+    /// Warn about requests to `endpoint` that take longer than `threshold`, in place of the
+    /// threshold for every endpoint
+    pub fn endpoint_threshold<S>(mut self, endpoint: S, threshold: Duration) -> Self where S: Into<String> {
+        self.thresholds.push((endpoint.into(), threshold));
+        self
+    }
+
+    /// Call `warn` for each slow request
+    pub fn on_slow<F>(mut self, warn: F) -> Self where F: Fn(&SlowRequest) + Send + Sync + 'static {
+        self.on_slow = Some(Arc::new(warn));
+        self
+    }
+
+    /// Name of the endpoint requested with `path`
+    pub fn endpoint(&self, path: &str) -> String {
+        let path = path.split('?').next().unwrap_or("");
+        if let Some(template) = self.templates.iter().find(|t| matches_template(t, path)) {
+            return template.clone();
+        }
+        endpoint_template(path)
+    }
+
+    /// Record the response time of a request, warning if it was slow
+    pub fn record(&mut self, method: &Method, path: &str, duration: Duration, status: Option<StatusCode>) {
+        let endpoint = self.endpoint(path);
+        self.histograms.entry(format!("{} {}", method, endpoint)).or_default().record(duration);
+        let threshold = self.thresholds.iter().find(|t| t.0 == endpoint).map(|t| t.1).or(self.threshold);
+        if let Some(threshold) = threshold.filter(|&t| duration > t) {
+            self.slow += 1;
+            if let Some(ref warn) = self.on_slow {
+                warn(&SlowRequest { method: method.clone(), endpoint, duration, threshold, status });
+            }
+        }
+    }
+
+    /// Histogram of the endpoint requested with `method` and named `endpoint`
+    pub fn histogram(&self, method: &Method, endpoint: &str) -> Option<&LatencyHistogram> {
+        self.histograms.get(&format!("{} {}", method, endpoint))
+    }
+
+    /// Histograms by method and endpoint, such as `GET /api/v4/projects/{id}`
+    pub fn histograms(&self) -> Iter<'_, String, LatencyHistogram> {
+        self.histograms.iter()
+    }
+
+    /// Number of requests that were slower than their threshold
+    pub fn slow_requests(&self) -> u64 {
+        self.slow
+    }
+
+    /// Forget every recorded response time
+    pub fn clear(&mut self) {
+        self.histograms.clear();
+        self.slow = 0;
+    }
+
+    /// JSON object of the histogram summaries by method and endpoint
+    pub fn to_json(&self) -> Value {
+        Value::Object(self.histograms.iter().map(|(k, h)| (k.clone(), h.to_json())).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+    use testing::{Fixture,FixtureServer,client_for};
+
+    #[test]
+    fn test_latency_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(50.0), None);
+        for ms in &[5, 8, 40, 90, 300, 12000] {
+            histogram.record(Duration::from_millis(*ms));
+        }
+        assert_eq!(histogram.count(), 6);
+        assert_eq!(histogram.percentile(50.0), Some(Duration::from_millis(50)));
+        assert_eq!(histogram.percentile(100.0), Some(Duration::from_millis(12000)));
+        assert_eq!(histogram.buckets()[0], (Some(Duration::from_millis(10)), 2));
+        assert_eq!(histogram.to_json()["max_ms"], 12000);
+    }
+
+    #[test]
+    fn test_latency_tracker() {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let recorded = warnings.clone();
+        let mut tracker = LatencyTracker::new()
+            .template("/api/v4/projects/{project}/issues/{iid}")
+            .slow_threshold(Duration::from_millis(500))
+            .endpoint_threshold("/api/v4/projects/{id}/export", Duration::from_secs(5))
+            .on_slow(move |slow| recorded.lock().unwrap().push(slow.to_string()));
+        assert_eq!(tracker.endpoint("/api/v4/projects/group%2Fapp/merge_requests?state=opened"),
+                   "/api/v4/projects/{id}/merge_requests");
+        assert_eq!(tracker.endpoint("/api/v4/projects/12/issues/3"), "/api/v4/projects/{project}/issues/{iid}");
+
+        tracker.record(&Method::Get, "/api/v4/projects/12/issues/3", Duration::from_millis(40), Some(StatusCode::Ok));
+        tracker.record(&Method::Get, "/api/v4/projects/13/issues/4", Duration::from_millis(700), None);
+        tracker.record(&Method::Post, "/api/v4/projects/13/export", Duration::from_secs(2), Some(StatusCode::Accepted));
+        assert_eq!(tracker.slow_requests(), 1);
+        assert_eq!(*warnings.lock().unwrap(), vec![
+            "Slow request: GET /api/v4/projects/{project}/issues/{iid} took 700ms, over the 500ms threshold (no response)".to_string(),
+        ]);
+        assert_eq!(tracker.histogram(&Method::Get, "/api/v4/projects/{project}/issues/{iid}").unwrap().count(), 2);
+        assert_eq!(tracker.to_json()["POST /api/v4/projects/{id}/export"]["count"], 1);
+    }
+
+    #[test]
+    fn test_latency_tracking() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/projects/7", Fixture::json(200, "{}").delay(Duration::from_millis(60)))
+            .route(Method::Get, "/projects/8", Fixture::json(200, "{}"))
+            .start().unwrap();
+        let slow = Arc::new(Mutex::new(Vec::new()));
+        let recorded = slow.clone();
+        let tracker = LatencyTracker::new().slow_threshold(Duration::from_millis(40))
+            .on_slow(move |s| recorded.lock().unwrap().push((s.endpoint.clone(), s.status)));
+        let http = SimpleHttpClientBuilder::new().latency_tracker(tracker)
+            .build_for(&server.base_uri().parse().unwrap()).unwrap();
+        let mut client = client_for(&server).with_http(http);
+        client.request(Method::Get, "/projects/7".parse().unwrap(), None::<String>).unwrap();
+        client.request(Method::Get, "/projects/8?simple=true".parse().unwrap(), None::<String>).unwrap();
+
+        let tracker = client.http_client().latency_tracker().unwrap();
+        assert_eq!(tracker.histogram(&Method::Get, "/projects/{id}").unwrap().count(), 2);
+        assert_eq!(*slow.lock().unwrap(), vec![("/projects/{id}".to_string(), Some(StatusCode::Ok))]);
+    }
+}
@@ -29,17 +29,30 @@
 //! data structures defined in `lib.rs` as these will outline parameter types,
 //! return types and required implementation bits.
 //!
+//! Clients that also implement `Transport` to name their HTTP client can be used by generic
+//! code through `AnyApiClient` and `AnyJsonApiClient` without a separate transport type
+//! parameter, or held as `Box<dyn DynApiClient>` when clients for different services must be
+//! mixed - wrapped in `AnyTransport` unless they use `SimpleHttpClient`.
+//!
 //! ## Traditional request-response flows vs. future-based flows
 //!
 //! Once the `ApiClient` trait is implemented, an API can either be made through the `request`
 //! method for a hyper `Response` type or `request_json` for automatic conversion of the 
-//! response body to JSON.
+//! response body to JSON. `request_typed` deserializes the body straight into any type
+//! implementing `serde::Deserialize`.
 //!
 //! For cases where futures are desirable, there are two method calls for the request-response
 //! flow. The first is `request_future`. This will return a future which can be left as is while
 //! other work is done. This is the same for both `Response` and JSON flows. The resolution
 //! functions that resolve to `Response`s and JSON objects are `response_future`
-//! and `response_future_json` respectively.
+//! and `response_future_json` respectively, with `response_future_typed` for typed bodies.
+//!
+//! ## Limitations
+//!
+//! Interim `1xx` responses such as `102 Processing` and `103 Early Hints` and chunked
+//! response trailers are not visible to callers. The hyper 0.11 client skips interim responses
+//! while waiting for the final one and discards trailers while decoding the body, so there is
+//! no point in the request flow where they could be handed on.
 
 #![deny(missing_docs)]
 
@@ -50,6 +63,8 @@ extern crate hyper;
 extern crate hyper_tls;
 extern crate native_tls;
 extern crate tokio_core;
+extern crate tokio_io;
+extern crate serde;
 extern crate serde_json;
 
 #[cfg(feature = "gitlab")]
@@ -57,42 +72,211 @@ extern crate serde_json;
 extern crate nom;
 
 extern crate rpassword;
+extern crate url;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(windows)]
+extern crate winapi;
+
+#[cfg(feature = "msgpack")]
+extern crate rmp_serde;
+#[cfg(feature = "protobuf")]
+extern crate prost;
+#[cfg(feature = "csv")]
+extern crate csv as csv_crate;
+#[cfg(feature = "yaml")]
+extern crate serde_yaml;
+#[cfg(feature = "checksum")]
+extern crate md5;
+#[cfg(any(feature = "checksum", feature = "totp"))]
+extern crate sha1;
+#[cfg(any(feature = "checksum", feature = "webhook", feature = "encryption"))]
+extern crate sha2;
+#[cfg(any(feature = "checksum", feature = "openssl"))]
+extern crate base64;
+#[cfg(any(feature = "totp", feature = "webhook"))]
+extern crate hmac;
+#[cfg(feature = "keyring")]
+extern crate keyring;
+#[cfg(feature = "charset")]
+extern crate encoding_rs;
+#[cfg(feature = "zeroize")]
+extern crate zeroize;
+#[cfg(feature = "encryption")]
+extern crate chacha20poly1305;
+#[cfg(feature = "encryption")]
+extern crate pbkdf2;
+#[cfg(feature = "openssl")]
+extern crate openssl;
+#[cfg(feature = "revocation")]
+extern crate openssl_sys;
 
+/// Bounded record of requests made, for support and debugging
+pub mod audit;
+/// Shared tokens, credential providers and re-authentication flows
+pub mod auth;
+/// Bulk writes split into batches with per-item results
+pub mod batch;
+/// Recorded responses for running without network access
+pub mod cache;
+/// Response charset detection and transcoding
+#[cfg(feature = "charset")]
+pub mod charset;
+/// Checksum verification for downloads and uploads
+#[cfg(feature = "checksum")]
+pub mod checksum;
+/// Clock and sleep abstractions for time based features
+pub mod clock;
+/// Cached name listings for shell completion
+pub mod completion;
+/// Conditional writes that prevent lost updates
+pub mod conditional;
+/// CSV response parsing helpers
+#[cfg(feature = "csv")]
+pub mod csv;
+/// Detection of deprecated and sunset endpoints from response headers
+pub mod deprecation;
+/// In-process caching of host lookups for new connections
+pub mod dns;
+/// Object-safe client interface for holding different clients as trait objects
+pub mod dynamic;
+/// Chunked and resumable download helpers
+pub mod download;
+/// Encryption at rest for stored client state
+#[cfg(feature = "encryption")]
+pub mod encryption;
 /// Gitlab API client
 #[cfg(feature = "gitlab")]
 pub mod gitlab;
+/// Per-endpoint latency histograms and slow request warnings
+pub mod latency;
+/// Caps on the number of requests in flight at once
+pub mod limit;
+/// MessagePack request and response helpers
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
+/// OAuth 2.0 helpers such as the device authorization grant
+pub mod oauth2;
+/// Rendering results as JSON, aligned tables or selected values for command line tools
+pub mod output;
+/// Page-by-page reading and bounded pipelines between APIs
+pub mod pipeline;
+/// Detection of the endpoints a server supports
+pub mod probe;
+/// Protobuf-over-HTTP request and response helpers
+#[cfg(feature = "protobuf")]
+pub mod protobuf;
+/// HTTP proxy settings, given explicitly or read from the environment
+pub mod proxy;
 /// Sensu API client
 #[cfg(feature = "sensu")]
 pub mod sensu;
+/// Syncing remote collections to a desired state
+pub mod reconcile;
+/// Masking of secrets in diagnostic output
+pub mod redact;
+/// Retry policies with backoff and retry notifications
+pub mod retry;
+/// Sensitive string type for tokens and passwords
+pub mod secret;
+/// Named clients for several services sharing one login
+pub mod session;
+/// Request signing with clock skew correction
+pub mod signing;
+/// X.509 SVID credentials for SPIFFE workloads
+#[cfg(feature = "spiffe")]
+pub mod spiffe;
+/// Pluggable storage for tokens, recorded responses and pagination checkpoints
+pub mod state;
+/// Incremental parsing of large JSON array responses
+pub mod stream;
+/// Request path templates with percent-encoded parameters
+pub mod template;
+/// Terminal input helpers for prompts, such as masked echo
+pub mod terminal;
+/// Connect, read and total time limits for requests
+pub mod timeout;
+/// TLS policy presets, public key pinning and revocation checks
+pub mod tls;
+/// Time-based one time passwords from stored two factor secrets
+#[cfg(feature = "totp")]
+pub mod totp;
+/// Embedded fixture server for tests and examples
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 /// Vault API client
 #[cfg(feature = "vault")]
 pub mod vault;
+/// Signature verification and payload types for received webhooks
+#[cfg(feature = "webhook")]
+pub mod webhook;
+/// Dependent API steps run in order with shared retries, deadline and dry runs
+pub mod workflow;
+/// YAML request and response helpers
+#[cfg(feature = "yaml")]
+pub mod yaml;
 
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::{self,Formatter,Display};
 use std::io::{self,Write};
+use std::mem;
+use std::thread;
 use std::num;
 use std::result;
 use std::str;
+use std::time::{Duration,Instant,SystemTime};
 
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
-use hyper::{Client,Method,Request,Response,Uri};
-use hyper::client::{HttpConnector,FutureResponse};
-use hyper::header::Header;
+use hyper::{Chunk,Client,Method,Request,Response,StatusCode,Uri};
+use hyper::client::FutureResponse;
+use hyper::header::{CacheControl,CacheDirective,ContentLength,ContentType,Header,Headers,Host,Raw};
+use hyper::mime::Mime;
 use hyper_tls::HttpsConnector;
-use tokio_core::reactor::Core;
+use tokio_core::reactor::{Core,Handle,Timeout};
 use futures::{Future,Stream};
+use futures::future::Either;
+
+use audit::{AuditEntry,AuditTrail};
+use cache::ResponseCache;
+use deprecation::DeprecationMonitor;
+use dns::{CachingConnector,DnsCache};
+use latency::LatencyTracker;
+use limit::{ConcurrencyLimit,Limited};
+use pipeline::PageInfo;
+use proxy::ProxyConfig;
+use redact::RedactionPolicy;
+use timeout::Timeouts;
+use tls::TlsConfig;
+use retry::{RetryEvent,RetryPolicy};
+use secret::Secret;
 
 macro_rules! error_impl {
     ($error:ident, $( $from_error:path ),* ) => {
         /// Custom error type
         #[derive(Debug,PartialEq,Eq)]
-        pub struct $error(String);
+        pub struct $error {
+            message: String,
+            kind: ErrorKind,
+        }
 
         impl $error {
             /// Create new error from a type able to be converted to a `String`
             pub fn new<S>(inner_err: S) -> Self where S: Into<String> {
-                $error(inner_err.into())
+                $error::with_kind(ErrorKind::Other, inner_err)
+            }
+
+            /// Create new error of a specific kind
+            pub fn with_kind<S>(kind: ErrorKind, inner_err: S) -> Self where S: Into<String> {
+                $error { message: inner_err.into(), kind }
+            }
+
+            /// Get the kind of error
+            pub fn kind(&self) -> &ErrorKind {
+                &self.kind
             }
         }
 
@@ -106,7 +290,7 @@ macro_rules! error_impl {
 
         impl Error for $error {
             fn description(&self) -> &str {
-                self.0.as_str()
+                self.message.as_str()
             }
         }
 
@@ -118,55 +302,203 @@ macro_rules! error_impl {
     }
 }
 
+/// Categories of errors that callers may want to handle differently
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum ErrorKind {
+    /// Error with no more specific category
+    Other,
+    /// A downloaded body did not match its expected checksum
+    ChecksumMismatch {
+        /// Checksum algorithm used
+        algorithm: String,
+        /// Expected hex encoded digest
+        expected: String,
+        /// Hex encoded digest of the data received
+        actual: String,
+    },
+    /// The server rejected the credentials or token (`401 Unauthorized`)
+    AuthenticationFailed,
+    /// The credentials are valid but do not grant access (`403 Forbidden`)
+    PermissionDenied,
+    /// No connection, response or data was received within the configured timeout
+    TimedOut,
+    /// A conditional write was rejected because the resource changed since it was read
+    /// (`412 Precondition Failed`, or `409 Conflict` for version field checks)
+    PreconditionFailed,
+    /// A response body could not be parsed - the response is kept so callers can fall back to
+    /// their own parsing or log the payload
+    InvalidResponse {
+        /// Response status
+        status: StatusCode,
+        /// Response headers as name and value pairs, masked by the HTTP client's redaction
+        /// policy
+        headers: Vec<(String, String)>,
+        /// Full response body as received
+        body: Vec<u8>,
+    },
+}
+
+impl ErrorKind {
+    /// Describe a response whose body could not be parsed, masking headers with `policy`
+    pub fn invalid_response(status: StatusCode, headers: &Headers, body: Vec<u8>,
+                            policy: &RedactionPolicy) -> Self {
+        ErrorKind::InvalidResponse { status, headers: policy.headers(headers), body }
+    }
+}
+
 error_impl!(ClientError, serde_json::Error, hyper::Error, hyper::error::UriError,
             native_tls::Error, num::ParseIntError);
 
+/// Size of the chunks read by `SimpleHttpClient::add_stream_body`
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
 /// Result with `Error` type defined
 pub type Result<T> = std::result::Result<T, ClientError>;
 
 /// Prompt for text echoed in the terminal - _do not use for sensitive data_
+///
+/// The line ending is removed whether it is `\n` or the `\r\n` of Windows consoles. Fails
+/// with `io::ErrorKind::UnexpectedEof` if input ends before anything is entered.
 pub fn interactive_text(prompt: &str) -> result::Result<String, io::Error> {
     let stdin = io::stdin();
     let mut stdout = io::stdout();
     print!("{}", prompt);
     try!(stdout.flush());
-    let mut line = String::new();
-    let line_len = try!(stdin.read_line(&mut line));
-    line.truncate(line_len - 1);
+    read_line_from(&mut stdin.lock())
+}
 
+/// Read one line without its line ending, failing at the end of input
+fn read_line_from<R>(input: &mut R) -> result::Result<String, io::Error> where R: io::BufRead {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "No input given"));
+    }
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
     Ok(line)
 }
 
+fn read_answer<R>(input: &mut R) -> result::Result<String, io::Error> where R: io::BufRead {
+    read_line_from(input).map(|line| line.trim().to_string())
+}
+
+fn select_from<R, W, S>(input: &mut R, output: &mut W, prompt: &str, options: &[S])
+        -> result::Result<usize, io::Error> where R: io::BufRead, W: Write, S: AsRef<str> {
+    if options.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Nothing to choose from"));
+    }
+    writeln!(output, "{}", prompt)?;
+    for (i, option) in options.iter().enumerate() {
+        writeln!(output, "  {}) {}", i + 1, option.as_ref())?;
+    }
+    loop {
+        write!(output, "Choose 1-{}: ", options.len())?;
+        output.flush()?;
+        let answer = read_answer(input)?;
+        match answer.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= options.len() => return Ok(n - 1),
+            _ => match options.iter().position(|o| o.as_ref() == answer) {
+                Some(i) => return Ok(i),
+                None => writeln!(output, "Please enter a number from 1 to {}", options.len())?,
+            },
+        }
+    }
+}
+
+fn confirm_from<R, W>(input: &mut R, output: &mut W, prompt: &str, default: bool)
+        -> result::Result<bool, io::Error> where R: io::BufRead, W: Write {
+    loop {
+        write!(output, "{} [{}] ", prompt, if default { "Y/n" } else { "y/N" })?;
+        output.flush()?;
+        match read_answer(input)?.to_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => writeln!(output, "Please answer y or n")?,
+        }
+    }
+}
+
+/// Prompt to choose one of `options` from a numbered list, returning the index chosen
+///
+/// The answer may be the number or the option itself; anything else asks again. Fails if
+/// `options` is empty or input ends before a choice is made.
+pub fn interactive_select<S>(prompt: &str, options: &[S]) -> result::Result<usize, io::Error> where S: AsRef<str> {
+    let stdin = io::stdin();
+    select_from(&mut stdin.lock(), &mut io::stdout(), prompt, options)
+}
+
+/// Prompt for a yes or no answer, returning `default` if the answer is left empty
+pub fn interactive_confirm(prompt: &str, default: bool) -> result::Result<bool, io::Error> {
+    let stdin = io::stdin();
+    confirm_from(&mut stdin.lock(), &mut io::stdout(), prompt, default)
+}
+
 /// An enum representing three types of credentials or no authentication
-#[derive(Debug,PartialEq,Eq)]
+#[derive(Clone,Debug,PartialEq,Eq)]
 pub enum ApiCredentials {
     /// No authentication
     NoAuth,
     /// API key
-    ApiKey(String),
+    ApiKey(Secret),
     /// Username and password
-    UserPass(String, String),
+    UserPass(String, Secret),
     /// Username, password, and two factor authentication
-    UserPassTwoFactor(String, String, String),
+    UserPassTwoFactor(String, Secret, Secret),
 }
 
 impl ApiCredentials {
     /// Interactively prompt for username and password
-    pub fn interactive_get_uname_pw() -> std::result::Result<(String, String), io::Error> {
+    pub fn interactive_get_uname_pw() -> std::result::Result<(String, Secret), io::Error> {
         let username = interactive_text("Username: ")?;
         let pass = rpassword::prompt_password_stdout("Password: ")?;
-        Ok((username, pass))
+        Ok((username, Secret::from(pass)))
     }
 
     /// Interactively prompt for two two factor authentication
-    pub fn interactive_get_2fa() -> std::result::Result<String, io::Error> {
-        interactive_text("2FA: ")
+    pub fn interactive_get_2fa() -> std::result::Result<Secret, io::Error> {
+        interactive_text("2FA: ").map(|code| Secret::from(terminal::clean_pasted(&code)))
+    }
+
+    /// Interactively prompt for username and password, echoing `*` for each character of the
+    /// password - see `terminal::read_masked`
+    pub fn interactive_get_uname_masked_pw() -> std::result::Result<(String, Secret), io::Error> {
+        let username = interactive_text("Username: ")?;
+        let pass = terminal::read_masked("Password: ")?;
+        Ok((username, pass))
+    }
+
+    /// Interactively prompt for an API key or token with masked echo, removing whitespace
+    /// around it
+    pub fn interactive_get_api_key(prompt: &str) -> std::result::Result<Self, io::Error> {
+        let key = terminal::read_masked(prompt)?;
+        Ok(ApiCredentials::ApiKey(Secret::from(terminal::clean_pasted(key.expose()))))
     }
 
     /// Interactively prompt for username and password with optional two factor prompt
     pub fn interactive_get(need_2fa: bool) -> std::result::Result<Self, io::Error> {
+        ApiCredentials::interactive_get_with(need_2fa, false)
+    }
+
+    /// `interactive_get_with` on a helper thread, so the event loop keeps running requests
+    /// already in flight while the operator types - see `terminal::prompt_async`
+    pub fn interactive_get_async(need_2fa: bool, masked: bool) -> terminal::PromptFuture<Self> {
+        terminal::prompt_async(move || ApiCredentials::interactive_get_with(need_2fa, masked))
+    }
+
+    /// Like `interactive_get`, echoing `*` for each character of the password if `masked` is
+    /// set rather than hiding it completely
+    pub fn interactive_get_with(need_2fa: bool, masked: bool) -> std::result::Result<Self, io::Error> {
         println!("Please enter credentials to proceed");
-        let (username, pass) = ApiCredentials::interactive_get_uname_pw()?;
+        let (username, pass) = if masked {
+            ApiCredentials::interactive_get_uname_masked_pw()?
+        } else {
+            ApiCredentials::interactive_get_uname_pw()?
+        };
 
         if need_2fa {
             let twofactor = ApiCredentials::interactive_get_2fa()?;
@@ -177,200 +509,2281 @@ impl ApiCredentials {
     }
 }
 
-/// Type alias for HTTPS client
-pub type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+/// Request bodies accepted by the JSON flows
+///
+/// Any `Serialize` type is serialized to JSON before being sent, including `String`s, which
+/// become JSON strings. Use `RawBody` for payloads that are already encoded and should be sent
+/// as is.
+///
+/// ```
+/// # use std::collections::BTreeMap;
+/// # use teatime::{JsonBody,RawBody};
+/// let mut labels = BTreeMap::new();
+/// labels.insert("team", "ops");
+/// assert_eq!(labels.to_json_body().unwrap(), r#"{"team":"ops"}"#);
+/// assert_eq!(RawBody(r#"{"team": "ops"}"#.to_string()).to_json_body().unwrap(), r#"{"team": "ops"}"#);
+/// ```
+pub trait JsonBody {
+    /// Encode the body as the string sent in the request
+    fn to_json_body(&self) -> Result<String>;
+}
 
-/// Methods defining low-level HTTP handling
-pub trait HttpClient {
-    /// Handle implementation details of creating an HTTPS client and return the client as well
-    /// as the underlying Tokio `Core` object required for driving the client
-    fn create_https_client(threads: usize) -> Result<(HttpsClient, Core)> {
-        let core = match Core::new() {
-            Ok(core) => core,
-            Err(e) => {
-                return Err(ClientError::new(
-                        format!("Failed to start Tokio event loop: {}", e.description())
-                ));
-            },
-        };
-        let https_conn = HttpsConnector::new(threads, &core.handle())?;
-        let client = Client::configure().connector(https_conn).build(&core.handle());
-        Ok((client, core))
+impl<T> JsonBody for T where T: Serialize {
+    fn to_json_body(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
     }
+}
 
-    /// Create a hyper `Request` object
-    fn start_request(&mut self, Method, Uri) -> &mut Self;
-    /// Add request headers
-    fn add_header<H>(&mut self, H) -> &mut Self where H: Header;
-    /// Set an individual header in the HTTP request
-    fn add_body<S>(&mut self, S) -> &mut Self where S: ToString;
-    /// Make HTTP request
-    fn make_request(&mut self) -> &mut Self;
-    /// Get complete HTTP response
-    fn response(&mut self) -> Result<Response>;
-    /// Get `Response` future
-    fn future(&mut self) -> Option<FutureResponse>;
-    /// Evaluate a future
-    fn evaluate_future<F>(&mut self, future: F)
-        -> result::Result<F::Item, F::Error> where F: Future;
+/// Pre-encoded request body that the JSON flows send without serializing it again
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct RawBody(pub String);
+
+impl JsonBody for RawBody {
+    fn to_json_body(&self) -> Result<String> {
+        Ok(self.0.clone())
+    }
 }
 
-/// Reference implementation of `HttpClient` trait - should be good enough for most use cases
-pub struct SimpleHttpClient {
-    https_client: HttpsClient,
-    core: Core,
-    request: Option<Request>,
-    response_fut: Option<FutureResponse>,
+fn encode_json_body<B>(body: Option<B>) -> Result<Option<String>> where B: JsonBody {
+    match body {
+        Some(b) => Ok(Some(b.to_json_body()?)),
+        None => Ok(None),
+    }
 }
 
-impl SimpleHttpClient {
-    /// Create a new `SimpleHttpClient`
-    pub fn new() -> Result<Self> {
-        let (https_client, core) = <Self as HttpClient>::create_https_client(4)?;
-        Ok(SimpleHttpClient { https_client, core, request: None, response_fut: None })
+/// Rewrite a `PUT`, `PATCH` or `DELETE` request as a `POST` carrying `X-HTTP-Method-Override`
+fn tunnel_method(request: &mut Request) {
+    match *request.method() {
+        Method::Put | Method::Patch | Method::Delete => (),
+        _ => return,
     }
+    let method = request.method().to_string();
+    request.headers_mut().set_raw("X-HTTP-Method-Override", method);
+    request.set_method(Method::Post);
 }
 
-impl HttpClient for SimpleHttpClient {
-    fn start_request(&mut self, method: Method, uri: Uri) -> &mut Self {
-        self.request = Some(Request::new(method, uri));
+/// Body read from `reader` on a background thread in chunks of `STREAM_CHUNK_SIZE` bytes as the
+/// connection accepts them
+fn stream_body<R>(mut reader: R) -> hyper::Body where R: io::Read + Send + 'static {
+    let (mut sender, body) = hyper::Body::pair();
+    thread::spawn(move || {
+        let mut buffer = vec![0; STREAM_CHUNK_SIZE];
+        loop {
+            let chunk = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => Ok(Chunk::from(buffer[..n].to_vec())),
+                Err(e) => Err(hyper::Error::Io(e)),
+            };
+            let failed = chunk.is_err();
+            sender = match futures::Sink::send(sender, chunk).wait() {
+                Ok(sender) if !failed => sender,
+                _ => break,
+            };
+        }
+    });
+    body
+}
+
+enum OverrideBody {
+    Bytes(Vec<u8>),
+    Stream(Box<dyn io::Read + Send>, Option<u64>),
+}
+
+impl OverrideBody {
+    fn len(&self) -> Option<u64> {
+        match *self {
+            OverrideBody::Bytes(ref bytes) => Some(bytes.len() as u64),
+            OverrideBody::Stream(_, len) => len,
+        }
+    }
+}
+
+/// Headers, body and timeout applied to the next request sent by an `HttpClient`
+///
+/// These take precedence over anything set by `ApiClient::request_future`, which allows the
+/// default flows to send non-JSON payloads through clients that only know about JSON.
+#[derive(Default)]
+pub struct RequestOverrides {
+    headers: Headers,
+    body: Option<OverrideBody>,
+    timeout: Option<Duration>,
+}
+
+impl fmt::Debug for RequestOverrides {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("RequestOverrides")
+            .field("headers", &RedactionPolicy::default().headers(&self.headers))
+            .field("body_len", &self.body.as_ref().and_then(OverrideBody::len))
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl RequestOverrides {
+    /// Create an empty set of overrides
+    pub fn new() -> Self {
+        RequestOverrides::default()
+    }
+
+    /// Set a header, replacing any header of the same name on the request
+    pub fn set_header<H>(&mut self, header: H) -> &mut Self where H: Header {
+        self.headers.set(header);
         self
     }
 
-    fn add_header<H>(&mut self, header: H) -> &mut Self where H: Header {
-        self.request.as_mut().map(|ref mut req| req.headers_mut().set::<H>(header));
+    /// Set a header by name from its raw value, replacing any header of the same name
+    pub fn set_raw_header<K, V>(&mut self, name: K, value: V) -> &mut Self
+            where K: Into<Cow<'static, str>>, V: Into<Raw> {
+        self.headers.set_raw(name, value);
         self
     }
 
-    fn add_body<S>(&mut self, body: S) -> &mut Self where S: ToString {
-        self.request.as_mut().map(|ref mut req| req.set_body(body.to_string()));
+    /// Set every header in `headers`, replacing any headers of the same names
+    pub fn set_headers(&mut self, headers: &Headers) -> &mut Self {
+        self.headers.extend(headers.iter());
         self
     }
 
-    fn make_request(&mut self) -> &mut Self {
-        let request = self.request.take();
-        self.response_fut = request.map(|req| self.https_client.request(req));
+    /// Set the raw request body
+    pub fn set_body<B>(&mut self, body: B) -> &mut Self where B: Into<Vec<u8>> {
+        self.body = Some(OverrideBody::Bytes(body.into()));
         self
     }
 
-    fn response(&mut self) -> Result<Response> {
-        let response_fut = self.response_fut.take().ok_or(ClientError::new("No request made"))?;
-        self.evaluate_future(response_fut).map_err(|e| {
-            ClientError::new(e.description())
-        })
+    /// Stream the request body from `reader` instead of buffering it
+    ///
+    /// `len` is sent as the `Content-Length` when it is known, otherwise the body is sent
+    /// chunked.
+    pub fn set_stream_body<R>(&mut self, reader: R, len: Option<u64>) -> &mut Self
+            where R: io::Read + Send + 'static {
+        self.body = Some(OverrideBody::Stream(Box::new(reader), len));
+        self
     }
 
-    fn future(&mut self) -> Option<FutureResponse> {
-        self.response_fut.take()
+    /// Fail the request if no response arrives within `timeout`, in place of any timeout
+    /// configured for the host
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
     }
 
-    fn evaluate_future<F>(&mut self, future: F) -> result::Result<F::Item, F::Error>
-            where F: Future {
-        self.core.run(future)
+    /// Headers that will be set on the request
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Timeout that will apply to the request
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// True if no overrides have been set
+    pub fn is_empty(&self) -> bool {
+        self.headers.len() == 0 && self.body.is_none() && self.timeout.is_none()
     }
 
+    /// Apply the overrides to a request
+    pub fn apply(self, request: &mut Request) {
+        request.headers_mut().extend(self.headers.iter());
+        match self.body {
+            Some(OverrideBody::Bytes(body)) => {
+                request.headers_mut().set(ContentLength(body.len() as u64));
+                request.set_body(body);
+            },
+            Some(OverrideBody::Stream(reader, len)) => {
+                if let Some(len) = len {
+                    request.headers_mut().set(ContentLength(len));
+                }
+                request.set_body(stream_body(reader));
+            },
+            None => {},
+        }
+    }
 }
 
-/// Provides some default implementations for handling API level requests and flows
-pub trait ApiClient<HTTP> where HTTP: ?Sized + HttpClient {
-    /// Get base API URI to which all relative endpoint requests will be appended
-    fn base_uri(&self) -> &Uri;
-    /// Generate full URI for requests
-    fn full_uri(&self, uri: Uri) -> Result<Uri> {
-        let is_absolute_uri = uri.is_absolute();
-        let full_uri = if !is_absolute_uri {
-            let mut no_leading_slash_uri = uri.as_ref();
-            if let "/" = &no_leading_slash_uri[..1] {
-                no_leading_slash_uri = &no_leading_slash_uri[1..];
-            }
-            let mut base_uri = self.base_uri().to_string();
-            if !base_uri.ends_with("/") {
-                base_uri.push('/');
-            }
-            let uri_concat = base_uri + no_leading_slash_uri;
-            uri_concat.parse::<Uri>()?
-        } else {
-            uri
-        };
-        Ok(full_uri)
+/// Version of an API to ask for, sent as headers, a path prefix or both
+///
+/// Templates may contain `{version}`, which is replaced with the version, so switching
+/// versions only takes `set_version`. Clients return it from `ApiClient::api_version`.
+///
+/// ```
+/// # use teatime::ApiVersion;
+/// let version = ApiVersion::new("2")
+///     .accept("application/vnd.example+json; version={version}")
+///     .path_prefix("/v{version}");
+/// assert_eq!(version.apply_path("/users"), "/v2/users");
+/// ```
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ApiVersion {
+    version: String,
+    headers: Vec<(String, String)>,
+    path_prefix: Option<String>,
+}
+
+impl ApiVersion {
+    /// Ask for `version`, without headers or a path prefix yet
+    pub fn new<S>(version: S) -> Self where S: Into<String> {
+        ApiVersion { version: version.into(), headers: Vec::new(), path_prefix: None }
     }
-    /// Get underlying HTTP client
-    fn http_client(&self) -> &HTTP;
-    /// Get underlying HTTP client mutably
-    fn http_client_mut(&mut self) -> &mut HTTP;
-    /// Implement authentication here
-    fn login(&mut self, &ApiCredentials) -> Result<()>;
 
-    /// Make an API request and resolve the future to a response
-    fn request<B>(&mut self, method: Method, uri: Uri, body: Option<B>) -> Result<Response>
-            where B: ToString {
-        let future = self.request_future(method, uri, body).ok_or(ClientError::new("No request made"))?;
-        self.response_future(future)
+    /// Send the `Accept` header from `template`, such as
+    /// `application/vnd.github+json; version={version}`
+    pub fn accept<S>(self, template: S) -> Self where S: Into<String> {
+        self.header("Accept", template)
     }
-    /// Make an API request and return the future
-    fn request_future<B>(&mut self, method: Method, uri: Uri, body: Option<B>) -> Option<FutureResponse> where B: ToString;
-    /// Resolve the future to a response
-    fn response_future(&mut self, f: FutureResponse) -> Result<Response> {
-        Ok(self.http_client_mut().evaluate_future(f)?)
+
+    /// Send the header `name` from `template`, such as `{version}` for `X-Api-Version`
+    pub fn header<K, V>(mut self, name: K, template: V) -> Self where K: Into<String>, V: Into<String> {
+        let name = name.into();
+        self.headers.retain(|h| !h.0.eq_ignore_ascii_case(&name));
+        self.headers.push((name, template.into()));
+        self
     }
-}
 
-/// Provides a default implementation for pagination in JSON API flows and automatic conversion from
-/// response body to JSON
-pub trait JsonApiClient<HTTP>: ApiClient<HTTP> where HTTP: HttpClient {
-    /// Retrieves a URL for the request to get the next page in
-    /// a paginated response
-    fn next_page_uri<'a>(&mut self, resp: &Response)
-                         -> Result<Option<Uri>>;
+    /// Put the path from `template`, such as `/v{version}`, in front of relative request paths
+    pub fn path_prefix<S>(mut self, template: S) -> Self where S: Into<String> {
+        self.path_prefix = Some(template.into());
+        self
+    }
 
-    /// Default implementation to make an API request and convert the response to JSON
-    fn request_json<B>(&mut self, method: Method, uri: Uri,
-                       body: Option<B>) -> Result<Value>
-                       where B: ToString {
-        let response = self.request(method, uri, body)?;
-        self.response_to_json(response)
+    /// Ask for `version` from now on
+    pub fn set_version<S>(&mut self, version: S) where S: Into<String> {
+        self.version = version.into();
     }
 
-    /// Resolve the future to a response and convert to JSON
-    fn response_future_json(&mut self, fut: FutureResponse) -> Result<Value> {
-        let response = self.response_future(fut)?;
-        self.response_to_json(response)
+    /// The version asked for
+    pub fn version(&self) -> &str {
+        &self.version
     }
 
-    /// Default implementation for handling pagination in JSON API contexts that will retrieve and
-    /// parse all pages - *should not be used if page-by-page behavior is required*
-    fn autopagination<B>(&mut self, method: Method, uri: Uri, body: Option<B>)
-                         -> Result<Value> where B: ToString + Clone {
-        let add_json = |vec: &mut Vec<Value>, value: Value| {
-            match value {
-                Value::Array(v) => { vec.extend(v.into_iter()); },
-                any => { vec.push(any); }
+    fn render(&self, template: &str) -> String {
+        template.replace("{version}", &self.version)
+    }
+
+    /// The headers to send, with the version filled in
+    pub fn headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        for (name, template) in &self.headers {
+            headers.set_raw(name.clone(), self.render(template));
+        }
+        headers
+    }
+
+    /// The relative request `path` with the path prefix in front
+    pub fn apply_path(&self, path: &str) -> String {
+        match self.path_prefix {
+            Some(ref prefix) => format!("/{}/{}", self.render(prefix).trim_matches('/'), path.trim_start_matches('/')),
+            None => path.to_string(),
+        }
+    }
+}
+
+/// Add the headers of the client's API version to the overrides of the next request, unless
+/// the overrides already set them
+fn apply_api_version<C, HTTP>(client: &mut C) -> Result<()> where C: ?Sized + ApiClient<HTTP>, HTTP: ?Sized + HttpClient {
+    let headers = match client.api_version() {
+        Some(version) => version.headers(),
+        None => return Ok(()),
+    };
+    match client.http_client_mut().request_overrides() {
+        Some(o) => {
+            for header in headers.iter() {
+                if o.headers.get_raw(header.name()).is_none() {
+                    o.headers.set_raw(header.name().to_string(), header.raw().clone());
+                }
             }
-        };
+            Ok(())
+        },
+        None => Err(ClientError::new("HTTP client does not support request overrides")),
+    }
+}
 
-        let mut vec: Vec<Value> = Vec::new();
-        let mut response = <Self as ApiClient<HTTP>>::request(
-            self, method.clone(), uri.clone(), body.clone()
-        )?;
-        while let Some(page) = try!(self.next_page_uri(&response)) {
-            let json = self.response_to_json(response)?;
-            add_json(&mut vec, json);
-            response = <Self as ApiClient<HTTP>>::request(self, method.clone(), page, body.clone())?;
+/// Start a request with `overrides` taking precedence over what `request_future` sets
+///
+/// The overrides are cleared again if no request is made, so they never leak into the next
+/// request.
+fn future_with_overrides<C, HTTP, B>(client: &mut C, method: Method, uri: Uri, body: Option<B>,
+                                     overrides: RequestOverrides) -> Result<FutureResponse>
+        where C: ?Sized + ApiClient<HTTP>, HTTP: ?Sized + HttpClient, B: ToString {
+    match client.http_client_mut().request_overrides() {
+        Some(o) => { *o = overrides; },
+        None => { return Err(ClientError::new("HTTP client does not support request overrides")); },
+    }
+    let future = apply_api_version(client).and_then(|_| {
+        client.request_future(method, uri, body).ok_or(ClientError::new("No request made"))
+    });
+    if future.is_err() {
+        if let Some(o) = client.http_client_mut().request_overrides() {
+            *o = RequestOverrides::new();
         }
-        let json = self.response_to_json(response)?;
-        add_json(&mut vec, json);
-        Ok(Value::from(vec))
     }
+    future
+}
+
+/// Lowercase hex encoding of `bytes`
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        hex.push(DIGITS[(b >> 4) as usize] as char);
+        hex.push(DIGITS[(b & 0xf) as usize] as char);
+    }
+    hex
+}
 
-    /// Convert a response body directly to JSON
-    fn response_to_json(&mut self, response: Response) -> Result<Value> {
-        let chunk = self.http_client_mut().evaluate_future(response.body().concat2())?;
-        serde_json::from_slice(&chunk).map_err(|_e| {
-            let string_body = match str::from_utf8(&chunk) {
-                Ok(s) => s,
-                _ => { return ClientError::new("API seems to have returned non-UTF8 garbage"); },
-            };
-            ClientError::new(format!("Failed to parse JSON: {}", string_body))
-        })
+/// True if repeating a request with `method` has the same effect as sending it once
+fn is_idempotent(method: &Method) -> bool {
+    matches!(*method, Method::Get | Method::Head | Method::Put | Method::Delete | Method::Options)
+}
+
+/// Make the request with `attempt` until it succeeds, fails in a way `policy` does not retry or
+/// runs out of retries - failing to build the request ends the loop at once
+fn with_retries<F>(policy: Option<RetryPolicy>, mut attempt: F) -> Result<Response>
+        where F: FnMut() -> Result<Result<Response>> {
+    let mut retry = 0;
+    loop {
+        let result = attempt()?;
+        match policy {
+            Some(ref policy) if retry < policy.retry_count() && policy.should_retry(&result) => {
+                retry += 1;
+                policy.before_retry(retry, &result);
+            },
+            _ => return result,
+        }
+    }
+}
+
+/// Per-call settings for `ApiClient::request_with_options` and
+/// `JsonApiClient::request_json_with_options`
+///
+/// ```
+/// # use std::time::Duration;
+/// # use teatime::RequestOptions;
+/// let options = RequestOptions::new()
+///     .timeout(Duration::from_secs(5))
+///     .retries(2)
+///     .on_retry(|event| eprintln!("{}", event))
+///     .raw_header("Sudo", "ops")
+///     .query("state", "opened");
+/// ```
+#[derive(Clone,Debug,Default)]
+pub struct RequestOptions {
+    timeout: Option<Duration>,
+    retry: Option<RetryPolicy>,
+    headers: Headers,
+    query: Vec<(String, String)>,
+    idempotency_key: Option<String>,
+    cache: Option<Vec<CacheDirective>>,
+}
+
+impl RequestOptions {
+    /// Options that change nothing about the request
+    pub fn new() -> Self {
+        RequestOptions::default()
+    }
+
+    /// Fail the request if no response arrives within `timeout`, in place of any timeout
+    /// configured for the host
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Repeat the request up to `retries` more times if it fails without a response or with
+    /// one of the statuses of a `RetryPolicy`, in place of any policy of the HTTP client
+    ///
+    /// Only requests that are safe to repeat are retried: `GET`, `HEAD`, `PUT`, `DELETE` and
+    /// `OPTIONS`, or any method once an idempotency key is set.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retry = Some(self.retry.unwrap_or_default().max_retries(retries));
+        self
+    }
+
+    /// Retry failures as `policy` says, in place of the retry count and any policy of the HTTP
+    /// client
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Call `listener` before each retry, with the attempt number, failure and wait
+    pub fn on_retry<F>(mut self, listener: F) -> Self where F: Fn(&RetryEvent) + Send + Sync + 'static {
+        self.retry = Some(self.retry.unwrap_or_default().on_retry(listener));
+        self
+    }
+
+    /// Send `header` with the request, replacing any header of the same name
+    pub fn header<H>(mut self, header: H) -> Self where H: Header {
+        self.headers.set(header);
+        self
+    }
+
+    /// Send a raw header with the request, replacing any header of the same name
+    pub fn raw_header<K, V>(mut self, name: K, value: V) -> Self
+            where K: Into<Cow<'static, str>>, V: Into<Raw> {
+        self.headers.set_raw(name, value);
+        self
+    }
+
+    /// Send every header in `headers`, replacing any headers of the same names
+    pub fn headers(mut self, headers: &Headers) -> Self {
+        self.headers.extend(headers.iter());
+        self
+    }
+
+    /// Append the query parameter `key=value` to the request URI
+    pub fn query<K, V>(mut self, key: K, value: V) -> Self where K: Into<String>, V: Into<String> {
+        self.query.push((key.into(), value.into()));
+        self
+    }
+
+    /// Send `key` as the `Idempotency-Key` header so the server can recognise repeats of the
+    /// request, which also makes it safe to retry
+    pub fn idempotency_key<S>(mut self, key: S) -> Self where S: Into<String> {
+        self.idempotency_key = Some(key.into());
+        self
+    }
+
+    /// Send `Cache-Control` with `directives`, for example `CacheDirective::NoCache` to make
+    /// caches between the client and the server revalidate
+    pub fn cache(mut self, directives: Vec<CacheDirective>) -> Self {
+        self.cache = Some(directives);
+        self
+    }
+
+    /// Number of times the request is repeated after a failure
+    pub fn retry_count(&self) -> u32 {
+        self.retry.as_ref().map_or(0, RetryPolicy::retry_count)
+    }
+
+    /// The policy retries follow, if the options set one
+    pub fn retry(&self) -> Option<&RetryPolicy> {
+        self.retry.as_ref()
+    }
+
+    /// True if a request with `method` may be retried
+    pub fn can_retry(&self, method: &Method) -> bool {
+        self.idempotency_key.is_some() || is_idempotent(method)
+    }
+
+    /// `uri` with the query parameters appended
+    pub fn apply_query(&self, uri: Uri) -> Result<Uri> {
+        append_query(uri, self.query.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+    }
+
+    /// Overrides carrying the headers and timeout of these options
+    pub fn overrides(&self) -> RequestOverrides {
+        let mut overrides = RequestOverrides::new();
+        overrides.set_headers(&self.headers);
+        if let Some(ref key) = self.idempotency_key {
+            overrides.set_raw_header("Idempotency-Key", key.clone());
+        }
+        if let Some(ref directives) = self.cache {
+            overrides.set_header(CacheControl(directives.clone()));
+        }
+        if let Some(timeout) = self.timeout {
+            overrides.set_timeout(timeout);
+        }
+        overrides
+    }
+}
+
+/// `uri` with the form encoded `params` appended to its query string
+fn append_query<'a, I>(uri: Uri, params: I) -> Result<Uri> where I: IntoIterator<Item=(&'a str, &'a str)> {
+    let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+    serializer.extend_pairs(params);
+    let query = serializer.finish();
+    if query.is_empty() {
+        return Ok(uri);
+    }
+    let separator = if uri.query().is_some() { '&' } else { '?' };
+    Ok(format!("{}{}{}", uri, separator, query).parse::<Uri>()?)
+}
+
+/// `uri` with the query parameter `name` set to `value`, replacing any values it already had
+///
+/// Other parameters are kept exactly as they were written.
+pub fn set_query_parameter(uri: Uri, name: &str, value: &str) -> Result<Uri> {
+    let text = uri.to_string();
+    let (path, query) = match text.find('?') {
+        Some(i) => (&text[..i], &text[i + 1..]),
+        None => (text.as_str(), ""),
+    };
+    let mut params = query.split('&').filter(|param| {
+        !param.is_empty() && url::form_urlencoded::parse(param.as_bytes()).next().is_none_or(|(k, _)| k != name)
+    }).map(str::to_string).collect::<Vec<_>>();
+    params.push(url::form_urlencoded::Serializer::new(String::new()).append_pair(name, value).finish());
+    Ok(format!("{}?{}", path, params.join("&")).parse::<Uri>()?)
+}
+
+/// Pool of byte buffers reused for reading response bodies to avoid allocating a fresh buffer
+/// for every request
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: Vec<Vec<u8>>,
+    max_buffers: usize,
+    max_capacity: usize,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        BufferPool::new(4, 8 * 1024 * 1024)
+    }
+}
+
+impl BufferPool {
+    /// Keep up to `max_buffers` buffers, discarding any that grew past `max_capacity` bytes
+    /// so one huge response does not pin its memory for the life of the client
+    pub fn new(max_buffers: usize, max_capacity: usize) -> Self {
+        BufferPool { buffers: Vec::new(), max_buffers, max_capacity }
+    }
+
+    /// Take an empty buffer from the pool, allocating one if the pool is empty
+    pub fn take(&mut self) -> Vec<u8> {
+        self.buffers.pop().unwrap_or_default()
+    }
+
+    /// Return a buffer to the pool for reuse
+    pub fn give(&mut self, mut buffer: Vec<u8>) {
+        if self.buffers.len() < self.max_buffers && buffer.capacity() <= self.max_capacity {
+            buffer.clear();
+            self.buffers.push(buffer);
+        }
+    }
+
+    /// Number of buffers available for reuse
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// True if no buffers are available for reuse
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+}
+
+/// Type alias for HTTPS client
+pub type HttpsClient = Client<HttpsConnector<CachingConnector>>;
+
+/// HTTPS client with `threads` lookup threads resolving hosts through `cache`, using `tls` or
+/// the default TLS settings, the connect and read limits of `timeouts` and the proxies of
+/// `proxy`
+pub fn https_client(threads: usize, handle: &Handle, cache: DnsCache, timeouts: Timeouts,
+                    proxy: ProxyConfig, tls: Option<native_tls::TlsConnector>) -> Result<HttpsClient> {
+    let tls = match tls {
+        Some(tls) => tls,
+        None => native_tls::TlsConnector::builder()?.build()?,
+    };
+    let connector = HttpsConnector::from((CachingConnector::new(threads, handle, cache).timeouts(timeouts).proxy(proxy), tls));
+    Ok(Client::configure().connector(connector).build(handle))
+}
+
+/// Settings applied by `SimpleHttpClient` to requests for one host
+///
+/// Useful when one client talks to several hosts, for example when next page links or
+/// redirects point at a CDN.
+#[derive(Default)]
+pub struct HostConfig {
+    headers: Headers,
+    query: Vec<(String, Secret)>,
+    timeout: Option<Duration>,
+    tls: Option<native_tls::TlsConnector>,
+}
+
+impl HostConfig {
+    /// Create an empty configuration
+    pub fn new() -> Self {
+        HostConfig::default()
+    }
+
+    /// Send `header` with every request to the host unless the request already sets it
+    pub fn header<H>(mut self, header: H) -> Self where H: Header {
+        self.headers.set(header);
+        self
+    }
+
+    /// Send a raw header with every request to the host unless the request already sets it
+    pub fn raw_header<K, V>(mut self, name: K, value: V) -> Self
+            where K: Into<Cow<'static, str>>, V: Into<Raw> {
+        self.headers.set_raw(name, value);
+        self
+    }
+
+    /// Authenticate every request to the host with `token` in the query parameter `name`, for
+    /// APIs that do not accept credentials in headers
+    ///
+    /// The value is form encoded, so tokens with characters such as `&`, `+` or `=` arrive
+    /// intact, and the parameter is masked by the client's redaction policy.
+    pub fn query_token<S>(mut self, name: S, token: Secret) -> Self where S: Into<String> {
+        self.query.push((name.into(), token));
+        self
+    }
+
+    /// Fail requests to the host that take longer than `timeout` to respond
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Connect to the host with custom TLS settings such as extra root certificates or client
+    /// identities
+    pub fn tls_connector(mut self, tls: native_tls::TlsConnector) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+}
+
+/// Settings for building a `SimpleHttpClient`, used by the builders of the reference clients
+///
+/// Headers, timeout and TLS settings without a host are applied to the host of the base URI
+/// the client is built for.
+#[derive(Default)]
+pub struct SimpleHttpClientBuilder {
+    base: HostConfig,
+    hosts: Vec<(String, HostConfig)>,
+    redaction: Option<RedactionPolicy>,
+    method_override: bool,
+    cache: Option<ResponseCache>,
+    audit: Option<AuditTrail>,
+    latency: Option<LatencyTracker>,
+    deprecations: Option<DeprecationMonitor>,
+    retry: Option<RetryPolicy>,
+    dns_cache: Option<DnsCache>,
+    timeouts: Timeouts,
+    proxy: Option<ProxyConfig>,
+    concurrency: Option<usize>,
+    shared_concurrency: Option<ConcurrencyLimit>,
+}
+
+impl SimpleHttpClientBuilder {
+    /// Start with the defaults of `SimpleHttpClient::new`
+    pub fn new() -> Self {
+        SimpleHttpClientBuilder::default()
+    }
+
+    /// Send `header` with every request to the API unless the request already sets it
+    pub fn header<H>(mut self, header: H) -> Self where H: Header {
+        self.base = self.base.header(header);
+        self
+    }
+
+    /// Send a raw header with every request to the API unless the request already sets it
+    pub fn raw_header<K, V>(mut self, name: K, value: V) -> Self
+            where K: Into<Cow<'static, str>>, V: Into<Raw> {
+        self.base = self.base.raw_header(name, value);
+        self
+    }
+
+    /// Authenticate every request to the API with `token` in the query parameter `name` - see
+    /// `HostConfig::query_token`
+    pub fn query_token<S>(mut self, name: S, token: Secret) -> Self where S: Into<String> {
+        self.base = self.base.query_token(name, token);
+        self
+    }
+
+    /// Fail requests to the API that take longer than `timeout` to respond
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.base = self.base.timeout(timeout);
+        self
+    }
+
+    /// Connect to the API with custom TLS settings
+    pub fn tls_connector(mut self, tls: native_tls::TlsConnector) -> Self {
+        self.base = self.base.tls_connector(tls);
+        self
+    }
+
+    /// Apply `config` to requests for another `authority` - see
+    /// `SimpleHttpClient::set_host_config`
+    pub fn host_config<S>(mut self, authority: S, config: HostConfig) -> Self where S: Into<String> {
+        self.hosts.push((authority.into(), config));
+        self
+    }
+
+    /// Mask secrets in diagnostics with `policy`
+    pub fn redaction_policy(mut self, policy: RedactionPolicy) -> Self {
+        self.redaction = Some(policy);
+        self
+    }
+
+    /// Tunnel `PUT`, `PATCH` and `DELETE` through `POST` - see
+    /// `SimpleHttpClient::set_method_override`
+    pub fn method_override(mut self, enabled: bool) -> Self {
+        self.method_override = enabled;
+        self
+    }
+
+    /// Record or replay JSON responses with `cache`
+    pub fn response_cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Record requests into `trail`
+    pub fn audit_trail(mut self, trail: AuditTrail) -> Self {
+        self.audit = Some(trail);
+        self
+    }
+
+    /// Track response times per endpoint with `tracker`
+    pub fn latency_tracker(mut self, tracker: LatencyTracker) -> Self {
+        self.latency = Some(tracker);
+        self
+    }
+
+    /// Retry failed requests as `policy` says - see `SimpleHttpClient::set_retry_policy`
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Watch responses for deprecation notices with `monitor`
+    pub fn deprecation_monitor(mut self, monitor: DeprecationMonitor) -> Self {
+        self.deprecations = Some(monitor);
+        self
+    }
+
+    /// Resolve hosts through `cache` - see `SimpleHttpClient::with_dns_cache`
+    pub fn dns_cache(mut self, cache: DnsCache) -> Self {
+        self.dns_cache = Some(cache);
+        self
+    }
+
+    /// Give up on requests that exceed `timeouts` - see `SimpleHttpClient::with_timeouts`
+    ///
+    /// A `timeout` set for the API or another host takes precedence over the total limit.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Connect through the proxies of `proxy` in place of those named by the environment
+    pub fn proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Keep at most `max` requests of this client in flight at once - see
+    /// `SimpleHttpClient::set_concurrency_limit`
+    pub fn concurrency_limit(mut self, max: usize) -> Self {
+        self.concurrency = Some(max);
+        self
+    }
+
+    /// Count requests against `limit`, which may be shared with other clients - see
+    /// `SimpleHttpClient::set_shared_concurrency_limit`
+    pub fn shared_concurrency_limit(mut self, limit: ConcurrencyLimit) -> Self {
+        self.shared_concurrency = Some(limit);
+        self
+    }
+
+    /// Create the client for an API at the absolute `base_uri`
+    pub fn build_for(self, base_uri: &Uri) -> Result<SimpleHttpClient> {
+        let authority = base_uri.authority().ok_or_else(|| {
+            ClientError::new(format!("Base URI {} has no host", base_uri))
+        })?;
+        let cache = self.dns_cache.unwrap_or_else(DnsCache::disabled);
+        let proxy = match self.proxy {
+            Some(proxy) => proxy,
+            None => ProxyConfig::from_env_or_direct(),
+        };
+        let mut client = SimpleHttpClient::with_connection_settings(cache, self.timeouts, proxy)?;
+        client.set_host_config(authority, self.base);
+        for (authority, config) in self.hosts {
+            client.set_host_config(&authority, config);
+        }
+        if let Some(policy) = self.redaction {
+            client.set_redaction_policy(policy);
+        }
+        client.set_method_override(self.method_override);
+        client.set_response_cache(self.cache);
+        client.set_audit_trail(self.audit);
+        client.set_latency_tracker(self.latency);
+        client.set_deprecation_monitor(self.deprecations);
+        client.set_retry_policy(self.retry);
+        client.set_concurrency_limit(self.concurrency);
+        client.set_shared_concurrency_limit(self.shared_concurrency);
+        Ok(client)
+    }
+}
+
+/// Parse the base URI given to a client builder, which must be absolute `http` or `https`
+pub fn parse_base_uri(base_uri: &str) -> Result<Uri> {
+    let uri = base_uri.parse::<Uri>()?;
+    match uri.scheme() {
+        Some("http") | Some("https") if uri.host().is_some() => Ok(uri),
+        _ => Err(ClientError::new(format!("Base URI {} must be an absolute http or https URI", base_uri))),
+    }
+}
+
+struct HostEntry {
+    headers: Headers,
+    query: Vec<(String, Secret)>,
+    timeout: Option<Duration>,
+    client: Option<HttpsClient>,
+}
+
+fn start_core() -> Result<Core> {
+    match Core::new() {
+        Ok(core) => Ok(core),
+        Err(e) => Err(ClientError::new(
+                format!("Failed to start Tokio event loop: {}", e.description())
+        )),
+    }
+}
+
+/// Methods defining low-level HTTP handling
+pub trait HttpClient {
+    /// Handle implementation details of creating an HTTPS client and return the client as well
+    /// as the underlying Tokio `Core` object required for driving the client
+    ///
+    /// Connections go through the proxies named by the environment - see
+    /// `ProxyConfig::from_env_or_direct`.
+    fn create_https_client(threads: usize) -> Result<(HttpsClient, Core)> {
+        Self::create_https_client_with(threads, ProxyConfig::from_env_or_direct(), &TlsConfig::new())
+    }
+
+    /// Create an HTTPS client connecting through the proxies of `proxy` with the TLS settings
+    /// of `tls`, such as a private CA or a client certificate, and the Tokio `Core` driving it
+    ///
+    /// Requests for `http` URIs must be passed to `ProxyConfig::prepare_request` before they
+    /// are sent.
+    fn create_https_client_with(threads: usize, proxy: ProxyConfig, tls: &TlsConfig) -> Result<(HttpsClient, Core)> {
+        let core = start_core()?;
+        let client = https_client(threads, &core.handle(), DnsCache::disabled(), Timeouts::default(), proxy,
+                                  Some(tls.build()?))?;
+        Ok((client, core))
+    }
+
+    /// Create a hyper `Request` object
+    fn start_request(&mut self, Method, Uri) -> &mut Self;
+    /// Add request headers
+    fn add_header<H>(&mut self, H) -> &mut Self where H: Header;
+    /// Set the body of the HTTP request
+    fn add_body<S>(&mut self, S) -> &mut Self where S: ToString;
+    /// Set the body of the HTTP request, moving it into the request so a `String` or `Vec<u8>`
+    /// is sent without being copied
+    ///
+    /// The default sends the body through `add_body`, replacing any invalid UTF-8.
+    fn add_body_bytes<S>(&mut self, body: S) -> &mut Self where S: Into<Chunk> {
+        let body = body.into();
+        self.add_body(String::from_utf8_lossy(&body))
+    }
+    /// Make HTTP request
+    fn make_request(&mut self) -> &mut Self;
+    /// Get complete HTTP response
+    fn response(&mut self) -> Result<Response>;
+    /// Get `Response` future
+    fn future(&mut self) -> Option<FutureResponse>;
+    /// Evaluate a future
+    fn evaluate_future<F>(&mut self, future: F)
+        -> result::Result<F::Item, F::Error> where F: Future;
+    /// Overrides applied to the next request made - `None` if the client does not support them
+    fn request_overrides(&mut self) -> Option<&mut RequestOverrides> {
+        None
+    }
+    /// Get an empty buffer for reading a response body - allocates unless the client pools
+    /// buffers
+    fn take_buffer(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+    /// Hand back a buffer obtained from `take_buffer` once it is no longer needed
+    fn return_buffer(&mut self, _buffer: Vec<u8>) {
+    }
+    /// Policy for masking secrets in diagnostics - `None` uses the default policy
+    fn redaction_policy(&self) -> Option<&RedactionPolicy> {
+        None
+    }
+    /// Cache of recorded responses used by `request_json` - `None` if there is none
+    fn response_cache(&self) -> Option<&ResponseCache> {
+        None
+    }
+    /// Policy for retrying failed requests made by `ApiClient::request` and the flows built on
+    /// it - `None` sends each request once
+    fn retry_policy(&self) -> Option<&RetryPolicy> {
+        None
+    }
+    /// Hold `future` back until the client's concurrency limits allow another request - sent
+    /// at once unless the client has limits
+    fn limit_concurrency<F>(&self, future: F) -> Limited<F> where F: Future {
+        Limited::new(future, Vec::new())
+    }
+    /// Resolve, connect and complete any TLS handshake with the host of `uri` ahead of the
+    /// first request to it - in the `background` while the client is busy with other requests
+    /// if set. Does nothing unless the client pools connections.
+    fn preconnect(&mut self, _uri: &Uri, _background: bool) -> Result<()> {
+        Ok(())
+    }
+    /// The hyper client and Tokio `Core` the client is built on, for what the trait does not
+    /// cover yet - `None` if it is not built on them
+    fn into_inner(self) -> Option<(HttpsClient, Core)> where Self: Sized {
+        None
+    }
+    /// Evaluate the future of the most recent request, applying any timeout configured for it
+    fn evaluate_response(&mut self, future: FutureResponse) -> Result<Response> {
+        Ok(self.evaluate_future(future)?)
+    }
+}
+
+/// Reference implementation of `HttpClient` trait - should be good enough for most use cases
+pub struct SimpleHttpClient {
+    https_client: HttpsClient,
+    core: Core,
+    request: Option<Request>,
+    response_fut: Option<FutureResponse>,
+    overrides: RequestOverrides,
+    buffers: BufferPool,
+    hosts: HashMap<String, HostEntry>,
+    timeout: Option<Duration>,
+    redaction: RedactionPolicy,
+    method_override: bool,
+    cache: Option<ResponseCache>,
+    audit: Option<AuditTrail>,
+    audit_tag: Option<String>,
+    pending_audit: Option<(Method, String)>,
+    latency: Option<LatencyTracker>,
+    pending_latency: Option<(Method, String)>,
+    deprecations: Option<DeprecationMonitor>,
+    pending_deprecation: Option<(Method, String)>,
+    retry: Option<RetryPolicy>,
+    dns_cache: DnsCache,
+    timeouts: Timeouts,
+    proxy: ProxyConfig,
+    concurrency: ConcurrencyLimit,
+    shared_concurrency: Option<ConcurrencyLimit>,
+}
+
+impl SimpleHttpClient {
+    /// Create a new `SimpleHttpClient`
+    ///
+    /// Like the other constructors, it connects through the proxies named by the environment -
+    /// see `ProxyConfig::from_env_or_direct`.
+    pub fn new() -> Result<Self> {
+        SimpleHttpClient::with_dns_cache(DnsCache::disabled())
+    }
+
+    /// Create a `SimpleHttpClient` resolving hosts through `cache`, which may be shared with
+    /// other clients
+    pub fn with_dns_cache(cache: DnsCache) -> Result<Self> {
+        SimpleHttpClient::with_connection_settings(cache, Timeouts::default(), ProxyConfig::from_env_or_direct())
+    }
+
+    /// Create a `SimpleHttpClient` giving up on requests that exceed `timeouts`
+    ///
+    /// The connect and read limits are fixed for the life of the client.
+    pub fn with_timeouts(timeouts: Timeouts) -> Result<Self> {
+        SimpleHttpClient::with_connection_settings(DnsCache::disabled(), timeouts, ProxyConfig::from_env_or_direct())
+    }
+
+    /// Create a `SimpleHttpClient` connecting through the proxies of `proxy` in place of those
+    /// named by the environment
+    pub fn with_proxy(proxy: ProxyConfig) -> Result<Self> {
+        SimpleHttpClient::with_connection_settings(DnsCache::disabled(), Timeouts::default(), proxy)
+    }
+
+    fn with_connection_settings(cache: DnsCache, timeouts: Timeouts, proxy: ProxyConfig) -> Result<Self> {
+        let core = start_core()?;
+        let https_client = https_client(4, &core.handle(), cache.clone(), timeouts, proxy.clone(), None)?;
+        Ok(SimpleHttpClient {
+            https_client,
+            core,
+            request: None,
+            response_fut: None,
+            overrides: RequestOverrides::new(),
+            buffers: BufferPool::default(),
+            hosts: HashMap::new(),
+            timeout: None,
+            redaction: RedactionPolicy::default(),
+            method_override: false,
+            cache: None,
+            audit: None,
+            audit_tag: None,
+            pending_audit: None,
+            latency: None,
+            pending_latency: None,
+            deprecations: None,
+            pending_deprecation: None,
+            retry: None,
+            dns_cache: cache,
+            timeouts,
+            proxy,
+            concurrency: ConcurrencyLimit::unlimited(),
+            shared_concurrency: None,
+        })
+    }
+
+    /// Cache of host lookups used for new connections, disabled unless a TTL is set on it
+    pub fn dns_cache(&self) -> &DnsCache {
+        &self.dns_cache
+    }
+
+    /// Proxies the client connects through
+    pub fn proxy(&self) -> &ProxyConfig {
+        &self.proxy
+    }
+
+    /// Limits on how long requests may take
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// Give up on requests that receive no response headers within `timeout` unless their
+    /// host or options set another - `None` waits for as long as the limits on connecting and
+    /// reading allow
+    pub fn set_total_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeouts.total = timeout;
+    }
+
+    /// Send `PUT`, `PATCH` and `DELETE` requests as `POST` with the original method in the
+    /// `X-HTTP-Method-Override` header, for proxies that only allow `GET` and `POST`
+    ///
+    /// The server must support the header for this to have any effect.
+    pub fn set_method_override(&mut self, enabled: bool) {
+        self.method_override = enabled;
+    }
+
+    /// True if methods are tunneled through `POST`
+    pub fn method_override(&self) -> bool {
+        self.method_override
+    }
+
+    /// Record JSON responses into, or serve them offline from, `cache` - `None` turns
+    /// caching off
+    pub fn set_response_cache(&mut self, cache: Option<ResponseCache>) {
+        self.cache = cache;
+    }
+
+    /// Record requests into `trail` - `None` turns recording off
+    pub fn set_audit_trail(&mut self, trail: Option<AuditTrail>) {
+        self.audit = trail;
+    }
+
+    /// Requests recorded so far, if recording is on
+    pub fn audit_trail(&self) -> Option<&AuditTrail> {
+        self.audit.as_ref()
+    }
+
+    /// Track response times per endpoint with `tracker` - `None` turns tracking off
+    pub fn set_latency_tracker(&mut self, tracker: Option<LatencyTracker>) {
+        self.latency = tracker;
+    }
+
+    /// Response times tracked so far, if tracking is on
+    pub fn latency_tracker(&self) -> Option<&LatencyTracker> {
+        self.latency.as_ref()
+    }
+
+    /// Retry failed requests as `policy` says - `None` sends each request once
+    ///
+    /// `RequestOptions::retry_policy` replaces the policy for a single request.
+    pub fn set_retry_policy(&mut self, policy: Option<RetryPolicy>) {
+        self.retry = policy;
+    }
+
+    /// Watch responses for deprecation notices with `monitor` - `None` turns watching off
+    pub fn set_deprecation_monitor(&mut self, monitor: Option<DeprecationMonitor>) {
+        self.deprecations = monitor;
+    }
+
+    /// Deprecation notices seen so far, if watching is on
+    pub fn deprecation_monitor(&self) -> Option<&DeprecationMonitor> {
+        self.deprecations.as_ref()
+    }
+
+    /// Keep at most `max` requests of this client in flight at once - `None` lifts the limit
+    ///
+    /// Fan-out helpers send the rest as permits are released. Requests made one at a time
+    /// never wait on this limit, only on a shared one.
+    pub fn set_concurrency_limit(&mut self, max: Option<usize>) {
+        self.concurrency.set_max(max);
+    }
+
+    /// Count requests against `limit` as well as this client's own, for example to cap
+    /// several clients on different threads together - `None` stops sharing
+    ///
+    /// A request takes a permit from this client's limit before one from the shared limit, so
+    /// requests waiting for the client's own limit do not hold up other clients.
+    pub fn set_shared_concurrency_limit(&mut self, limit: Option<ConcurrencyLimit>) {
+        self.shared_concurrency = limit;
+    }
+
+    /// This client's own concurrency limit
+    pub fn concurrency_limit(&self) -> &ConcurrencyLimit {
+        &self.concurrency
+    }
+
+    /// Tag the requests recorded from now on with the name of the operation making them
+    pub fn set_audit_tag<S>(&mut self, tag: Option<S>) where S: Into<String> {
+        self.audit_tag = tag.map(Into::into);
+    }
+
+    /// Replace the policy for masking secrets in diagnostics
+    ///
+    /// Query parameters carrying tokens from a `HostConfig` stay masked whatever the policy.
+    pub fn set_redaction_policy(&mut self, policy: RedactionPolicy) {
+        self.redaction = policy;
+        self.redact_query_tokens();
+    }
+
+    fn redact_query_tokens(&mut self) {
+        let names = self.hosts.values().flat_map(|e| e.query.iter().map(|q| q.0.clone())).collect::<Vec<_>>();
+        for name in names {
+            self.redaction = mem::replace(&mut self.redaction, RedactionPolicy::empty()).query_parameter(name);
+        }
+    }
+
+    /// Apply `config` to requests for `authority`, either `host` or `host:port`
+    ///
+    /// A `host:port` entry takes precedence over a `host` entry for the same host.
+    /// IPv6 addresses may be given with or without brackets.
+    pub fn set_host_config(&mut self, authority: &str, config: HostConfig) {
+        let client = config.tls.and_then(|tls| {
+            https_client(4, &self.core.handle(), self.dns_cache.clone(), self.timeouts, self.proxy.clone(),
+                         Some(tls)).ok()
+        });
+        self.hosts.insert(normalize_authority(authority), HostEntry {
+            headers: config.headers,
+            query: config.query,
+            timeout: config.timeout,
+            client,
+        });
+        self.redact_query_tokens();
+    }
+
+    fn host_entry(&self, uri: &Uri) -> Option<&HostEntry> {
+        host_authority(uri).and_then(|a| self.hosts.get(&a.to_lowercase()))
+            .or_else(|| uri_host(uri).and_then(|h| self.hosts.get(&h.to_lowercase())))
+    }
+
+    /// Replace the pool used for response body buffers
+    pub fn set_buffer_pool(&mut self, pool: BufferPool) {
+        self.buffers = pool;
+    }
+
+    /// Pool used for response body buffers
+    pub fn buffer_pool(&self) -> &BufferPool {
+        &self.buffers
+    }
+
+    /// Stream the body of the request being built from `reader` instead of buffering it
+    ///
+    /// The body is read on a background thread in chunks of `STREAM_CHUNK_SIZE` bytes as the
+    /// connection accepts them. Without a `Content-Length` header it is sent chunked.
+    pub fn add_stream_body<R>(&mut self, reader: R) -> &mut Self where R: io::Read + Send + 'static {
+        if let Some(req) = self.request.as_mut() {
+            req.set_body(stream_body(reader));
+        }
+        self
+    }
+}
+
+impl HttpClient for SimpleHttpClient {
+    fn start_request(&mut self, method: Method, uri: Uri) -> &mut Self {
+        self.request = Some(Request::new(method, uri));
+        self
+    }
+
+    fn add_header<H>(&mut self, header: H) -> &mut Self where H: Header {
+        self.request.as_mut().map(|ref mut req| req.headers_mut().set::<H>(header));
+        self
+    }
+
+    fn add_body<S>(&mut self, body: S) -> &mut Self where S: ToString {
+        self.add_body_bytes(body.to_string())
+    }
+
+    fn add_body_bytes<S>(&mut self, body: S) -> &mut Self where S: Into<Chunk> {
+        self.request.as_mut().map(|ref mut req| req.set_body(body.into()));
+        self
+    }
+
+    fn make_request(&mut self) -> &mut Self {
+        let request = self.request.take();
+        let overrides = mem::replace(&mut self.overrides, RequestOverrides::new());
+        let mut timeout = None;
+        let mut pending_audit = None;
+        let mut pending_latency = None;
+        let mut pending_deprecation = None;
+        let future = match request {
+            Some(mut req) => {
+                let entry = self.host_entry(req.uri());
+                if let Some(entry) = entry {
+                    if !entry.query.is_empty() {
+                        let params = entry.query.iter().map(|(k, v)| (k.as_str(), v.expose()));
+                        // Form encoded parameters always make a valid URI
+                        if let Ok(uri) = append_query(req.uri().clone(), params) {
+                            req.set_uri(uri);
+                        }
+                    }
+                    for header in entry.headers.iter() {
+                        if req.headers().get_raw(header.name()).is_none() {
+                            req.headers_mut().set_raw(header.name().to_string(), header.raw().clone());
+                        }
+                    }
+                    timeout = entry.timeout;
+                }
+                if self.audit.is_some() {
+                    pending_audit = Some((req.method().clone(), self.redaction.uri(req.uri())));
+                }
+                if self.latency.is_some() {
+                    pending_latency = Some((req.method().clone(), req.uri().path().to_string()));
+                }
+                if self.deprecations.is_some() {
+                    pending_deprecation = Some((req.method().clone(), req.uri().path().to_string()));
+                }
+                timeout = overrides.timeout.or(timeout).or(self.timeouts.total);
+                set_ipv6_host(&mut req);
+                overrides.apply(&mut req);
+                if self.method_override {
+                    tunnel_method(&mut req);
+                }
+                self.proxy.prepare_request(&mut req);
+                let client = entry.and_then(|e| e.client.as_ref()).unwrap_or(&self.https_client);
+                Some(client.request(req))
+            },
+            None => None,
+        };
+        self.timeout = timeout;
+        self.pending_audit = pending_audit;
+        self.pending_latency = pending_latency;
+        self.pending_deprecation = pending_deprecation;
+        self.response_fut = future;
+        self
+    }
+
+    fn response(&mut self) -> Result<Response> {
+        let response_fut = self.response_fut.take().ok_or(ClientError::new("No request made"))?;
+        self.evaluate_response(response_fut)
+    }
+
+    fn future(&mut self) -> Option<FutureResponse> {
+        self.response_fut.take()
+    }
+
+    fn evaluate_future<F>(&mut self, future: F) -> result::Result<F::Item, F::Error>
+            where F: Future {
+        self.core.run(future)
+    }
+
+    fn request_overrides(&mut self) -> Option<&mut RequestOverrides> {
+        Some(&mut self.overrides)
+    }
+
+    fn take_buffer(&mut self) -> Vec<u8> {
+        self.buffers.take()
+    }
+
+    fn return_buffer(&mut self, buffer: Vec<u8>) {
+        self.buffers.give(buffer);
+    }
+
+    fn redaction_policy(&self) -> Option<&RedactionPolicy> {
+        Some(&self.redaction)
+    }
+
+    fn response_cache(&self) -> Option<&ResponseCache> {
+        self.cache.as_ref()
+    }
+
+    fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry.as_ref()
+    }
+
+    /// A `HEAD` request is sent to the root of the host and its response ignored, leaving the
+    /// connection in the pool for the next request. In the background the connection is made
+    /// whenever the event loop next runs, and a request to the host made meanwhile takes it
+    /// over if it is ready first; failures are only reported when not in the background.
+    fn preconnect(&mut self, uri: &Uri, background: bool) -> Result<()> {
+        let mut req = Request::new(Method::Head, uri_origin(uri)?.parse::<Uri>()?);
+        set_ipv6_host(&mut req);
+        self.proxy.prepare_request(&mut req);
+        let entry = self.host_entry(req.uri());
+        let timeout = entry.and_then(|e| e.timeout).or(self.timeouts.total);
+        let future = entry.and_then(|e| e.client.as_ref()).unwrap_or(&self.https_client).request(req);
+        if background {
+            self.core.handle().spawn(future.then(|_| Ok(())));
+            return Ok(());
+        }
+        let pending = mem::replace(&mut self.timeout, timeout);
+        let result = self.evaluate_with_timeout(future);
+        self.timeout = pending;
+        result.map(|_| ())
+    }
+
+    fn limit_concurrency<F>(&self, future: F) -> Limited<F> where F: Future {
+        let shared = self.shared_concurrency.iter().cloned();
+        Limited::new(future, Some(self.concurrency.clone()).into_iter().chain(shared).collect())
+    }
+
+    /// Clients made for hosts configured with their own TLS settings are dropped
+    fn into_inner(self) -> Option<(HttpsClient, Core)> {
+        Some((self.https_client, self.core))
+    }
+
+    fn evaluate_response(&mut self, future: FutureResponse) -> Result<Response> {
+        let pending = self.pending_audit.take();
+        let pending_latency = self.pending_latency.take();
+        let pending_deprecation = self.pending_deprecation.take();
+        let time = SystemTime::now();
+        let start = Instant::now();
+        tls::take_rejection();
+        let response = self.evaluate_with_timeout(future).map_err(|e| tls::take_rejection().unwrap_or(e));
+        let duration = start.elapsed();
+        let status = response.as_ref().ok().map(Response::status);
+        if let (Some((method, uri)), Some(trail)) = (pending, self.audit.as_mut()) {
+            trail.record(AuditEntry {
+                time,
+                method,
+                uri,
+                status,
+                duration,
+                tag: self.audit_tag.clone(),
+            });
+        }
+        if let (Some((method, path)), Some(tracker)) = (pending_latency, self.latency.as_mut()) {
+            tracker.record(&method, &path, duration, status);
+        }
+        if let (Some((method, path)), Some(monitor), Ok(r)) =
+                (pending_deprecation, self.deprecations.as_mut(), response.as_ref()) {
+            monitor.check(&method, &path, r.headers());
+        }
+        response
+    }
+
+}
+
+impl SimpleHttpClient {
+    fn evaluate_with_timeout(&mut self, future: FutureResponse) -> Result<Response> {
+        let future = self.limit_concurrency(future);
+        let timeout = match self.timeout.take() {
+            Some(timeout) => timeout,
+            None => return self.evaluate_future(future).map_err(transport_error),
+        };
+        let timer = Timeout::new(timeout, &self.core.handle()).map_err(|e| {
+            ClientError::new(format!("Failed to start request timer: {}", e))
+        })?;
+        match self.core.run(future.select2(timer)) {
+            Ok(Either::A((response, _))) => Ok(response),
+            Ok(Either::B(_)) => Err(ClientError::with_kind(
+                ErrorKind::TimedOut, format!("No response received within {:?}", timeout)
+            )),
+            Err(Either::A((e, _))) => Err(transport_error(e)),
+            Err(Either::B((e, _))) => Err(ClientError::new(format!("Request timer failed: {}", e))),
+        }
+    }
+}
+
+/// Error for a failed request, keeping the message of connection errors such as a proxy
+/// refusing a tunnel and marking connect and read timeouts as `ErrorKind::TimedOut`
+fn transport_error(e: hyper::Error) -> ClientError {
+    match e {
+        hyper::Error::Io(ref io) if io.kind() == io::ErrorKind::TimedOut => {
+            ClientError::with_kind(ErrorKind::TimedOut, io.to_string())
+        },
+        hyper::Error::Io(ref io) => ClientError::new(io.to_string()),
+        e => ClientError::from(e),
+    }
+}
+
+/// Provides some default implementations for handling API level requests and flows
+pub trait ApiClient<HTTP> where HTTP: ?Sized + HttpClient {
+    /// Get base API URI to which all relative endpoint requests will be appended
+    fn base_uri(&self) -> &Uri;
+    /// Generate full URI for requests
+    fn full_uri(&self, uri: Uri) -> Result<Uri> {
+        if uri.is_absolute() {
+            Ok(uri)
+        } else if let Some(version) = self.api_version() {
+            join_uri(self.base_uri(), &version.apply_path(uri.as_ref()))
+        } else {
+            join_uri(self.base_uri(), uri.as_ref())
+        }
+    }
+    /// Version of the API requests ask for - `None` leaves it to the server
+    ///
+    /// Its path prefix is applied by `full_uri` and its headers by the default request flows.
+    fn api_version(&self) -> Option<&ApiVersion> {
+        None
+    }
+    /// Get underlying HTTP client
+    fn http_client(&self) -> &HTTP;
+    /// Get underlying HTTP client mutably
+    fn http_client_mut(&mut self) -> &mut HTTP;
+    /// Implement authentication here
+    fn login(&mut self, &ApiCredentials) -> Result<()>;
+
+    /// Hook run by the default request flows before each request is built, for example to
+    /// rotate tokens that are about to expire
+    fn before_request(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Make an API request and resolve the future to a response
+    ///
+    /// Failures are retried as the HTTP client's `retry_policy` says for methods that are
+    /// safe to repeat.
+    fn request<B>(&mut self, method: Method, uri: Uri, body: Option<B>) -> Result<Response>
+            where B: ToString {
+        let body = body.map(|b| b.to_string());
+        let policy = self.http_client().retry_policy().filter(|_| is_idempotent(&method)).cloned();
+        with_retries(policy, || {
+            self.before_request()?;
+            apply_api_version(self)?;
+            let future = self.request_future(method.clone(), uri.clone(), body.clone())
+                .ok_or(ClientError::new("No request made"))?;
+            Ok(self.response_future(future))
+        })
+    }
+    /// Make an API request that fails with `ErrorKind::TimedOut` unless its response arrives
+    /// within `timeout`, in place of any timeout configured for the client
+    fn request_with_timeout<B>(&mut self, method: Method, uri: Uri, body: Option<B>, timeout: Duration)
+            -> Result<Response> where B: ToString {
+        self.request_with_options(method, uri, body, &RequestOptions::new().timeout(timeout))
+    }
+    /// Connect to the API ahead of the first request so that it does not wait for DNS, TCP
+    /// and TLS setup - see `HttpClient::preconnect`
+    fn preconnect(&mut self, background: bool) -> Result<()> {
+        let uri = self.base_uri().clone();
+        self.http_client_mut().preconnect(&uri, background)
+    }
+
+    /// Send a raw hyper `request` with the client's authentication and event loop, for what
+    /// the other request methods do not cover yet such as extension methods
+    ///
+    /// A relative URI is resolved against the base URI. The request's headers and body take
+    /// precedence over anything set by `request_future`, authentication headers included.
+    fn execute_raw(&mut self, request: Request) -> Result<Response> {
+        let (method, uri, _, headers, body) = request.deconstruct();
+        let body = self.http_client_mut().evaluate_future(body.concat2())?;
+        self.before_request()?;
+        let mut overrides = RequestOverrides::new();
+        overrides.set_headers(&headers);
+        if !body.is_empty() {
+            overrides.set_body(body.to_vec());
+        }
+        let future = future_with_overrides(self, method, uri, None::<String>, overrides)?;
+        self.response_future(future)
+    }
+
+    /// Make an API request with extra `headers`, such as `Sudo`, that take precedence over
+    /// those set by `request_future` for this request only
+    fn request_with_headers<B>(&mut self, method: Method, uri: Uri, body: Option<B>, headers: &Headers)
+            -> Result<Response> where B: ToString {
+        self.request_with_options(method, uri, body, &RequestOptions::new().headers(headers))
+    }
+    /// Make an API request with per-call `options` and resolve the future to a response
+    ///
+    /// The options take precedence over anything set by `request_future` for this request
+    /// only.
+    fn request_with_options<B>(&mut self, method: Method, uri: Uri, body: Option<B>,
+                               options: &RequestOptions) -> Result<Response> where B: ToString {
+        let uri = options.apply_query(uri)?;
+        let body = body.map(|b| b.to_string());
+        let policy = options.retry().or(self.http_client().retry_policy()).filter(|_| options.can_retry(&method)).cloned();
+        with_retries(policy, || {
+            self.before_request()?;
+            let future = future_with_overrides(self, method.clone(), uri.clone(), body.clone(), options.overrides())?;
+            Ok(self.response_future(future))
+        })
+    }
+    /// Make an API request and return the future
+    fn request_future<B>(&mut self, method: Method, uri: Uri, body: Option<B>) -> Option<FutureResponse> where B: ToString;
+    /// Make an API request with headers and body that take precedence over those set by
+    /// `request_future` and return the future
+    fn request_future_with_overrides(&mut self, method: Method, uri: Uri,
+                                     overrides: RequestOverrides) -> Result<FutureResponse> {
+        self.before_request()?;
+        future_with_overrides(self, method, uri, None::<String>, overrides)
+    }
+    /// Resolve the future to a response
+    fn response_future(&mut self, f: FutureResponse) -> Result<Response> {
+        self.http_client_mut().evaluate_response(f)
+    }
+}
+
+fn origin(uri: &Uri) -> Option<(String, String, u16)> {
+    let scheme = uri.scheme()?.to_lowercase();
+    let port = match (uri.port(), scheme.as_str()) {
+        (Some(port), _) => port,
+        (None, "http") => 80,
+        (None, "https") => 443,
+        (None, _) => return None,
+    };
+    Some((scheme, uri.host()?.to_lowercase(), port))
+}
+
+/// Host of `uri` as written in an authority, with IPv6 addresses in brackets
+pub fn uri_host(uri: &Uri) -> Option<String> {
+    uri.host().map(|host| if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    })
+}
+
+/// Authority of `uri` without any user information, such as `[2001:db8::1]:8200`
+pub fn host_authority(uri: &Uri) -> Option<&str> {
+    uri.authority().map(|a| a.rsplit('@').next().unwrap_or(a))
+}
+
+/// `scheme://authority` of the absolute `uri`, without user information or a trailing slash
+pub fn uri_origin(uri: &Uri) -> Result<String> {
+    match (uri.scheme(), host_authority(uri)) {
+        (Some(scheme), Some(authority)) => Ok(format!("{}://{}", scheme, authority)),
+        _ => Err(ClientError::new(format!("URI {} is not absolute", uri))),
+    }
+}
+
+/// Append the relative `path`, which may have a query string, to the path of the absolute
+/// `base` URI
+///
+/// Exactly one slash separates the two whatever either ends or starts with, and a query string
+/// on `base` is kept after that of `path`.
+pub fn join_uri(base: &Uri, path: &str) -> Result<Uri> {
+    let (scheme, authority) = match (base.scheme(), base.authority()) {
+        (Some(scheme), Some(authority)) => (scheme, authority),
+        _ => return Err(ClientError::new(format!("Base URI {} is not absolute", base))),
+    };
+    let mut joined = format!("{}://{}{}/{}", scheme, authority, base.path().trim_end_matches('/'),
+                             path.trim_start_matches('/'));
+    if let Some(query) = base.query() {
+        joined.push(if path.contains('?') { '&' } else { '?' });
+        joined.push_str(query);
+    }
+    Ok(joined.parse::<Uri>()?)
+}
+
+/// Lowercase `authority` without user information as used for host keyed settings, adding
+/// brackets to a bare IPv6 address
+fn normalize_authority(authority: &str) -> String {
+    let authority = authority.trim().trim_end_matches('/');
+    let authority = authority.rsplit('@').next().unwrap_or(authority).to_lowercase();
+    if authority.matches(':').count() > 1 && !authority.starts_with('[') {
+        format!("[{}]", authority)
+    } else {
+        authority
+    }
+}
+
+/// Set the `Host` header for IPv6 addresses, which hyper writes without the brackets
+fn set_ipv6_host(req: &mut Request) {
+    if req.headers().has::<Host>() {
+        return;
+    }
+    let host = match req.uri().host() {
+        Some(host) if host.contains(':') => Host::new(format!("[{}]", host), req.uri().port()),
+        _ => return,
+    };
+    req.headers_mut().set(host);
+}
+
+/// True if two absolute URIs share a scheme, host and port
+pub fn same_origin(a: &Uri, b: &Uri) -> bool {
+    match (origin(a), origin(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(feature = "charset")]
+fn transcode_body<'a>(body: &'a [u8], content_type: Option<&Mime>) -> Result<Cow<'a, [u8]>> {
+    charset::to_utf8(body, content_type)
+}
+
+#[cfg(not(feature = "charset"))]
+fn transcode_body<'a>(body: &'a [u8], _content_type: Option<&Mime>) -> Result<Cow<'a, [u8]>> {
+    Ok(Cow::Borrowed(body))
+}
+
+/// Unparsed JSON response body, for callers that forward JSON without inspecting it
+#[derive(Clone,Debug,PartialEq)]
+pub struct RawJson {
+    body: Vec<u8>,
+    content_type: Option<Mime>,
+}
+
+impl RawJson {
+    /// Wrap a body after checking that it starts like a JSON document - the rest of the body is
+    /// not validated
+    pub fn new(body: Vec<u8>, content_type: Option<Mime>) -> Result<Self> {
+        match body.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') | Some(b'[') | Some(b'"') | Some(b'-') | Some(b't') | Some(b'f') | Some(b'n') => (),
+            Some(b) if b.is_ascii_digit() => (),
+            _ => return Err(ClientError::new("Response body is not JSON")),
+        }
+        Ok(RawJson { body, content_type })
+    }
+
+    /// The raw body
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Take the raw body
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.body
+    }
+
+    /// `Content-Type` of the response, if it had one
+    pub fn content_type(&self) -> Option<&Mime> {
+        self.content_type.as_ref()
+    }
+
+    /// Parse the body after all
+    pub fn parse<T>(&self) -> Result<T> where T: DeserializeOwned {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// Reader over the chunks of a response body that avoids concatenating them into one buffer
+pub struct ChunkReader {
+    chunks: std::vec::IntoIter<Chunk>,
+    current: Option<Chunk>,
+    position: usize,
+}
+
+impl ChunkReader {
+    /// Read the chunks in order
+    pub fn new(chunks: Vec<Chunk>) -> Self {
+        ChunkReader { chunks: chunks.into_iter(), current: None, position: 0 }
+    }
+}
+
+impl io::Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(ref chunk) = self.current {
+                if self.position < chunk.len() {
+                    let len = std::cmp::min(buf.len(), chunk.len() - self.position);
+                    buf[..len].copy_from_slice(&chunk[self.position..self.position + len]);
+                    self.position += len;
+                    return Ok(len);
+                }
+            }
+            match self.chunks.next() {
+                Some(chunk) => {
+                    self.current = Some(chunk);
+                    self.position = 0;
+                },
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Provides a default implementation for pagination in JSON API flows and automatic conversion from
+/// response body to JSON
+pub trait JsonApiClient<HTTP>: ApiClient<HTTP> where HTTP: HttpClient {
+    /// Retrieves a URL for the request to get the next page in
+    /// a paginated response
+    fn next_page_uri<'a>(&mut self, resp: &Response)
+                         -> Result<Option<Uri>>;
+
+    /// Page numbers, totals and neighbouring page URIs reported by a response
+    ///
+    /// The default reads the `X-Page` family of headers and takes the next page from
+    /// `next_page_uri`; clients whose APIs report more should override it.
+    fn page_info(&mut self, resp: &Response) -> Result<PageInfo> {
+        let mut info = PageInfo::from_headers(resp.headers());
+        info.next = self.next_page_uri(resp)?;
+        Ok(info)
+    }
+
+    /// Page size that autopagination and page-by-page reads request, replacing any size in the
+    /// URI or next page links - `None` leaves the URIs as they are
+    fn per_page(&self) -> Option<u32> {
+        None
+    }
+
+    /// Query parameter the API reads the page size from
+    fn per_page_parameter(&self) -> &str {
+        "per_page"
+    }
+
+    /// `uri` requesting the page size from `per_page`, if one is set
+    fn apply_per_page(&self, uri: Uri) -> Result<Uri> {
+        match self.per_page() {
+            Some(per_page) => set_query_parameter(uri, self.per_page_parameter(), &per_page.to_string()),
+            None => Ok(uri),
+        }
+    }
+
+    /// Authorities (`host` or `host:port`) besides that of the base URI that autopagination
+    /// may follow next page links to
+    ///
+    /// Links to them must use `https` or the scheme of the base URI, unless an entry names its
+    /// scheme as in `http://host:port`.
+    fn pagination_allowlist(&self) -> &[String] {
+        &[]
+    }
+
+    /// Refuse to follow a next page link to another origin unless its authority is allowed,
+    /// since the request would carry the client's credentials
+    fn check_next_page(&self, uri: &Uri) -> Result<()> {
+        if same_origin(self.base_uri(), uri) {
+            return Ok(());
+        }
+        let scheme = uri.scheme().unwrap_or("");
+        let allowed = self.pagination_allowlist().iter().any(|entry| {
+            let (entry_scheme, authority) = match entry.find("://") {
+                Some(i) => (Some(&entry[..i]), &entry[i + 3..]),
+                None => (None, entry.as_str()),
+            };
+            // Never downgrade to a plaintext scheme the entry did not ask for
+            let scheme_allowed = match entry_scheme {
+                Some(s) => s.eq_ignore_ascii_case(scheme),
+                None => scheme.eq_ignore_ascii_case("https")
+                    || self.base_uri().scheme().is_some_and(|s| s.eq_ignore_ascii_case(scheme)),
+            };
+            let authority = normalize_authority(authority);
+            scheme_allowed && (host_authority(uri).is_some_and(|u| u.eq_ignore_ascii_case(&authority))
+                || uri_host(uri).is_some_and(|h| h.eq_ignore_ascii_case(&authority)))
+        });
+        if allowed {
+            Ok(())
+        } else {
+            let policy = self.http_client().redaction_policy().cloned().unwrap_or_default();
+            Err(ClientError::new(format!("Refusing to follow next page link to another origin: {}", policy.uri(uri))))
+        }
+    }
+
+    /// Default implementation to make an API request and convert the response to JSON
+    ///
+    /// The body is serialized to JSON; the `request_future` implementation is responsible
+    /// for setting the JSON `Content-Type` header. When the HTTP client has a response cache
+    /// the response is recorded to it or, offline, served from it without a request.
+    fn request_json<B>(&mut self, method: Method, uri: Uri,
+                       body: Option<B>) -> Result<Value>
+                       where B: JsonBody {
+        let body = encode_json_body(body)?;
+        if self.http_client().response_cache().is_none() {
+            let response = self.request(method, uri, body)?;
+            return self.response_to_json(response);
+        }
+        let full_uri = self.full_uri(uri.clone())?;
+        if let Some(cache) = self.http_client().response_cache() {
+            if let Some(recorded) = cache.lookup(&method, &full_uri, body.as_deref())? {
+                return Ok(recorded);
+            }
+        }
+        let response = self.request(method.clone(), uri, body.clone())?;
+        let json = self.response_to_json(response)?;
+        if let Some(cache) = self.http_client().response_cache() {
+            cache.store(&method, &full_uri, body.as_deref(), &json)?;
+        }
+        Ok(json)
+    }
+
+    /// Make an API request with extra `headers` for this request only and convert the
+    /// response to JSON
+    fn request_json_with_headers<B>(&mut self, method: Method, uri: Uri, body: Option<B>,
+                                    headers: &Headers) -> Result<Value> where B: JsonBody {
+        let body = encode_json_body(body)?;
+        let response = self.request_with_headers(method, uri, body, headers)?;
+        self.response_to_json(response)
+    }
+
+    /// Make an API request with per-call `options` and convert the response to JSON
+    fn request_json_with_options<B>(&mut self, method: Method, uri: Uri, body: Option<B>,
+                                    options: &RequestOptions) -> Result<Value> where B: JsonBody {
+        let body = encode_json_body(body)?;
+        let response = self.request_with_options(method, uri, body, options)?;
+        self.response_to_json(response)
+    }
+
+    /// Make an API request and return the JSON response body without parsing it
+    fn request_json_raw<B>(&mut self, method: Method, uri: Uri, body: Option<B>) -> Result<RawJson>
+            where B: JsonBody {
+        let body = encode_json_body(body)?;
+        let response = self.request(method, uri, body)?;
+        self.response_to_json_raw(response)
+    }
+
+    /// Resolve the future to a response and convert to JSON
+    fn response_future_json(&mut self, fut: FutureResponse) -> Result<Value> {
+        let response = self.response_future(fut)?;
+        self.response_to_json(response)
+    }
+
+    /// Make an API request and deserialize the response body into `T`
+    ///
+    /// Like `request_json`, the body is serialized to JSON and responses are recorded to or
+    /// served from the HTTP client's response cache when it has one.
+    fn request_typed<T, B>(&mut self, method: Method, uri: Uri, body: Option<B>) -> Result<T>
+            where T: DeserializeOwned, B: JsonBody {
+        if self.http_client().response_cache().is_some() {
+            let json = self.request_json(method, uri, body)?;
+            return serde_json::from_value(json).map_err(|e| ClientError::new(format!("Failed to deserialize JSON: {}", e)));
+        }
+        let body = encode_json_body(body)?;
+        let response = self.request(method, uri, body)?;
+        self.response_to_typed(response)
+    }
+
+    /// Resolve the future to a response and deserialize its body into `T`
+    fn response_future_typed<T>(&mut self, fut: FutureResponse) -> Result<T> where T: DeserializeOwned {
+        let response = self.response_future(fut)?;
+        self.response_to_typed(response)
+    }
+
+    /// Default implementation for handling pagination in JSON API contexts that will retrieve and
+    /// parse all pages - *should not be used if page-by-page behavior is required*
+    fn autopagination<B>(&mut self, method: Method, uri: Uri, body: Option<B>)
+                         -> Result<Value> where B: JsonBody {
+        let body = encode_json_body(body)?;
+        let add_json = |vec: &mut Vec<Value>, value: Value| {
+            match value {
+                Value::Array(v) => { vec.extend(v.into_iter()); },
+                any => { vec.push(any); }
+            }
+        };
+
+        let mut vec: Vec<Value> = Vec::new();
+        let uri = self.apply_per_page(uri)?;
+        let mut response = <Self as ApiClient<HTTP>>::request(
+            self, method.clone(), uri.clone(), body.clone()
+        )?;
+        while let Some(page) = try!(self.next_page_uri(&response)) {
+            let page = self.full_uri(page)?;
+            self.check_next_page(&page)?;
+            let page = self.apply_per_page(page)?;
+            let json = self.response_to_json(response)?;
+            add_json(&mut vec, json);
+            response = <Self as ApiClient<HTTP>>::request(self, method.clone(), page, body.clone())?;
+        }
+        let json = self.response_to_json(response)?;
+        add_json(&mut vec, json);
+        Ok(Value::from(vec))
+    }
+
+    /// Convert a response body directly to JSON
+    ///
+    /// The body is read into a buffer from the HTTP client's pool when it has one. With the
+    /// `charset` feature, bodies in other charsets are transcoded to UTF-8 first.
+    fn response_to_json(&mut self, response: Response) -> Result<Value> {
+        self.response_to_typed(response)
+    }
+
+    /// Convert a response body directly to `T` the same way as `response_to_json`
+    ///
+    /// A body that is valid JSON but does not match `T` fails with the reason in the message and
+    /// the response kept in `ErrorKind::InvalidResponse`.
+    fn response_to_typed<T>(&mut self, response: Response) -> Result<T> where T: DeserializeOwned {
+        let policy = self.http_client().redaction_policy().cloned().unwrap_or_default();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let content_type = headers.get::<ContentType>().map(|c| c.0.clone());
+        let mut buffer = self.http_client_mut().take_buffer();
+        let read = self.http_client_mut().evaluate_future(response.body().for_each(|chunk| {
+            buffer.extend_from_slice(&chunk);
+            Ok(())
+        }));
+        let result = read.map_err(ClientError::from).and_then(|_| {
+            let body = transcode_body(&buffer, content_type.as_ref()).map_err(|e| {
+                ClientError::with_kind(ErrorKind::invalid_response(status, &headers, buffer.clone(), &policy),
+                                       e.to_string())
+            })?;
+            serde_json::from_slice(&body).map_err(|e| {
+                let message = match str::from_utf8(&body) {
+                    Ok(_) if e.is_data() => format!("Failed to deserialize JSON: {}", e),
+                    Ok(s) => format!("Failed to parse JSON: {}", s),
+                    _ => "API seems to have returned non-UTF8 garbage".to_string(),
+                };
+                ClientError::with_kind(ErrorKind::invalid_response(status, &headers, buffer.clone(), &policy), message)
+            })
+        });
+        self.http_client_mut().return_buffer(buffer);
+        result
+    }
+
+    /// Read a response body as unparsed JSON along with its `Content-Type`
+    fn response_to_json_raw(&mut self, response: Response) -> Result<RawJson> {
+        let policy = self.http_client().redaction_policy().cloned().unwrap_or_default();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let content_type = headers.get::<ContentType>().map(|c| c.0.clone());
+        let chunk = self.http_client_mut().evaluate_future(response.body().concat2())?;
+        RawJson::new(chunk.to_vec(), content_type).map_err(|e| {
+            ClientError::with_kind(ErrorKind::invalid_response(status, &headers, chunk.to_vec(), &policy),
+                                   e.to_string())
+        })
+    }
+
+    /// Deserialize a response body directly from its chunks without first copying them into
+    /// one contiguous buffer - preferable for large listings
+    ///
+    /// Unlike `response_to_json`, parse errors do not include the body.
+    fn response_to_json_streaming<T>(&mut self, response: Response) -> Result<T>
+            where T: DeserializeOwned {
+        let chunks = self.http_client_mut().evaluate_future(
+            response.body().collect()
+        )?;
+        let result = if chunks.len() == 1 {
+            serde_json::from_slice(&chunks[0])
+        } else {
+            serde_json::from_reader(io::BufReader::new(ChunkReader::new(chunks)))
+        };
+        result.map_err(|e| ClientError::new(format!("Failed to parse JSON: {}", e)))
+    }
+}
+
+/// Names the HTTP client an API client is built on, so code generic over API clients does not
+/// need a separate type parameter for the transport
+pub trait Transport {
+    /// HTTP client the API client sends requests with
+    type Http: HttpClient;
+}
+
+/// Any `ApiClient` that names its transport - use as `C: AnyApiClient` rather than
+/// `C: ApiClient<HTTP>, HTTP: HttpClient`
+pub trait AnyApiClient: Transport + ApiClient<<Self as Transport>::Http> {}
+
+impl<C> AnyApiClient for C where C: Transport + ApiClient<<C as Transport>::Http> {}
+
+/// Any `JsonApiClient` that names its transport - use as `C: AnyJsonApiClient` rather than
+/// `C: JsonApiClient<HTTP>, HTTP: HttpClient`
+pub trait AnyJsonApiClient: Transport + JsonApiClient<<Self as Transport>::Http> {}
+
+impl<C> AnyJsonApiClient for C where C: Transport + JsonApiClient<<C as Transport>::Http> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::mime;
+    use testing::{Fixture,FixtureServer,TestClient,client_for};
+
+    #[test]
+    fn test_read_line_endings() {
+        let mut input = &b"unix\nwindows\r\n\r\n  spaced \r\nlast"[..];
+        let lines = (0..5).map(|_| read_line_from(&mut input).unwrap()).collect::<Vec<_>>();
+        assert_eq!(lines, vec!["unix", "windows", "", "  spaced ", "last"]);
+        assert_eq!(read_line_from(&mut input).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_interactive_prompts() {
+        let options = ["ldap/", "userpass/", "okta/"];
+        let mut output = Vec::new();
+        let chosen = select_from(&mut &b"7\nokta\n2\n"[..], &mut output, "Auth mount:", &options).unwrap();
+        assert_eq!(chosen, 1);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("Auth mount:\n  1) ldap/\n  2) userpass/\n  3) okta/\nChoose 1-3: "));
+        assert_eq!(output.matches("Please enter a number").count(), 2);
+        assert_eq!(select_from(&mut &b"okta/\n"[..], &mut Vec::new(), "", &options).unwrap(), 2);
+        assert!(select_from(&mut &b""[..], &mut Vec::new(), "", &options).is_err());
+        assert!(select_from(&mut &b"1\n"[..], &mut Vec::new(), "", &[] as &[&str]).is_err());
+
+        assert!(confirm_from(&mut &b"\n"[..], &mut Vec::new(), "Delete?", true).unwrap());
+        assert!(!confirm_from(&mut &b"maybe\nNo\n"[..], &mut Vec::new(), "Delete?", true).unwrap());
+        assert!(confirm_from(&mut &b"y\n"[..], &mut Vec::new(), "Delete?", false).unwrap());
+        assert!(confirm_from(&mut &b""[..], &mut Vec::new(), "Delete?", false).is_err());
+    }
+
+    #[test]
+    fn test_json_body_encoding() {
+        let mut map = std::collections::BTreeMap::new();
+        map.insert("name", "teatime");
+        assert_eq!(map.to_json_body().unwrap(), r#"{"name":"teatime"}"#);
+        assert_eq!("quoted".to_json_body().unwrap(), r#""quoted""#);
+        assert_eq!(Value::from("quoted").to_json_body().unwrap(), r#""quoted""#);
+        assert_eq!(RawBody(r#"{"raw":true}"#.to_string()).to_json_body().unwrap(),
+                   r#"{"raw":true}"#);
+    }
+
+    #[test]
+    fn test_pre_encoded_json_body() {
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/items", Fixture::json(201, "{}"))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let encoded = r#"{"name": "x", "tags": ["a"]}"#;
+        client.request_json(Method::Post, "/items".parse().unwrap(), Some(RawBody(encoded.to_string()))).unwrap();
+        client.request_json(Method::Post, "/items".parse().unwrap(), Some(vec!["a"])).unwrap();
+        let bodies: Vec<Vec<u8>> = server.requests().into_iter().map(|r| r.body).collect();
+        assert_eq!(bodies, vec![encoded.as_bytes().to_vec(), br#"["a"]"#.to_vec()]);
+    }
+
+    #[test]
+    fn test_request_overrides_apply() {
+        let mut request = Request::new(Method::Post, "http://localhost/".parse().unwrap());
+        request.headers_mut().set(ContentType::json());
+        let mut overrides = RequestOverrides::new();
+        assert!(overrides.is_empty());
+        overrides.set_header(ContentType::plaintext()).set_body("body");
+        overrides.apply(&mut request);
+        assert_eq!(request.headers().get::<ContentType>(), Some(&ContentType::plaintext()));
+        assert_eq!(request.headers().get::<ContentLength>(), Some(&ContentLength(4)));
+    }
+
+    #[test]
+    fn test_tunnel_method() {
+        let mut request = Request::new(Method::Delete, "http://localhost/x".parse().unwrap());
+        tunnel_method(&mut request);
+        assert_eq!(request.method(), &Method::Post);
+        assert_eq!(request.headers().get_raw("X-HTTP-Method-Override").and_then(|r| r.one()),
+                   Some(&b"DELETE"[..]));
+        let mut request = Request::new(Method::Get, "http://localhost/x".parse().unwrap());
+        tunnel_method(&mut request);
+        assert_eq!(request.method(), &Method::Get);
+        assert!(request.headers().get_raw("X-HTTP-Method-Override").is_none());
+    }
+
+    #[test]
+    fn test_request_options() {
+        let options = RequestOptions::new().query("search", "a&b").query("page", "2")
+            .raw_header("Sudo", "ops").cache(vec![CacheDirective::NoCache]).timeout(Duration::from_secs(3));
+        assert_eq!(options.apply_query("/issues".parse().unwrap()).unwrap().to_string(),
+                   "/issues?search=a%26b&page=2");
+        assert_eq!(options.apply_query("/issues?state=opened".parse().unwrap()).unwrap().query(),
+                   Some("state=opened&search=a%26b&page=2"));
+        let overrides = options.overrides();
+        assert_eq!(overrides.headers().get_raw("Sudo").and_then(|r| r.one()), Some(&b"ops"[..]));
+        assert_eq!(overrides.headers().get::<CacheControl>(), Some(&CacheControl(vec![CacheDirective::NoCache])));
+        assert_eq!(overrides.timeout(), Some(Duration::from_secs(3)));
+
+        assert!(options.can_retry(&Method::Get));
+        assert!(!options.can_retry(&Method::Post));
+        assert!(options.idempotency_key("k-1").can_retry(&Method::Post));
+    }
+
+    #[test]
+    fn test_request_overrides_debug_is_redacted() {
+        let mut overrides = RequestOverrides::new();
+        overrides.set_raw_header("Private-Token", "glpat-secret").set_body("body");
+        let debug = format!("{:?}", overrides);
+        assert!(!debug.contains("glpat-secret"));
+        assert!(debug.contains("body_len: Some(4)"));
+    }
+
+    #[test]
+    fn test_raw_json() {
+        let raw = RawJson::new(b" {\"id\": 1}".to_vec(), Some(mime::APPLICATION_JSON)).unwrap();
+        assert_eq!(raw.content_type(), Some(&mime::APPLICATION_JSON));
+        assert_eq!(raw.parse::<Value>().unwrap()["id"], 1);
+        assert_eq!(raw.into_bytes(), b" {\"id\": 1}".to_vec());
+        assert!(RawJson::new(b"-12".to_vec(), None).is_ok());
+        assert!(RawJson::new(b"<html>".to_vec(), None).is_err());
+        assert!(RawJson::new(b"  ".to_vec(), None).is_err());
+    }
+
+    #[test]
+    fn test_same_origin() {
+        let uri = |s: &str| s.parse::<Uri>().unwrap();
+        assert!(same_origin(&uri("https://gitlab.example.com/api/v4"),
+                            &uri("https://GITLAB.example.com:443/api/v4/projects?page=2")));
+        assert!(!same_origin(&uri("https://gitlab.example.com/"), &uri("http://gitlab.example.com/")));
+        assert!(!same_origin(&uri("https://gitlab.example.com/"), &uri("https://gitlab.example.com:8443/")));
+        assert!(!same_origin(&uri("https://gitlab.example.com/"), &uri("https://cdn.example.com/")));
+        assert!(!same_origin(&uri("https://gitlab.example.com/"), &uri("/relative")));
+    }
+
+    #[test]
+    fn test_set_query_parameter() {
+        let set = |uri: &str, value| set_query_parameter(uri.parse().unwrap(), "per_page", value).unwrap().to_string();
+        assert_eq!(set("/projects", "100"), "/projects?per_page=100");
+        assert_eq!(set("/projects?per_page=20&cursor=a%3Db&per_page=5", "100"), "/projects?cursor=a%3Db&per_page=100");
+        assert_eq!(set("https://gitlab.example.com/api/v4/users?page=2", "50"),
+                   "https://gitlab.example.com/api/v4/users?page=2&per_page=50");
+    }
+
+    #[test]
+    fn test_ipv6_and_port_uris() {
+        let uri = |s: &str| s.parse::<Uri>().unwrap();
+        let base = uri("https://[2001:db8::1]:8200");
+        assert_eq!(join_uri(&base, "/v1/secret/app").unwrap(), uri("https://[2001:db8::1]:8200/v1/secret/app"));
+        assert_eq!(join_uri(&uri("https://[2001:db8::1]:8200/vault/"), "v1/sys/health?standbyok=true").unwrap(),
+                   uri("https://[2001:db8::1]:8200/vault/v1/sys/health?standbyok=true"));
+        assert_eq!(join_uri(&uri("http://gitlab:8080/api/v4?sudo=1"), "/projects?page=2").unwrap().to_string(),
+                   "http://gitlab:8080/api/v4/projects?page=2&sudo=1");
+        assert!(join_uri(&uri("/relative"), "x").is_err());
+        assert_eq!(uri_host(&base).as_deref(), Some("[2001:db8::1]"));
+        assert_eq!(host_authority(&uri("https://me@[::1]:8200/")), Some("[::1]:8200"));
+        assert_eq!(uri_origin(&uri("https://me@[::1]:8200/api/v4/")).unwrap(), "https://[::1]:8200");
+        assert_eq!(normalize_authority("2001:DB8::1"), "[2001:db8::1]");
+        assert_eq!(normalize_authority("Vault.example.com:8200"), "vault.example.com:8200");
+
+        let mut req = Request::new(Method::Get, uri("http://[::1]:8200/v1/"));
+        set_ipv6_host(&mut req);
+        assert_eq!(req.headers().get::<Host>().map(ToString::to_string).as_deref(), Some("[::1]:8200"));
+    }
+
+    #[test]
+    fn test_buffer_pool_reuse() {
+        let mut pool = BufferPool::new(1, 16);
+        let mut buffer = pool.take();
+        buffer.extend_from_slice(b"0123456789");
+        let capacity = buffer.capacity();
+        pool.give(buffer);
+        pool.give(Vec::with_capacity(8));
+        assert_eq!(pool.len(), 1);
+        let buffer = pool.take();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.capacity(), capacity);
+        pool.give(Vec::with_capacity(32));
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_chunk_reader() {
+        let chunks = || vec![Chunk::from(&b"[1, "[..]), Chunk::from(&b""[..]), Chunk::from(&b"2, 3]"[..])];
+        let mut body = String::new();
+        io::Read::read_to_string(&mut ChunkReader::new(chunks()), &mut body).unwrap();
+        assert_eq!(body, "[1, 2, 3]");
+        let value: Value = serde_json::from_reader(ChunkReader::new(chunks())).unwrap();
+        assert_eq!(value, Value::from(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_invalid_json_keeps_response() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/broken", Fixture::new(502).header("X-Upstream", "down")
+                   .header("Set-Cookie", "session=secret").body("<html>"))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let err = client.request_json(Method::Get, "/broken".parse().unwrap(), None::<Value>).unwrap_err();
+        match *err.kind() {
+            ErrorKind::InvalidResponse { status, ref headers, ref body } => {
+                assert_eq!(status, StatusCode::BadGateway);
+                assert!(headers.contains(&("X-Upstream".to_string(), "down".to_string())));
+                assert!(headers.contains(&("Set-Cookie".to_string(), "[REDACTED]".to_string())));
+                assert_eq!(body, b"<html>");
+            },
+            ref kind => panic!("Unexpected error kind {:?}", kind),
+        }
+        assert_eq!(err.to_string(), "Failed to parse JSON: <html>");
+    }
+
+    #[test]
+    #[cfg(feature = "charset")]
+    fn test_unknown_charset_keeps_response() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/items", Fixture::new(200)
+                   .header("Content-Type", "application/json; charset=x-unknown").body("[]"))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let err = client.request_json(Method::Get, "/items".parse().unwrap(), None::<Value>).unwrap_err();
+        match *err.kind() {
+            ErrorKind::InvalidResponse { status, ref body, .. } => {
+                assert_eq!(status, StatusCode::Ok);
+                assert_eq!(body, b"[]");
+            },
+            ref kind => panic!("Unexpected error kind {:?}", kind),
+        }
+        assert_eq!(err.to_string(), "Unsupported response charset x-unknown");
+    }
+
+    #[test]
+    fn test_host_config() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/fast", Fixture::new(200))
+            .route(Method::Get, "/slow", Fixture::new(200).delay(Duration::from_millis(500)))
+            .start().unwrap();
+        let mut http = SimpleHttpClient::new().unwrap();
+        http.set_host_config("127.0.0.1", HostConfig::new().raw_header("X-Team", "ops"));
+        let authority = server.base_uri().trim_start_matches("http://").to_string();
+        http.set_host_config(&authority, HostConfig::new()
+                             .raw_header("X-Host", "fixture")
+                             .raw_header("X-Override", "default")
+                             .timeout(Duration::from_millis(100)));
+        let mut client = client_for(&server).with_http(http);
+
+        let mut overrides = RequestOverrides::new();
+        overrides.set_raw_header("X-Override", "request");
+        let future = client.request_future_with_overrides(Method::Get, "/fast".parse().unwrap(), overrides)
+            .unwrap();
+        assert_eq!(client.response_future(future).unwrap().status(), StatusCode::Ok);
+        server.assert_sent().get("/fast")
+            .header("X-Host", "fixture")
+            .header("X-Override", "request")
+            .no_header("X-Team");
+
+        let err = client.request(Method::Get, "/slow".parse().unwrap(), None::<String>).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_failed_overrides_do_not_leak() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/items", Fixture::new(200))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let base_uri = client.base_uri.clone();
+        client.base_uri = "/no-host".parse().unwrap();
+
+        let mut overrides = RequestOverrides::new();
+        overrides.set_raw_header("X-Override", "request");
+        assert!(client.request_future_with_overrides(Method::Get, "/items".parse().unwrap(), overrides).is_err());
+
+        client.base_uri = base_uri;
+        client.request(Method::Get, "/items".parse().unwrap(), None::<String>).unwrap();
+        server.assert_sent().get("/items").no_header("X-Override");
+    }
+
+    #[test]
+    fn test_query_token() {
+        use audit::AuditTrail;
+        use redact::RedactionPolicy;
+
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/items", Fixture::json(200, "[]"))
+            .start().unwrap();
+        let http = SimpleHttpClientBuilder::new()
+            .query_token("key", Secret::from("a&b=c +/d"))
+            .redaction_policy(RedactionPolicy::empty())
+            .audit_trail(AuditTrail::new(10))
+            .build_for(&server.base_uri().parse().unwrap()).unwrap();
+        let mut client = client_for(&server).with_http(http);
+        client.request(Method::Get, "/items?page=2".parse().unwrap(), None::<String>).unwrap();
+
+        assert_eq!(server.requests()[0].path, "/items?page=2&key=a%26b%3Dc+%2B%2Fd");
+        let entries: Vec<_> = client.http_client().audit_trail().unwrap().entries().collect();
+        assert_eq!(entries[0].uri, format!("{}/items?page=2&key=%5BREDACTED%5D", server.base_uri()));
+        assert!(client.http_client().redaction_policy().unwrap().is_redacted_query_parameter("KEY"));
+    }
+
+    #[test]
+    fn test_preconnect() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/user", Fixture::json(200, "{}"))
+            .start().unwrap();
+        let mut client = TestClient {
+            base_uri: server.uri("/api/").parse().unwrap(),
+            client: SimpleHttpClient::new().unwrap(),
+        };
+        client.preconnect(false).unwrap();
+        server.assert_sent().count(1).request(Method::Head, "/");
+        client.preconnect(true).unwrap();
+        let response = client.request(Method::Get, server.uri("/user").parse().unwrap(), None::<String>).unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+
+        let mut closed = TestClient { base_uri: "http://127.0.0.1:1/".parse().unwrap(), client: SimpleHttpClient::new().unwrap() };
+        assert!(closed.preconnect(false).is_err());
+        assert!(closed.preconnect(true).is_ok());
+    }
+
+    #[test]
+    fn test_request_typed() {
+        use std::collections::BTreeMap;
+
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/groups", Fixture::json(200, r#"{"ops": ["ann", "bo"], "dev": []}"#))
+            .route(Method::Get, "/count", Fixture::json(200, r#""many""#))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let groups: BTreeMap<String, Vec<String>> = client.request_typed(Method::Get, "/groups".parse().unwrap(), None::<Value>)
+            .unwrap();
+        assert_eq!(groups["ops"], vec!["ann", "bo"]);
+        assert!(groups["dev"].is_empty());
+
+        let future = client.request_future(Method::Get, "/groups".parse().unwrap(), None::<String>).unwrap();
+        let names: BTreeMap<String, Vec<String>> = client.response_future_typed(future).unwrap();
+        assert_eq!(names.keys().collect::<Vec<_>>(), vec!["dev", "ops"]);
+
+        let err = client.request_typed::<u64, Value>(Method::Get, "/count".parse().unwrap(), None).unwrap_err();
+        assert!(err.to_string().starts_with("Failed to deserialize JSON: invalid type: string \"many\""));
+        match *err.kind() {
+            ErrorKind::InvalidResponse { ref body, .. } => assert_eq!(body, br#""many""#),
+            ref kind => panic!("Unexpected error kind {:?}", kind),
+        }
     }
 }
@@ -0,0 +1,242 @@
+use std::collections::VecDeque;
+use std::sync::{Arc,Mutex,MutexGuard};
+
+use futures::{Async,Poll};
+use futures::task::{self,Task};
+
+use *;
+
+struct LimitState {
+    max: Option<usize>,
+    in_use: usize,
+    waiters: VecDeque<Task>,
+}
+
+/// Semaphore capping how many requests are in flight at once, so fan-out helpers such as
+/// `VaultClient::read_secrets` or `SensuClient::delete_results_matching` do not exhaust file
+/// descriptors or trip server connection limits
+///
+/// Clones share the same permits, so a limit given to several clients with
+/// `SimpleHttpClient::set_shared_concurrency_limit` caps them together, across threads. A
+/// request waiting for a permit is not sent until one is released.
+#[derive(Clone)]
+pub struct ConcurrencyLimit {
+    state: Arc<Mutex<LimitState>>,
+}
+
+impl fmt::Debug for ConcurrencyLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("ConcurrencyLimit").field("max", &state.max).field("in_use", &state.in_use).finish()
+    }
+}
+
+impl Default for ConcurrencyLimit {
+    fn default() -> Self {
+        ConcurrencyLimit::unlimited()
+    }
+}
+
+impl ConcurrencyLimit {
+    /// Limit allowing `max` requests at once - at least one
+    pub fn new(max: usize) -> Self {
+        ConcurrencyLimit::with_max(Some(max))
+    }
+
+    /// Limit allowing any number of requests until a maximum is set
+    pub fn unlimited() -> Self {
+        ConcurrencyLimit::with_max(None)
+    }
+
+    fn with_max(max: Option<usize>) -> Self {
+        ConcurrencyLimit {
+            state: Arc::new(Mutex::new(LimitState {
+                max: max.map(|m| m.max(1)),
+                in_use: 0,
+                waiters: VecDeque::new(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LimitState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Allow `max` requests at once from now on - `None` lifts the limit
+    ///
+    /// Lowering the limit does not interrupt requests already in flight.
+    pub fn set_max(&self, max: Option<usize>) {
+        let mut state = self.lock();
+        state.max = max.map(|m| m.max(1));
+        wake_all(&mut state);
+    }
+
+    /// Number of requests allowed at once, or `None` if there is no limit
+    pub fn max(&self) -> Option<usize> {
+        self.lock().max
+    }
+
+    /// Number of permits currently held
+    pub fn in_use(&self) -> usize {
+        self.lock().in_use
+    }
+
+    /// Take a permit if one is free
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let mut state = self.lock();
+        if state.max.is_some_and(|max| state.in_use >= max) {
+            return None;
+        }
+        state.in_use += 1;
+        Some(Permit { state: self.state.clone() })
+    }
+
+    /// Take a permit, or arrange for the current task to be woken when one may be free
+    fn poll_acquire(&self) -> Async<Permit> {
+        match self.try_acquire() {
+            Some(permit) => Async::Ready(permit),
+            None => {
+                let mut state = self.lock();
+                state.waiters.push_back(task::current());
+                // A permit released between the two locks would not have seen this waiter
+                if state.max.is_some_and(|max| state.in_use < max) {
+                    wake_all(&mut state);
+                }
+                Async::NotReady
+            },
+        }
+    }
+
+    /// Hold `future` back until a permit is free and release it when the future completes
+    pub fn limit<F>(&self, future: F) -> Limited<F> where F: Future {
+        Limited::new(future, vec![self.clone()])
+    }
+}
+
+fn wake_all(state: &mut LimitState) {
+    for waiter in state.waiters.drain(..) {
+        waiter.notify();
+    }
+}
+
+/// Right to have one request in flight, given back when dropped
+pub struct Permit {
+    state: Arc<Mutex<LimitState>>,
+}
+
+impl fmt::Debug for Permit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Permit")
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.in_use -= 1;
+        wake_all(&mut state);
+    }
+}
+
+/// Future that waits for a permit from each of its limits, in order, before polling the
+/// inner future
+#[derive(Debug)]
+pub struct Limited<F> {
+    future: F,
+    limits: Vec<ConcurrencyLimit>,
+    permits: Vec<Permit>,
+}
+
+impl<F> Limited<F> where F: Future {
+    /// Hold `future` back until every one of `limits` has a free permit - none sends it at
+    /// once
+    pub fn new(future: F, limits: Vec<ConcurrencyLimit>) -> Self {
+        Limited { future, limits, permits: Vec::new() }
+    }
+}
+
+impl<F> Future for Limited<F> where F: Future {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        while self.permits.len() < self.limits.len() {
+            match self.limits[self.permits.len()].poll_acquire() {
+                Async::Ready(permit) => self.permits.push(permit),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+        let result = self.future.poll();
+        if !matches!(result, Ok(Async::NotReady)) {
+            self.permits.clear();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use futures::future::{self,join_all};
+
+    use super::*;
+    use testing::{Fixture,FixtureServer,client_for};
+
+    #[test]
+    fn test_concurrency_limit() {
+        let limit = ConcurrencyLimit::new(2);
+        let held = limit.try_acquire().unwrap();
+        let _other = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        drop(held);
+        assert_eq!(limit.in_use(), 1);
+        limit.set_max(None);
+        assert!(limit.try_acquire().is_some());
+
+        // Every future runs, but never more than two at once
+        let limit = ConcurrencyLimit::new(2);
+        let running = Rc::new(Cell::new(0));
+        let peak = Rc::new(Cell::new(0));
+        let futures = (0..6).map(|_| {
+            let (running, peak) = (running.clone(), peak.clone());
+            let mut polled = false;
+            limit.limit(future::poll_fn(move || -> Poll<(), ()> {
+                if polled {
+                    running.set(running.get() - 1);
+                    return Ok(Async::Ready(()));
+                }
+                polled = true;
+                running.set(running.get() + 1);
+                peak.set(peak.get().max(running.get()));
+                task::current().notify();
+                Ok(Async::NotReady)
+            }))
+        }).collect::<Vec<_>>();
+        assert_eq!(join_all(futures).wait().unwrap().len(), 6);
+        assert_eq!(peak.get(), 2);
+        assert_eq!(limit.in_use(), 0);
+    }
+
+    #[test]
+    fn test_batched_concurrency_limit() {
+        use batch::{BatchApiClient,BatchOptions};
+
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/bulk", Fixture::new(201).delay(Duration::from_millis(100)))
+            .start().unwrap();
+        let shared = ConcurrencyLimit::new(4);
+        let http = SimpleHttpClientBuilder::new().concurrency_limit(1).shared_concurrency_limit(shared.clone())
+            .build_for(&server.base_uri().parse().unwrap()).unwrap();
+        let mut client = client_for(&server).with_http(http);
+        let items: Vec<Value> = (0..3).map(Value::from).collect();
+        let start = Instant::now();
+        let report = client.post_batched_with("/bulk".parse().unwrap(), items, 1,
+                                              &BatchOptions::new().concurrency(3)).unwrap();
+        assert!(report.is_success());
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(shared.in_use(), 0);
+        assert_eq!(client.http_client().concurrency_limit().max(), Some(1));
+    }
+}
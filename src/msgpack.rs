@@ -0,0 +1,83 @@
+use hyper::Response;
+use hyper::header::{Accept,ContentType,qitem};
+use hyper::mime::Mime;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use rmp_serde;
+
+use *;
+
+fn msgpack_mime() -> Mime {
+    "application/msgpack".parse().expect("MessagePack MIME type is valid")
+}
+
+/// Encode a value as MessagePack with struct fields written as maps
+pub fn to_msgpack<T>(value: &T) -> Result<Vec<u8>> where T: ?Sized + Serialize {
+    rmp_serde::to_vec_named(value).map_err(|e| {
+        ClientError::new(format!("Failed to encode MessagePack: {}", e))
+    })
+}
+
+/// Decode a MessagePack payload
+pub fn from_msgpack<T>(bytes: &[u8]) -> Result<T> where T: DeserializeOwned {
+    rmp_serde::from_slice(bytes).map_err(|e| {
+        ClientError::new(format!("Failed to parse MessagePack: {}", e))
+    })
+}
+
+/// MessagePack flows parallel to the JSON flows in `JsonApiClient` - implemented for every
+/// `ApiClient`
+///
+/// Requests are sent with `Accept` and `Content-Type` set to `application/msgpack`. Responses
+/// can be decoded into any `Deserialize` type, including `serde_json::Value`.
+pub trait MsgpackApiClient<HTTP>: ApiClient<HTTP> where HTTP: HttpClient {
+    /// Make an API request with a MessagePack body and decode the MessagePack response
+    fn request_msgpack<B, T>(&mut self, method: Method, uri: Uri, body: Option<B>) -> Result<T>
+            where B: Serialize, T: DeserializeOwned {
+        let future = self.request_future_msgpack(method, uri, body)?;
+        self.response_future_msgpack(future)
+    }
+
+    /// Make an API request with a MessagePack body and return the future
+    fn request_future_msgpack<B>(&mut self, method: Method, uri: Uri, body: Option<B>)
+            -> Result<FutureResponse> where B: Serialize {
+        let mut overrides = RequestOverrides::new();
+        overrides.set_header(Accept(vec![qitem(msgpack_mime())]))
+                 .set_header(ContentType(msgpack_mime()));
+        if let Some(ref b) = body {
+            overrides.set_body(to_msgpack(b)?);
+        }
+        self.request_future_with_overrides(method, uri, overrides)
+    }
+
+    /// Resolve the future to a response and decode the MessagePack body
+    fn response_future_msgpack<T>(&mut self, fut: FutureResponse) -> Result<T>
+            where T: DeserializeOwned {
+        let response = self.response_future(fut)?;
+        self.response_to_msgpack(response)
+    }
+
+    /// Decode a response body as MessagePack
+    fn response_to_msgpack<T>(&mut self, response: Response) -> Result<T>
+            where T: DeserializeOwned {
+        let chunk = self.http_client_mut().evaluate_future(response.body().concat2())?;
+        from_msgpack(&chunk)
+    }
+}
+
+impl<HTTP, C> MsgpackApiClient<HTTP> for C where HTTP: HttpClient, C: ApiClient<HTTP> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_msgpack_roundtrip() {
+        let value: Value = serde_json::from_str(
+            r#"{"name": "teatime", "ids": [1, 2, 3], "nested": {"ok": true}}"#
+        ).unwrap();
+        let bytes = to_msgpack(&value).unwrap();
+        assert_eq!(from_msgpack::<Value>(&bytes).unwrap(), value);
+        assert!(from_msgpack::<Value>(&[0xc1]).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use std::sync::Arc;
+
+use hyper::header::ContentType;
+use url::form_urlencoded;
+
+use *;
+use clock::{Sleeper,SystemClock};
+
+/// Grant type sent when polling for a device flow token
+pub const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// Seconds added to the polling interval each time the server asks to slow down
+const SLOW_DOWN_STEP: u64 = 5;
+
+/// Endpoints and client settings for the OAuth 2.0 device authorization grant (RFC 8628),
+/// for signing in from headless machines where a browser redirect is impossible
+///
+/// The user is shown a short code and a URL to open on any other device; meanwhile the token
+/// endpoint is polled until they approve or deny the request or the code expires.
+#[derive(Clone)]
+pub struct DeviceFlow {
+    device_uri: Uri,
+    token_uri: Uri,
+    client_id: String,
+    scopes: Vec<String>,
+    sleeper: Arc<dyn Sleeper>,
+}
+
+impl fmt::Debug for DeviceFlow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DeviceFlow")
+            .field("device_uri", &self.device_uri)
+            .field("token_uri", &self.token_uri)
+            .field("client_id", &self.client_id)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+impl DeviceFlow {
+    /// Flow requesting codes from `device_uri` and tokens from `token_uri` as `client_id`
+    pub fn new<S>(device_uri: Uri, token_uri: Uri, client_id: S) -> Self where S: Into<String> {
+        DeviceFlow {
+            device_uri,
+            token_uri,
+            client_id: client_id.into(),
+            scopes: Vec::new(),
+            sleeper: Arc::new(SystemClock),
+        }
+    }
+
+    /// Request `scope` for the token
+    pub fn scope<S>(mut self, scope: S) -> Self where S: Into<String> {
+        self.scopes.push(scope.into());
+        self
+    }
+
+    /// Wait between polls with `sleeper` rather than `thread::sleep`
+    pub fn sleeper<S>(mut self, sleeper: S) -> Self where S: Sleeper + 'static {
+        self.sleeper = Arc::new(sleeper);
+        self
+    }
+
+    /// Endpoint the device code is requested from
+    pub fn device_uri(&self) -> &Uri {
+        &self.device_uri
+    }
+
+    /// Endpoint polled for the token
+    pub fn token_uri(&self) -> &Uri {
+        &self.token_uri
+    }
+}
+
+/// A pending device authorization, to be shown to the user while the token is polled for
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct DeviceAuthorization {
+    /// Code identifying this device to the token endpoint
+    pub device_code: Secret,
+    /// Code the user enters at `verification_uri`
+    pub user_code: String,
+    /// Page where the user enters `user_code`
+    pub verification_uri: String,
+    /// Page with the code already filled in, if the server provides one
+    pub verification_uri_complete: Option<String>,
+    /// How long the codes remain valid
+    pub expires_in: Duration,
+    /// Minimum time between polls of the token endpoint
+    pub interval: Duration,
+}
+
+/// Token issued by an OAuth 2.0 token endpoint
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct OAuthToken {
+    /// Access token for API requests
+    pub access_token: Secret,
+    /// Refresh token, if one was issued
+    pub refresh_token: Option<Secret>,
+    /// Lifetime of the access token, if the server gave one
+    pub expires_in: Option<Duration>,
+    /// Space separated scopes granted, if they differ from those requested
+    pub scope: Option<String>,
+}
+
+/// Print the code and URL from `authorization` in the terminal
+pub fn print_device_prompt(authorization: &DeviceAuthorization) {
+    match authorization.verification_uri_complete {
+        Some(ref uri) => println!("To sign in, open {} and confirm the code {}", uri, authorization.user_code),
+        None => println!("To sign in, open {} and enter the code {}", authorization.verification_uri,
+                         authorization.user_code),
+    }
+}
+
+fn form_headers() -> Headers {
+    let mut headers = Headers::new();
+    headers.set(ContentType::form_url_encoded());
+    headers
+}
+
+fn str_field(json: &Value, name: &str) -> Option<String> {
+    json.get(name).and_then(Value::as_str).map(str::to_string)
+}
+
+fn secs_field(json: &Value, name: &str) -> Option<Duration> {
+    json.get(name).and_then(Value::as_u64).map(Duration::from_secs)
+}
+
+/// OAuth 2.0 device authorization grant for any `JsonApiClient`
+pub trait DeviceFlowApiClient<HTTP>: JsonApiClient<HTTP> where HTTP: HttpClient {
+    /// Request a device and user code
+    fn start_device_flow(&mut self, flow: &DeviceFlow) -> Result<DeviceAuthorization> {
+        let mut form = form_urlencoded::Serializer::new(String::new());
+        form.append_pair("client_id", &flow.client_id);
+        if !flow.scopes.is_empty() {
+            form.append_pair("scope", &flow.scopes.join(" "));
+        }
+        let json = self.request_json_with_headers(Method::Post, flow.device_uri.clone(),
+                                                  Some(RawBody(form.finish())), &form_headers())?;
+        if let Some(error) = str_field(&json, "error") {
+            return Err(ClientError::new(format!("Device authorization failed: {}", error)));
+        }
+        let missing = |name: &str| ClientError::new(format!("Device authorization response has no {}", name));
+        Ok(DeviceAuthorization {
+            device_code: str_field(&json, "device_code").map(Secret::from).ok_or_else(|| missing("device_code"))?,
+            user_code: str_field(&json, "user_code").ok_or_else(|| missing("user_code"))?,
+            verification_uri: str_field(&json, "verification_uri")
+                .or_else(|| str_field(&json, "verification_url"))
+                .ok_or_else(|| missing("verification_uri"))?,
+            verification_uri_complete: str_field(&json, "verification_uri_complete"),
+            expires_in: secs_field(&json, "expires_in").ok_or_else(|| missing("expires_in"))?,
+            interval: secs_field(&json, "interval").unwrap_or_else(|| Duration::from_secs(5)),
+        })
+    }
+
+    /// Poll the token endpoint until the user approves `authorization`
+    ///
+    /// Polls are spaced by the server's interval, which grows by five seconds whenever the
+    /// server answers `slow_down`. Fails with `ErrorKind::AuthenticationFailed` if the user
+    /// denies the request and `ErrorKind::TimedOut` once the code expires.
+    fn poll_device_token(&mut self, flow: &DeviceFlow, authorization: &DeviceAuthorization) -> Result<OAuthToken> {
+        let mut interval = authorization.interval;
+        let mut waited = Duration::from_secs(0);
+        loop {
+            if waited >= authorization.expires_in {
+                return Err(ClientError::with_kind(ErrorKind::TimedOut, "Device code expired before sign in was approved"));
+            }
+            flow.sleeper.sleep(interval);
+            waited += interval;
+
+            let mut form = form_urlencoded::Serializer::new(String::new());
+            form.append_pair("grant_type", DEVICE_CODE_GRANT);
+            form.append_pair("device_code", authorization.device_code.expose());
+            form.append_pair("client_id", &flow.client_id);
+            let json = self.request_json_with_headers(Method::Post, flow.token_uri.clone(),
+                                                      Some(RawBody(form.finish())), &form_headers())?;
+            match str_field(&json, "error").as_deref() {
+                None => (),
+                Some("authorization_pending") => continue,
+                Some("slow_down") => {
+                    interval += Duration::from_secs(SLOW_DOWN_STEP);
+                    continue;
+                },
+                Some("access_denied") => {
+                    return Err(ClientError::with_kind(ErrorKind::AuthenticationFailed, "Sign in was denied"));
+                },
+                Some("expired_token") => {
+                    return Err(ClientError::with_kind(ErrorKind::TimedOut, "Device code expired before sign in was approved"));
+                },
+                Some(error) => {
+                    let description = str_field(&json, "error_description").unwrap_or_default();
+                    return Err(ClientError::new(format!("Device sign in failed: {} {}", error, description).trim_end()));
+                },
+            }
+            return Ok(OAuthToken {
+                access_token: str_field(&json, "access_token").map(Secret::from)
+                    .ok_or(ClientError::new("Token response has no access_token"))?,
+                refresh_token: str_field(&json, "refresh_token").map(Secret::from),
+                expires_in: secs_field(&json, "expires_in"),
+                scope: str_field(&json, "scope"),
+            });
+        }
+    }
+
+    /// Run the whole flow: request a code, pass it to `prompt` to show the user (see
+    /// `print_device_prompt`) and wait for the token
+    fn device_flow_login<F>(&mut self, flow: &DeviceFlow, prompt: F) -> Result<OAuthToken>
+            where F: FnOnce(&DeviceAuthorization) {
+        let authorization = self.start_device_flow(flow)?;
+        prompt(&authorization);
+        self.poll_device_token(flow, &authorization)
+    }
+}
+
+impl<HTTP, C> DeviceFlowApiClient<HTTP> for C where HTTP: HttpClient, C: JsonApiClient<HTTP> {}
+
+#[cfg(all(test, feature = "gitlab"))]
+mod test {
+    use super::*;
+    use clock::MockClock;
+    use gitlab::GitlabClient;
+    use testing::{Fixture,FixtureServer};
+
+    fn flow(server: &FixtureServer, clock: &MockClock) -> DeviceFlow {
+        DeviceFlow::new(server.uri("/device").parse().unwrap(), server.uri("/token").parse().unwrap(), "cli")
+            .scope("read").scope("write").sleeper(clock.clone())
+    }
+
+    #[test]
+    fn test_device_flow() {
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/device", Fixture::json(200, r#"{"device_code": "dev", "user_code": "ABCD-1234",
+                "verification_uri": "https://example.com/device", "expires_in": 60, "interval": 2}"#))
+            .route(Method::Post, "/token", Fixture::json(400, r#"{"error": "authorization_pending"}"#))
+            .route(Method::Post, "/token", Fixture::json(400, r#"{"error": "slow_down"}"#))
+            .route(Method::Post, "/token", Fixture::json(200, r#"{"access_token": "tok", "refresh_token": "ref",
+                "expires_in": 7200, "token_type": "Bearer"}"#))
+            .start().unwrap();
+        let clock = MockClock::new();
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let mut shown = None;
+        let token = client.device_flow_login(&flow(&server, &clock), |a| shown = Some(a.user_code.clone())).unwrap();
+        assert_eq!(shown.as_deref(), Some("ABCD-1234"));
+        assert_eq!(token.access_token.expose(), "tok");
+        assert_eq!(token.refresh_token.map(|t| t.expose().to_string()).as_deref(), Some("ref"));
+        assert_eq!(token.expires_in, Some(Duration::from_secs(7200)));
+        assert_eq!(clock.sleeps(), vec![Duration::from_secs(2), Duration::from_secs(2), Duration::from_secs(7)]);
+
+        let requests = server.requests();
+        assert_eq!(requests[0].header("Content-Type"), Some("application/x-www-form-urlencoded"));
+        assert_eq!(requests[0].body, b"client_id=cli&scope=read+write".to_vec());
+        assert_eq!(String::from_utf8(requests[1].body.clone()).unwrap(),
+                   "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Adevice_code&device_code=dev&client_id=cli");
+    }
+
+    #[test]
+    fn test_device_flow_failures() {
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/token", Fixture::json(400, r#"{"error": "authorization_pending"}"#))
+            .start().unwrap();
+        let clock = MockClock::new();
+        let mut client = GitlabClient::new(server.uri("/api/v4/")).unwrap();
+        let authorization = DeviceAuthorization {
+            device_code: "dev".into(),
+            user_code: "ABCD".to_string(),
+            verification_uri: "https://example.com/device".to_string(),
+            verification_uri_complete: None,
+            expires_in: Duration::from_secs(10),
+            interval: Duration::from_secs(5),
+        };
+        let err = client.poll_device_token(&flow(&server, &clock), &authorization).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::TimedOut);
+        server.assert_sent().count(2);
+
+        server.add_route(Method::Post, "/token", Fixture::json(400, r#"{"error": "access_denied"}"#));
+        let err = client.poll_device_token(&flow(&server, &clock), &authorization).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::AuthenticationFailed);
+    }
+}
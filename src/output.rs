@@ -0,0 +1,297 @@
+use *;
+
+/// Render `value` as indented JSON
+pub fn pretty_json(value: &Value) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string())
+}
+
+/// Text for a single table cell - strings are shown without quotes and missing or null values
+/// as an empty cell
+pub fn cell_text(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(other) => other.to_string(),
+    }
+}
+
+/// A table column taking its value from each row by JSON pointer
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Column {
+    header: String,
+    pointer: String,
+}
+
+impl Column {
+    /// Column titled `header` showing the value at `pointer` (for example `/owner/name`)
+    pub fn new<H, P>(header: H, pointer: P) -> Self where H: Into<String>, P: Into<String> {
+        Column { header: header.into(), pointer: pointer.into() }
+    }
+
+    /// Column showing the value at `pointer`, titled with its last segment
+    pub fn pointer<P>(pointer: P) -> Self where P: Into<String> {
+        let pointer = pointer.into();
+        let header = pointer.rsplit('/').next().unwrap_or("").replace("~1", "/").replace("~0", "~");
+        Column { header, pointer }
+    }
+
+    /// Column title
+    pub fn header(&self) -> &str {
+        &self.header
+    }
+
+    /// Value shown in this column for `row`
+    pub fn cell(&self, row: &Value) -> String {
+        cell_text(row.pointer(&self.pointer))
+    }
+}
+
+/// Renders JSON rows as a plain text table with aligned columns
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct Table {
+    columns: Vec<Column>,
+    headers: bool,
+}
+
+impl Table {
+    /// Table with the given columns and a header row
+    pub fn new(columns: Vec<Column>) -> Self {
+        Table { columns, headers: true }
+    }
+
+    /// Table with one column per JSON pointer, titled with the last pointer segment
+    pub fn pointers(pointers: &[&str]) -> Self {
+        Table::new(pointers.iter().map(|p| Column::pointer(*p)).collect())
+    }
+
+    /// Set whether the header row is printed
+    pub fn headers(mut self, headers: bool) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Render `rows`, which may be an array of rows or a single row, one line per row
+    ///
+    /// Columns are separated by two spaces and padded to the widest cell; trailing whitespace
+    /// is trimmed from each line.
+    pub fn render(&self, rows: &Value) -> String {
+        let rows = match *rows {
+            Value::Array(ref rows) => rows.iter().collect::<Vec<_>>(),
+            ref row => vec![row],
+        };
+        let mut lines: Vec<Vec<String>> = Vec::with_capacity(rows.len() + 1);
+        if self.headers {
+            lines.push(self.columns.iter().map(|c| c.header.clone()).collect());
+        }
+        lines.extend(rows.iter().map(|row| self.columns.iter().map(|c| c.cell(row)).collect::<Vec<_>>()));
+
+        let widths = (0..self.columns.len()).map(|i| {
+            lines.iter().map(|line| line[i].chars().count()).max().unwrap_or(0)
+        }).collect::<Vec<_>>();
+        let mut out = String::new();
+        for line in lines {
+            let mut text = String::new();
+            for (i, cell) in line.iter().enumerate() {
+                if i > 0 {
+                    text.push_str("  ");
+                }
+                text.push_str(&format!("{:width$}", cell, width = widths[i]));
+            }
+            out.push_str(text.trim_end());
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// One step of a parsed selector
+#[derive(Clone,Debug,PartialEq,Eq)]
+enum Step {
+    /// Object member by name
+    Field(String),
+    /// Array element by index, counting from the end if negative
+    Index(i64),
+    /// Every member of an object or element of an array
+    Wildcard,
+    /// The following step applied at any depth
+    Descend,
+}
+
+fn selector_error(selector: &str, reason: &str) -> ClientError {
+    ClientError::new(format!("Invalid selector {}: {}", selector, reason))
+}
+
+fn parse_selector(selector: &str) -> Result<Vec<Step>> {
+    let rest = selector.trim();
+    let mut rest = rest.strip_prefix('$').unwrap_or(rest);
+    let mut steps = Vec::new();
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            steps.push(Step::Descend);
+            rest = after;
+            if rest.starts_with('[') {
+                continue;
+            }
+        } else if let Some(after) = rest.strip_prefix('.') {
+            rest = after;
+        } else if !rest.starts_with('[') && !steps.is_empty() {
+            return Err(selector_error(selector, "expected '.' or '['"));
+        }
+
+        if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(|| selector_error(selector, "unclosed '['"))?;
+            let inner = after[..end].trim();
+            steps.push(if inner == "*" {
+                Step::Wildcard
+            } else if inner.len() >= 2 && (inner.starts_with('\'') && inner.ends_with('\'')
+                                           || inner.starts_with('"') && inner.ends_with('"')) {
+                Step::Field(inner[1..inner.len() - 1].to_string())
+            } else {
+                Step::Index(inner.parse().map_err(|_| selector_error(selector, "bad array index"))?)
+            });
+            rest = &after[end + 1..];
+        } else {
+            let end = rest.find(['.', '[']).unwrap_or(rest.len());
+            let name = &rest[..end];
+            if name.is_empty() {
+                return Err(selector_error(selector, "empty field name"));
+            }
+            steps.push(if name == "*" { Step::Wildcard } else { Step::Field(name.to_string()) });
+            rest = &rest[end..];
+        }
+    }
+    if steps.last() == Some(&Step::Descend) {
+        return Err(selector_error(selector, "'..' must be followed by a field"));
+    }
+    Ok(steps)
+}
+
+fn apply_step<'a>(step: &Step, value: &'a Value, out: &mut Vec<&'a Value>) {
+    match (step, value) {
+        (Step::Field(name), Value::Object(map)) => out.extend(map.get(name)),
+        (Step::Index(i), Value::Array(items)) => {
+            let i = if *i < 0 { items.len() as i64 + i } else { *i };
+            if i >= 0 {
+                out.extend(items.get(i as usize));
+            }
+        },
+        (Step::Wildcard, Value::Array(items)) => out.extend(items.iter()),
+        (Step::Wildcard, Value::Object(map)) => out.extend(map.values()),
+        _ => (),
+    }
+}
+
+fn descendants<'a>(value: &'a Value, out: &mut Vec<&'a Value>) {
+    out.push(value);
+    match *value {
+        Value::Array(ref items) => items.iter().for_each(|v| descendants(v, out)),
+        Value::Object(ref map) => map.values().for_each(|v| descendants(v, out)),
+        _ => (),
+    }
+}
+
+/// Select the parts of `value` matched by a JSONPath-like `selector`
+///
+/// Supported syntax is a subset of JSONPath: an optional leading `$`, `.name` or `['name']`
+/// for object members, `[0]` or `[-1]` for array elements, `*` or `[*]` for every member or
+/// element and `..name` for a member at any depth. For example
+/// `$.projects[*].namespace.name` or `..id`.
+pub fn select<'a>(value: &'a Value, selector: &str) -> Result<Vec<&'a Value>> {
+    let steps = parse_selector(selector)?;
+    let mut current = vec![value];
+    let mut descend = false;
+    for step in &steps {
+        if *step == Step::Descend {
+            descend = true;
+            continue;
+        }
+        if descend {
+            let mut all = Vec::new();
+            current.iter().for_each(|v| descendants(v, &mut all));
+            current = all;
+            descend = false;
+        }
+        let mut next = Vec::new();
+        current.iter().for_each(|v| apply_step(step, v, &mut next));
+        current = next;
+    }
+    Ok(current)
+}
+
+/// How to render a result
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum OutputFormat {
+    /// Indented JSON
+    Json,
+    /// One line per value - strings unquoted, everything else as compact JSON
+    Lines,
+    /// Aligned table
+    Table(Table),
+}
+
+/// Render `value` in `format`, first narrowing it with `selector` if one is given
+///
+/// Selected values are rendered as an array for `Json` and `Table`, or one per line for
+/// `Lines`. Without a selector, `Lines` prints each element of a top level array.
+pub fn render(value: &Value, format: &OutputFormat, selector: Option<&str>) -> Result<String> {
+    let selected = match selector {
+        Some(selector) => Value::Array(select(value, selector)?.into_iter().cloned().collect()),
+        None => value.clone(),
+    };
+    Ok(match *format {
+        OutputFormat::Json => pretty_json(&selected) + "\n",
+        OutputFormat::Table(ref table) => table.render(&selected),
+        OutputFormat::Lines => {
+            let items = match selected {
+                Value::Array(items) => items,
+                other => vec![other],
+            };
+            items.iter().map(|v| cell_text(Some(v)) + "\n").collect()
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn projects() -> Value {
+        serde_json::from_str(r#"[
+            {"id": 1, "path": "ops/teatime", "namespace": {"name": "ops"}, "archived": false},
+            {"id": 22, "path": "web/site", "namespace": {"name": "web"}, "archived": true, "topics": ["a", "b"]}
+        ]"#).unwrap()
+    }
+
+    #[test]
+    fn test_table() {
+        let table = Table::new(vec![Column::pointer("/id"), Column::pointer("/path"),
+                                    Column::new("NAMESPACE", "/namespace/name"), Column::pointer("/topics/0")]);
+        assert_eq!(table.render(&projects()),
+                   "id  path         NAMESPACE  0\n\
+                    1   ops/teatime  ops\n\
+                    22  web/site     web        a\n");
+        assert_eq!(Table::pointers(&["/id", "/archived"]).headers(false).render(&projects()[1]), "22  true\n");
+    }
+
+    #[test]
+    fn test_select() {
+        let mut map = serde_json::Map::new();
+        map.insert("projects".to_string(), projects());
+        let value = Value::Object(map);
+        let ids = |s: &str| select(&value, s).unwrap().into_iter().cloned().collect::<Vec<_>>();
+        assert_eq!(ids("$.projects[*].id"), vec![Value::from(1), Value::from(22)]);
+        assert_eq!(ids("projects[-1]['path']"), vec![Value::from("web/site")]);
+        assert_eq!(ids("..name"), vec![Value::from("ops"), Value::from("web")]);
+        assert_eq!(ids("$..topics[1]"), vec![Value::from("b")]);
+        assert_eq!(ids("$.projects.*.namespace.name").len(), 2);
+        assert!(ids("$.missing[0]").is_empty());
+        assert_eq!(ids("$").len(), 1);
+        assert!(select(&value, "$.projects[").is_err());
+        assert!(select(&value, "$.projects[x]").is_err());
+        assert!(select(&value, "$..").is_err());
+
+        assert_eq!(render(&value, &OutputFormat::Lines, Some("$.projects[*].path")).unwrap(),
+                   "ops/teatime\nweb/site\n");
+        assert_eq!(render(&value, &OutputFormat::Json, Some("..id")).unwrap(), "[\n  1,\n  22\n]\n");
+    }
+}
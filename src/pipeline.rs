@@ -0,0 +1,510 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use hyper::header::Headers;
+
+use *;
+use state::{StateStore,CHECKPOINTS};
+
+/// Position of one page in a paginated response, as far as the API reports it
+///
+/// Filled in by `JsonApiClient::page_info` from the response headers and then from fields of
+/// the same names in the body, or its `meta` object, for APIs that paginate in the body.
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct PageInfo {
+    /// Number of this page, starting from 1
+    pub page: Option<u64>,
+    /// Number of elements per page
+    pub per_page: Option<u64>,
+    /// Number of elements across all pages
+    pub total: Option<u64>,
+    /// Number of pages
+    pub total_pages: Option<u64>,
+    /// URI of the next page
+    pub next: Option<Uri>,
+    /// URI of the previous page
+    pub prev: Option<Uri>,
+}
+
+impl PageInfo {
+    /// Page numbers and totals from the `X-Page`, `X-Per-Page`, `X-Total` and `X-Total-Pages`
+    /// headers used by GitLab and many other APIs
+    pub fn from_headers(headers: &Headers) -> Self {
+        let number = |name: &str| {
+            headers.get_raw(name).and_then(|raw| raw.one())
+                .and_then(|value| str::from_utf8(value).ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+        };
+        PageInfo {
+            page: number("X-Page"),
+            per_page: number("X-Per-Page"),
+            total: number("X-Total"),
+            total_pages: number("X-Total-Pages"),
+            next: None,
+            prev: None,
+        }
+    }
+
+    /// Fill in anything the headers did not report from the `page`, `per_page`, `total`,
+    /// `total_pages`, `next` and `prev` fields of `body` or of its `meta` object
+    pub fn update_from_body(&mut self, body: &Value) {
+        let field = |name: &str| body.get(name).or_else(|| body.get("meta").and_then(|m| m.get(name)));
+        let number = |name: &str| field(name).and_then(|v| {
+            v.as_u64().or_else(|| v.as_str().and_then(|s| s.parse::<u64>().ok()))
+        });
+        let uri = |name: &str| field(name).and_then(Value::as_str).and_then(|s| s.parse::<Uri>().ok());
+        self.page = self.page.or_else(|| number("page"));
+        self.per_page = self.per_page.or_else(|| number("per_page"));
+        self.total = self.total.or_else(|| number("total"));
+        self.total_pages = self.total_pages.or_else(|| number("total_pages"));
+        self.next = self.next.take().or_else(|| uri("next"));
+        self.prev = self.prev.take().or_else(|| uri("prev"));
+    }
+
+    /// Number of pages, reported or worked out from the totals
+    pub fn page_count(&self) -> Option<u64> {
+        self.total_pages.or_else(|| match (self.total, self.per_page) {
+            (Some(total), Some(per_page)) if per_page > 0 => Some(total.div_ceil(per_page)),
+            _ => None,
+        })
+    }
+
+    /// True if this is known to be the last page
+    pub fn is_last(&self) -> bool {
+        self.next.is_none() && match (self.page, self.page_count()) {
+            (Some(page), Some(count)) => page >= count,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for PageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.page, self.page_count()) {
+            (Some(page), Some(count)) => write!(f, "page {} of {}", page, count),
+            (Some(page), None) => write!(f, "page {}", page),
+            _ => write!(f, "page ?"),
+        }
+    }
+}
+
+/// One page of a paginated JSON response
+#[derive(Clone,Debug,PartialEq)]
+pub struct Page {
+    /// Elements of the page; a page that is not an array is a single element
+    pub items: Vec<Value>,
+    /// Where the page sits in the response
+    pub info: PageInfo,
+}
+
+/// Sign that the data changed while it was being paginated, reported by a `Deduplicator`
+#[derive(Clone,Debug,PartialEq)]
+pub enum PaginationWarning {
+    /// An element with an already seen key was dropped
+    Duplicate {
+        /// Key of the element
+        key: Value,
+        /// Number of the page it was on, starting from 1
+        page: usize,
+    },
+    /// Keys stopped rising or falling, so elements may have been skipped
+    OutOfOrder {
+        /// Key of the element
+        key: Value,
+        /// Key of the element before it
+        previous: Value,
+        /// Number of the page it was on, starting from 1
+        page: usize,
+    },
+}
+
+impl fmt::Display for PaginationWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PaginationWarning::Duplicate { ref key, page } => {
+                write!(f, "Dropped duplicate of {} on page {}", key, page)
+            },
+            PaginationWarning::OutOfOrder { ref key, ref previous, page } => {
+                write!(f, "Key {} after {} on page {} is out of order, elements may be missing", key, previous, page)
+            },
+        }
+    }
+}
+
+type WarningFn = Box<dyn FnMut(&PaginationWarning)>;
+
+/// Compare pagination keys, numerically when both are numbers
+fn compare_keys(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// Drops elements repeated across pages and warns about keys out of order, which happen when
+/// the data changes while it is being paginated
+///
+/// Elements are keyed by the value at a JSON pointer such as `/id`; elements without one are
+/// passed through unchecked. The order of the keys is taken from the first two that differ, so
+/// both ascending and descending listings are checked.
+pub struct Deduplicator {
+    pointer: String,
+    seen: HashSet<String>,
+    previous: Option<Value>,
+    direction: Option<Ordering>,
+    page: usize,
+    warn: Option<WarningFn>,
+}
+
+impl fmt::Debug for Deduplicator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Deduplicator").field("pointer", &self.pointer).field("seen", &self.seen.len())
+            .field("page", &self.page).finish()
+    }
+}
+
+impl Deduplicator {
+    /// Key elements by the value at `pointer`
+    pub fn by_pointer<S>(pointer: S) -> Self where S: Into<String> {
+        Deduplicator {
+            pointer: pointer.into(),
+            seen: HashSet::new(),
+            previous: None,
+            direction: None,
+            page: 0,
+            warn: None,
+        }
+    }
+
+    /// Call `warn` for each duplicate dropped or key out of order
+    pub fn on_warning<F>(mut self, warn: F) -> Self where F: FnMut(&PaginationWarning) + 'static {
+        self.warn = Some(Box::new(warn));
+        self
+    }
+
+    /// Number of distinct keys seen so far
+    pub fn seen(&self) -> usize {
+        self.seen.len()
+    }
+
+    fn warn(&mut self, warning: PaginationWarning) {
+        if let Some(ref mut warn) = self.warn {
+            warn(&warning);
+        }
+    }
+
+    /// The elements of the next page that have not been seen before
+    pub fn filter_page(&mut self, items: Vec<Value>) -> Vec<Value> {
+        self.page += 1;
+        let mut kept = Vec::with_capacity(items.len());
+        for item in items {
+            let key = match item.pointer(&self.pointer) {
+                Some(key) => key.clone(),
+                None => {
+                    kept.push(item);
+                    continue;
+                },
+            };
+            if !self.seen.insert(key.to_string()) {
+                let page = self.page;
+                self.warn(PaginationWarning::Duplicate { key, page });
+                continue;
+            }
+            if let Some(previous) = self.previous.replace(key.clone()) {
+                match (compare_keys(&previous, &key), self.direction) {
+                    (Some(Ordering::Equal), _) | (None, _) => (),
+                    (Some(order), None) => self.direction = Some(order),
+                    (Some(order), Some(direction)) if order != direction => {
+                        let page = self.page;
+                        self.warn(PaginationWarning::OutOfOrder { key, previous, page });
+                    },
+                    _ => (),
+                }
+            }
+            kept.push(item);
+        }
+        kept
+    }
+}
+
+/// Iterator over the pages of a paginated JSON response, one request per page
+///
+/// Each item holds the elements of one page; a page that is not an array is returned as a
+/// single element. Use `next_page` to get the `PageInfo` of each page as well. Iteration stops
+/// after the first error.
+pub struct Pages<'a, C, HTTP> where C: JsonApiClient<HTTP> + 'a, HTTP: HttpClient {
+    client: &'a mut C,
+    method: Method,
+    next: Option<Uri>,
+    body: Option<String>,
+    dedup: Option<Deduplicator>,
+    http: PhantomData<HTTP>,
+}
+
+impl<'a, C, HTTP> Pages<'a, C, HTTP> where C: JsonApiClient<HTTP> + 'a, HTTP: HttpClient {
+    fn fetch(&mut self, uri: Uri) -> Result<Page> {
+        let response = <C as ApiClient<HTTP>>::request(self.client, self.method.clone(), uri,
+                                                       self.body.clone())?;
+        let mut info = self.client.page_info(&response)?;
+        if let Some(page) = self.client.next_page_uri(&response)? {
+            let page = self.client.full_uri(page)?;
+            self.client.check_next_page(&page)?;
+            let page = self.client.apply_per_page(page)?;
+            info.next = Some(page.clone());
+            self.next = Some(page);
+        }
+        let json = self.client.response_to_json(response)?;
+        info.update_from_body(&json);
+        let items = match json {
+            Value::Array(v) => v,
+            any => vec![any],
+        };
+        let items = match self.dedup {
+            Some(ref mut dedup) => dedup.filter_page(items),
+            None => items,
+        };
+        Ok(Page { items, info })
+    }
+
+    /// Pass every page through `dedup`, dropping elements already returned
+    pub fn deduplicate(mut self, dedup: Deduplicator) -> Self {
+        self.dedup = Some(dedup);
+        self
+    }
+
+    /// Request the next page, returning its elements along with its `PageInfo`
+    pub fn next_page(&mut self) -> Option<Result<Page>> {
+        let uri = self.next.take()?;
+        Some(self.fetch(uri))
+    }
+}
+
+impl<'a, C, HTTP> Iterator for Pages<'a, C, HTTP> where C: JsonApiClient<HTTP> + 'a, HTTP: HttpClient {
+    type Item = Result<Vec<Value>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_page().map(|page| page.map(|p| p.items))
+    }
+}
+
+/// Where a resumable pipeline records the next page to read - see
+/// `PipelineApiClient::pipe_pages_resumable`
+#[derive(Clone)]
+pub struct Checkpoint {
+    store: Arc<dyn StateStore>,
+    key: String,
+}
+
+impl fmt::Debug for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Checkpoint").field("key", &self.key).finish()
+    }
+}
+
+impl Checkpoint {
+    /// Keep the checkpoint named `key` in `store`
+    pub fn new<S>(store: Arc<dyn StateStore>, key: S) -> Self where S: Into<String> {
+        Checkpoint { store, key: key.into() }
+    }
+
+    /// The saved next page URI, if a run was interrupted
+    pub fn load(&self) -> Result<Option<Uri>> {
+        match self.store.get(CHECKPOINTS, &self.key)? {
+            Some(saved) => {
+                let uri = String::from_utf8(saved).map_err(|_| {
+                    ClientError::new(format!("Checkpoint {} is not a valid URI", self.key))
+                })?;
+                Ok(Some(uri.parse::<Uri>()?))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Save `next` as the page to resume from
+    pub fn save(&self, next: &Uri) -> Result<()> {
+        self.store.put(CHECKPOINTS, &self.key, next.to_string().as_bytes())
+    }
+
+    /// Forget the checkpoint so the next run starts from the first page
+    pub fn clear(&self) -> Result<()> {
+        self.store.delete(CHECKPOINTS, &self.key)
+    }
+}
+
+/// Feed the elements produced by `source` to `sink` in batches of `batch_size`, returning the
+/// number of elements written
+///
+/// A page is only requested from the source once the previous one has been written, so no more
+/// than `batch_size` elements plus one page are held in memory however large the dataset. The
+/// first error from either side stops the pipe; elements already passed to the sink stay
+/// written.
+///
+/// ```
+/// # extern crate serde_json;
+/// # extern crate teatime;
+/// # use serde_json::Value;
+/// # use teatime::pipeline::pipe;
+/// # fn main() {
+/// let pages = vec![Ok(vec![Value::from(1), Value::from(2), Value::from(3)]), Ok(vec![Value::from(4)])];
+/// let mut batches = Vec::new();
+/// let written = pipe(pages, 2, |batch| { batches.push(batch.len()); Ok(()) }).unwrap();
+/// assert_eq!(written, 4);
+/// assert_eq!(batches, vec![2, 2]);
+/// # }
+/// ```
+pub fn pipe<I, F>(source: I, batch_size: usize, mut sink: F) -> Result<u64>
+        where I: IntoIterator<Item = Result<Vec<Value>>>, F: FnMut(Vec<Value>) -> Result<()> {
+    if batch_size == 0 {
+        return Err(ClientError::new("Batch size must be greater than zero"));
+    }
+    let mut written = 0;
+    let mut batch = Vec::with_capacity(batch_size);
+    for page in source {
+        for element in page? {
+            batch.push(element);
+            if batch.len() == batch_size {
+                sink(mem::replace(&mut batch, Vec::with_capacity(batch_size)))?;
+                written += batch_size as u64;
+            }
+        }
+    }
+    if !batch.is_empty() {
+        written += batch.len() as u64;
+        sink(batch)?;
+    }
+    Ok(written)
+}
+
+/// Page-by-page reading and bounded pipelines - implemented for every `JsonApiClient`
+pub trait PipelineApiClient<HTTP>: JsonApiClient<HTTP> + Sized where HTTP: HttpClient {
+    /// Iterate over the pages of a paginated request, requesting each page only when the
+    /// previous one has been consumed
+    ///
+    /// Every page is requested with the client's `per_page` size, if it has one.
+    fn pages<'a, B>(&'a mut self, method: Method, uri: Uri, body: Option<B>)
+            -> Result<Pages<'a, Self, HTTP>> where B: JsonBody {
+        let body = encode_json_body(body)?;
+        let uri = self.apply_per_page(uri)?;
+        Ok(Pages { client: self, method, next: Some(uri), body, dedup: None, http: PhantomData })
+    }
+
+    /// Like `JsonApiClient::autopagination`, but with the elements passed through `dedup` so
+    /// that those repeated across pages appear once
+    fn autopagination_deduplicated<B>(&mut self, method: Method, uri: Uri, body: Option<B>,
+                                      dedup: Deduplicator) -> Result<Value> where B: JsonBody {
+        let mut elements = Vec::new();
+        for page in self.pages(method, uri, body)?.deduplicate(dedup) {
+            elements.extend(page?);
+        }
+        Ok(Value::from(elements))
+    }
+
+    /// Read every page of a paginated request and write the elements to `sink` in batches of
+    /// `batch_size` - see `pipe`
+    fn pipe_pages<B, F>(&mut self, method: Method, uri: Uri, body: Option<B>, batch_size: usize,
+                        sink: F) -> Result<u64>
+            where B: JsonBody, F: FnMut(Vec<Value>) -> Result<()> {
+        let pages = self.pages(method, uri, body)?;
+        pipe(pages, batch_size, sink)
+    }
+
+    /// Like `pipe_pages`, but saving the next page URI to `checkpoint` after each page is
+    /// written, so a run that fails part way resumes from the failed page
+    ///
+    /// Batches do not span pages, so a page is fully written before its checkpoint is saved.
+    /// The checkpoint is removed once the last page is written. Pages before the failure are
+    /// not written again, but a page that failed in its sink may have been partly written.
+    fn pipe_pages_resumable<B, F>(&mut self, method: Method, uri: Uri, body: Option<B>, batch_size: usize,
+                                  checkpoint: &Checkpoint, mut sink: F) -> Result<u64>
+            where B: JsonBody, F: FnMut(Vec<Value>) -> Result<()> {
+        if batch_size == 0 {
+            return Err(ClientError::new("Batch size must be greater than zero"));
+        }
+        let start = checkpoint.load()?.unwrap_or(uri);
+        let mut pages = self.pages(method, start, body)?;
+        let mut written = 0;
+        while let Some(page) = pages.next() {
+            written += pipe(Some(page), batch_size, &mut sink)?;
+            match pages.next {
+                Some(ref next) => checkpoint.save(next)?,
+                None => checkpoint.clear()?,
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl<HTTP, C> PipelineApiClient<HTTP> for C where HTTP: HttpClient, C: JsonApiClient<HTTP> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pipe_batches() {
+        let pages = (0..5).map(|p| Ok((0..3).map(|i| Value::from(p * 3 + i)).collect()));
+        let mut batches = Vec::new();
+        assert_eq!(pipe(pages, 4, |batch| { batches.push(batch); Ok(()) }).unwrap(), 15);
+        assert_eq!(batches.iter().map(Vec::len).collect::<Vec<_>>(), vec![4, 4, 4, 3]);
+        assert_eq!(batches[3][2], 14);
+
+        let mut pulled = 0;
+        let pages = (0..5).map(|p| { pulled += 1; Ok(vec![Value::from(p)]) });
+        let err = pipe(pages, 2, |batch| {
+            if batch[0] == 2 { Err(ClientError::new("sink full")) } else { Ok(()) }
+        }).unwrap_err();
+        assert_eq!(err.to_string(), "sink full");
+        assert_eq!(pulled, 4);
+
+        let pages = vec![Ok(vec![Value::from(1)]), Err(ClientError::new("page failed"))];
+        assert!(pipe(pages, 1, |_| Ok(())).is_err());
+        assert!(pipe(Vec::new(), 0, |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_deduplicator() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let page = |json: &str| match serde_json::from_str(json).unwrap() {
+            Value::Array(items) => items,
+            _ => unreachable!(),
+        };
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let recorded = warnings.clone();
+        let mut dedup = Deduplicator::by_pointer("/id").on_warning(move |w| recorded.borrow_mut().push(w.clone()));
+        assert_eq!(dedup.filter_page(page(r#"[{"id": 1}, {"id": 2}, {"id": 3}]"#)).len(), 3);
+        assert_eq!(dedup.filter_page(page(r#"[{"id": 3}, {"id": 4}]"#)), page(r#"[{"id": 4}]"#));
+        assert_eq!(dedup.filter_page(page(r#"[{"name": "no id"}, {"id": 2.5}]"#)).len(), 2);
+        assert_eq!(dedup.seen(), 5);
+        assert_eq!(*warnings.borrow(), vec![
+            PaginationWarning::Duplicate { key: Value::from(3), page: 2 },
+            PaginationWarning::OutOfOrder { key: Value::from(2.5), previous: Value::from(4), page: 3 },
+        ]);
+        assert_eq!(warnings.borrow()[0].to_string(), "Dropped duplicate of 3 on page 2");
+
+        let mut descending = Deduplicator::by_pointer("/name");
+        assert_eq!(descending.filter_page(page(r#"[{"name": "c"}, {"name": "b"}, {"name": "a"}]"#)).len(), 3);
+        assert_eq!(descending.direction, Some(Ordering::Greater));
+    }
+
+    #[test]
+    fn test_page_info_from_body() {
+        let mut headers = Headers::new();
+        headers.set_raw("X-Total", "101");
+        let mut info = PageInfo::from_headers(&headers);
+        info.update_from_body(&serde_json::from_str(
+            r#"{"meta": {"page": "3", "per_page": 20, "total": 7, "next": "/items?page=4"}, "items": []}"#
+        ).unwrap());
+        assert_eq!((info.page, info.per_page, info.total), (Some(3), Some(20), Some(101)));
+        assert_eq!(info.page_count(), Some(6));
+        assert_eq!(info.to_string(), "page 3 of 6");
+        assert_eq!(info.next.as_ref().map(Uri::path), Some("/items"));
+        assert!(!info.is_last());
+        assert_eq!(PageInfo::default().to_string(), "page ?");
+        assert!(PageInfo::default().is_last());
+    }
+}
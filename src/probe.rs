@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map::Iter;
+
+use *;
+
+/// What probing one endpoint found
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Capability {
+    /// The endpoint answered with a success or redirect status
+    Available(StatusCode),
+    /// The endpoint exists but the credentials may not use it - `401` or `403`
+    Unauthorized(StatusCode),
+    /// The server does not have the endpoint - `404`, `405`, `410` or `501`
+    Missing(StatusCode),
+    /// The endpoint answered with another status, such as a server error
+    Failed(StatusCode),
+    /// No response was received
+    Unreachable(String),
+}
+
+impl Capability {
+    fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::Unauthorized | StatusCode::Forbidden => Capability::Unauthorized(status),
+            StatusCode::NotFound | StatusCode::MethodNotAllowed | StatusCode::Gone
+                | StatusCode::NotImplemented => Capability::Missing(status),
+            s if s.is_success() || s.is_redirection() => Capability::Available(status),
+            _ => Capability::Failed(status),
+        }
+    }
+
+    /// True if the server has the endpoint, whether or not the credentials may use it
+    pub fn exists(&self) -> bool {
+        matches!(*self, Capability::Available(_) | Capability::Unauthorized(_))
+    }
+
+    /// Status of the response, if one was received
+    pub fn status(&self) -> Option<StatusCode> {
+        match *self {
+            Capability::Available(s) | Capability::Unauthorized(s) | Capability::Missing(s)
+                | Capability::Failed(s) => Some(s),
+            Capability::Unreachable(_) => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Capability::Available(_) => "available",
+            Capability::Unauthorized(_) => "unauthorized",
+            Capability::Missing(_) => "missing",
+            Capability::Failed(_) => "failed",
+            Capability::Unreachable(_) => "unreachable",
+        }
+    }
+}
+
+/// What probing found for each endpoint, by path
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct Capabilities {
+    endpoints: BTreeMap<String, Capability>,
+}
+
+impl Capabilities {
+    /// What probing `path` found, if it was probed
+    pub fn get(&self, path: &str) -> Option<&Capability> {
+        self.endpoints.get(path)
+    }
+
+    /// True if `path` was probed and the server has it
+    pub fn supports(&self, path: &str) -> bool {
+        self.get(path).is_some_and(Capability::exists)
+    }
+
+    /// The first of `paths` the server has, for picking between API flavours or versions
+    pub fn first_supported<'a>(&self, paths: &[&'a str]) -> Option<&'a str> {
+        paths.iter().cloned().find(|p| self.supports(p))
+    }
+
+    /// Results by path
+    pub fn iter(&self) -> Iter<'_, String, Capability> {
+        self.endpoints.iter()
+    }
+
+    /// JSON object of the result and status for each path
+    pub fn to_json(&self) -> Value {
+        Value::Object(self.endpoints.iter().map(|(path, capability)| {
+            let mut result = serde_json::Map::new();
+            result.insert("result".to_string(), Value::from(capability.name()));
+            result.insert("status".to_string(), capability.status().map(|s| Value::from(s.as_u16())).unwrap_or(Value::Null));
+            (path.clone(), Value::Object(result))
+        }).collect())
+    }
+}
+
+/// Detection of the endpoints a server supports - implemented for every `ApiClient`
+///
+/// Tools that must work against several versions or flavours of an API, such as Sensu
+/// classic and Sensu Go, can probe a few well-known paths once and branch on the result.
+pub trait ProbeApiClient<HTTP>: ApiClient<HTTP> where HTTP: HttpClient {
+    /// `GET` each of `paths` and record whether it exists
+    fn probe(&mut self, paths: &[&str]) -> Result<Capabilities> {
+        self.probe_with(Method::Get, paths)
+    }
+
+    /// Request each of `paths` with `method` and record whether it exists
+    ///
+    /// Probing with `OPTIONS` avoids fetching large resources; paths answering it with `405`
+    /// or `501` are probed again with `GET`, since many servers do not implement it. Only an
+    /// invalid path is an error - failed requests are recorded as `Unreachable`.
+    fn probe_with(&mut self, method: Method, paths: &[&str]) -> Result<Capabilities> {
+        let mut capabilities = Capabilities::default();
+        for path in paths {
+            let uri = path.parse::<Uri>()?;
+            let mut capability = probe_one(self, method.clone(), uri.clone());
+            let unsupported = matches!(capability.status(), Some(StatusCode::MethodNotAllowed) | Some(StatusCode::NotImplemented));
+            if method == Method::Options && unsupported {
+                capability = probe_one(self, Method::Get, uri);
+            }
+            capabilities.endpoints.insert(path.to_string(), capability);
+        }
+        Ok(capabilities)
+    }
+}
+
+fn probe_one<C, HTTP>(client: &mut C, method: Method, uri: Uri) -> Capability
+        where C: ?Sized + ApiClient<HTTP>, HTTP: HttpClient {
+    match client.request(method, uri, None::<String>) {
+        Ok(response) => Capability::from_status(response.status()),
+        Err(e) => Capability::Unreachable(e.to_string()),
+    }
+}
+
+impl<HTTP, C> ProbeApiClient<HTTP> for C where HTTP: HttpClient, C: ApiClient<HTTP> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testing::{Fixture,FixtureServer,client_for};
+
+    #[test]
+    fn test_probe() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/api/v1/info", Fixture::json(200, "{}"))
+            .route(Method::Get, "/clients", Fixture::json(401, "{}"))
+            .route(Method::Options, "/health", Fixture::new(405))
+            .route(Method::Get, "/health", Fixture::new(204))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let capabilities = client.probe_with(Method::Options, &["/health"]).unwrap();
+        assert_eq!(capabilities.get("/health"), Some(&Capability::Available(StatusCode::NoContent)));
+
+        let capabilities = client.probe(&["/info", "/api/v1/info", "/clients"]).unwrap();
+        assert_eq!(capabilities.get("/info"), Some(&Capability::Missing(StatusCode::NotFound)));
+        assert!(capabilities.supports("/clients"));
+        assert_eq!(capabilities.first_supported(&["/info", "/api/v1/info"]), Some("/api/v1/info"));
+        assert_eq!(capabilities.to_json()["/clients"]["result"], "unauthorized");
+        assert_eq!(capabilities.to_json()["/info"]["status"], 404);
+        assert!(client.probe(&["http://[::1"]).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use hyper::Response;
+use hyper::header::{Accept,ContentType,qitem};
+use hyper::mime::Mime;
+use prost::Message;
+
+use *;
+
+fn protobuf_mime() -> Mime {
+    "application/x-protobuf".parse().expect("Protobuf MIME type is valid")
+}
+
+/// Adapter for sending and receiving prost-generated messages as request and response bodies
+#[derive(Clone,Debug,PartialEq,Default)]
+pub struct ProstBody<T>(pub T);
+
+impl<T> ProstBody<T> where T: Message {
+    /// Encode the message to protobuf wire format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.encode_to_vec()
+    }
+
+    /// Get the wrapped message
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> ProstBody<T> where T: Message + Default {
+    /// Decode a message from protobuf wire format
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        T::decode(bytes).map(ProstBody).map_err(|e| {
+            ClientError::new(format!("Failed to decode protobuf message: {}", e))
+        })
+    }
+}
+
+/// Protobuf-over-HTTP flows for gRPC-gateway style APIs - implemented for every `ApiClient`
+///
+/// Requests are sent with `Accept` and `Content-Type` set to `application/x-protobuf`.
+pub trait ProtobufApiClient<HTTP>: ApiClient<HTTP> where HTTP: HttpClient {
+    /// Make an API request with a protobuf body and decode the protobuf response
+    fn request_protobuf<B, T>(&mut self, method: Method, uri: Uri, body: Option<ProstBody<B>>)
+            -> Result<ProstBody<T>> where B: Message, T: Message + Default {
+        let future = self.request_future_protobuf(method, uri, body)?;
+        self.response_future_protobuf(future)
+    }
+
+    /// Make an API request with a protobuf body and return the future
+    fn request_future_protobuf<B>(&mut self, method: Method, uri: Uri, body: Option<ProstBody<B>>)
+            -> Result<FutureResponse> where B: Message {
+        let mut overrides = RequestOverrides::new();
+        overrides.set_header(Accept(vec![qitem(protobuf_mime())]))
+                 .set_header(ContentType(protobuf_mime()));
+        if let Some(ref b) = body {
+            overrides.set_body(b.to_bytes());
+        }
+        self.request_future_with_overrides(method, uri, overrides)
+    }
+
+    /// Resolve the future to a response and decode the protobuf body
+    fn response_future_protobuf<T>(&mut self, fut: FutureResponse) -> Result<ProstBody<T>>
+            where T: Message + Default {
+        let response = self.response_future(fut)?;
+        self.response_to_protobuf(response)
+    }
+
+    /// Decode a response body as a protobuf message
+    fn response_to_protobuf<T>(&mut self, response: Response) -> Result<ProstBody<T>>
+            where T: Message + Default {
+        let chunk = self.http_client_mut().evaluate_future(response.body().concat2())?;
+        ProstBody::from_bytes(&chunk)
+    }
+}
+
+impl<HTTP, C> ProtobufApiClient<HTTP> for C where HTTP: HttpClient, C: ApiClient<HTTP> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prost_body_roundtrip() {
+        let body = ProstBody("teatime".to_string());
+        let bytes = body.to_bytes();
+        assert_eq!(ProstBody::<String>::from_bytes(&bytes).unwrap(), body);
+        assert!(ProstBody::<String>::from_bytes(&[0xff]).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::fmt::{self,Debug,Formatter};
+
+use *;
+use pipeline::PipelineApiClient;
+
+/// A remote item whose fields differ from the desired state
+#[derive(Clone,Debug,PartialEq)]
+pub struct Update {
+    /// Item as it exists remotely
+    pub current: Value,
+    /// Item as it should be
+    pub desired: Value,
+}
+
+/// Changes needed to bring a remote collection to the desired state
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Plan {
+    /// Desired items missing remotely
+    pub create: Vec<Value>,
+    /// Remote items with fields that differ from the desired items
+    pub update: Vec<Update>,
+    /// Remote items that are not desired
+    pub delete: Vec<Value>,
+    /// Number of remote items already matching the desired items
+    pub unchanged: usize,
+}
+
+impl Plan {
+    /// True if the remote collection already matches the desired state
+    pub fn is_empty(&self) -> bool {
+        self.create.is_empty() && self.update.is_empty() && self.delete.is_empty()
+    }
+
+    /// Number of requests needed to apply the plan
+    pub fn len(&self) -> usize {
+        self.create.len() + self.update.len() + self.delete.len()
+    }
+}
+
+/// True if every field set in `desired` has the same value in `current`, so fields only the
+/// server fills in, like `id` or `created_at`, do not cause updates
+pub fn is_subset(desired: &Value, current: &Value) -> bool {
+    match (desired, current) {
+        (Value::Object(d), Value::Object(c)) => {
+            d.iter().all(|(k, v)| c.get(k).is_some_and(|cv| is_subset(v, cv)))
+        },
+        (d, c) => d == c,
+    }
+}
+
+fn key_to_string(value: &Value) -> Option<String> {
+    match *value {
+        Value::String(ref s) => Some(s.clone()),
+        Value::Number(ref n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+type KeyFn = Box<dyn Fn(&Value) -> Option<String>>;
+
+/// Compares a remote collection with a desired set of items matched by key
+pub struct Reconciler {
+    key: KeyFn,
+    prune: bool,
+}
+
+impl Debug for Reconciler {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Reconciler").field("prune", &self.prune).finish()
+    }
+}
+
+impl Reconciler {
+    /// Match items by the key `key` extracts - items without a key cannot be matched
+    pub fn new<F>(key: F) -> Self where F: Fn(&Value) -> Option<String> + 'static {
+        Reconciler { key: Box::new(key), prune: true }
+    }
+
+    /// Match items by the string, number or boolean value of top level `field`
+    pub fn by_field<S>(field: S) -> Self where S: Into<String> {
+        let field = field.into();
+        Reconciler::new(move |item| item.get(&field).and_then(key_to_string))
+    }
+
+    /// Whether remote items that are not desired are deleted - true by default
+    pub fn prune(mut self, prune: bool) -> Self {
+        self.prune = prune;
+        self
+    }
+
+    /// Work out the changes needed to turn `remote` into `desired`
+    ///
+    /// Fails if a desired item has no key or two desired items share one. Remote items without
+    /// a key are left alone.
+    pub fn diff(&self, remote: Vec<Value>, desired: Vec<Value>) -> Result<Plan> {
+        let mut wanted = BTreeMap::new();
+        let mut order = Vec::new();
+        for item in desired {
+            let key = (self.key)(&item).ok_or_else(|| {
+                ClientError::new(format!("Desired item has no key: {}", item))
+            })?;
+            if wanted.insert(key.clone(), item).is_some() {
+                return Err(ClientError::new(format!("Duplicate key in desired items: {}", key)));
+            }
+            order.push(key);
+        }
+
+        let mut plan = Plan::default();
+        for item in remote {
+            let desired = match (self.key)(&item) {
+                Some(key) => wanted.remove(&key),
+                None => continue,
+            };
+            match desired {
+                Some(desired) if is_subset(&desired, &item) => plan.unchanged += 1,
+                Some(desired) => plan.update.push(Update { current: item, desired }),
+                None if self.prune => plan.delete.push(item),
+                None => (),
+            }
+        }
+        plan.create = order.into_iter().filter_map(|key| wanted.remove(&key)).collect();
+        Ok(plan)
+    }
+}
+
+/// Syncing remote collections to a desired state - implemented for every `JsonApiClient`
+pub trait ReconcileApiClient<HTTP>: JsonApiClient<HTTP> + Sized where HTTP: HttpClient {
+    /// Read every page of the collection at `uri` and diff it against `desired`
+    fn plan(&mut self, uri: Uri, desired: Vec<Value>, reconciler: &Reconciler) -> Result<Plan> {
+        let mut remote = Vec::new();
+        for page in self.pages(Method::Get, uri, None::<Value>)? {
+            remote.extend(page?);
+        }
+        reconciler.diff(remote, desired)
+    }
+
+    /// Apply `plan`, returning the number of changes made
+    ///
+    /// Items are created with a `POST` to `collection`, updated with `update_method` and
+    /// deleted with a `DELETE` to the URI `item_uri` gives for the remote item. Creates run
+    /// first and deletes last; the first failure stops the sync.
+    fn apply_plan<F>(&mut self, collection: Uri, plan: &Plan, update_method: Method, item_uri: F)
+            -> Result<usize> where F: Fn(&Value) -> Result<Uri> {
+        let mut applied = 0;
+        for item in &plan.create {
+            self.apply_change(Method::Post, collection.clone(), Some(item))?;
+            applied += 1;
+        }
+        for update in &plan.update {
+            self.apply_change(update_method.clone(), item_uri(&update.current)?, Some(&update.desired))?;
+            applied += 1;
+        }
+        for item in &plan.delete {
+            self.apply_change(Method::Delete, item_uri(item)?, None)?;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    /// Send one change of a plan, failing on any unsuccessful status
+    fn apply_change(&mut self, method: Method, uri: Uri, body: Option<&Value>) -> Result<()> {
+        let response = self.request(method.clone(), uri.clone(), body.map(Value::to_string))?;
+        let status = response.status();
+        if !status.is_success() {
+            let kind = auth::auth_failure(status).unwrap_or(ErrorKind::Other);
+            return Err(ClientError::with_kind(kind, format!("{} {} failed with status {}", method, uri, status)));
+        }
+        Ok(())
+    }
+}
+
+impl<HTTP, C> ReconcileApiClient<HTTP> for C where HTTP: HttpClient, C: JsonApiClient<HTTP> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn json(s: &str) -> Value {
+        serde_json::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_reconciler_diff() {
+        let remote = json(r#"[{"id": 1, "name": "a", "color": "red"}, {"id": 2, "name": "b", "color": "red"},
+                              {"id": 3, "name": "c"}, {"id": 4}]"#);
+        let desired = json(r#"[{"name": "d"}, {"name": "b", "color": "blue"}, {"name": "a", "color": "red"}]"#);
+        let remote = remote.as_array().unwrap().clone();
+        let desired = desired.as_array().unwrap().clone();
+
+        let plan = Reconciler::by_field("name").diff(remote.clone(), desired.clone()).unwrap();
+        assert_eq!(plan.create, vec![json(r#"{"name": "d"}"#)]);
+        assert_eq!(plan.update, vec![Update { current: remote[1].clone(), desired: desired[1].clone() }]);
+        assert_eq!(plan.delete, vec![remote[2].clone()]);
+        assert_eq!(plan.unchanged, 1);
+        assert_eq!(plan.len(), 3);
+
+        let plan = Reconciler::by_field("name").prune(false).diff(remote.clone(), desired).unwrap();
+        assert!(plan.delete.is_empty());
+        assert!(Reconciler::by_field("id").diff(remote.clone(), vec![json(r#"{"name": "x"}"#)]).is_err());
+        assert!(Reconciler::by_field("id").diff(Vec::new(), vec![json(r#"{"id": 1}"#), json(r#"{"id": 1}"#)]).is_err());
+        assert!(Reconciler::by_field("id").prune(false).diff(remote.clone(), remote).unwrap().is_empty());
+
+        assert!(is_subset(&json(r#"{"a": {"b": 1}}"#), &json(r#"{"a": {"b": 1, "c": 2}, "d": 3}"#)));
+        assert!(!is_subset(&json(r#"{"a": [1]}"#), &json(r#"{"a": [1, 2]}"#)));
+    }
+}
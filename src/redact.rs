@@ -0,0 +1,176 @@
+use std::borrow::Cow;
+
+use hyper::header::Headers;
+use url::form_urlencoded;
+
+use *;
+
+/// Placeholder written in place of redacted values
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Which headers, query parameters and JSON body fields must be masked before requests or
+/// responses are written to any diagnostic output such as logs, error messages, dry runs or
+/// recordings
+///
+/// The default policy masks the headers and query parameters the reference clients and common
+/// APIs authenticate with, along with `/password`, `/passcode` and `/auth/client_token` in JSON
+/// bodies.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct RedactionPolicy {
+    headers: Vec<String>,
+    query_parameters: Vec<String>,
+    json_pointers: Vec<String>,
+}
+
+impl Default for RedactionPolicy {
+    fn default() -> Self {
+        RedactionPolicy::empty()
+            .header("Authorization")
+            .header("Proxy-Authorization")
+            .header("Cookie")
+            .header("Set-Cookie")
+            .header("Private-Token")
+            .header("X-Vault-Token")
+            .query_parameter("private_token")
+            .query_parameter("access_token")
+            .query_parameter("token")
+            .query_parameter("api_key")
+            .query_parameter("password")
+            .json_pointer("/password")
+            .json_pointer("/passcode")
+            .json_pointer("/auth/client_token")
+    }
+}
+
+impl RedactionPolicy {
+    /// Policy masking the default headers and fields
+    pub fn new() -> Self {
+        RedactionPolicy::default()
+    }
+
+    /// Policy masking nothing
+    pub fn empty() -> Self {
+        RedactionPolicy { headers: Vec::new(), query_parameters: Vec::new(), json_pointers: Vec::new() }
+    }
+
+    /// Also mask the header `name`, compared case insensitively
+    pub fn header<S>(mut self, name: S) -> Self where S: Into<String> {
+        self.headers.push(name.into().to_lowercase());
+        self
+    }
+
+    /// Also mask the query parameter `name`, compared case insensitively
+    pub fn query_parameter<S>(mut self, name: S) -> Self where S: Into<String> {
+        let name = name.into().to_lowercase();
+        if !self.query_parameters.contains(&name) {
+            self.query_parameters.push(name);
+        }
+        self
+    }
+
+    /// Also mask the JSON body field at `pointer`, for example `/data/token`
+    pub fn json_pointer<S>(mut self, pointer: S) -> Self where S: Into<String> {
+        self.json_pointers.push(pointer.into());
+        self
+    }
+
+    /// True if the value of header `name` is masked
+    pub fn is_redacted_header(&self, name: &str) -> bool {
+        self.headers.iter().any(|h| h.eq_ignore_ascii_case(name))
+    }
+
+    /// True if the value of query parameter `name` is masked
+    pub fn is_redacted_query_parameter(&self, name: &str) -> bool {
+        self.query_parameters.iter().any(|p| p.eq_ignore_ascii_case(name))
+    }
+
+    /// Value of header `name` safe to display
+    pub fn header_value<'a>(&self, name: &str, value: &'a str) -> Cow<'a, str> {
+        if self.is_redacted_header(name) {
+            Cow::Borrowed(REDACTED)
+        } else {
+            Cow::Borrowed(value)
+        }
+    }
+
+    /// Header names and values safe to display
+    pub fn headers(&self, headers: &Headers) -> Vec<(String, String)> {
+        headers.iter().map(|h| {
+            let value = h.value_string();
+            (h.name().to_string(), self.header_value(h.name(), &value).into_owned())
+        }).collect()
+    }
+
+    /// `uri` safe to display, with the values of masked query parameters replaced
+    pub fn uri(&self, uri: &Uri) -> String {
+        let text = uri.to_string();
+        let (path, query) = match text.find('?') {
+            Some(i) => (&text[..i], &text[i + 1..]),
+            None => return text,
+        };
+        let pairs = form_urlencoded::parse(query.as_bytes()).map(|(k, v)| {
+            if self.is_redacted_query_parameter(&k) {
+                (k.into_owned(), REDACTED.to_string())
+            } else {
+                (k.into_owned(), v.into_owned())
+            }
+        });
+        format!("{}?{}", path, form_urlencoded::Serializer::new(String::new()).extend_pairs(pairs).finish())
+    }
+
+    /// Mask the configured fields of a JSON value in place
+    pub fn json(&self, value: &mut Value) {
+        for pointer in &self.json_pointers {
+            if let Some(field) = value.pointer_mut(pointer) {
+                *field = Value::from(REDACTED);
+            }
+        }
+    }
+
+    /// Body safe to display - JSON bodies have the configured fields masked and other bodies
+    /// are shown as text
+    pub fn body(&self, body: &[u8]) -> String {
+        match serde_json::from_slice::<Value>(body) {
+            Ok(mut value) => {
+                self.json(&mut value);
+                value.to_string()
+            },
+            Err(_) => String::from_utf8_lossy(body).into_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redact_headers_and_json() {
+        let policy = RedactionPolicy::new().header("X-Api-Key").json_pointer("/data/token");
+        let mut headers = Headers::new();
+        headers.set_raw("x-api-key", "secret");
+        headers.set_raw("X-Vault-Token", "s.abc");
+        headers.set_raw("Accept", "application/json");
+        let mut redacted = policy.headers(&headers);
+        redacted.sort();
+        assert_eq!(redacted, vec![
+            ("Accept".to_string(), "application/json".to_string()),
+            ("X-Vault-Token".to_string(), REDACTED.to_string()),
+            ("x-api-key".to_string(), REDACTED.to_string()),
+        ]);
+        assert_eq!(policy.body(br#"{"password": "pw", "data": {"token": "t", "ttl": 60}}"#),
+                   r#"{"data":{"token":"[REDACTED]","ttl":60},"password":"[REDACTED]"}"#);
+        assert_eq!(policy.body(b"not json"), "not json");
+        assert!(!RedactionPolicy::empty().is_redacted_header("Authorization"));
+    }
+
+    #[test]
+    fn test_redact_query_parameters() {
+        let policy = RedactionPolicy::new().query_parameter("Sig");
+        let uri = "https://api.example.com/v1/items?page=2&API_KEY=a%26b%3Dc&sig=xyz".parse().unwrap();
+        assert_eq!(policy.uri(&uri), "https://api.example.com/v1/items?page=2&API_KEY=%5BREDACTED%5D&sig=%5BREDACTED%5D");
+        assert_eq!(policy.uri(&"/items".parse().unwrap()), "/items");
+        assert!(policy.is_redacted_query_parameter("private_token"));
+        assert!(!RedactionPolicy::empty().is_redacted_query_parameter("token"));
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher,Hasher};
+use std::sync::Arc;
+
+use hyper::header::RetryAfter;
+
+use *;
+use clock::{Sleeper,SystemClock};
+
+/// Statuses retried by a new `RetryPolicy` - rate limited, and gateways or servers that are
+/// briefly unavailable
+pub const RETRYABLE_STATUSES: &[StatusCode] = &[
+    StatusCode::TooManyRequests,
+    StatusCode::BadGateway,
+    StatusCode::ServiceUnavailable,
+    StatusCode::GatewayTimeout,
+];
+
+/// A retry about to happen, passed to the `RetryPolicy::on_retry` callback
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct RetryEvent {
+    /// Number of the attempt about to be made, starting from 2 for the first retry
+    pub attempt: u32,
+    /// Number of attempts the policy allows in total
+    pub max_attempts: u32,
+    /// Status of the failed attempt, if a response was received
+    pub status: Option<StatusCode>,
+    /// Error of the failed attempt, if no response was received
+    pub error: Option<String>,
+    /// Time waited before the attempt
+    pub delay: Duration,
+}
+
+impl fmt::Display for RetryEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "retrying in {:?} (attempt {}/{})", self.delay, self.attempt, self.max_attempts)?;
+        match (self.status, self.error.as_ref()) {
+            (Some(status), _) => write!(f, " after {}", status),
+            (None, Some(error)) => write!(f, " after {}", error),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+type RetryListener = Arc<dyn Fn(&RetryEvent) + Send + Sync>;
+
+/// Which failed requests are repeated, how many times and how quickly
+///
+/// Requests are retried when no response arrives, such as when the connection is refused,
+/// reset or times out, or when the status is one of `RETRYABLE_STATUSES`. The wait before each
+/// retry doubles from `initial_backoff` up to `max_backoff`, shortened at random by up to the
+/// jitter fraction so many clients failing together do not retry in step. A longer
+/// `Retry-After` from the server is waited out instead, up to `max_backoff`. Retries are
+/// reported to the `on_retry` callback before the wait, so tools can tell users why a command
+/// is slow.
+///
+/// Set a policy for every request with `SimpleHttpClient::set_retry_policy`, or for one with
+/// `RequestOptions::retry_policy`. Only methods that are safe to repeat are retried.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use teatime::retry::RetryPolicy;
+/// let policy = RetryPolicy::new(4)
+///     .backoff(Duration::from_secs(1), Duration::from_secs(30))
+///     .jitter(0.5)
+///     .on_retry(|event| eprintln!("{}", event));
+/// assert!(policy.delay(3) <= Duration::from_secs(4));
+/// ```
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: f64,
+    statuses: Vec<StatusCode>,
+    connection_errors: bool,
+    on_retry: Option<RetryListener>,
+    sleeper: Arc<dyn Sleeper>,
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_retries", &self.max_retries)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .field("statuses", &self.statuses)
+            .field("connection_errors", &self.connection_errors)
+            .finish()
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new(0)
+    }
+}
+
+impl RetryPolicy {
+    /// Repeat a failed request up to `max_retries` more times without waiting
+    pub fn new(max_retries: u32) -> Self {
+        RetryPolicy {
+            max_retries,
+            initial_backoff: Duration::from_secs(0),
+            max_backoff: Duration::from_secs(0),
+            jitter: 0.0,
+            statuses: RETRYABLE_STATUSES.to_vec(),
+            connection_errors: true,
+            on_retry: None,
+            sleeper: Arc::new(SystemClock),
+        }
+    }
+
+    /// Repeat a failed request up to `max_retries` more times
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Wait `initial` before the first retry, doubling for each retry after up to `max`
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Shorten each wait at random by up to `fraction` of it, from 0 to 1
+    pub fn jitter(mut self, fraction: f64) -> Self {
+        self.jitter = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Retry responses with `statuses` in place of `RETRYABLE_STATUSES`
+    pub fn statuses(mut self, statuses: Vec<StatusCode>) -> Self {
+        self.statuses = statuses;
+        self
+    }
+
+    /// Retry requests that get no response - on unless turned off
+    pub fn connection_errors(mut self, retry: bool) -> Self {
+        self.connection_errors = retry;
+        self
+    }
+
+    /// Call `listener` before waiting for each retry
+    pub fn on_retry<F>(mut self, listener: F) -> Self where F: Fn(&RetryEvent) + Send + Sync + 'static {
+        self.on_retry = Some(Arc::new(listener));
+        self
+    }
+
+    /// Wait with `sleeper`
+    pub fn sleeper<S>(mut self, sleeper: S) -> Self where S: Sleeper + 'static {
+        self.sleeper = Arc::new(sleeper);
+        self
+    }
+
+    /// Number of times a failed request is repeated
+    pub fn retry_count(&self) -> u32 {
+        self.max_retries
+    }
+
+    /// True if a request with the outcome `result` should be repeated, retries permitting
+    pub fn should_retry(&self, result: &Result<Response>) -> bool {
+        match *result {
+            Ok(ref response) => self.statuses.contains(&response.status()),
+            Err(_) => self.connection_errors,
+        }
+    }
+
+    /// Time waited before the `retry`th retry, starting from 1, with jitter applied
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        let delay = self.initial_backoff.checked_mul(factor).unwrap_or(self.max_backoff).min(self.max_backoff);
+        if self.jitter > 0.0 {
+            let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+            delay.mul_f64(1.0 - self.jitter * random)
+        } else {
+            delay
+        }
+    }
+
+    /// Report and wait for the `retry`th retry after the failed `result`
+    pub fn before_retry(&self, retry: u32, result: &Result<Response>) {
+        let retry_after = result.as_ref().ok().and_then(|r| r.headers().get::<RetryAfter>()).map(|h| match *h {
+            RetryAfter::Delay(delay) => delay,
+            RetryAfter::DateTime(date) => SystemTime::from(date).duration_since(SystemTime::now()).unwrap_or_default(),
+        });
+        let delay = self.delay(retry);
+        let delay = retry_after.map_or(delay, |after| after.min(self.max_backoff).max(delay));
+        if let Some(ref listener) = self.on_retry {
+            listener(&RetryEvent {
+                attempt: retry + 1,
+                max_attempts: self.max_retries + 1,
+                status: result.as_ref().ok().map(Response::status),
+                error: result.as_ref().err().map(|e| e.to_string()),
+                delay,
+            });
+        }
+        if delay > Duration::from_secs(0) {
+            self.sleeper.sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+    use clock::MockClock;
+    use testing::{Fixture,FixtureServer,TestClient,client_for};
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy::new(5).backoff(Duration::from_millis(500), Duration::from_secs(3));
+        let delays = (1..=5).map(|r| policy.delay(r).as_millis()).collect::<Vec<_>>();
+        assert_eq!(delays, vec![500, 1000, 2000, 3000, 3000]);
+        assert_eq!(RetryPolicy::new(2).delay(40), Duration::from_secs(0));
+
+        let event = RetryEvent { attempt: 2, max_attempts: 5, status: Some(StatusCode::ServiceUnavailable),
+                                 error: None, delay: Duration::from_secs(2) };
+        assert_eq!(event.to_string(), "retrying in 2s (attempt 2/5) after 503 Service Unavailable");
+
+        let clock = MockClock::new();
+        RetryPolicy::new(1).backoff(Duration::from_secs(1), Duration::from_secs(1)).sleeper(clock.clone())
+            .before_retry(1, &Err(ClientError::new("connection reset")));
+        assert_eq!(clock.sleeps(), vec![Duration::from_secs(1)]);
+    }
+
+    #[test]
+    fn test_retry_conditions() {
+        let policy = RetryPolicy::new(3).backoff(Duration::from_secs(8), Duration::from_secs(20)).jitter(0.25);
+        assert!((0..20).all(|_| (6..=8).contains(&policy.delay(1).as_secs())));
+        assert!(policy.should_retry(&Err(ClientError::new("connection refused"))));
+        assert!(!policy.clone().connection_errors(false).should_retry(&Err(ClientError::new("connection refused"))));
+        assert!(policy.should_retry(&Ok(Response::new().with_status(StatusCode::TooManyRequests))));
+        assert!(!policy.should_retry(&Ok(Response::new().with_status(StatusCode::InternalServerError))));
+        assert!(policy.clone().statuses(vec![StatusCode::InternalServerError])
+            .should_retry(&Ok(Response::new().with_status(StatusCode::InternalServerError))));
+
+        // A longer Retry-After is waited out, up to the maximum backoff
+        let clock = MockClock::new();
+        let policy = RetryPolicy::new(3).backoff(Duration::from_secs(1), Duration::from_secs(20)).sleeper(clock.clone());
+        let limited = |seconds| Ok(Response::new().with_status(StatusCode::TooManyRequests)
+            .with_header(RetryAfter::Delay(Duration::from_secs(seconds))));
+        policy.before_retry(1, &limited(5));
+        policy.before_retry(2, &limited(60));
+        policy.before_retry(3, &limited(0));
+        assert_eq!(clock.sleeps(), vec![Duration::from_secs(5), Duration::from_secs(20), Duration::from_secs(4)]);
+    }
+
+    #[test]
+    fn test_retry_events() {
+        use clock::MockClock;
+
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/unavailable", Fixture::json(503, "{}"))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let clock = MockClock::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let policy = RetryPolicy::new(2).backoff(Duration::from_secs(2), Duration::from_secs(60)).sleeper(clock.clone())
+            .on_retry(move |event| recorded.lock().unwrap().push(event.to_string()));
+        let options = RequestOptions::new().retry_policy(policy);
+        let response = client.request_with_options(Method::Get, "/unavailable".parse().unwrap(), None::<String>, &options).unwrap();
+
+        assert_eq!(response.status(), StatusCode::ServiceUnavailable);
+        server.assert_sent().count(3);
+        assert_eq!(*events.lock().unwrap(), vec![
+            "retrying in 2s (attempt 2/3) after 503 Service Unavailable".to_string(),
+            "retrying in 4s (attempt 3/3) after 503 Service Unavailable".to_string(),
+        ]);
+        assert_eq!(clock.sleeps(), vec![Duration::from_secs(2), Duration::from_secs(4)]);
+    }
+
+    #[test]
+    fn test_client_retry_policy() {
+        use clock::MockClock;
+
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/flaky", Fixture::json(503, "{}"))
+            .route(Method::Get, "/flaky", Fixture::json(502, "{}"))
+            .route(Method::Get, "/flaky", Fixture::json(200, r#"{"ok": true}"#))
+            .route(Method::Post, "/flaky", Fixture::json(503, "{}"))
+            .route(Method::Get, "/broken", Fixture::json(500, "{}"))
+            .start().unwrap();
+        let clock = MockClock::new();
+        let policy = RetryPolicy::new(3).backoff(Duration::from_millis(100), Duration::from_secs(1)).sleeper(clock.clone());
+        let http = SimpleHttpClientBuilder::new().retry_policy(policy.clone())
+            .build_for(&server.base_uri().parse().unwrap()).unwrap();
+        let mut client = client_for(&server).with_http(http);
+        let json = client.request_json(Method::Get, "/flaky".parse().unwrap(), None::<Value>).unwrap();
+        assert_eq!(json["ok"], true);
+        assert_eq!(clock.sleeps(), vec![Duration::from_millis(100), Duration::from_millis(200)]);
+
+        // Not safe to repeat, and not a retryable status
+        let response = client.request(Method::Post, "/flaky".parse().unwrap(), Some("{}")).unwrap();
+        assert_eq!(response.status(), StatusCode::ServiceUnavailable);
+        let response = client.request(Method::Get, "/broken".parse().unwrap(), None::<String>).unwrap();
+        assert_eq!(response.status(), StatusCode::InternalServerError);
+        server.assert_sent().count(5);
+
+        let mut closed = TestClient {
+            base_uri: "http://127.0.0.1:1/".parse().unwrap(),
+            client: SimpleHttpClientBuilder::new().retry_policy(policy).build_for(&"http://127.0.0.1:1/".parse().unwrap()).unwrap(),
+        };
+        assert!(closed.request(Method::Get, "/".parse().unwrap(), None::<String>).is_err());
+        assert_eq!(clock.sleeps().len(), 5);
+    }
+}
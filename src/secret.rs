@@ -0,0 +1,69 @@
+use std::fmt::{self,Formatter};
+
+#[cfg(feature = "zeroize")]
+use zeroize::Zeroize;
+
+use redact::REDACTED;
+
+/// A token, password or other sensitive string
+///
+/// The value never appears in `Debug` output and must be read explicitly with `expose`. With
+/// the `zeroize` feature the memory holding the value is wiped when it is dropped.
+#[derive(Clone,Default,PartialEq,Eq,Hash)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Wrap a sensitive value
+    pub fn new<S>(value: S) -> Self where S: Into<String> {
+        Secret(value.into())
+    }
+
+    /// Read the sensitive value
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// True if the value is empty
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Secret({})", REDACTED)
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl<'a> From<&'a str> for Secret {
+    fn from(value: &'a str) -> Self {
+        Secret(value.to_string())
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_secret_debug_is_redacted() {
+        let secret = Secret::from("hunter2");
+        assert_eq!(secret.expose(), "hunter2");
+        assert_eq!(format!("{:?}", secret), "Secret([REDACTED])");
+        assert!(!format!("{:?}", Some(secret.clone())).contains("hunter2"));
+        assert!(Secret::default().is_empty());
+    }
+}
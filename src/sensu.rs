@@ -1,22 +1,440 @@
+use std::sync::Arc;
+use std::time::{Duration,UNIX_EPOCH};
+
 use hyper::{Uri};
-use hyper::header::{ContentType,ContentLength};
+use hyper::header::{Authorization,Basic,ContentType,ContentLength};
+use futures::future::join_all;
+use serde_json::Value;
 
 use *;
+use clock::{Clock,SystemClock};
+use completion::{NameCache,complete,names_at};
+use probe::ProbeApiClient;
+
+/// An event from the Sensu classic `/events` endpoint
+#[derive(Clone,Debug,PartialEq)]
+pub struct Event {
+    /// Event ID
+    pub id: Option<String>,
+    /// Name of the client the check ran on
+    pub client: String,
+    /// Name of the check
+    pub check: String,
+    /// Check status - 0 OK, 1 warning, 2 critical, anything else unknown
+    pub status: u64,
+    /// Check output
+    pub output: String,
+    /// Number of consecutive occurrences of the event
+    pub occurrences: u64,
+    /// Event action such as `create`, `resolve` or `flapping`
+    pub action: Option<String>,
+    /// Time of the latest check result in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// The full event as returned by Sensu
+    pub raw: Value,
+}
 
-/// Sensu API client
+impl Event {
+    /// Parse an event from its JSON representation
+    pub fn from_json(json: &Value) -> Result<Self> {
+        let check = json.get("check").ok_or(ClientError::new("Sensu event has no check"))?;
+        let string = |v: Option<&Value>| v.and_then(Value::as_str).map(str::to_string);
+        Ok(Event {
+            id: string(json.get("id")),
+            client: string(json.get("client").and_then(|c| c.get("name")))
+                .ok_or(ClientError::new("Sensu event has no client name"))?,
+            check: string(check.get("name")).ok_or(ClientError::new("Sensu event has no check name"))?,
+            status: check.get("status").and_then(Value::as_u64).unwrap_or(3),
+            output: string(check.get("output")).unwrap_or_default(),
+            occurrences: json.get("occurrences").and_then(Value::as_u64).unwrap_or(0),
+            action: string(json.get("action")),
+            timestamp: json.get("timestamp").or_else(|| check.get("issued"))
+                .and_then(Value::as_u64).unwrap_or(0),
+            raw: json.clone(),
+        })
+    }
+
+    /// Parse an event from its Sensu Go JSON representation, where the client is the event's
+    /// entity
+    pub fn from_go_json(json: &Value) -> Result<Self> {
+        let check = json.get("check").ok_or(ClientError::new("Sensu event has no check"))?;
+        let name = |v: Option<&Value>| v.and_then(|v| v.pointer("/metadata/name")).and_then(Value::as_str).map(str::to_string);
+        Ok(Event {
+            id: json.get("id").and_then(Value::as_str).map(str::to_string),
+            client: name(json.get("entity")).ok_or(ClientError::new("Sensu event has no entity name"))?,
+            check: name(Some(check)).ok_or(ClientError::new("Sensu event has no check name"))?,
+            status: check.get("status").and_then(Value::as_u64).unwrap_or(3),
+            output: check.get("output").and_then(Value::as_str).map(str::to_string).unwrap_or_default(),
+            occurrences: check.get("occurrences").and_then(Value::as_u64).unwrap_or(0),
+            action: None,
+            timestamp: json.get("timestamp").or_else(|| check.get("executed"))
+                .and_then(Value::as_u64).unwrap_or(0),
+            raw: json.clone(),
+        })
+    }
+}
+
+/// Client side filter for event listings, which the classic API cannot filter itself
+///
+/// ```
+/// # use teatime::sensu::EventFilter;
+/// let filter = EventFilter::new().check_contains("disk").min_status(2).occurrences_gt(3);
+/// ```
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct EventFilter {
+    client: Option<String>,
+    check_contains: Option<String>,
+    output_contains: Option<String>,
+    min_status: Option<u64>,
+    occurrences_gt: Option<u64>,
+}
+
+impl EventFilter {
+    /// Match every event
+    pub fn new() -> Self {
+        EventFilter::default()
+    }
+
+    /// Only events from the client named `name`
+    pub fn client<S>(mut self, name: S) -> Self where S: Into<String> {
+        self.client = Some(name.into());
+        self
+    }
+
+    /// Only events whose check name contains `text`
+    pub fn check_contains<S>(mut self, text: S) -> Self where S: Into<String> {
+        self.check_contains = Some(text.into());
+        self
+    }
+
+    /// Only events whose check output contains `text`
+    pub fn output_contains<S>(mut self, text: S) -> Self where S: Into<String> {
+        self.output_contains = Some(text.into());
+        self
+    }
+
+    /// Only events with a status of at least `status`
+    pub fn min_status(mut self, status: u64) -> Self {
+        self.min_status = Some(status);
+        self
+    }
+
+    /// Only events that have occurred more than `occurrences` times in a row
+    pub fn occurrences_gt(mut self, occurrences: u64) -> Self {
+        self.occurrences_gt = Some(occurrences);
+        self
+    }
+
+    /// True if `event` passes every condition of the filter
+    pub fn matches(&self, event: &Event) -> bool {
+        self.client.as_ref().is_none_or(|c| event.client == *c)
+            && self.check_contains.as_ref().is_none_or(|c| event.check.contains(c.as_str()))
+            && self.output_contains.as_ref().is_none_or(|o| event.output.contains(o.as_str()))
+            && self.min_status.is_none_or(|s| event.status >= s)
+            && self.occurrences_gt.is_none_or(|o| event.occurrences > o)
+    }
+
+    /// Keep the events that pass the filter
+    pub fn apply(&self, events: Vec<Event>) -> Vec<Event> {
+        events.into_iter().filter(|e| self.matches(e)).collect()
+    }
+}
+
+/// Recent results of one check on one client from the `/clients/:client/history` endpoint
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct CheckHistory {
+    /// Name of the check
+    pub check: String,
+    /// Statuses of the most recent executions, oldest first
+    pub statuses: Vec<u64>,
+    /// Time of the latest execution in seconds since the Unix epoch
+    pub last_execution: u64,
+    /// Status of the latest execution
+    pub last_status: u64,
+}
+
+impl CheckHistory {
+    /// Parse a history entry from its JSON representation
+    pub fn from_json(json: &Value) -> Result<Self> {
+        let statuses = json.get("history").and_then(Value::as_array)
+            .ok_or(ClientError::new("Sensu check history has no history list"))?
+            .iter()
+            .map(|s| s.as_u64().or_else(|| s.as_str().and_then(|s| s.parse().ok())).unwrap_or(3))
+            .collect::<Vec<_>>();
+        Ok(CheckHistory {
+            check: json.get("check").and_then(Value::as_str)
+                .ok_or(ClientError::new("Sensu check history has no check name"))?.to_string(),
+            last_execution: json.get("last_execution").and_then(Value::as_u64).unwrap_or(0),
+            last_status: json.get("last_status").and_then(Value::as_u64)
+                .or_else(|| statuses.last().cloned()).unwrap_or(3),
+            statuses,
+        })
+    }
+
+    /// Weighted percentage of consecutive executions that changed status, like the percent
+    /// state change Sensu uses for its own flap detection
+    ///
+    /// Changes are weighted from 0.8 for the oldest to 1.2 for the newest so that recent
+    /// alternation counts for more, and a status that changes on every execution scores 100.
+    /// Histories of fewer than two executions score 0.
+    pub fn percent_state_change(&self) -> f64 {
+        if self.statuses.len() < 2 {
+            return 0.0;
+        }
+        let transitions = (self.statuses.len() - 1) as f64;
+        let step = if transitions > 1.0 { 0.4 / (transitions - 1.0) } else { 0.0 };
+        let first = if transitions > 1.0 { 0.8 } else { 1.0 };
+        let changes: f64 = self.statuses.windows(2).enumerate()
+            .filter(|&(_, pair)| pair[0] != pair[1])
+            .map(|(i, _)| first + step * i as f64)
+            .sum();
+        changes / transitions * 100.0
+    }
+
+    /// True if the check's percent state change is at least `threshold`, such as the
+    /// `high_flap_threshold` of its definition
+    pub fn is_flapping(&self, threshold: f64) -> bool {
+        self.percent_state_change() >= threshold
+    }
+}
+
+/// Outcome of a maintenance operation that deletes several objects
+#[derive(Debug,Default)]
+pub struct CleanupReport {
+    /// True if nothing was deleted because the operation was a dry run
+    pub dry_run: bool,
+    /// Objects deleted, or that would have been deleted in a dry run
+    pub deleted: Vec<String>,
+    /// Objects that could not be deleted and why
+    pub failed: Vec<(String, ClientError)>,
+}
+
+/// Match `text` against `pattern`, where `*` matches any run of characters
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !text.starts_with(first) {
+        return false;
+    }
+    let mut rest = &text[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+    match parts.split_last() {
+        None => rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(i) => rest = &rest[i + part.len()..],
+                    None => return false,
+                }
+            }
+            rest.ends_with(last)
+        },
+    }
+}
+
+/// `Authorization` header value for HTTP basic authentication
+fn basic_authorization(user: &str, pass: &Secret) -> Secret {
+    let header = Authorization(Basic { username: user.to_string(), password: Some(pass.expose().to_string()) });
+    Secret::new(header.to_string())
+}
+
+/// Sensu classic API client
 pub struct SensuClient {
     api_uri: Uri,
+    clock: Arc<dyn Clock>,
+    authorization: Option<Secret>,
     client: SimpleHttpClient,
 }
 
+/// Builder for a `SensuClient` - see `SensuClient::builder`
+pub struct SensuClientBuilder {
+    api_uri: String,
+    clock: Arc<dyn Clock>,
+    http: SimpleHttpClientBuilder,
+}
+
+impl SensuClientBuilder {
+    /// Use `clock` to decide which clients are stale
+    pub fn clock<C>(mut self, clock: C) -> Self where C: Clock + 'static {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Configure the HTTP client - headers, timeout, TLS and the other `SimpleHttpClient`
+    /// settings
+    pub fn http<F>(mut self, configure: F) -> Self
+            where F: FnOnce(SimpleHttpClientBuilder) -> SimpleHttpClientBuilder {
+        self.http = configure(self.http);
+        self
+    }
+
+    /// Check the options and create the client
+    pub fn build(self) -> Result<SensuClient> {
+        let api_uri = parse_base_uri(&self.api_uri)?;
+        Ok(SensuClient {
+            client: self.http.build_for(&api_uri)?,
+            api_uri,
+            clock: self.clock,
+            authorization: None,
+        })
+    }
+}
+
 impl SensuClient {
     /// Create a new Sensu API client
     pub fn new(api_uri: &str) -> Result<Self> {
         Ok(SensuClient {
             api_uri: api_uri.parse::<Uri>()?,
+            clock: Arc::new(SystemClock),
+            authorization: None,
             client: SimpleHttpClient::new()?,
         })
     }
+
+    /// Create a client for the Sensu API at `api_uri`, classic or Go, and log in with `creds`
+    ///
+    /// The server is probed for `/info`, which only Sensu classic serves, and `/version`,
+    /// which only Sensu Go serves, so tooling can work against a mixed fleet during a
+    /// migration.
+    pub fn detect(api_uri: &str, creds: &ApiCredentials) -> Result<SensuApi> {
+        let mut classic = SensuClient::new(api_uri)?;
+        let capabilities = classic.probe(&["/info", "/version"])?;
+        let mut api = match capabilities.first_supported(&["/info", "/version"]) {
+            Some("/info") => SensuApi::Classic(classic),
+            Some(_) => SensuApi::Go(SensuGoClient::new(api_uri)?),
+            None => return Err(ClientError::new(format!("No Sensu API found at {}", api_uri))),
+        };
+        api.login(creds)?;
+        Ok(api)
+    }
+
+    /// Start building a client for the API at the absolute `api_uri` with more options than
+    /// `new` offers
+    pub fn builder<S>(api_uri: S) -> SensuClientBuilder where S: Into<String> {
+        SensuClientBuilder { api_uri: api_uri.into(), clock: Arc::new(SystemClock), http: SimpleHttpClientBuilder::new() }
+    }
+
+    /// Use `clock` to decide which clients are stale
+    pub fn set_clock<C>(&mut self, clock: C) where C: Clock + 'static {
+        self.clock = Arc::new(clock);
+    }
+
+    /// List all current events
+    pub fn list_events(&mut self) -> Result<Vec<Event>> {
+        let events = self.request_json(Method::Get, "/events".parse::<Uri>()?, None::<Value>)?;
+        events.as_array().ok_or(ClientError::new("Sensu events response is not a list"))?
+            .iter().map(Event::from_json).collect()
+    }
+
+    /// List the current events that pass `filter`
+    pub fn list_events_filtered(&mut self, filter: &EventFilter) -> Result<Vec<Event>> {
+        Ok(filter.apply(self.list_events()?))
+    }
+
+    /// History of `check` on `client`
+    pub fn check_history(&mut self, client: &str, check: &str) -> Result<CheckHistory> {
+        let uri = format!("/clients/{}/history", client).parse::<Uri>()?;
+        let history = self.request_json(Method::Get, uri, None::<Value>)?;
+        history.as_array().ok_or(ClientError::new("Sensu history response is not a list"))?
+            .iter()
+            .find(|h| h.get("check").and_then(Value::as_str) == Some(check))
+            .ok_or_else(|| ClientError::new(format!("Client {} has no history for check {}", client, check)))
+            .and_then(CheckHistory::from_json)
+    }
+
+    /// Delete every client whose last keepalive is more than `older_than` ago
+    ///
+    /// With `dry_run` the stale clients are listed in the report without being deleted.
+    pub fn delete_stale_clients(&mut self, older_than: Duration, dry_run: bool) -> Result<CleanupReport> {
+        let now = self.clock.system_now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let cutoff = now.saturating_sub(older_than.as_secs());
+        let clients = self.request_json(Method::Get, "/clients".parse::<Uri>()?, None::<Value>)?;
+        let stale = clients.as_array().ok_or(ClientError::new("Sensu clients response is not a list"))?
+            .iter()
+            .filter(|c| c.get("timestamp").and_then(Value::as_u64).unwrap_or(0) < cutoff)
+            .filter_map(|c| c.get("name").and_then(Value::as_str))
+            .map(|name| (name.to_string(), format!("/clients/{}", name)))
+            .collect();
+        self.delete_all(stale, dry_run)
+    }
+
+    /// Delete every check result whose check name matches `pattern`, where `*` matches any run
+    /// of characters
+    ///
+    /// Results are reported as `client/check`. With `dry_run` the matching results are listed
+    /// in the report without being deleted.
+    pub fn delete_results_matching(&mut self, pattern: &str, dry_run: bool) -> Result<CleanupReport> {
+        let results = self.request_json(Method::Get, "/results".parse::<Uri>()?, None::<Value>)?;
+        let matching = results.as_array().ok_or(ClientError::new("Sensu results response is not a list"))?
+            .iter()
+            .filter_map(|r| {
+                let client = r.get("client").and_then(Value::as_str)?;
+                let check = r.get("check").and_then(|c| c.get("name")).and_then(Value::as_str)?;
+                Some((client, check))
+            })
+            .filter(|&(_, check)| wildcard_match(pattern, check))
+            .map(|(client, check)| (format!("{}/{}", client, check), format!("/results/{}/{}", client, check)))
+            .collect();
+        self.delete_all(matching, dry_run)
+    }
+
+    /// Names of clients starting with `prefix`, listed at most once per `cache` TTL
+    pub fn complete_client_names(&mut self, cache: &mut NameCache, prefix: &str) -> Result<Vec<String>> {
+        let key = format!("sensu {} clients", self.api_uri);
+        let names = cache.get_or_fetch(&key, || {
+            Ok(names_at(&self.request_json(Method::Get, "/clients".parse::<Uri>()?, None::<Value>)?, "name"))
+        })?;
+        Ok(complete(&names, prefix))
+    }
+
+    /// Silence all checks on `client`, optionally expiring after `expire`
+    pub fn silence_client(&mut self, client: &str, expire: Option<Duration>, reason: Option<&str>) -> Result<()> {
+        let mut body = serde_json::Map::new();
+        body.insert("subscription".to_string(), Value::from(format!("client:{}", client)));
+        if let Some(expire) = expire {
+            body.insert("expire".to_string(), Value::from(expire.as_secs()));
+        }
+        if let Some(reason) = reason {
+            body.insert("reason".to_string(), Value::from(reason));
+        }
+        let response = self.request(Method::Post, "/silenced".parse::<Uri>()?, Some(Value::Object(body)))?;
+        if !response.status().is_success() {
+            return Err(ClientError::new(format!("Failed to silence {}: status {}", client, response.status())));
+        }
+        Ok(())
+    }
+
+    /// Send DELETE requests for all `(name, path)` pairs concurrently
+    fn delete_all(&mut self, targets: Vec<(String, String)>, dry_run: bool) -> Result<CleanupReport> {
+        let mut report = CleanupReport { dry_run, ..CleanupReport::default() };
+        if dry_run {
+            report.deleted = targets.into_iter().map(|(name, _)| name).collect();
+            return Ok(report);
+        }
+        let mut requests = Vec::new();
+        for target in &targets {
+            let future = self.request_future(Method::Delete, target.1.parse::<Uri>()?, None::<String>)
+                .ok_or(ClientError::new("No request made"))?;
+            requests.push(self.http_client().limit_concurrency(future).then(Ok::<_, ()>));
+        }
+        let responses = self.http_client_mut().evaluate_future(join_all(requests))
+            .map_err(|_| ClientError::new("Failed to delete from Sensu"))?;
+        for ((name, _), response) in targets.into_iter().zip(responses) {
+            match response {
+                Ok(ref r) if r.status().is_success() => report.deleted.push(name),
+                Ok(r) => {
+                    let message = format!("Failed to delete {}: status {}", name, r.status());
+                    report.failed.push((name, ClientError::new(message)));
+                },
+                Err(e) => report.failed.push((name, ClientError::from(e))),
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl Transport for SensuClient {
+    type Http = SimpleHttpClient;
 }
 
 impl ApiClient<SimpleHttpClient> for SensuClient {
@@ -40,16 +458,29 @@ impl ApiClient<SimpleHttpClient> for SensuClient {
         };
 
         let full_uri = self.full_uri(uri).ok()?;
+        let authorization = self.authorization.as_ref().map(|a| Authorization(a.expose().to_string()));
         let client = self.http_client_mut();
         client.start_request(method, full_uri)
             .add_header(ContentLength(body_len as u64)).add_header(ContentType::json());
+        if let Some(authorization) = authorization {
+            client.add_header(authorization);
+        }
         if let Some(ref b) = body {
-            client.add_body(b.to_string());
+            client.add_body_bytes(b.to_string());
         }
         client.make_request().future()
     }
 
-    fn login(&mut self, _creds: &ApiCredentials) -> Result<()> {
+    fn login(&mut self, creds: &ApiCredentials) -> Result<()> {
+        self.authorization = match *creds {
+            ApiCredentials::NoAuth => None,
+            ApiCredentials::UserPass(ref user, ref pass) | ApiCredentials::UserPassTwoFactor(ref user, ref pass, _) => {
+                Some(basic_authorization(user, pass))
+            },
+            ApiCredentials::ApiKey(_) => {
+                return Err(ClientError::new("Sensu classic only accepts a username and password"));
+            },
+        };
         Ok(())
     }
 }
@@ -59,3 +490,332 @@ impl JsonApiClient<SimpleHttpClient> for SensuClient {
         Ok(None)
     }
 }
+
+/// Sensu Go API client
+pub struct SensuGoClient {
+    api_uri: Uri,
+    namespace: String,
+    authorization: Option<Secret>,
+    client: SimpleHttpClient,
+}
+
+impl SensuGoClient {
+    /// Create a new Sensu Go API client for the `default` namespace
+    pub fn new(api_uri: &str) -> Result<Self> {
+        Ok(SensuGoClient {
+            api_uri: api_uri.parse::<Uri>()?,
+            namespace: "default".to_string(),
+            authorization: None,
+            client: SimpleHttpClient::new()?,
+        })
+    }
+
+    /// Namespace that namespaced resources such as events are read from
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Read namespaced resources from `namespace`
+    pub fn set_namespace<S>(&mut self, namespace: S) where S: Into<String> {
+        self.namespace = namespace.into();
+    }
+
+    /// List all current events in the namespace
+    pub fn list_events(&mut self) -> Result<Vec<Event>> {
+        let uri = format!("/api/core/v2/namespaces/{}/events", self.namespace).parse::<Uri>()?;
+        let events = self.request_json(Method::Get, uri, None::<Value>)?;
+        events.as_array().ok_or(ClientError::new("Sensu events response is not a list"))?
+            .iter().map(Event::from_go_json).collect()
+    }
+
+    /// List the current events in the namespace that pass `filter`
+    pub fn list_events_filtered(&mut self, filter: &EventFilter) -> Result<Vec<Event>> {
+        Ok(filter.apply(self.list_events()?))
+    }
+}
+
+impl Transport for SensuGoClient {
+    type Http = SimpleHttpClient;
+}
+
+impl ApiClient<SimpleHttpClient> for SensuGoClient {
+    fn base_uri(&self) -> &Uri {
+        &self.api_uri
+    }
+
+    fn http_client(&self) -> &SimpleHttpClient {
+        &self.client
+    }
+
+    fn http_client_mut(&mut self) -> &mut SimpleHttpClient {
+        &mut self.client
+    }
+
+    fn request_future<B>(&mut self, method: Method, uri: Uri, body: Option<B>) -> Option<FutureResponse>
+            where B: ToString {
+        let body_len = match body {
+            Some(ref b) => b.to_string().len(),
+            None => 0,
+        };
+
+        let full_uri = self.full_uri(uri).ok()?;
+        let authorization = self.authorization.as_ref().map(|a| Authorization(a.expose().to_string()));
+        let client = self.http_client_mut();
+        client.start_request(method, full_uri)
+            .add_header(ContentLength(body_len as u64)).add_header(ContentType::json());
+        if let Some(authorization) = authorization {
+            client.add_header(authorization);
+        }
+        if let Some(ref b) = body {
+            client.add_body_bytes(b.to_string());
+        }
+        client.make_request().future()
+    }
+
+    /// Use an API key directly, or exchange a username and password for an access token at
+    /// `/auth`
+    fn login(&mut self, creds: &ApiCredentials) -> Result<()> {
+        self.authorization = match *creds {
+            ApiCredentials::NoAuth => None,
+            ApiCredentials::ApiKey(ref key) => Some(Secret::new(format!("Key {}", key.expose()))),
+            ApiCredentials::UserPass(ref user, ref pass) | ApiCredentials::UserPassTwoFactor(ref user, ref pass, _) => {
+                self.authorization = Some(basic_authorization(user, pass));
+                let json = self.request_json(Method::Get, "/auth".parse::<Uri>()?, None::<Value>);
+                self.authorization = None;
+                let token = json?.get("access_token").and_then(Value::as_str).map(str::to_string)
+                    .ok_or(ClientError::new("Could not log in with given username and password"))?;
+                Some(Secret::new(format!("Bearer {}", token)))
+            },
+        };
+        Ok(())
+    }
+}
+
+impl JsonApiClient<SimpleHttpClient> for SensuGoClient {
+    fn next_page_uri(&mut self, _resp: &Response) -> Result<Option<Uri>> {
+        Ok(None)
+    }
+}
+
+/// A Sensu classic or Sensu Go client, as found by `SensuClient::detect`
+pub enum SensuApi {
+    /// The server runs Sensu classic
+    Classic(SensuClient),
+    /// The server runs Sensu Go
+    Go(SensuGoClient),
+}
+
+impl SensuApi {
+    /// True if the server runs Sensu Go
+    pub fn is_go(&self) -> bool {
+        matches!(*self, SensuApi::Go(_))
+    }
+
+    /// Log in to either API with `creds`
+    pub fn login(&mut self, creds: &ApiCredentials) -> Result<()> {
+        match *self {
+            SensuApi::Classic(ref mut client) => client.login(creds),
+            SensuApi::Go(ref mut client) => client.login(creds),
+        }
+    }
+
+    /// List all current events - for Sensu Go, in the client's namespace
+    pub fn list_events(&mut self) -> Result<Vec<Event>> {
+        match *self {
+            SensuApi::Classic(ref mut client) => client.list_events(),
+            SensuApi::Go(ref mut client) => client.list_events(),
+        }
+    }
+
+    /// List the current events that pass `filter`
+    pub fn list_events_filtered(&mut self, filter: &EventFilter) -> Result<Vec<Event>> {
+        Ok(filter.apply(self.list_events()?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clock::MockClock;
+    use testing::{Fixture,FixtureServer};
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("check-*", "check-disk"));
+        assert!(wildcard_match("*disk*", "check-disk-usage"));
+        assert!(wildcard_match("check-disk", "check-disk"));
+        assert!(!wildcard_match("check-disk", "check-disk-usage"));
+        assert!(!wildcard_match("*-load", "check-disk"));
+        assert!(wildcard_match("*", ""));
+    }
+
+    #[test]
+    fn test_check_history_and_flapping() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/clients/web-1/history", Fixture::json(200, r#"[
+                {"check": "keepalive", "history": [0, 0, 0], "last_execution": 1700000000, "last_status": 0},
+                {"check": "check-disk", "history": ["0", "2", "0", "2", "0"], "last_execution": 1700000010}
+            ]"#))
+            .start().unwrap();
+        let mut client = SensuClient::new(&server.base_uri()).unwrap();
+        let history = client.check_history("web-1", "check-disk").unwrap();
+        assert_eq!(history.statuses, vec![0, 2, 0, 2, 0]);
+        assert_eq!((history.last_execution, history.last_status), (1700000010, 0));
+        assert!((history.percent_state_change() - 100.0).abs() < 1e-9);
+        assert!(history.is_flapping(25.0));
+        assert!(client.check_history("web-1", "check-load").is_err());
+
+        let steady = client.check_history("web-1", "keepalive").unwrap();
+        assert_eq!(steady.percent_state_change(), 0.0);
+        let recent = CheckHistory { statuses: vec![0, 0, 0, 0, 2], ..steady.clone() };
+        let old = CheckHistory { statuses: vec![2, 0, 0, 0, 0], ..steady };
+        assert!(recent.percent_state_change() > old.percent_state_change());
+        assert!((recent.percent_state_change() - 30.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_cleanup_workflows() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/clients", Fixture::json(200, r#"[
+                {"name": "web-1", "timestamp": 1699990000},
+                {"name": "web-2", "timestamp": 1699999990},
+                {"name": "db-1", "timestamp": 1690000000}
+            ]"#))
+            .route(Method::Delete, "/clients/web-1", Fixture::new(202))
+            .route(Method::Delete, "/clients/db-1", Fixture::new(500))
+            .route(Method::Get, "/results", Fixture::json(200, r#"[
+                {"client": "web-1", "check": {"name": "check-disk"}},
+                {"client": "web-2", "check": {"name": "check-load"}},
+                {"client": "web-2", "check": {"name": "old-disk-check"}}
+            ]"#))
+            .route(Method::Delete, "/results/web-1/check-disk", Fixture::new(204))
+            .route(Method::Delete, "/results/web-2/old-disk-check", Fixture::new(204))
+            .start().unwrap();
+        let mut client = SensuClient::new(&server.base_uri()).unwrap();
+        client.set_clock(MockClock::at(UNIX_EPOCH + Duration::from_secs(1700000000)));
+
+        let report = client.delete_stale_clients(Duration::from_secs(3600), true).unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.deleted, vec!["web-1", "db-1"]);
+        server.assert_sent().none(Method::Delete, "/clients/web-1");
+
+        let report = client.delete_stale_clients(Duration::from_secs(3600), false).unwrap();
+        assert_eq!(report.deleted, vec!["web-1"]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "db-1");
+
+        let report = client.delete_results_matching("*disk*", false).unwrap();
+        assert_eq!(report.deleted, vec!["web-1/check-disk", "web-2/old-disk-check"]);
+        server.assert_sent().delete("/results/web-2/old-disk-check");
+    }
+
+    #[test]
+    fn test_complete_client_names() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/clients", Fixture::json(200,
+                   r#"[{"name": "web-2"}, {"name": "db-1"}, {"name": "web-1"}]"#))
+            .start().unwrap();
+        let mut client = SensuClient::new(&server.base_uri()).unwrap();
+        let mut cache = NameCache::new(Duration::from_secs(30));
+        assert_eq!(client.complete_client_names(&mut cache, "web").unwrap(), vec!["web-1", "web-2"]);
+        assert_eq!(client.complete_client_names(&mut cache, "").unwrap().len(), 3);
+        server.assert_sent().count(1).get("/clients");
+    }
+
+    #[test]
+    fn test_silence_client() {
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/silenced", Fixture::new(201))
+            .start().unwrap();
+        let mut client = SensuClient::new(&server.base_uri()).unwrap();
+        client.silence_client("web-1", Some(Duration::from_secs(3600)), Some("deploy")).unwrap();
+        server.assert_sent().post("/silenced").json_body_contains(serde_json::from_str(
+            r#"{"subscription": "client:web-1", "expire": 3600, "reason": "deploy"}"#).unwrap());
+        client.silence_client("web-2", None, None).unwrap();
+        assert_eq!(server.requests()[1].body, br#"{"subscription":"client:web-2"}"#.to_vec());
+
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/silenced", Fixture::json(400, r#"{"error": "bad request"}"#))
+            .start().unwrap();
+        let mut client = SensuClient::new(&server.base_uri()).unwrap();
+        assert!(client.silence_client("web-1", None, None).is_err());
+    }
+
+    #[test]
+    fn test_client_builder() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/events", Fixture::json(200, "[]"))
+            .start().unwrap();
+        let mut client = SensuClient::builder(server.base_uri())
+            .clock(MockClock::at(UNIX_EPOCH + Duration::from_secs(1700000000)))
+            .http(|h| h.raw_header("Authorization", "Basic YWRtaW46c2VjcmV0"))
+            .build().unwrap();
+        assert!(client.list_events().unwrap().is_empty());
+        server.assert_sent().get("/events").header("Authorization", "Basic YWRtaW46c2VjcmV0");
+        assert!(SensuClient::builder("/events").build().is_err());
+    }
+
+    #[test]
+    fn test_list_events_filtered() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/events", Fixture::json(200, r#"[
+                {"id": "1", "client": {"name": "web-1"}, "occurrences": 5, "action": "create",
+                 "timestamp": 1700000000, "check": {"name": "check-disk", "status": 2, "output": "95% full"}},
+                {"id": "2", "client": {"name": "web-2"}, "occurrences": 2,
+                 "check": {"name": "check-disk-usage", "status": 2, "output": "91% full"}},
+                {"id": "3", "client": {"name": "db-1"}, "occurrences": 9,
+                 "check": {"name": "check-disk", "status": 1, "output": "81% full"}},
+                {"id": "4", "client": {"name": "db-1"}, "occurrences": 9,
+                 "check": {"name": "check-load", "status": 2, "output": "load 12"}}
+            ]"#))
+            .start().unwrap();
+        let mut client = SensuClient::new(&server.base_uri()).unwrap();
+        let filter = EventFilter::new().check_contains("disk").min_status(2).occurrences_gt(3);
+        let events = client.list_events_filtered(&filter).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].client.as_str(), events[0].check.as_str()), ("web-1", "check-disk"));
+        assert_eq!((events[0].status, events[0].occurrences, events[0].timestamp), (2, 5, 1700000000));
+        assert_eq!(events[0].action.as_deref(), Some("create"));
+
+        let all = client.list_events().unwrap();
+        assert_eq!(EventFilter::new().client("db-1").apply(all.clone()).len(), 2);
+        assert_eq!(EventFilter::new().output_contains("full").apply(all).len(), 3);
+    }
+
+    #[test]
+    fn test_detect() {
+        let classic = FixtureServer::builder()
+            .route(Method::Get, "/info", Fixture::json(200, r#"{"sensu": {"version": "1.9.0"}}"#))
+            .route(Method::Get, "/events", Fixture::json(200, r#"[
+                {"client": {"name": "web-1"}, "check": {"name": "check-disk", "status": 2}}
+            ]"#))
+            .start().unwrap();
+        let creds = ApiCredentials::UserPass("admin".to_string(), Secret::from("secret"));
+        let mut api = SensuClient::detect(&classic.base_uri(), &creds).unwrap();
+        assert!(!api.is_go());
+        assert_eq!(api.list_events().unwrap()[0].client, "web-1");
+        classic.assert_sent().get("/events").header("Authorization", "Basic YWRtaW46c2VjcmV0");
+
+        let go = FixtureServer::builder()
+            .route(Method::Get, "/version", Fixture::json(200, r#"{"sensu_backend": "6.10.0"}"#))
+            .route(Method::Get, "/auth", Fixture::json(200, r#"{"access_token": "go-token"}"#))
+            .route(Method::Get, "/api/core/v2/namespaces/ops/events", Fixture::json(200, r#"[
+                {"entity": {"metadata": {"name": "web-2"}}, "timestamp": 1700000000,
+                 "check": {"metadata": {"name": "check-load"}, "status": 1, "output": "load 3", "occurrences": 4}}
+            ]"#))
+            .start().unwrap();
+        let mut api = SensuClient::detect(&go.base_uri(), &creds).unwrap();
+        assert!(api.is_go());
+        go.assert_sent().get("/auth").header("Authorization", "Basic YWRtaW46c2VjcmV0");
+        if let SensuApi::Go(ref mut client) = api {
+            client.set_namespace("ops");
+        }
+        let events = api.list_events_filtered(&EventFilter::new().min_status(1)).unwrap();
+        assert_eq!((events[0].client.as_str(), events[0].check.as_str()), ("web-2", "check-load"));
+        assert_eq!((events[0].status, events[0].occurrences, events[0].timestamp), (1, 4, 1700000000));
+        go.assert_sent().get("/api/core/v2/namespaces/ops/events").header("Authorization", "Bearer go-token");
+
+        let neither = FixtureServer::builder().start().unwrap();
+        assert!(SensuClient::detect(&neither.base_uri(), &ApiCredentials::NoAuth).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use std::any::Any;
+
+use *;
+use auth::{AuthPolicy,CredentialsProvider};
+use dynamic::DynApiClient;
+
+/// A client held by a `Session`, which can be used through `DynApiClient` or recovered as its
+/// concrete type for service specific methods
+pub trait SessionClient: DynApiClient {
+    /// The client as `Any`, for downcasting to its concrete type
+    fn as_any(&self) -> &dyn Any;
+    /// The client as mutable `Any`, for downcasting to its concrete type
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<C> SessionClient for C where C: DynApiClient + Any {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+struct SessionEntry {
+    name: String,
+    client: Box<dyn SessionClient>,
+    login: bool,
+}
+
+/// Named clients for several services that are logged in together, for runbooks that chain
+/// calls across Vault, Gitlab, Sensu and the like
+///
+/// `login` asks a single `CredentialsProvider` for credentials and uses them for every client
+/// added with `add`, asking again if a login is rejected. Clients are kept in the order they
+/// were added, which is also the order they are logged in.
+#[derive(Default)]
+pub struct Session {
+    clients: Vec<SessionEntry>,
+    policy: AuthPolicy,
+}
+
+impl Session {
+    /// Empty session asking for credentials at most three times
+    pub fn new() -> Self {
+        Session::default()
+    }
+
+    /// Set how often rejected credentials are replaced before `login` gives up
+    pub fn auth_policy(mut self, policy: AuthPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Add `client` as `name`, logging it in with the shared credentials - replaces any client
+    /// already added as `name`
+    pub fn add<S, C>(self, name: S, client: C) -> Self where S: Into<String>, C: SessionClient + 'static {
+        self.insert(name.into(), Box::new(client), true)
+    }
+
+    /// Add `client` as `name` without logging it in, for services that are not authenticated or
+    /// were given a token
+    pub fn add_anonymous<S, C>(self, name: S, client: C) -> Self
+            where S: Into<String>, C: SessionClient + 'static {
+        self.insert(name.into(), Box::new(client), false)
+    }
+
+    fn insert(mut self, name: String, client: Box<dyn SessionClient>, login: bool) -> Self {
+        self.clients.retain(|e| e.name != name);
+        self.clients.push(SessionEntry { name, client, login });
+        self
+    }
+
+    /// Names of the clients, in the order they were added
+    pub fn names(&self) -> Vec<&str> {
+        self.clients.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    /// The client added as `name`
+    pub fn get(&mut self, name: &str) -> Option<&mut (dyn SessionClient + 'static)> {
+        self.clients.iter_mut().find(|e| e.name == name).map(|e| &mut *e.client)
+    }
+
+    /// The client added as `name` as its concrete type, or `None` if there is no such client or
+    /// it has a different type
+    pub fn get_as<C>(&mut self, name: &str) -> Option<&mut C> where C: Any {
+        self.get(name).and_then(|c| c.as_any_mut().downcast_mut::<C>())
+    }
+
+    /// Log in every client added with `add` using credentials from `provider`
+    ///
+    /// The same credentials are used for each client in turn. When a login fails, fresh
+    /// credentials are requested and used for that client and the ones after it, until the
+    /// policy's credential attempts are used up and `login` fails with
+    /// `ErrorKind::AuthenticationFailed` naming the client.
+    pub fn login<P>(&mut self, provider: &mut P) -> Result<()> where P: CredentialsProvider {
+        let max_attempts = self.policy.credential_attempts();
+        let mut attempts = 0;
+        let mut creds = None;
+        for entry in self.clients.iter_mut().filter(|e| e.login) {
+            loop {
+                if creds.is_none() {
+                    if attempts >= max_attempts {
+                        return Err(ClientError::with_kind(ErrorKind::AuthenticationFailed, format!(
+                            "Login to {} failed after {} credential attempts", entry.name, attempts
+                        )));
+                    }
+                    attempts += 1;
+                    creds = Some(provider.get()?);
+                }
+                match entry.client.dyn_login(creds.as_ref().expect("credentials were just set")) {
+                    Ok(()) => break,
+                    Err(_) => creds = None,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "vault", feature = "sensu"))]
+mod test {
+    use super::*;
+    use auth::StaticCredentials;
+    use dynamic::DynApiClientExt;
+    use sensu::SensuClient;
+    use testing::{Fixture,FixtureServer};
+    use vault::VaultClient;
+
+    struct Attempts(Vec<&'static str>);
+
+    impl CredentialsProvider for Attempts {
+        fn get(&mut self) -> Result<ApiCredentials> {
+            let password = self.0.remove(0);
+            Ok(ApiCredentials::UserPass("me".to_string(), password.into()))
+        }
+    }
+
+    #[test]
+    fn test_session_login() {
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/v1/auth/ldap/login/me", Fixture::json(400, r#"{"errors": ["invalid credentials"]}"#))
+            .route(Method::Post, "/v1/auth/ldap/login/me", Fixture::json(200, r#"{"auth": {"client_token": "s.abc"}}"#))
+            .route(Method::Get, "/clients", Fixture::json(200, r#"[{"name": "web-1"}]"#))
+            .start().unwrap();
+        let mut session = Session::new()
+            .add("vault", VaultClient::new(&server.base_uri(), None).unwrap())
+            .add_anonymous("sensu", SensuClient::new(&server.base_uri()).unwrap());
+        assert_eq!(session.names(), vec!["vault", "sensu"]);
+        session.login(&mut Attempts(vec!["wrong", "right"])).unwrap();
+        assert_eq!(session.get_as::<VaultClient>("vault").unwrap().shared_token().get(), Some("s.abc".into()));
+        server.assert_sent().count(2);
+
+        let clients = session.get("sensu").unwrap().send_json(Method::Get, "/clients".parse().unwrap(), None::<Value>);
+        assert_eq!(clients.unwrap()[0]["name"], "web-1");
+        assert!(session.get_as::<VaultClient>("sensu").is_none());
+        assert!(session.get("gitlab").is_none());
+
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/v1/auth/ldap/login/me", Fixture::json(400, r#"{"errors": ["invalid credentials"]}"#))
+            .start().unwrap();
+        let mut session = Session::new().auth_policy(AuthPolicy::new().max_credential_attempts(2))
+            .add("vault", VaultClient::new(&server.base_uri(), None).unwrap());
+        let creds = ApiCredentials::UserPass("me".to_string(), "pw".into());
+        let err = session.login(&mut StaticCredentials(creds)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::AuthenticationFailed);
+        server.assert_sent().count(2);
+    }
+}
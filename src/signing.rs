@@ -0,0 +1,146 @@
+use std::time::{Duration,SystemTime};
+
+use hyper::Response;
+use hyper::header::{Date,Headers};
+
+use *;
+
+/// Offset between the local clock and a server's clock, learned from `Date` response headers
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ClockSkew {
+    offset_secs: i64,
+    tolerance_secs: u64,
+}
+
+impl Default for ClockSkew {
+    fn default() -> Self {
+        ClockSkew { offset_secs: 0, tolerance_secs: 5 }
+    }
+}
+
+impl ClockSkew {
+    /// No known offset, ignoring differences of up to five seconds
+    pub fn new() -> Self {
+        ClockSkew::default()
+    }
+
+    /// Set how far the clocks may drift apart before the offset is corrected - `Date` headers
+    /// only have second precision so this should be at least one second
+    pub fn tolerance(mut self, tolerance: Duration) -> Self {
+        self.tolerance_secs = tolerance.as_secs();
+        self
+    }
+
+    /// Seconds the server clock is ahead of the local clock - negative if it is behind
+    pub fn offset_secs(&self) -> i64 {
+        self.offset_secs
+    }
+
+    /// Adjust a local time to the server's clock
+    pub fn correct(&self, local: SystemTime) -> SystemTime {
+        if self.offset_secs >= 0 {
+            local + Duration::from_secs(self.offset_secs as u64)
+        } else {
+            local - Duration::from_secs(self.offset_secs.unsigned_abs())
+        }
+    }
+
+    /// Current time according to the server's clock
+    pub fn now(&self) -> SystemTime {
+        self.correct(SystemTime::now())
+    }
+
+    /// Record the server time observed at local time `local`, returning true if the offset
+    /// changed by more than the tolerance
+    pub fn observe(&mut self, server: SystemTime, local: SystemTime) -> bool {
+        let offset = match server.duration_since(local) {
+            Ok(ahead) => ahead.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        if (offset - self.offset_secs).unsigned_abs() > self.tolerance_secs {
+            self.offset_secs = offset;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Record the server time from the `Date` header of a response received at local time
+    /// `local`, returning true if the offset changed by more than the tolerance
+    pub fn observe_headers(&mut self, headers: &Headers, local: SystemTime) -> bool {
+        match headers.get::<Date>() {
+            Some(&Date(date)) => self.observe(SystemTime::from(date), local),
+            None => false,
+        }
+    }
+}
+
+/// Signs requests for APIs that authenticate with a signature over the request and a timestamp
+pub trait RequestSigner {
+    /// Add signature headers to `overrides` for a request sent at `timestamp` by the server's
+    /// clock
+    fn sign(&mut self, method: &Method, uri: &Uri, body: &[u8], timestamp: SystemTime,
+            overrides: &mut RequestOverrides) -> Result<()>;
+}
+
+/// Signed request flow that corrects for clock skew - implemented for every `ApiClient`
+pub trait SignedApiClient<HTTP>: ApiClient<HTTP> where HTTP: HttpClient {
+    /// Sign and send a request, using `skew` to correct the timestamp
+    ///
+    /// If the server rejects the request with a client error and its `Date` header shows the
+    /// clocks have drifted apart, the offset is updated and the request is signed and sent once
+    /// more with the corrected timestamp.
+    fn request_signed<S>(&mut self, signer: &mut S, skew: &mut ClockSkew, method: Method, uri: Uri,
+                         body: Option<Vec<u8>>) -> Result<Response> where S: RequestSigner {
+        let full_uri = self.full_uri(uri)?;
+        let body = body.unwrap_or_default();
+        let mut retried = false;
+        loop {
+            let mut overrides = RequestOverrides::new();
+            signer.sign(&method, &full_uri, &body, skew.now(), &mut overrides)?;
+            if !body.is_empty() {
+                overrides.set_body(body.clone());
+            }
+            let future = self.request_future_with_overrides(method.clone(), full_uri.clone(), overrides)?;
+            let response = self.response_future(future)?;
+            let received = SystemTime::now();
+            if retried || !response.status().is_client_error()
+                    || !skew.observe_headers(response.headers(), received) {
+                return Ok(response);
+            }
+            retried = true;
+        }
+    }
+}
+
+impl<HTTP, C> SignedApiClient<HTTP> for C where HTTP: HttpClient, C: ApiClient<HTTP> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::header::HttpDate;
+
+    #[test]
+    fn test_clock_skew_observe() {
+        let local = SystemTime::now();
+        let mut skew = ClockSkew::new();
+        assert!(!skew.observe(local + Duration::from_secs(3), local));
+        assert_eq!(skew.offset_secs(), 0);
+        assert!(skew.observe(local + Duration::from_secs(120), local));
+        assert_eq!(skew.offset_secs(), 120);
+        assert_eq!(skew.correct(local), local + Duration::from_secs(120));
+        assert!(skew.observe(local - Duration::from_secs(60), local));
+        assert_eq!(skew.correct(local), local - Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_clock_skew_from_date_header() {
+        let local = SystemTime::now();
+        let mut headers = Headers::new();
+        headers.set(Date(HttpDate::from(local + Duration::from_secs(600))));
+        let mut skew = ClockSkew::new().tolerance(Duration::from_secs(1));
+        assert!(skew.observe_headers(&headers, local));
+        assert!((skew.offset_secs() - 600).abs() <= 1);
+        assert!(!skew.observe_headers(&Headers::new(), local));
+    }
+}
@@ -0,0 +1,208 @@
+use std::fs;
+use std::path::{Path,PathBuf};
+
+use native_tls::{Certificate,Pkcs12,TlsConnector};
+use openssl::pkcs12::Pkcs12 as OpensslPkcs12;
+use openssl::pkey::PKey;
+use openssl::stack::Stack;
+use openssl::x509::X509;
+
+use *;
+
+/// Password protecting the in-memory PKCS #12 bundle handed to the TLS connector - the bundle
+/// never leaves the process so it only needs to be non-empty for some TLS backends
+const BUNDLE_PASSWORD: &str = "teatime";
+
+fn read(path: &Path, what: &str) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| ClientError::new(format!("Failed to read SVID {} {}: {}", what, path.display(), e)))
+}
+
+fn tls_error<E>(what: &str) -> impl FnOnce(E) -> ClientError where E: fmt::Display {
+    let what = what.to_string();
+    move |e| ClientError::new(format!("Invalid SVID {}: {}", what, e))
+}
+
+/// Every certificate in a PEM file, in order
+fn certs_from_pem(pem: &[u8], what: &str) -> Result<Vec<X509>> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    let text = str::from_utf8(pem).map_err(tls_error(what))?;
+    text.match_indices(BEGIN).map(|(start, _)| {
+        X509::from_pem(&pem[start..]).map_err(tls_error(what))
+    }).collect()
+}
+
+/// The SPIFFE ID in the URI subject alternative name of a DER encoded certificate
+///
+/// The URI name is a context specific IA5String (tag `0x86`), found by scanning for one that
+/// holds a `spiffe://` URI.
+fn spiffe_id_from_der(der: &[u8]) -> Option<String> {
+    const PREFIX: &[u8] = b"spiffe://";
+    (0..der.len().saturating_sub(2)).find_map(|i| {
+        if der[i] != 0x86 {
+            return None;
+        }
+        let (len, start) = match der[i + 1] {
+            len if len < 0x80 => (len as usize, i + 2),
+            0x81 => (*der.get(i + 2)? as usize, i + 3),
+            0x82 => ((*der.get(i + 2)? as usize) << 8 | *der.get(i + 3)? as usize, i + 4),
+            _ => return None,
+        };
+        let value = der.get(start..start + len)?;
+        if value.starts_with(PREFIX) {
+            String::from_utf8(value.to_vec()).ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// An X.509 SVID - the certificate and key identifying a workload in SPIFFE
+pub struct Svid {
+    spiffe_id: String,
+    identity: Vec<u8>,
+    bundle: Vec<Vec<u8>>,
+}
+
+impl fmt::Debug for Svid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Svid").field("spiffe_id", &self.spiffe_id).finish()
+    }
+}
+
+impl Svid {
+    /// The workload's SPIFFE ID, such as `spiffe://example.org/deploy`
+    pub fn spiffe_id(&self) -> &str {
+        &self.spiffe_id
+    }
+
+    /// TLS connector presenting this SVID as the client certificate and trusting the SPIFFE
+    /// trust bundle, if one was loaded, as well as the system roots
+    pub fn tls_connector(&self) -> Result<TlsConnector> {
+        let mut builder = TlsConnector::builder()?;
+        builder.identity(Pkcs12::from_der(&self.identity, BUNDLE_PASSWORD)?)?;
+        for cert in &self.bundle {
+            builder.add_root_certificate(Certificate::from_der(cert)?)?;
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Loads an X.509 SVID from PEM files, as written by `spiffe-helper` or a SPIRE agent sidecar,
+/// for environments where static tokens are not allowed
+///
+/// Talking to the SPIFFE workload API directly needs gRPC, which this crate does not include;
+/// run `spiffe-helper` to keep the files current instead. SVIDs are short lived, so `load`
+/// reads the files again each time it is called and clients should be rebuilt with a fresh
+/// SVID when it rotates. Requires OpenSSL to combine the certificate and key.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct SvidSource {
+    cert: PathBuf,
+    key: PathBuf,
+    bundle: Option<PathBuf>,
+}
+
+impl SvidSource {
+    /// SVID with its certificate chain in `cert` and private key in `key`
+    pub fn files<C, K>(cert: C, key: K) -> Self where C: Into<PathBuf>, K: Into<PathBuf> {
+        SvidSource { cert: cert.into(), key: key.into(), bundle: None }
+    }
+
+    /// Also trust the certificates of the SPIFFE trust bundle in `bundle` for servers
+    pub fn bundle<P>(mut self, bundle: P) -> Self where P: Into<PathBuf> {
+        self.bundle = Some(bundle.into());
+        self
+    }
+
+    /// Read the SVID files
+    pub fn load(&self) -> Result<Svid> {
+        let chain = certs_from_pem(&read(&self.cert, "certificate")?, "certificate")?;
+        let mut chain = chain.into_iter();
+        let leaf = chain.next().ok_or_else(|| ClientError::new(format!("No certificate in {}", self.cert.display())))?;
+        let key = PKey::private_key_from_pem(&read(&self.key, "key")?).map_err(tls_error("key"))?;
+        let leaf_der = leaf.to_der().map_err(tls_error("certificate"))?;
+        let spiffe_id = spiffe_id_from_der(&leaf_der).ok_or_else(|| {
+            ClientError::new(format!("Certificate in {} has no SPIFFE ID", self.cert.display()))
+        })?;
+
+        let mut intermediates = Stack::new().map_err(tls_error("certificate"))?;
+        for cert in chain {
+            intermediates.push(cert).map_err(tls_error("certificate"))?;
+        }
+        let mut builder = OpensslPkcs12::builder();
+        builder.ca(intermediates);
+        let identity = builder.build(BUNDLE_PASSWORD, &spiffe_id, &key, &leaf)
+            .and_then(|p| p.to_der()).map_err(tls_error("key"))?;
+
+        let bundle = match self.bundle {
+            Some(ref path) => certs_from_pem(&read(path, "trust bundle")?, "trust bundle")?
+                .iter().map(|c| c.to_der()).collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(tls_error("trust bundle"))?,
+            None => Vec::new(),
+        };
+        Ok(Svid { spiffe_id, identity, bundle })
+    }
+
+    /// Vault client presenting the SVID for mutual TLS and logged in with the cert auth method
+    /// mounted at `mount`, as `role` or whichever role matches the certificate
+    #[cfg(feature = "vault")]
+    pub fn vault_client(&self, api_uri: &str, mount: &str, role: Option<&str>) -> Result<::vault::VaultClient> {
+        let tls = self.load()?.tls_connector()?;
+        let mut vault = ::vault::VaultClient::builder(api_uri).http(|h| h.tls_connector(tls)).build()?;
+        vault.cert_login(mount, role)?;
+        Ok(vault)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::process;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::rsa::Rsa;
+    use openssl::x509::X509Name;
+    use openssl::x509::extension::SubjectAlternativeName;
+
+    use super::*;
+
+    fn self_signed(uri: &str) -> (Vec<u8>, Vec<u8>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "deploy").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let san = SubjectAlternativeName::new().uri(uri).build(&cert.x509v3_context(None, None)).unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        (cert.build().to_pem().unwrap(), key.private_key_to_pem().unwrap())
+    }
+
+    #[test]
+    fn test_svid_source() {
+        let dir = env::temp_dir().join(format!("teatime-spiffe-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = self_signed("spiffe://example.org/deploy");
+        fs::write(dir.join("svid.pem"), &cert).unwrap();
+        fs::write(dir.join("svid_key.pem"), &key).unwrap();
+        fs::write(dir.join("bundle.pem"), &cert).unwrap();
+
+        let source = SvidSource::files(dir.join("svid.pem"), dir.join("svid_key.pem")).bundle(dir.join("bundle.pem"));
+        let svid = source.load().unwrap();
+        assert_eq!(svid.spiffe_id(), "spiffe://example.org/deploy");
+        assert_eq!(svid.bundle.len(), 1);
+        svid.tls_connector().unwrap();
+
+        let (cert, _) = self_signed("https://example.org/");
+        fs::write(dir.join("svid.pem"), &cert).unwrap();
+        assert!(source.load().is_err());
+        assert!(SvidSource::files(dir.join("missing.pem"), dir.join("svid_key.pem")).load().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+use std::fs::{self,OpenOptions};
+use std::path::{Path,PathBuf};
+use std::sync::Mutex;
+
+use *;
+
+/// Namespace holding recorded responses - see `cache::ResponseCache`
+pub const RESPONSES: &str = "responses";
+/// Namespace holding saved tokens - see `auth::TokenCache`
+pub const TOKENS: &str = "tokens";
+/// Namespace holding pagination checkpoints - see `PipelineApiClient::pipe_pages_resumable`
+pub const CHECKPOINTS: &str = "checkpoints";
+
+/// Storage for state a client keeps between runs, such as tokens, recorded responses and
+/// pagination checkpoints
+///
+/// Values are opaque blobs addressed by a namespace and a key. Implement this to keep client
+/// state in a database or other shared storage; `FileStateStore` and `MemoryStateStore` are
+/// provided.
+pub trait StateStore: Send + Sync {
+    /// The value stored under `key` in `namespace`, if any
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>>;
+    /// Store `value` under `key` in `namespace`, replacing any earlier value
+    fn put(&self, namespace: &str, key: &str, value: &[u8]) -> Result<()>;
+    /// Remove the value under `key` in `namespace` - removing a missing value is not an error
+    fn delete(&self, namespace: &str, key: &str) -> Result<()>;
+}
+
+/// State kept in memory for the life of the process
+#[derive(Debug,Default)]
+pub struct MemoryStateStore {
+    values: Mutex<HashMap<(String, String), Vec<u8>>>,
+}
+
+impl MemoryStateStore {
+    /// Empty store
+    pub fn new() -> Self {
+        MemoryStateStore::default()
+    }
+}
+
+impl StateStore for MemoryStateStore {
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let values = self.values.lock().map_err(|_| ClientError::new("State store lock poisoned"))?;
+        Ok(values.get(&(namespace.to_string(), key.to_string())).cloned())
+    }
+
+    fn put(&self, namespace: &str, key: &str, value: &[u8]) -> Result<()> {
+        let mut values = self.values.lock().map_err(|_| ClientError::new("State store lock poisoned"))?;
+        values.insert((namespace.to_string(), key.to_string()), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, namespace: &str, key: &str) -> Result<()> {
+        let mut values = self.values.lock().map_err(|_| ClientError::new("State store lock poisoned"))?;
+        values.remove(&(namespace.to_string(), key.to_string()));
+        Ok(())
+    }
+}
+
+/// Bytes of a key in each file or directory name of a `FileStateStore`, keeping names within
+/// the limits of common file systems
+const KEY_BYTES_PER_NAME: usize = 64;
+
+/// State kept as one file per value under a directory, with a subdirectory per namespace
+///
+/// Files are named by the hex encoding of the key, so distinct keys never share a file even on
+/// case insensitive file systems. Keys longer than 64 bytes are split into nested directories.
+/// Namespaces must be plain directory names. Values are written to a temporary file and
+/// renamed into place, and on Unix are only readable by their owner.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct FileStateStore {
+    dir: PathBuf,
+}
+
+impl FileStateStore {
+    /// Store state under `dir`, which is created when the first value is written
+    pub fn new<P>(dir: P) -> Self where P: AsRef<Path> {
+        FileStateStore { dir: dir.as_ref().to_path_buf() }
+    }
+
+    /// Directory state is stored under
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, namespace: &str, key: &str) -> Result<PathBuf> {
+        if namespace.is_empty() || namespace == "." || namespace == ".."
+                || namespace.contains(&['/', '\\', '\0'][..]) {
+            return Err(ClientError::new(format!("Invalid state namespace {:?}", namespace)));
+        }
+        let mut path = self.dir.join(namespace);
+        let mut names = key.as_bytes().chunks(KEY_BYTES_PER_NAME).map(to_hex).collect::<Vec<_>>();
+        let file = names.pop().unwrap_or_default();
+        for name in names {
+            path.push(name + "+");
+        }
+        path.push(file + ".v");
+        Ok(path)
+    }
+}
+
+fn io_error(action: &str, path: &Path, e: io::Error) -> ClientError {
+    ClientError::new(format!("Failed to {} {}: {}", action, path.display(), e))
+}
+
+impl StateStore for FileStateStore {
+    fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(namespace, key)?;
+        match fs::read(&path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(io_error("read", &path, e)),
+        }
+    }
+
+    fn put(&self, namespace: &str, key: &str, value: &[u8]) -> Result<()> {
+        let path = self.path(namespace, key)?;
+        let parent = path.parent().unwrap_or(&self.dir);
+        fs::create_dir_all(parent).map_err(|e| io_error("create", parent, e))?;
+        let temp = path.with_extension("tmp");
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&temp).map_err(|e| io_error("create", &temp, e))?;
+        file.write_all(value)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| io_error("write", &temp, e))?;
+        fs::rename(&temp, &path).map_err(|e| io_error("replace", &path, e))
+    }
+
+    fn delete(&self, namespace: &str, key: &str) -> Result<()> {
+        let path = self.path(namespace, key)?;
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(io_error("remove", &path, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    fn round_trip(store: &dyn StateStore) {
+        assert_eq!(store.get(TOKENS, "vault").unwrap(), None);
+        store.put(TOKENS, "vault", b"s.abc").unwrap();
+        store.put(CHECKPOINTS, "vault", b"\n\x00binary").unwrap();
+        assert_eq!(store.get(TOKENS, "vault").unwrap(), Some(b"s.abc".to_vec()));
+        assert_eq!(store.get(CHECKPOINTS, "vault").unwrap(), Some(b"\n\x00binary".to_vec()));
+        store.put(TOKENS, "vault", b"s.def").unwrap();
+        assert_eq!(store.get(TOKENS, "vault").unwrap(), Some(b"s.def".to_vec()));
+        store.delete(TOKENS, "vault").unwrap();
+        store.delete(TOKENS, "vault").unwrap();
+        assert_eq!(store.get(TOKENS, "vault").unwrap(), None);
+        assert!(store.get(CHECKPOINTS, "vault").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_state_stores() {
+        round_trip(&MemoryStateStore::new());
+
+        let dir = env::temp_dir().join(format!("teatime-state-{}", process::id()));
+        let store = FileStateStore::new(&dir);
+        round_trip(&store);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let path = store.path(CHECKPOINTS, "vault").unwrap();
+            assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        // Keys differing only in case, keys of every length and the empty key get their own files
+        let long = "k".repeat(KEY_BYTES_PER_NAME);
+        let keys = ["Vault".to_string(), "vault".to_string(), String::new(), long.clone(), long.clone() + "k", "../x".to_string()];
+        for key in keys.iter() {
+            store.put(TOKENS, key, key.as_bytes()).unwrap();
+        }
+        for key in keys.iter() {
+            assert_eq!(store.get(TOKENS, key).unwrap(), Some(key.as_bytes().to_vec()));
+        }
+        assert_eq!(store.path(TOKENS, &long).unwrap(), dir.join(TOKENS).join(to_hex(long.as_bytes()) + ".v"));
+        for namespace in ["", ".", "..", "../tokens", "a/b", "a\\b"].iter() {
+            assert!(store.put(namespace, "vault", b"s.abc").is_err());
+            assert!(store.get(namespace, "vault").is_err());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
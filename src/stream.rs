@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+
+use hyper::Body;
+
+use *;
+
+/// Splits a JSON array arriving in pieces into its top level elements
+///
+/// Only the nesting of brackets, braces and strings is tracked; each element is fully parsed
+/// once it is complete, so memory use is bounded by the largest element rather than the whole
+/// array.
+#[derive(Debug,Default)]
+pub struct JsonArrayParser {
+    started: bool,
+    finished: bool,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    after_comma: bool,
+    current: Vec<u8>,
+}
+
+fn is_json_whitespace(b: u8) -> bool {
+    b == b' ' || b == b'\t' || b == b'\n' || b == b'\r'
+}
+
+impl JsonArrayParser {
+    /// Create a parser expecting the start of an array
+    pub fn new() -> Self {
+        JsonArrayParser::default()
+    }
+
+    /// True once the closing bracket of the array has been seen
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Feed the next piece of the body, returning the elements completed by it
+    pub fn push(&mut self, bytes: &[u8]) -> Result<Vec<Value>> {
+        let mut elements = Vec::new();
+        for &b in bytes {
+            if !self.started {
+                if b == b'[' {
+                    self.started = true;
+                } else if !is_json_whitespace(b) && b != 0xef && b != 0xbb && b != 0xbf {
+                    return Err(ClientError::new("Response body is not a JSON array"));
+                }
+            } else if self.finished {
+                if !is_json_whitespace(b) {
+                    return Err(ClientError::new("Unexpected data after the end of the JSON array"));
+                }
+            } else if self.in_string {
+                self.current.push(b);
+                if self.escaped {
+                    self.escaped = false;
+                } else if b == b'\\' {
+                    self.escaped = true;
+                } else if b == b'"' {
+                    self.in_string = false;
+                }
+            } else {
+                match b {
+                    b'"' => {
+                        self.in_string = true;
+                        self.current.push(b);
+                    },
+                    b'[' | b'{' => {
+                        self.depth += 1;
+                        self.current.push(b);
+                    },
+                    b']' | b'}' if self.depth > 0 => {
+                        self.depth -= 1;
+                        self.current.push(b);
+                    },
+                    b']' => {
+                        if !self.current.is_empty() || self.after_comma {
+                            elements.push(self.take_element()?);
+                        }
+                        self.finished = true;
+                    },
+                    b',' if self.depth == 0 => {
+                        elements.push(self.take_element()?);
+                        self.after_comma = true;
+                    },
+                    b if self.depth == 0 && self.current.is_empty() && is_json_whitespace(b) => (),
+                    b => self.current.push(b),
+                }
+            }
+        }
+        Ok(elements)
+    }
+
+    /// Check that the whole array was received
+    pub fn finish(&self) -> Result<()> {
+        if self.finished {
+            Ok(())
+        } else {
+            Err(ClientError::new("Response body ended before the end of the JSON array"))
+        }
+    }
+
+    fn take_element(&mut self) -> Result<Value> {
+        let element = serde_json::from_slice(&self.current)?;
+        self.current.clear();
+        self.after_comma = false;
+        Ok(element)
+    }
+}
+
+/// Iterator over the elements of a JSON array response, parsing each as soon as it has been
+/// downloaded
+pub struct JsonArrayStream<'a, HTTP> where HTTP: HttpClient + 'a {
+    client: &'a mut HTTP,
+    body: Option<Body>,
+    parser: JsonArrayParser,
+    pending: VecDeque<Value>,
+}
+
+impl<'a, HTTP> JsonArrayStream<'a, HTTP> where HTTP: HttpClient + 'a {
+    /// Stream the elements of the array in `response` using `client` to drive the download
+    pub fn new(client: &'a mut HTTP, response: Response) -> Self {
+        JsonArrayStream {
+            client,
+            body: Some(response.body()),
+            parser: JsonArrayParser::new(),
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<'a, HTTP> Iterator for JsonArrayStream<'a, HTTP> where HTTP: HttpClient + 'a {
+    type Item = Result<Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(element) = self.pending.pop_front() {
+                return Some(Ok(element));
+            }
+            let body = self.body.take()?;
+            let (chunk, rest) = match self.client.evaluate_future(body.into_future()) {
+                Ok(next) => next,
+                Err((e, _)) => return Some(Err(ClientError::from(e))),
+            };
+            match chunk {
+                Some(chunk) => {
+                    match self.parser.push(&chunk) {
+                        Ok(elements) => self.pending.extend(elements),
+                        Err(e) => return Some(Err(e)),
+                    }
+                    self.body = Some(rest);
+                },
+                None => return self.parser.finish().err().map(Err),
+            }
+        }
+    }
+}
+
+/// Streaming of large JSON array responses - implemented for every `JsonApiClient`
+pub trait StreamApiClient<HTTP>: JsonApiClient<HTTP> where HTTP: HttpClient {
+    /// Make an API request whose response is a JSON array and iterate over its elements as
+    /// they download, rather than waiting for and parsing the whole body
+    ///
+    /// The iteration ends after the first error. Autopagination is not performed. A
+    /// non-success status is returned as an error with the parsed error body.
+    fn request_json_array_stream<'a, B>(&'a mut self, method: Method, uri: Uri, body: Option<B>)
+            -> Result<JsonArrayStream<'a, HTTP>> where B: JsonBody, HTTP: 'a {
+        let body = encode_json_body(body)?;
+        let response = self.request(method, uri, body)?;
+        let status = response.status();
+        if !status.is_success() {
+            let message = match self.response_to_json(response) {
+                Ok(value) => format!("Request failed with status {}: {}", status, value),
+                Err(_) => format!("Request failed with status {}", status),
+            };
+            return Err(ClientError::new(message));
+        }
+        Ok(JsonArrayStream::new(self.http_client_mut(), response))
+    }
+}
+
+impl<HTTP, C> StreamApiClient<HTTP> for C where HTTP: HttpClient, C: JsonApiClient<HTTP> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testing::{Fixture,FixtureServer,client_for};
+
+    fn parse_in_pieces(body: &[u8], piece: usize) -> Result<Vec<Value>> {
+        let mut parser = JsonArrayParser::new();
+        let mut elements = Vec::new();
+        for chunk in body.chunks(piece) {
+            elements.extend(parser.push(chunk)?);
+        }
+        parser.finish()?;
+        Ok(elements)
+    }
+
+    #[test]
+    fn test_json_array_parser() {
+        let body = br#" [ {"name": "a]\"},", "tags": [1, [2]]}, "x,y", 3 , null, [] ]
+"#;
+        let expected: Vec<Value> = serde_json::from_slice(body).unwrap();
+        for piece in 1..body.len() {
+            assert_eq!(parse_in_pieces(body, piece).unwrap(), expected);
+        }
+        assert_eq!(parse_in_pieces(b"[]", 1).unwrap(), Vec::<Value>::new());
+        assert!(parse_in_pieces(b"{\"a\": 1}", 4).is_err());
+        assert!(parse_in_pieces(b"[1, 2", 4).is_err());
+        assert!(parse_in_pieces(b"[1, 2,]", 4).is_err());
+        assert!(parse_in_pieces(b"[1, 2] 3", 4).is_err());
+        assert!(parse_in_pieces(b"[1 2]", 4).is_err());
+    }
+
+    #[test]
+    fn test_json_array_stream() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/users", Fixture::json(200, r#"[{"id": 1}, {"id": 2, "name": "]"}, {"id": 3}]"#))
+            .route(Method::Get, "/user", Fixture::json(200, r#"{"id": 1}"#))
+            .route(Method::Get, "/denied", Fixture::json(403, r#"{"message": "403 Forbidden"}"#))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let ids: Vec<u64> = client.request_json_array_stream(Method::Get, "/users".parse().unwrap(), None::<Value>)
+            .unwrap().map(|user| user.unwrap()["id"].as_u64().unwrap()).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        let mut stream = client.request_json_array_stream(Method::Get, "/user".parse().unwrap(), None::<Value>)
+            .unwrap();
+        assert!(stream.next().unwrap().is_err());
+        let err = client.request_json_array_stream(Method::Get, "/denied".parse().unwrap(), None::<Value>)
+            .err().unwrap();
+        assert!(err.to_string().contains("403 Forbidden"));
+    }
+}
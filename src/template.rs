@@ -0,0 +1,105 @@
+use std::fmt::Display;
+
+use url::percent_encoding::{utf8_percent_encode,PATH_SEGMENT_ENCODE_SET};
+
+use *;
+
+/// A request path with named `{parameters}`, such as `/projects/{id}/issues/{iid}`
+///
+/// Each bound value is percent-encoded as a single path segment, so a value like
+/// `group/project` cannot change which endpoint is requested. Rendering fails if a parameter is
+/// left unbound or a value is bound to a name the template does not have.
+///
+/// ```
+/// # use teatime::template::Template;
+/// let uri = Template::new("/projects/{id}/issues/{iid}")
+///     .bind("id", "group/project")
+///     .bind("iid", 42)
+///     .to_uri()
+///     .unwrap();
+/// assert_eq!(uri.path(), "/projects/group%2Fproject/issues/42");
+/// ```
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Template {
+    template: String,
+    bindings: Vec<(String, String)>,
+}
+
+impl Template {
+    /// Create a template from a path containing `{name}` placeholders
+    pub fn new<S>(template: S) -> Self where S: Into<String> {
+        Template { template: template.into(), bindings: Vec::new() }
+    }
+
+    /// Substitute `value` for the `{name}` placeholder, replacing any earlier binding
+    pub fn bind<V>(mut self, name: &str, value: V) -> Self where V: Display {
+        let value = utf8_percent_encode(&value.to_string(), PATH_SEGMENT_ENCODE_SET).to_string();
+        self.bindings.retain(|b| b.0 != name);
+        self.bindings.push((name.to_string(), value));
+        self
+    }
+
+    /// Names of the placeholders in the template, in order
+    pub fn parameters(&self) -> Result<Vec<&str>> {
+        let mut parameters = Vec::new();
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').ok_or_else(|| {
+                ClientError::new(format!("Unclosed parameter in path template {}", self.template))
+            })?;
+            let name = &rest[start + 1..start + end];
+            if name.is_empty() || name.contains('{') {
+                return Err(ClientError::new(format!("Invalid parameter in path template {}", self.template)));
+            }
+            parameters.push(name);
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(ClientError::new(format!("Unopened parameter in path template {}", self.template)));
+        }
+        Ok(parameters)
+    }
+
+    /// Substitute every parameter and return the path
+    pub fn render(&self) -> Result<String> {
+        let parameters = self.parameters()?;
+        if let Some(unknown) = self.bindings.iter().find(|b| !parameters.contains(&b.0.as_str())) {
+            return Err(ClientError::new(format!("Path template {} has no parameter {}", self.template, unknown.0)));
+        }
+        let mut path = self.template.clone();
+        for name in parameters {
+            let value = self.bindings.iter().find(|b| b.0 == name).map(|b| &b.1).ok_or_else(|| {
+                ClientError::new(format!("Parameter {} of path template {} is not bound", name, self.template))
+            })?;
+            path = path.replacen(&format!("{{{}}}", name), value, 1);
+        }
+        Ok(path)
+    }
+
+    /// Substitute every parameter and parse the path as a URI
+    pub fn to_uri(&self) -> Result<Uri> {
+        Ok(self.render()?.parse::<Uri>()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_template_render() {
+        let template = Template::new("/projects/{id}/issues/{iid}/notes");
+        assert_eq!(template.parameters().unwrap(), vec!["id", "iid"]);
+        assert_eq!(template.clone().bind("id", "a b/c").bind("iid", 7).render().unwrap(),
+                   "/projects/a%20b%2Fc/issues/7/notes");
+        assert_eq!(template.clone().bind("id", 1).bind("id", 2).bind("iid", 3).render().unwrap(),
+                   "/projects/2/issues/3/notes");
+
+        assert!(template.clone().bind("id", 1).render().is_err());
+        assert!(template.bind("id", 1).bind("iid", 2).bind("idd", 3).render().is_err());
+        assert!(Template::new("/projects/{id").render().is_err());
+        assert!(Template::new("/projects/id}").render().is_err());
+        assert!(Template::new("/projects/{}").render().is_err());
+        assert_eq!(Template::new("/version").to_uri().unwrap().path(), "/version");
+    }
+}
@@ -0,0 +1,333 @@
+use std::io::{self,Read,Write};
+use std::thread;
+
+use futures::{Async,Future,Poll};
+use futures::sync::oneshot;
+
+use secret::Secret;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const CTRL_U: u8 = 0x15;
+
+/// Remove the whitespace and line endings that surround text pasted from a browser or
+/// password manager
+pub fn clean_pasted(text: &str) -> &str {
+    text.trim_matches(|c: char| c.is_whitespace())
+}
+
+fn is_continuation(byte: u8) -> bool {
+    byte & 0xc0 == 0x80
+}
+
+/// Line editor behind masked input, fed with whatever bytes each read of the terminal returns
+#[derive(Debug,Default)]
+struct MaskedLine {
+    bytes: Vec<u8>,
+    pasted: bool,
+}
+
+impl MaskedLine {
+    /// Handle one read of input, echoing to `echo`, and return true once the line is complete
+    ///
+    /// A read returning several characters at once is a paste rather than typing, since a
+    /// person cannot type faster than the terminal is read.
+    fn feed<W>(&mut self, chunk: &[u8], echo: &mut W) -> io::Result<bool> where W: Write {
+        if chunk.iter().filter(|b| !is_continuation(**b)).count() > 1 {
+            self.pasted = true;
+        }
+        for &byte in chunk {
+            match byte {
+                b'\r' | b'\n' => {
+                    echo.write_all(b"\n")?;
+                    echo.flush()?;
+                    return Ok(true);
+                },
+                CTRL_C => return Err(io::Error::new(io::ErrorKind::Interrupted, "Input cancelled")),
+                CTRL_D if self.bytes.is_empty() => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "No input given"));
+                },
+                BACKSPACE | DELETE => {
+                    while self.bytes.pop().is_some_and(is_continuation) {}
+                    echo.write_all(b"\x08 \x08")?;
+                },
+                CTRL_U => {
+                    let chars = self.bytes.iter().filter(|b| !is_continuation(**b)).count();
+                    for _ in 0..chars {
+                        echo.write_all(b"\x08 \x08")?;
+                    }
+                    self.bytes.clear();
+                },
+                byte if byte < 0x20 => (),
+                byte => {
+                    if !is_continuation(byte) {
+                        echo.write_all(b"*")?;
+                    }
+                    self.bytes.push(byte);
+                },
+            }
+        }
+        echo.flush()?;
+        Ok(false)
+    }
+
+    fn finish(self) -> io::Result<Secret> {
+        let text = String::from_utf8(self.bytes)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Input is not valid UTF-8"))?;
+        Ok(Secret::from(text))
+    }
+}
+
+fn read_masked_from<R, W>(input: &mut R, echo: &mut W) -> io::Result<(Secret, bool)> where R: Read, W: Write {
+    let mut line = MaskedLine::default();
+    let mut buffer = [0; 512];
+    loop {
+        let read = input.read(&mut buffer)?;
+        if read == 0 {
+            if line.bytes.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "No input given"));
+            }
+            break;
+        }
+        if line.feed(&buffer[..read], echo)? {
+            break;
+        }
+    }
+    let pasted = line.pasted;
+    Ok((line.finish()?, pasted))
+}
+
+#[cfg(unix)]
+mod raw {
+    use std::io::{self,Read};
+    use std::mem;
+
+    use libc::{self,termios,ECHO,ICANON,ISIG,STDIN_FILENO,TCSANOW,VMIN,VTIME};
+
+    /// Puts the terminal in non-canonical mode without echo or signals until dropped, so
+    /// `Ctrl-C` arrives as input and the terminal is restored before the prompt fails
+    pub struct RawMode {
+        original: termios,
+    }
+
+    impl RawMode {
+        /// Switch to raw mode, or `None` if standard input is not a terminal
+        pub fn enable() -> io::Result<Option<Self>> {
+            if unsafe { libc::isatty(STDIN_FILENO) } != 1 {
+                return Ok(None);
+            }
+            let mut original: termios = unsafe { mem::zeroed() };
+            if unsafe { libc::tcgetattr(STDIN_FILENO, &mut original) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = original;
+            raw.c_lflag &= !(ECHO | ICANON | ISIG);
+            raw.c_cc[VMIN] = 1;
+            raw.c_cc[VTIME] = 0;
+            if unsafe { libc::tcsetattr(STDIN_FILENO, TCSANOW, &raw) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Some(RawMode { original }))
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            unsafe { libc::tcsetattr(STDIN_FILENO, TCSANOW, &self.original) };
+        }
+    }
+
+    /// Unbuffered standard input, so each read returns only what has been typed or pasted
+    pub struct Stdin;
+
+    impl Read for Stdin {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = unsafe { libc::read(STDIN_FILENO, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if read < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(read as usize)
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod raw {
+    use std::io::{self,Read};
+
+    use winapi::shared::minwindef::DWORD;
+    use winapi::um::consoleapi::{GetConsoleMode,SetConsoleMode};
+    use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+    use winapi::um::processenv::GetStdHandle;
+    use winapi::um::winbase::STD_INPUT_HANDLE;
+    use winapi::um::winnt::HANDLE;
+    use winapi::um::wincon::{ENABLE_ECHO_INPUT,ENABLE_LINE_INPUT,ENABLE_PROCESSED_INPUT};
+
+    /// Turns off line input, echo and `Ctrl-C` handling on the console until dropped - Enter
+    /// then arrives as `\r` and Backspace as `0x08`
+    pub struct RawMode {
+        handle: HANDLE,
+        original: DWORD,
+    }
+
+    impl RawMode {
+        /// Switch to raw mode, or `None` if standard input is not a console
+        pub fn enable() -> io::Result<Option<Self>> {
+            let handle = unsafe { GetStdHandle(STD_INPUT_HANDLE) };
+            if handle.is_null() || handle == INVALID_HANDLE_VALUE {
+                return Ok(None);
+            }
+            let mut original = 0;
+            if unsafe { GetConsoleMode(handle, &mut original) } == 0 {
+                // Redirected input is not a console
+                return Ok(None);
+            }
+            let raw = original & !(ENABLE_ECHO_INPUT | ENABLE_LINE_INPUT | ENABLE_PROCESSED_INPUT);
+            if unsafe { SetConsoleMode(handle, raw) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Some(RawMode { handle, original }))
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            unsafe { SetConsoleMode(self.handle, self.original) };
+        }
+    }
+
+    /// Console input, which the standard library decodes from UTF-16 as it is read
+    pub struct Stdin;
+
+    impl Read for Stdin {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            io::stdin().read(buf)
+        }
+    }
+}
+
+/// Prompt for sensitive text, echoing a `*` for each character typed so the operator can see
+/// that typing or pasting worked without revealing the text
+///
+/// Backspace and `Ctrl-U` edit the input and `Ctrl-C` cancels it. Surrounding whitespace and
+/// line endings are removed from pasted input, since they are almost never part of a token.
+/// This works in Unix terminals and Windows consoles. When standard input is not a terminal,
+/// or on other platforms, a line is read without echo.
+pub fn read_masked(prompt: &str) -> io::Result<Secret> {
+    let mut stdout = io::stdout();
+    write!(stdout, "{}", prompt)?;
+    stdout.flush()?;
+    #[cfg(any(unix, windows))]
+    {
+        if let Some(_raw) = raw::RawMode::enable()? {
+            let (secret, pasted) = read_masked_from(&mut raw::Stdin, &mut stdout)?;
+            return Ok(if pasted { Secret::from(clean_pasted(secret.expose())) } else { secret });
+        }
+    }
+    let line = ::rpassword::read_password()?;
+    Ok(Secret::from(line.trim_end_matches(['\r', '\n'])))
+}
+
+/// Answer to a prompt read on a helper thread - see `prompt_async`
+#[must_use = "futures do nothing unless polled"]
+pub struct PromptFuture<T> {
+    receiver: oneshot::Receiver<io::Result<T>>,
+}
+
+impl<T> Future for PromptFuture<T> {
+    type Item = T;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<T, io::Error> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(answer)) => answer.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(io::Error::other("Prompt thread ended without an answer")),
+        }
+    }
+}
+
+/// Run the blocking prompt `read` on a helper thread, resolving with its answer
+///
+/// Waiting on the future rather than calling `read` directly keeps the event loop free, so
+/// requests already in flight on the same `Core` - for example `HttpClient::evaluate_future`
+/// on a future joined with the prompt - continue while the operator types.
+pub fn prompt_async<F, T>(read: F) -> PromptFuture<T> where F: FnOnce() -> io::Result<T> + Send + 'static,
+                                                          T: Send + 'static {
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = sender.send(read());
+    });
+    PromptFuture { receiver }
+}
+
+/// `interactive_text` without blocking the event loop
+pub fn read_line_async(prompt: &str) -> PromptFuture<String> {
+    let prompt = prompt.to_string();
+    prompt_async(move || ::interactive_text(&prompt))
+}
+
+/// `read_masked` without blocking the event loop
+pub fn read_masked_async(prompt: &str) -> PromptFuture<Secret> {
+    let prompt = prompt.to_string();
+    prompt_async(move || read_masked(&prompt))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Chunks(Vec<&'static [u8]>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let chunk = self.0.remove(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn test_masked_input() {
+        let mut echo = Vec::new();
+        let (secret, pasted) = read_masked_from(&mut Chunks(vec![b"a", b"b", b"\x7f", "é".as_bytes(), b"\r"]), &mut echo).unwrap();
+        assert_eq!(secret.expose(), "aé");
+        assert!(!pasted);
+        assert_eq!(echo, b"**\x08 \x08*\n".to_vec());
+
+        let (secret, pasted) = read_masked_from(&mut Chunks(vec![b"x", b"\x15", b" glpat-token \n"]), &mut Vec::new()).unwrap();
+        assert!(pasted);
+        assert_eq!(clean_pasted(secret.expose()), "glpat-token");
+
+        assert_eq!(read_masked_from(&mut Chunks(vec![b"ab", b"\x03"]), &mut Vec::new()).unwrap_err().kind(),
+                   io::ErrorKind::Interrupted);
+        assert_eq!(read_masked_from(&mut Chunks(vec![]), &mut Vec::new()).unwrap_err().kind(),
+                   io::ErrorKind::UnexpectedEof);
+        assert_eq!(read_masked_from(&mut Chunks(vec![b"abc"]), &mut Vec::new()).unwrap().0.expose(), "abc");
+    }
+
+    #[test]
+    fn test_prompt_async() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+        use tokio_core::reactor::{Core,Timeout};
+
+        // The prompt only answers once the reactor has run a timer, so this deadlocks if
+        // waiting on the prompt blocks the reactor
+        let mut core = Core::new().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let prompt = prompt_async(move || receiver.recv().map_err(|_| io::Error::other("closed")));
+        let timer = Timeout::new(Duration::from_millis(10), &core.handle()).unwrap()
+            .map(move |_| sender.send("web-1".to_string()).unwrap());
+        assert_eq!(core.run(prompt.join(timer)).unwrap().0, "web-1");
+
+        let failed = prompt_async(|| Err::<(), _>(io::Error::new(io::ErrorKind::UnexpectedEof, "closed")));
+        assert_eq!(core.run(failed).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,631 @@
+use std::collections::HashMap;
+use std::io::{BufRead,BufReader,Read,Write};
+use std::net::{Shutdown,SocketAddr,TcpListener,TcpStream};
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::thread::{self,JoinHandle};
+use std::time::Duration;
+
+use *;
+
+/// A canned HTTP response served by `FixtureServer`
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Fixture {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    delay: Option<Duration>,
+}
+
+impl Fixture {
+    /// Empty response with the given status code
+    pub fn new(status: u16) -> Self {
+        Fixture { status, headers: Vec::new(), body: Vec::new(), delay: None }
+    }
+
+    /// JSON response with the given status code
+    pub fn json<S>(status: u16, body: S) -> Self where S: Into<String> {
+        Fixture::new(status).header("Content-Type", "application/json").body(body.into())
+    }
+
+    /// Add a response header
+    pub fn header<N, V>(mut self, name: N, value: V) -> Self where N: Into<String>, V: Into<String> {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Set the response body
+    pub fn body<B>(mut self, body: B) -> Self where B: Into<Vec<u8>> {
+        self.body = body.into();
+        self
+    }
+
+    /// Wait for `delay` before responding, to simulate a slow server
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+}
+
+/// A request received by `FixtureServer`
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct RecordedRequest {
+    /// Request method
+    pub method: String,
+    /// Request path including any query string
+    pub path: String,
+    /// Request headers with lowercase names, in the order received
+    pub headers: Vec<(String, String)>,
+    /// Request body
+    pub body: Vec<u8>,
+}
+
+impl RecordedRequest {
+    /// Get the first value of a header by case insensitive name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers.iter().find(|&(n, _)| *n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// Request path without the query string
+    pub fn path_only(&self) -> &str {
+        self.path.split('?').next().unwrap_or("")
+    }
+}
+
+fn matches_path(request: &RecordedRequest, path: &str) -> bool {
+    if path.contains('?') {
+        request.path == path
+    } else {
+        request.path_only() == path
+    }
+}
+
+fn json_contains(actual: &Value, expected: &Value) -> bool {
+    match (actual, expected) {
+        (Value::Object(actual), Value::Object(expected)) => {
+            expected.iter().all(|(k, v)| actual.get(k).is_some_and(|a| json_contains(a, v)))
+        },
+        _ => actual == expected,
+    }
+}
+
+/// Shared log of the requests received by a `FixtureServer`, with fluent assertions for
+/// verifying how API clients build their requests
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate teatime;
+/// # use hyper::Method;
+/// # use teatime::testing::{Fixture,FixtureServer};
+/// # fn main() {
+/// let server = FixtureServer::builder()
+///     .route(Method::Post, "/v1/auth/ldap/login/me", Fixture::json(200, "{}"))
+///     .start().unwrap();
+/// // ... point a client at `server.base_uri()` and log in ...
+/// server.assert_sent().none(Method::Post, "/v1/auth/ldap/login/me");
+/// # }
+/// ```
+#[derive(Clone,Debug,Default)]
+pub struct RequestRecorder {
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl RequestRecorder {
+    /// Create an empty recorder
+    pub fn new() -> Self {
+        RequestRecorder::default()
+    }
+
+    /// Record a request
+    pub fn record(&self, request: RecordedRequest) {
+        self.requests.lock().unwrap_or_else(|e| e.into_inner()).push(request);
+    }
+
+    /// All requests recorded so far, in order
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Forget all recorded requests
+    pub fn clear(&self) {
+        self.requests.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    /// Start asserting on the recorded requests
+    pub fn assert_sent(&self) -> SentRequests {
+        SentRequests { requests: self.requests() }
+    }
+}
+
+/// Assertions selecting a recorded request by method and path
+///
+/// Paths containing a query string must match exactly; other paths ignore the query. Each
+/// selector panics if no matching request was sent.
+pub struct SentRequests {
+    requests: Vec<RecordedRequest>,
+}
+
+impl SentRequests {
+    /// Assert exactly `count` requests were sent
+    pub fn count(self, count: usize) -> Self {
+        assert_eq!(self.requests.len(), count, "Unexpected number of requests sent: {:?}",
+                   self.summary());
+        self
+    }
+
+    /// Assert a request with `method` was sent to `path`, returning the first one
+    pub fn request(self, method: Method, path: &str) -> SentRequest {
+        let method = method.to_string();
+        let summary = self.summary();
+        self.requests.into_iter().find(|r| r.method == method && matches_path(r, path))
+            .map(|request| SentRequest { request })
+            .unwrap_or_else(|| panic!("No {} {} request was sent, got {:?}", method, path, summary))
+    }
+
+    /// Assert a `GET` request was sent to `path`
+    pub fn get(self, path: &str) -> SentRequest {
+        self.request(Method::Get, path)
+    }
+
+    /// Assert a `POST` request was sent to `path`
+    pub fn post(self, path: &str) -> SentRequest {
+        self.request(Method::Post, path)
+    }
+
+    /// Assert a `PUT` request was sent to `path`
+    pub fn put(self, path: &str) -> SentRequest {
+        self.request(Method::Put, path)
+    }
+
+    /// Assert a `PATCH` request was sent to `path`
+    pub fn patch(self, path: &str) -> SentRequest {
+        self.request(Method::Patch, path)
+    }
+
+    /// Assert a `DELETE` request was sent to `path`
+    pub fn delete(self, path: &str) -> SentRequest {
+        self.request(Method::Delete, path)
+    }
+
+    /// Assert no request with `method` was sent to `path`
+    pub fn none(self, method: Method, path: &str) -> Self {
+        let method = method.to_string();
+        assert!(!self.requests.iter().any(|r| r.method == method && matches_path(r, path)),
+                "Unexpected {} {} request was sent", method, path);
+        self
+    }
+
+    fn summary(&self) -> Vec<String> {
+        self.requests.iter().map(|r| format!("{} {}", r.method, r.path)).collect()
+    }
+}
+
+/// Assertions on a single recorded request
+pub struct SentRequest {
+    request: RecordedRequest,
+}
+
+impl SentRequest {
+    /// The recorded request
+    pub fn request(&self) -> &RecordedRequest {
+        &self.request
+    }
+
+    /// Assert the request carried header `name` with `value`
+    pub fn header(self, name: &str, value: &str) -> Self {
+        assert_eq!(self.request.header(name), Some(value), "Unexpected value for header {} on {} {}",
+                   name, self.request.method, self.request.path);
+        self
+    }
+
+    /// Assert the request did not carry header `name`
+    pub fn no_header(self, name: &str) -> Self {
+        assert_eq!(self.request.header(name), None, "Unexpected header {} on {} {}",
+                   name, self.request.method, self.request.path);
+        self
+    }
+
+    /// Assert the request body is exactly `body`
+    pub fn body<B>(self, body: B) -> Self where B: AsRef<[u8]> {
+        assert_eq!(String::from_utf8_lossy(&self.request.body), String::from_utf8_lossy(body.as_ref()),
+                   "Unexpected body on {} {}", self.request.method, self.request.path);
+        self
+    }
+
+    /// Assert the request body is JSON containing `expected` - objects may have extra keys at
+    /// any depth but other values must match exactly
+    pub fn json_body_contains(self, expected: Value) -> Self {
+        let actual: Value = serde_json::from_slice(&self.request.body).unwrap_or_else(|e| {
+            panic!("Body of {} {} is not JSON: {}", self.request.method, self.request.path, e)
+        });
+        assert!(json_contains(&actual, &expected), "Body of {} {} is {} which does not contain {}",
+                self.request.method, self.request.path, actual, expected);
+        self
+    }
+}
+
+#[derive(Default)]
+struct ServerState {
+    routes: HashMap<(String, String), Vec<Fixture>>,
+    recorder: RequestRecorder,
+}
+
+impl ServerState {
+    fn respond(&mut self, request: &RecordedRequest) -> Fixture {
+        let exact = (request.method.clone(), request.path.clone());
+        let key = if self.routes.contains_key(&exact) {
+            exact
+        } else {
+            (request.method.clone(), request.path_only().to_string())
+        };
+        match self.routes.get_mut(&key) {
+            Some(ref mut queue) if queue.len() > 1 => queue.remove(0),
+            Some(queue) => queue[0].clone(),
+            None => Fixture::new(404).body(format!("No fixture for {} {}", request.method, request.path)),
+        }
+    }
+}
+
+/// Builder for a `FixtureServer`
+#[derive(Default)]
+pub struct FixtureServerBuilder {
+    state: ServerState,
+}
+
+impl FixtureServerBuilder {
+    /// Serve `fixture` for requests matching `method` and `path`
+    ///
+    /// Paths containing a query string only match requests with that exact query string; other
+    /// paths match regardless of the query. Adding several fixtures for the same route serves
+    /// them in order, repeating the last one once the others are used up.
+    pub fn route<S>(mut self, method: Method, path: S, fixture: Fixture) -> Self where S: Into<String> {
+        self.state.routes.entry((method.to_string(), path.into())).or_default().push(fixture);
+        self
+    }
+
+    /// Bind to a random localhost port and start serving
+    pub fn start(self) -> Result<FixtureServer> {
+        self.start_on("127.0.0.1:0")
+    }
+
+    /// Bind to `addr`, such as `[::1]:0` to test IPv6 literals in base URIs, and start serving
+    pub fn start_on(self, addr: &str) -> Result<FixtureServer> {
+        let listener = TcpListener::bind(addr).map_err(io_error)?;
+        let addr = listener.local_addr().map_err(io_error)?;
+        let state = Arc::new(Mutex::new(self.state));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_state = Arc::clone(&state);
+        let thread_stopped = Arc::clone(&stopped);
+        let handle = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    let connection_state = Arc::clone(&thread_state);
+                    thread::spawn(move || handle_connection(stream, &connection_state));
+                }
+            }
+        });
+        Ok(FixtureServer { addr, state, stopped, handle: Some(handle) })
+    }
+}
+
+fn io_error(e: io::Error) -> ClientError {
+    ClientError::new(format!("Fixture server error: {}", e))
+}
+
+fn read_request(reader: &mut BufReader<TcpStream>) -> io::Result<Option<RecordedRequest>> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let path = parts.next().unwrap_or("").to_string();
+    let mut headers = Vec::new();
+    let mut content_length = 0;
+    let mut chunked = false;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        if let Some(colon) = line.find(':') {
+            let name = line[..colon].trim().to_lowercase();
+            let value = line[colon + 1..].trim().to_string();
+            if name == "content-length" {
+                content_length = value.parse().unwrap_or(0);
+            } else if name == "transfer-encoding" {
+                chunked = value.eq_ignore_ascii_case("chunked");
+            }
+            headers.push((name, value));
+        }
+    }
+    let body = if chunked {
+        read_chunked(reader)?
+    } else {
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+        body
+    };
+    Ok(Some(RecordedRequest { method, path, headers, body }))
+}
+
+fn read_chunked(reader: &mut BufReader<TcpStream>) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let mut size_line = String::new();
+        reader.read_line(&mut size_line)?;
+        let size = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid chunk size"))?;
+        if size == 0 {
+            // Skip any trailers up to the terminating empty line
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                    return Ok(body);
+                }
+            }
+        }
+        // Chunk data is followed by CRLF
+        let mut chunk = vec![0; size + 2];
+        reader.read_exact(&mut chunk)?;
+        body.extend_from_slice(&chunk[..size]);
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: &Arc<Mutex<ServerState>>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+    stream.set_nodelay(true)?;
+    while let Some(request) = read_request(&mut reader)? {
+        let fixture = {
+            let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+            let fixture = state.respond(&request);
+            state.recorder.record(request);
+            fixture
+        };
+        if let Some(delay) = fixture.delay {
+            thread::sleep(delay);
+        }
+        let mut response = format!("HTTP/1.1 {} Fixture\r\n", fixture.status);
+        for (name, value) in &fixture.headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str(&format!("Content-Length: {}\r\n\r\n", fixture.body.len()));
+        // Send the head and body together so small responses are not held back by Nagle
+        let mut response = response.into_bytes();
+        response.extend_from_slice(&fixture.body);
+        stream.write_all(&response)?;
+        stream.flush()?;
+    }
+    Ok(())
+}
+
+/// Embedded HTTP server bound to localhost serving canned responses, for tests and examples
+/// exercising API clients end to end
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate teatime;
+/// # use hyper::Method;
+/// # use teatime::testing::{Fixture,FixtureServer};
+/// # fn main() {
+/// let server = FixtureServer::builder()
+///     .route(Method::Get, "/v1/sys/health", Fixture::json(200, r#"{"sealed": false}"#))
+///     .start().unwrap();
+/// assert!(server.base_uri().starts_with("http://127.0.0.1:"));
+/// # }
+/// ```
+///
+/// The server stops when dropped.
+pub struct FixtureServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<ServerState>>,
+    stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl FixtureServer {
+    /// Start configuring a server
+    pub fn builder() -> FixtureServerBuilder {
+        FixtureServerBuilder::default()
+    }
+
+    /// Serve `fixture` for requests matching `method` and `path` on a running server, with the
+    /// same matching rules as `FixtureServerBuilder::route` - useful for fixtures that link
+    /// back to the server
+    pub fn add_route<S>(&self, method: Method, path: S, fixture: Fixture) where S: Into<String> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.routes.entry((method.to_string(), path.into())).or_default().push(fixture);
+    }
+
+    /// Base URI of the server, without a trailing slash
+    pub fn base_uri(&self) -> String {
+        // `SocketAddr` formats IPv6 addresses in brackets, as URIs need
+        format!("http://{}", self.addr)
+    }
+
+    /// Build a full URI for a path on the server
+    pub fn uri(&self, path: &str) -> String {
+        format!("{}/{}", self.base_uri(), path.trim_start_matches('/'))
+    }
+
+    /// All requests received so far, in order
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.recorder().requests()
+    }
+
+    /// Handle to the log of received requests, which stays valid after the server stops
+    pub fn recorder(&self) -> RequestRecorder {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).recorder.clone()
+    }
+
+    /// Start asserting on the requests received so far
+    pub fn assert_sent(&self) -> SentRequests {
+        self.recorder().assert_sent()
+    }
+}
+
+impl Drop for FixtureServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake the accept loop so it sees the stop flag. If it cannot be woken the thread is
+        // left to stop at its next connection rather than blocking the drop forever.
+        match TcpStream::connect_timeout(&self.addr, Duration::from_secs(1)) {
+            Ok(stream) => {
+                let _ = stream.shutdown(Shutdown::Both);
+                if let Some(handle) = self.handle.take() {
+                    let _ = handle.join();
+                }
+            }
+            Err(_) => drop(self.handle.take()),
+        }
+    }
+}
+
+/// Minimal JSON API client sending plain requests to a `FixtureServer`, for the crate's own
+/// tests of the generic client traits
+#[cfg(test)]
+pub struct TestClient {
+    /// Base URI requests are resolved against
+    pub base_uri: Uri,
+    /// HTTP client the requests are sent with
+    pub client: SimpleHttpClient,
+}
+
+#[cfg(test)]
+impl TestClient {
+    /// Send requests with `http` in place of a default `SimpleHttpClient`
+    pub fn with_http(self, http: SimpleHttpClient) -> Self {
+        TestClient { client: http, ..self }
+    }
+}
+
+#[cfg(test)]
+impl Transport for TestClient {
+    type Http = SimpleHttpClient;
+}
+
+#[cfg(test)]
+impl ApiClient<SimpleHttpClient> for TestClient {
+    fn base_uri(&self) -> &Uri {
+        &self.base_uri
+    }
+
+    fn http_client(&self) -> &SimpleHttpClient {
+        &self.client
+    }
+
+    fn http_client_mut(&mut self) -> &mut SimpleHttpClient {
+        &mut self.client
+    }
+
+    fn login(&mut self, _creds: &ApiCredentials) -> Result<()> {
+        Ok(())
+    }
+
+    fn request_future<B>(&mut self, method: Method, uri: Uri, body: Option<B>)
+            -> Option<FutureResponse> where B: ToString {
+        let full_uri = self.full_uri(uri).ok()?;
+        let client = self.http_client_mut();
+        client.start_request(method, full_uri);
+        if let Some(b) = body {
+            client.add_body_bytes(b.to_string());
+        }
+        client.make_request().future()
+    }
+}
+
+#[cfg(test)]
+impl JsonApiClient<SimpleHttpClient> for TestClient {
+    fn next_page_uri(&mut self, _response: &Response) -> Result<Option<Uri>> {
+        Ok(None)
+    }
+}
+
+/// A `TestClient` for `server` with a default `SimpleHttpClient`
+#[cfg(test)]
+pub fn client_for(server: &FixtureServer) -> TestClient {
+    TestClient { base_uri: server.base_uri().parse().unwrap(), client: SimpleHttpClient::new().unwrap() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fixture_server_routes() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/status", Fixture::new(503))
+            .route(Method::Get, "/status", Fixture::json(200, "{}"))
+            .route(Method::Post, "/echo", Fixture::new(201).header("X-Fixture", "yes"))
+            .start().unwrap();
+        let mut client = client_for(&server);
+        let statuses: Vec<u16> = (0..3).map(|_| {
+            u16::from(client.request(Method::Get, "/status?x=1".parse().unwrap(), None::<String>)
+                      .unwrap().status())
+        }).collect();
+        assert_eq!(statuses, vec![503, 200, 200]);
+
+        let response = client.request(Method::Post, "/echo".parse().unwrap(), Some("hello")).unwrap();
+        assert_eq!(u16::from(response.status()), 201);
+        assert_eq!(response.headers().get_raw("X-Fixture").and_then(|r| r.one()), Some(&b"yes"[..]));
+        let missing = client.request(Method::Delete, "/echo".parse().unwrap(), None::<String>).unwrap();
+        assert_eq!(u16::from(missing.status()), 404);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 5);
+        assert_eq!(requests[3].body, b"hello".to_vec());
+        assert_eq!(requests[3].path_only(), "/echo");
+    }
+
+    fn recorded(method: &str, path: &str, body: &str) -> RecordedRequest {
+        RecordedRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: vec![("x-vault-token".to_string(), "s.abc".to_string())],
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_request_recorder_assertions() {
+        let recorder = RequestRecorder::new();
+        recorder.record(recorded("POST", "/v1/auth/ldap/login/me",
+                                 r#"{"password": "pw", "opts": {"a": 1, "b": [1, 2]}}"#));
+        recorder.record(recorded("GET", "/v1/secret/x?version=2", ""));
+        recorder.assert_sent().count(2)
+            .post("/v1/auth/ldap/login/me")
+            .header("X-Vault-Token", "s.abc")
+            .no_header("Authorization")
+            .json_body_contains(serde_json::from_str(r#"{"opts": {"b": [1, 2]}}"#).unwrap());
+        recorder.assert_sent().get("/v1/secret/x").body("");
+        recorder.assert_sent().get("/v1/secret/x?version=2");
+        recorder.assert_sent().none(Method::Get, "/v1/secret/x?version=1")
+            .none(Method::Delete, "/v1/secret/x");
+        recorder.clear();
+        assert!(recorder.requests().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "does not contain")]
+    fn test_json_body_contains_mismatch() {
+        let recorder = RequestRecorder::new();
+        recorder.record(recorded("POST", "/login", r#"{"opts": {"b": [1, 2]}}"#));
+        recorder.assert_sent().post("/login").json_body_contains(serde_json::from_str(r#"{"opts": {"b": [1]}}"#).unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "No PUT /login request was sent")]
+    fn test_assert_sent_missing_request() {
+        RequestRecorder::new().assert_sent().put("/login");
+    }
+}
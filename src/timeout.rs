@@ -0,0 +1,179 @@
+use futures::{Async,Poll};
+use futures::future;
+use tokio_core::net::TcpStream;
+use tokio_io::{AsyncRead,AsyncWrite};
+
+use *;
+
+/// Limits on how long a `SimpleHttpClient` waits on a request, so a hung endpoint fails the
+/// request instead of blocking the event loop forever
+///
+/// Each limit is off while `None`. `HostConfig::timeout` and `RequestOptions::timeout` take
+/// precedence over `total` for their hosts and requests.
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub struct Timeouts {
+    /// Longest wait for a connection, including the host lookup
+    pub connect: Option<Duration>,
+    /// Longest wait for more data on an open connection, covering response bodies as well as
+    /// headers - idle pooled connections are closed once it passes
+    pub read: Option<Duration>,
+    /// Longest wait from sending a request to receiving its response headers
+    pub total: Option<Duration>,
+}
+
+impl Timeouts {
+    /// No limits
+    pub fn new() -> Self {
+        Timeouts::default()
+    }
+
+    /// Limit the wait for a connection to `timeout`
+    pub fn connect(mut self, timeout: Duration) -> Self {
+        self.connect = Some(timeout);
+        self
+    }
+
+    /// Limit the wait for more data on an open connection to `timeout`
+    pub fn read(mut self, timeout: Duration) -> Self {
+        self.read = Some(timeout);
+        self
+    }
+
+    /// Limit the wait for response headers to `timeout`
+    pub fn total(mut self, timeout: Duration) -> Self {
+        self.total = Some(timeout);
+        self
+    }
+}
+
+/// Fail `future` with `ErrorKind::TimedOut` if it has not completed within `timeout`
+pub fn connect_within(future: Box<dyn Future<Item=TcpStream, Error=io::Error>>, timeout: Duration,
+                      handle: &Handle) -> Box<dyn Future<Item=TcpStream, Error=io::Error>> {
+    let timer = match Timeout::new(timeout, handle) {
+        Ok(timer) => timer,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    Box::new(future.select2(timer).then(move |result| match result {
+        Ok(Either::A((stream, _))) => Ok(stream),
+        Ok(Either::B(_)) => Err(io::Error::new(
+            io::ErrorKind::TimedOut, format!("No connection made within {:?}", timeout)
+        )),
+        Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+    }))
+}
+
+/// Connection failing reads with `ErrorKind::TimedOut` once no data has arrived for its read
+/// timeout
+pub struct TimeoutStream {
+    stream: TcpStream,
+    timeout: Option<Duration>,
+    handle: Handle,
+    timer: Option<Timeout>,
+}
+
+impl fmt::Debug for TimeoutStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TimeoutStream").field("stream", &self.stream).field("timeout", &self.timeout).finish()
+    }
+}
+
+impl TimeoutStream {
+    /// Wrap `stream`, timing reads out after `timeout` - `None` never times out
+    pub fn new(stream: TcpStream, timeout: Option<Duration>, handle: &Handle) -> Self {
+        TimeoutStream { stream, timeout, handle: handle.clone(), timer: None }
+    }
+
+    /// The wrapped connection
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    /// Start the timer on the first read that would block and check whether it has fired
+    fn poll_timer(&mut self) -> io::Result<()> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Ok(()),
+        };
+        if self.timer.is_none() {
+            self.timer = Some(Timeout::new(timeout, &self.handle)?);
+        }
+        match self.timer.as_mut().map(Future::poll) {
+            Some(Ok(Async::Ready(()))) => {
+                self.timer = None;
+                Err(io::Error::new(io::ErrorKind::TimedOut, format!("No data received within {:?}", timeout)))
+            },
+            Some(Err(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl io::Read for TimeoutStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream.read(buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.poll_timer()?;
+                Err(io::ErrorKind::WouldBlock.into())
+            },
+            result => {
+                self.timer = None;
+                result
+            },
+        }
+    }
+}
+
+impl io::Write for TimeoutStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AsyncRead for TimeoutStream {}
+
+impl AsyncWrite for TimeoutStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.stream)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testing::{Fixture,FixtureServer,client_for};
+
+    #[test]
+    fn test_timeouts() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/slow", Fixture::new(200).delay(Duration::from_millis(300)))
+            .route(Method::Get, "/slow", Fixture::new(200).delay(Duration::from_millis(300)))
+            .route(Method::Get, "/slow", Fixture::new(200).delay(Duration::from_millis(300)))
+            .route(Method::Get, "/slow", Fixture::new(200).delay(Duration::from_millis(300)))
+            .start().unwrap();
+        let base_uri = server.base_uri().parse::<Uri>().unwrap();
+
+        // A stalled connection fails the request once the read limit passes
+        let http = SimpleHttpClient::with_timeouts(Timeouts::new().connect(Duration::from_secs(5))
+                                                   .read(Duration::from_millis(100))).unwrap();
+        let mut client = client_for(&server).with_http(http);
+        let err = client.request(Method::Get, "/slow".parse().unwrap(), None::<String>).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::TimedOut);
+
+        // The total limit applies unless the request sets its own
+        let http = SimpleHttpClientBuilder::new().timeouts(Timeouts::new().total(Duration::from_millis(100)))
+            .build_for(&base_uri).unwrap();
+        let mut client = client_for(&server).with_http(http);
+        let err = client.request(Method::Get, "/slow".parse().unwrap(), None::<String>).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::TimedOut);
+        let response = client.request_with_timeout(Method::Get, "/slow".parse().unwrap(), None::<String>,
+                                                   Duration::from_secs(5)).unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        client.client.set_total_timeout(None);
+        assert_eq!(client.request(Method::Get, "/slow".parse().unwrap(), None::<String>).unwrap().status(),
+                   StatusCode::Ok);
+    }
+}
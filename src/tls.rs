@@ -0,0 +1,741 @@
+use std::cell::RefCell;
+use std::fs;
+use std::path::{Path,PathBuf};
+
+use native_tls::{Certificate,Pkcs12,Protocol,TlsConnector};
+
+use *;
+
+/// Prefix of SPKI pins, which are the base64 encoded SHA-256 hash of a certificate's DER
+/// encoded SubjectPublicKeyInfo
+pub const PIN_PREFIX: &str = "sha256/";
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+thread_local! {
+    // Set by the verification callbacks on the thread driving the handshake so that the failed
+    // request can report why the certificate was rejected
+    static REJECTION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Take the description of a certificate rejected for not matching the pins or for being
+/// revoked during the last handshake on this thread
+pub(crate) fn take_rejection() -> Option<ClientError> {
+    REJECTION.with(|m| m.borrow_mut().take()).map(ClientError::new)
+}
+
+#[cfg(all(feature = "openssl", not(any(target_os = "windows", target_os = "macos", target_os = "ios"))))]
+fn reject(message: String) {
+    REJECTION.with(|m| *m.borrow_mut() = Some(message));
+}
+
+/// Revocation check for server certificates
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Revocation {
+    /// Ask the server to staple an OCSP response and check it when one is sent
+    OcspStapled,
+    /// Require the server to staple an OCSP response showing its certificate is good
+    RequireOcspStapled,
+    /// Check every certificate in the chain against the CRLs in a PEM or DER file, which must
+    /// include a CRL from each issuing CA
+    CrlFile(PathBuf),
+}
+
+/// Normalize `pin` to `sha256/<base64>`, also accepting curl's `sha256//<base64>`
+fn parse_pin(pin: &str) -> Result<String> {
+    let hash = pin.trim().strip_prefix(PIN_PREFIX).map(|h| h.strip_prefix('/').unwrap_or(h));
+    let hash = hash.filter(|h| h.len() == 44 && h.ends_with('=') && h[..43].bytes().all(|b| {
+        b.is_ascii_alphanumeric() || b == b'+' || b == b'/'
+    })).ok_or_else(|| {
+        ClientError::new(format!("Invalid SPKI pin {:?}, expected sha256/<base64 SHA-256 hash>", pin))
+    })?;
+    Ok(format!("{}{}", PIN_PREFIX, hash))
+}
+
+#[cfg(all(feature = "openssl", not(any(target_os = "windows", target_os = "macos", target_os = "ios"))))]
+mod backend {
+    use native_tls::TlsConnectorBuilder;
+    use native_tls::backend::openssl::TlsConnectorBuilderExt;
+    use openssl::pkcs12::Pkcs12;
+    use openssl::pkey::PKey;
+    use openssl::sha::sha256;
+    use openssl::ssl::SSL_VERIFY_PEER;
+    use openssl::stack::Stack;
+    use openssl::x509::{X509,X509Ref};
+
+    use *;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use super::{PIN_PREFIX,Revocation,reject};
+
+    fn pin_of(cert: &X509Ref) -> Option<String> {
+        let spki = cert.public_key().and_then(|k| k.public_key_to_der()).ok()?;
+        Some(format!("{}{}", PIN_PREFIX, BASE64.encode(sha256(&spki))))
+    }
+
+    pub fn spki_pin(der: &[u8]) -> Result<String> {
+        X509::from_der(der).ok().as_ref().and_then(|c| pin_of(c))
+            .ok_or_else(|| ClientError::new("Invalid certificate for SPKI pin"))
+    }
+
+    pub fn set_cipher_list(builder: &mut TlsConnectorBuilder, ciphers: &str) -> Result<()> {
+        builder.builder_mut().set_cipher_list(ciphers)
+            .map_err(|e| ClientError::new(format!("Invalid TLS cipher list: {}", e)))
+    }
+
+    /// `X509_V_ERR_HOSTNAME_MISMATCH`, reported for the server's certificate when it does not
+    /// name the host connected to
+    const HOSTNAME_MISMATCH: ::std::os::raw::c_long = 62;
+
+    pub fn set_verify(builder: &mut TlsConnectorBuilder, pins: Vec<String>, accept_invalid_hostnames: bool)
+            -> Result<()> {
+        // Runs for each certificate from the root down, after OpenSSL's own checks including
+        // any CRLs - the pins are compared once the whole chain is known, at the server's
+        // certificate
+        builder.builder_mut().set_verify_callback(SSL_VERIFY_PEER, move |preverified, ctx| {
+            let host = || ctx.ssl().ok().and_then(|ssl| ssl.and_then(|s| s.servername()).map(str::to_string))
+                .unwrap_or_else(|| "server".to_string());
+            let mismatch = ctx.error().is_some_and(|e| e.as_raw() == HOSTNAME_MISMATCH);
+            if !(preverified || accept_invalid_hostnames && mismatch) {
+                let error = ctx.error().map(|e| e.error_string()).unwrap_or("verification failed");
+                reject(format!("Certificate for {} was rejected: {}", host(), error));
+                return false;
+            }
+            if pins.is_empty() || ctx.error_depth() != 0 {
+                return true;
+            }
+            let presented = ctx.chain().map(|chain| chain.iter().filter_map(pin_of).collect::<Vec<_>>())
+                .unwrap_or_default();
+            if presented.iter().any(|p| pins.contains(p)) {
+                return true;
+            }
+            reject(format!("Certificate for {} does not match any pinned public key; presented {}",
+                           host(), presented.join(", ")));
+            false
+        });
+        Ok(())
+    }
+
+    pub fn pkcs12_from_pem(cert: &[u8], key: &[u8], password: &str) -> Result<Vec<u8>> {
+        let invalid = |what: &str, e: ::openssl::error::ErrorStack| ClientError::new(format!("Invalid client {}: {}", what, e));
+        let text = str::from_utf8(cert).map_err(|e| ClientError::new(format!("Invalid client certificate: {}", e)))?;
+        let mut chain = text.match_indices(super::PEM_BEGIN).map(|(start, _)| X509::from_pem(&cert[start..]))
+            .collect::<::std::result::Result<Vec<_>, _>>().map_err(|e| invalid("certificate", e))?.into_iter();
+        let leaf = chain.next().ok_or_else(|| ClientError::new("No certificate in client certificate file"))?;
+        let key = PKey::private_key_from_pem(key).map_err(|e| invalid("key", e))?;
+        let mut intermediates = Stack::new().map_err(|e| invalid("certificate", e))?;
+        for cert in chain {
+            intermediates.push(cert).map_err(|e| invalid("certificate", e))?;
+        }
+        let mut builder = Pkcs12::builder();
+        builder.ca(intermediates);
+        builder.build(password, "client", &key, &leaf).and_then(|p| p.to_der()).map_err(|e| invalid("key", e))
+    }
+
+    #[cfg(feature = "revocation")]
+    pub fn set_revocation(builder: &mut TlsConnectorBuilder, checks: &[Revocation]) -> Result<()> {
+        let mut ocsp = None;
+        for check in checks {
+            match *check {
+                Revocation::OcspStapled => ocsp = Some(ocsp.unwrap_or(false)),
+                Revocation::RequireOcspStapled => ocsp = Some(true),
+                Revocation::CrlFile(ref path) => revocation::load_crl_file(builder.builder_mut(), path)?,
+            }
+        }
+        if let Some(required) = ocsp {
+            revocation::check_stapled_ocsp(builder.builder_mut(), required)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "revocation"))]
+    pub fn set_revocation(_builder: &mut TlsConnectorBuilder, _checks: &[Revocation]) -> Result<()> {
+        Err(ClientError::new("Revocation checks require the revocation feature"))
+    }
+
+    #[cfg(feature = "revocation")]
+    mod revocation {
+        use std::ffi::CString;
+        use std::fs;
+        use std::os::raw::c_long;
+        use std::os::unix::ffi::OsStrExt;
+        use std::path::Path;
+        use std::ptr;
+
+        use openssl::hash::MessageDigest;
+        use openssl::ocsp::{self,OcspCertId,OcspResponse};
+        use openssl::ssl::{SslContextBuilder,SslRef};
+        use openssl_sys as ffi;
+
+        use *;
+        use super::super::reject;
+
+        /// Slack allowed for clock skew when checking that an OCSP response is current
+        const OCSP_LEEWAY_SECS: u32 = 300;
+
+        pub fn load_crl_file(ctx: &mut SslContextBuilder, path: &Path) -> Result<()> {
+            let contents = fs::read(path).map_err(|e| {
+                ClientError::new(format!("Failed to read CRL file {}: {}", path.display(), e))
+            })?;
+            let kind = if contents.windows(10).any(|w| w == b"-----BEGIN") {
+                ffi::X509_FILETYPE_PEM
+            } else {
+                ffi::X509_FILETYPE_ASN1
+            };
+            let c_path = CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| ClientError::new(format!("Invalid CRL file path {}", path.display())))?;
+            // The context's store is also used to verify server certificates, so the CRLs added
+            // here and the check flags apply to every connection made with it
+            let loaded = unsafe {
+                let store = ffi::SSL_CTX_get_cert_store(ctx.as_ptr());
+                let lookup = ffi::X509_STORE_add_lookup(store, ffi::X509_LOOKUP_file());
+                let loaded = !lookup.is_null() && ffi::X509_load_crl_file(lookup, c_path.as_ptr(), kind) > 0;
+                loaded && ffi::X509_STORE_set_flags(store, ffi::X509_V_FLAG_CRL_CHECK | ffi::X509_V_FLAG_CRL_CHECK_ALL) == 1
+            };
+            if !loaded {
+                return Err(ClientError::new(format!("No CRL could be loaded from {}", path.display())));
+            }
+            Ok(())
+        }
+
+        pub fn check_stapled_ocsp(ctx: &mut SslContextBuilder, required: bool) -> Result<()> {
+            let requested = unsafe {
+                ffi::SSL_CTX_ctrl(ctx.as_ptr(), ffi::SSL_CTRL_SET_TLSEXT_STATUS_REQ_TYPE,
+                                  ffi::TLSEXT_STATUSTYPE_ocsp as c_long, ptr::null_mut())
+            };
+            if requested != 1 {
+                return Err(ClientError::new("Failed to request stapled OCSP responses"));
+            }
+            ctx.set_status_callback(move |ssl| match check_response(ssl, required) {
+                Ok(()) => Ok(true),
+                Err(message) => {
+                    reject(message);
+                    Ok(false)
+                },
+            }).map_err(|e| ClientError::new(format!("Failed to set OCSP check: {}", e)))
+        }
+
+        fn check_response(ssl: &SslRef, required: bool) -> std::result::Result<(), String> {
+            let host = ssl.servername().unwrap_or("server").to_string();
+            let response = match ssl.ocsp_status() {
+                Some(response) => response,
+                None if required => return Err(format!("{} did not staple an OCSP response", host)),
+                None => return Ok(()),
+            };
+            let invalid = |e: &dyn fmt::Display| format!("Invalid OCSP response from {}: {}", host, e);
+            let response = OcspResponse::from_der(response).map_err(|e| invalid(&e))?;
+            if response.status() != ocsp::RESPONSE_STATUS_SUCCESSFUL {
+                return Err(invalid(&format!("status {}", response.status().as_raw())));
+            }
+            let basic = response.basic().map_err(|e| invalid(&e))?;
+            // On the client the chain starts with the server's own certificate
+            let chain = ssl.peer_cert_chain().ok_or_else(|| invalid(&"no certificate chain"))?;
+            let leaf = chain.iter().next().ok_or_else(|| invalid(&"no server certificate"))?;
+            let issuer = chain.iter().find(|c| c.issued(leaf).is_ok())
+                .ok_or_else(|| invalid(&"issuer of the server certificate was not sent"))?;
+            basic.verify(chain, ssl.ssl_context().cert_store(), ocsp::Flag::empty())
+                .map_err(|e| invalid(&e))?;
+            let id = OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer).map_err(|e| invalid(&e))?;
+            let status = basic.find_status(&id).ok_or_else(|| invalid(&"no status for the server certificate"))?;
+            status.check_validity(OCSP_LEEWAY_SECS, None).map_err(|_| invalid(&"response is out of date"))?;
+            match status.status {
+                ocsp::CERT_STATUS_GOOD => Ok(()),
+                ocsp::CERT_STATUS_REVOKED => Err(format!("Certificate for {} has been revoked", host)),
+                _ => Err(format!("OCSP responder does not know the certificate for {}", host)),
+            }
+        }
+    }
+}
+
+#[cfg(not(all(feature = "openssl", not(any(target_os = "windows", target_os = "macos", target_os = "ios")))))]
+mod backend {
+    use native_tls::TlsConnectorBuilder;
+
+    use *;
+
+    pub fn spki_pin(_der: &[u8]) -> Result<String> {
+        Err(ClientError::new("SPKI pins can only be computed with the openssl feature on this platform"))
+    }
+
+    pub fn set_cipher_list(_builder: &mut TlsConnectorBuilder, _ciphers: &str) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn set_verify(_builder: &mut TlsConnectorBuilder, pins: Vec<String>, _accept_invalid_hostnames: bool)
+            -> Result<()> {
+        if pins.is_empty() {
+            Err(ClientError::new("Accepting invalid hostnames requires the openssl feature on this platform"))
+        } else {
+            Err(ClientError::new("SPKI pinning requires the openssl feature on this platform"))
+        }
+    }
+
+    pub fn pkcs12_from_pem(_cert: &[u8], _key: &[u8], _password: &str) -> Result<Vec<u8>> {
+        Err(ClientError::new("Client certificates in PEM files require the openssl feature on this platform; \
+                              use a PKCS #12 bundle"))
+    }
+
+    pub fn set_revocation(_builder: &mut TlsConnectorBuilder, _checks: &[super::Revocation]) -> Result<()> {
+        Err(ClientError::new("Revocation checks require the revocation feature on this platform"))
+    }
+}
+
+/// SPKI pin of the DER encoded certificate `der`, in the form accepted by `TlsConfig::pin` -
+/// requires the `openssl` feature on platforms where `native-tls` uses OpenSSL
+pub fn spki_pin(der: &[u8]) -> Result<String> {
+    backend::spki_pin(der)
+}
+
+fn read_file(path: &Path, what: &str) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| ClientError::new(format!("Failed to read {} {}: {}", what, path.display(), e)))
+}
+
+/// Every certificate in the PEM bundle at `path`
+fn ca_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let pem = read_file(path, "CA bundle")?;
+    let text = String::from_utf8_lossy(&pem);
+    let certs = text.match_indices(PEM_BEGIN).map(|(start, _)| {
+        // Only the last certificate of a PEM block is kept, so each is parsed on its own
+        let end = text[start..].find(PEM_END).map_or(text.len(), |end| start + end + PEM_END.len());
+        Certificate::from_pem(text[start..end].as_bytes())
+    }).collect::<result::Result<Vec<_>, _>>().map_err(|e| {
+        ClientError::new(format!("Invalid certificate in CA bundle {}: {}", path.display(), e))
+    })?;
+    if certs.is_empty() {
+        return Err(ClientError::new(format!("No certificates in CA bundle {}", path.display())));
+    }
+    Ok(certs)
+}
+
+/// Certificate and key presented to servers that ask for one
+#[derive(Clone,Debug,PartialEq,Eq)]
+enum ClientIdentity {
+    Pem { cert: PathBuf, key: PathBuf },
+    Pkcs12 { bundle: PathBuf, password: Secret },
+}
+
+impl ClientIdentity {
+    fn load(&self) -> Result<Pkcs12> {
+        let (der, password, path) = match *self {
+            ClientIdentity::Pem { ref cert, ref key } => {
+                // The bundle never leaves the process, so the password only has to be non-empty
+                let der = backend::pkcs12_from_pem(&read_file(cert, "client certificate")?,
+                                                   &read_file(key, "client key")?, "teatime")?;
+                (der, "teatime", cert)
+            },
+            ClientIdentity::Pkcs12 { ref bundle, ref password } => {
+                (read_file(bundle, "client certificate bundle")?, password.expose(), bundle)
+            },
+        };
+        Pkcs12::from_der(&der, password).map_err(|e| {
+            ClientError::new(format!("Invalid client certificate {}: {}", path.display(), e))
+        })
+    }
+}
+
+/// Cipher suites for TLS 1.2 connections under `Policy::Intermediate` (Mozilla's intermediate
+/// configuration)
+pub const INTERMEDIATE_CIPHERS: &str = "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:\
+ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:ECDHE-ECDSA-CHACHA20-POLY1305:\
+ECDHE-RSA-CHACHA20-POLY1305:DHE-RSA-AES128-GCM-SHA256:DHE-RSA-AES256-GCM-SHA384";
+
+/// Cipher suites for TLS 1.2 connections under `Policy::Modern` - ECDHE key exchange with AEAD
+/// ciphers only
+pub const MODERN_CIPHERS: &str = "ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:\
+ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:ECDHE-ECDSA-AES128-GCM-SHA256:\
+ECDHE-RSA-AES128-GCM-SHA256";
+
+/// TLS policy presets for deployments that must meet a compliance baseline
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq)]
+pub enum Policy {
+    /// Whatever the TLS backend allows by default
+    #[default]
+    Default,
+    /// TLS 1.2 or later with forward secret AEAD cipher suites
+    Intermediate,
+    /// TLS 1.2 or later with ECDHE key exchange and AEAD cipher suites only
+    Modern,
+}
+
+fn rank(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::Sslv3 => 0,
+        Protocol::Tlsv10 => 1,
+        Protocol::Tlsv11 => 2,
+        _ => 3,
+    }
+}
+
+/// Settings for the TLS connections of a client, turned into a `native_tls::TlsConnector` for
+/// `SimpleHttpClientBuilder::tls_connector` with `build`
+///
+/// Protocol versions are enforced by every backend. Cipher suites can only be restricted with
+/// the `openssl` feature on platforms where `native-tls` uses OpenSSL; elsewhere the system
+/// defaults apply, which already exclude the weak suites on the platforms concerned.
+///
+/// Public key pins need the same backend, and revocation checks the `revocation` feature as
+/// well; `build` fails rather than connect without checking them. So do client certificates in
+/// PEM files and accepting invalid hostnames, while CA bundles and PKCS #12 client
+/// certificates work with every backend.
+#[derive(Clone,Debug,Default)]
+pub struct TlsConfig {
+    policy: Policy,
+    min_protocol: Option<Protocol>,
+    pins: Vec<String>,
+    revocation: Vec<Revocation>,
+    ca_bundles: Vec<PathBuf>,
+    identity: Option<ClientIdentity>,
+    accept_invalid_hostnames: bool,
+}
+
+impl TlsConfig {
+    /// Configuration with the backend's defaults
+    pub fn new() -> Self {
+        TlsConfig::default()
+    }
+
+    /// Apply the `policy` preset
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Refuse protocol versions older than `protocol`, or than the policy's minimum if that is
+    /// newer
+    pub fn min_protocol(mut self, protocol: Protocol) -> Self {
+        self.min_protocol = Some(protocol);
+        self
+    }
+
+    /// Only accept servers whose certificate chain includes a public key matching `pin`, a
+    /// `sha256/<base64>` SPKI hash as produced by `spki_pin` - may be given several times, for
+    /// example to pin a backup key
+    ///
+    /// Certificates must still be trusted; pins narrow which trusted certificates are accepted.
+    pub fn pin<S>(mut self, pin: S) -> Self where S: Into<String> {
+        self.pins.push(pin.into());
+        self
+    }
+
+    /// Check server certificates for revocation with `check` - may be given several times to
+    /// combine checks
+    pub fn revocation(mut self, check: Revocation) -> Self {
+        self.revocation.push(check);
+        self
+    }
+
+    /// Trust the certificates in the PEM file at `path` as well as the system's roots - may be
+    /// given several times, for example for the private CA of an internal Vault or GitLab
+    pub fn ca_bundle<P>(mut self, path: P) -> Self where P: Into<PathBuf> {
+        self.ca_bundles.push(path.into());
+        self
+    }
+
+    /// Present the PEM certificate at `cert`, followed by any intermediates, with the PEM
+    /// private key at `key` to servers requiring mutual TLS
+    pub fn client_certificate<C, K>(mut self, cert: C, key: K) -> Self where C: Into<PathBuf>, K: Into<PathBuf> {
+        self.identity = Some(ClientIdentity::Pem { cert: cert.into(), key: key.into() });
+        self
+    }
+
+    /// Present the certificate and key in the PKCS #12 bundle at `path`, protected by
+    /// `password`, to servers requiring mutual TLS
+    pub fn client_pkcs12<P>(mut self, path: P, password: Secret) -> Self where P: Into<PathBuf> {
+        self.identity = Some(ClientIdentity::Pkcs12 { bundle: path.into(), password });
+        self
+    }
+
+    /// Accept trusted certificates that do not name the host connected to - _only for lab
+    /// environments_, since any trusted certificate then passes for any host
+    pub fn danger_accept_invalid_hostnames(mut self, accept: bool) -> Self {
+        self.accept_invalid_hostnames = accept;
+        self
+    }
+
+    /// The policy preset in use
+    pub fn current_policy(&self) -> Policy {
+        self.policy
+    }
+
+    /// Protocol versions that will be offered, or `None` for the backend's defaults
+    pub fn protocols(&self) -> Option<Vec<Protocol>> {
+        let policy_min = match self.policy {
+            Policy::Default => None,
+            Policy::Intermediate | Policy::Modern => Some(Protocol::Tlsv12),
+        };
+        let min = match (policy_min, self.min_protocol) {
+            (Some(a), Some(b)) => Some(if rank(a) >= rank(b) { a } else { b }),
+            (a, b) => a.or(b),
+        };
+        min.map(|min| {
+            [Protocol::Sslv3, Protocol::Tlsv10, Protocol::Tlsv11, Protocol::Tlsv12].iter()
+                .cloned().filter(|p| rank(*p) >= rank(min)).collect()
+        })
+    }
+
+    /// OpenSSL cipher list for TLS 1.2 connections, or `None` for the backend's defaults
+    pub fn cipher_list(&self) -> Option<&'static str> {
+        match self.policy {
+            Policy::Default => None,
+            Policy::Intermediate => Some(INTERMEDIATE_CIPHERS),
+            Policy::Modern => Some(MODERN_CIPHERS),
+        }
+    }
+
+    /// Build the connector
+    pub fn build(&self) -> Result<TlsConnector> {
+        let mut builder = TlsConnector::builder()?;
+        if let Some(protocols) = self.protocols() {
+            builder.supported_protocols(&protocols)?;
+        }
+        for path in &self.ca_bundles {
+            for cert in ca_certificates(path)? {
+                builder.add_root_certificate(cert)?;
+            }
+        }
+        if let Some(ref identity) = self.identity {
+            builder.identity(identity.load()?)?;
+        }
+        if let Some(ciphers) = self.cipher_list() {
+            backend::set_cipher_list(&mut builder, ciphers)?;
+        }
+        let pins = self.pins.iter().map(|p| parse_pin(p)).collect::<Result<Vec<_>>>()?;
+        if !self.revocation.is_empty() {
+            backend::set_revocation(&mut builder, &self.revocation)?;
+        }
+        // Also installed for CRLs so that revoked certificates are reported clearly
+        let crls = self.revocation.iter().any(|r| matches!(*r, Revocation::CrlFile(_)));
+        if !pins.is_empty() || crls || self.accept_invalid_hostnames {
+            backend::set_verify(&mut builder, pins, self.accept_invalid_hostnames)?;
+        }
+        Ok(builder.build()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ranks(config: &TlsConfig) -> Option<Vec<u8>> {
+        config.protocols().map(|p| p.into_iter().map(rank).collect())
+    }
+
+    #[test]
+    fn test_tls_policies() {
+        assert_eq!(ranks(&TlsConfig::new()), None);
+        assert_eq!(TlsConfig::new().cipher_list(), None);
+        assert_eq!(ranks(&TlsConfig::new().min_protocol(Protocol::Tlsv11)), Some(vec![2, 3]));
+        assert_eq!(ranks(&TlsConfig::new().policy(Policy::Modern)), Some(vec![3]));
+        // An older pin does not weaken the policy
+        let intermediate = TlsConfig::new().policy(Policy::Intermediate).min_protocol(Protocol::Tlsv10);
+        assert_eq!(ranks(&intermediate), Some(vec![3]));
+        assert_eq!(intermediate.cipher_list(), Some(INTERMEDIATE_CIPHERS));
+
+        for policy in &[Policy::Default, Policy::Intermediate, Policy::Modern] {
+            TlsConfig::new().policy(*policy).build().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_pins() {
+        let pin = "sha256/BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+        assert_eq!(parse_pin(&pin.replace("sha256/", "sha256//")).unwrap(), pin);
+        assert!(parse_pin("sha256/short=").is_err());
+        assert!(parse_pin(pin.trim_start_matches("sha256/")).is_err());
+        assert!(parse_pin(&pin.replace("sha256/", "sha256/sha256/")).is_err());
+        assert!(parse_pin(&pin.replace("sha256/", "sha256///")).is_err());
+        assert!(TlsConfig::new().pin("md5/abc").build().is_err());
+        let pinned = TlsConfig::new().pin(pin).build().err().map(|e| e.to_string());
+        if cfg!(all(feature = "openssl", not(any(target_os = "windows", target_os = "macos", target_os = "ios")))) {
+            assert_eq!(pinned, None);
+        } else {
+            assert!(pinned.unwrap().contains("SPKI pinning requires the openssl feature"));
+        }
+    }
+
+    #[cfg(all(feature = "openssl", not(any(target_os = "windows", target_os = "macos", target_os = "ios"))))]
+    #[test]
+    fn test_spki_pin() {
+        use base64::Engine;
+        use base64::engine::general_purpose::STANDARD as BASE64;
+        use openssl::asn1::Asn1Time;
+        use openssl::pkey::PKey;
+        use openssl::rsa::Rsa;
+        use openssl::sha::sha256;
+        use openssl::x509::{X509,X509Name};
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "vault").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.sign(&key, openssl::hash::MessageDigest::sha256()).unwrap();
+        let der = cert.build().to_der().unwrap();
+        let expected = format!("sha256/{}", BASE64.encode(sha256(&key.public_key_to_der().unwrap())));
+        assert_eq!(spki_pin(&der).unwrap(), expected);
+        assert!(spki_pin(b"not a certificate").is_err());
+        TlsConfig::new().pin(expected).build().unwrap();
+    }
+
+    #[cfg(all(feature = "openssl", not(any(target_os = "windows", target_os = "macos", target_os = "ios"))))]
+    #[test]
+    fn test_pinned_handshake() {
+        use std::env;
+        use std::io::{Read,Write};
+        use std::net::TcpListener;
+        use std::process;
+        use std::thread;
+        use native_tls::TlsAcceptor;
+        use openssl::asn1::Asn1Time;
+        use openssl::hash::MessageDigest;
+        use openssl::pkey::PKey;
+        use openssl::rsa::Rsa;
+        use openssl::x509::{X509,X509Name};
+        use openssl::x509::extension::SubjectAlternativeName;
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        let san = SubjectAlternativeName::new().dns("localhost").build(&cert.x509v3_context(None, None)).unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+        let ca = env::temp_dir().join(format!("teatime-pinned-{}.pem", process::id()));
+        fs::write(&ca, cert.to_pem().unwrap()).unwrap();
+
+        let pkcs12 = ::openssl::pkcs12::Pkcs12::builder().build("", "localhost", &key, &cert).unwrap();
+        let identity = Pkcs12::from_der(&pkcs12.to_der().unwrap(), "").unwrap();
+        let acceptor = TlsAcceptor::builder(identity).unwrap().build().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri: Uri = format!("https://localhost:{}/", listener.local_addr().unwrap().port()).parse().unwrap();
+        let server = thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                if let Ok(mut tls) = acceptor.accept(stream.unwrap()) {
+                    let mut buf = [0; 1024];
+                    let _ = tls.read(&mut buf);
+                    let _ = tls.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+                }
+            }
+        });
+
+        let get = |config: TlsConfig| {
+            let mut client = SimpleHttpClientBuilder::new().tls_connector(config.build().unwrap())
+                .build_for(&uri).unwrap();
+            client.start_request(Method::Get, uri.clone()).make_request().response()
+        };
+        let pin = spki_pin(&cert.to_der().unwrap()).unwrap();
+        let response = get(TlsConfig::new().ca_bundle(&ca).pin(pin)).unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        let other = "sha256/BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+        let err = get(TlsConfig::new().ca_bundle(&ca).pin(other)).unwrap_err();
+        assert!(err.to_string().contains("does not match any pinned public key"), "{}", err);
+
+        server.join().unwrap();
+        fs::remove_file(&ca).unwrap();
+    }
+
+    #[test]
+    fn test_tls_files() {
+        use std::env;
+        use std::process;
+
+        let missing = env::temp_dir().join(format!("teatime-missing-{}.pem", process::id()));
+        let err = TlsConfig::new().ca_bundle(&missing).build().err().unwrap();
+        assert!(err.to_string().starts_with("Failed to read CA bundle"), "{}", err);
+        assert!(TlsConfig::new().client_pkcs12(&missing, Secret::new("pw")).build().is_err());
+
+        let empty = env::temp_dir().join(format!("teatime-empty-{}.pem", process::id()));
+        fs::write(&empty, "no certificates here").unwrap();
+        let err = TlsConfig::new().ca_bundle(&empty).build().err().unwrap();
+        fs::remove_file(&empty).unwrap();
+        assert!(err.to_string().starts_with("No certificates in CA bundle"), "{}", err);
+    }
+
+    #[cfg(all(feature = "openssl", not(any(target_os = "windows", target_os = "macos", target_os = "ios"))))]
+    #[test]
+    fn test_ca_bundle_and_client_certificate() {
+        use std::env;
+        use std::process;
+
+        use openssl::asn1::Asn1Time;
+        use openssl::pkey::PKey;
+        use openssl::rsa::Rsa;
+        use openssl::x509::{X509,X509Name};
+
+        let mut pem = Vec::new();
+        let mut key_pem = Vec::new();
+        for cn in &["internal-ca", "client"] {
+            let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+            let mut name = X509Name::builder().unwrap();
+            name.append_entry_by_text("CN", cn).unwrap();
+            let name = name.build();
+            let mut cert = X509::builder().unwrap();
+            cert.set_subject_name(&name).unwrap();
+            cert.set_issuer_name(&name).unwrap();
+            cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+            cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+            cert.set_pubkey(&key).unwrap();
+            cert.sign(&key, openssl::hash::MessageDigest::sha256()).unwrap();
+            pem.extend(cert.build().to_pem().unwrap());
+            key_pem = key.private_key_to_pem().unwrap();
+        }
+        let bundle = env::temp_dir().join(format!("teatime-ca-{}.pem", process::id()));
+        let key = env::temp_dir().join(format!("teatime-client-key-{}.pem", process::id()));
+        fs::write(&bundle, &pem).unwrap();
+        fs::write(&key, &key_pem).unwrap();
+        assert_eq!(ca_certificates(&bundle).unwrap().len(), 2);
+        let text = String::from_utf8(pem).unwrap();
+        let client = env::temp_dir().join(format!("teatime-client-{}.pem", process::id()));
+        fs::write(&client, &text[text.rfind(PEM_BEGIN).unwrap()..]).unwrap();
+
+        let config = TlsConfig::new().ca_bundle(&bundle).client_certificate(&client, &key)
+            .danger_accept_invalid_hostnames(true);
+        let result = config.build();
+        // The key belongs to the second certificate, not the CA
+        let mismatched = TlsConfig::new().client_certificate(&bundle, &key).build();
+        for path in &[&bundle, &key, &client] {
+            fs::remove_file(path).unwrap();
+        }
+        result.unwrap();
+        assert!(mismatched.is_err());
+    }
+
+    #[cfg(all(feature = "revocation", not(any(target_os = "windows", target_os = "macos", target_os = "ios"))))]
+    #[test]
+    fn test_revocation() {
+        use std::env;
+        use std::fs;
+        use std::process;
+
+        const CRL: &str = "-----BEGIN X509 CRL-----
+MIGqMFMCAQEwCgYIKoZIzj0EAwIwEjEQMA4GA1UEAwwHdGVzdC1jYRcNMjYxMDE0
+MDcxNjA5WhgPMjEyNjA5MjAwNzE2MDlaoA4wDDAKBgNVHRQEAwIBATAKBggqhkjO
+PQQDAgNHADBEAiA1iJorqHIBawiJxccBp9/l4J9DmMDhS1fdCEXd18c3wwIgLuvj
+F2d/n+ZLQ5vuCs2RyCfdE4kjztGIadBUXopbT/0=
+-----END X509 CRL-----
+";
+        let path = env::temp_dir().join(format!("teatime-crl-{}.pem", process::id()));
+        fs::write(&path, CRL).unwrap();
+        TlsConfig::new().revocation(Revocation::CrlFile(path.clone())).revocation(Revocation::OcspStapled)
+            .build().unwrap();
+        fs::write(&path, "not a CRL").unwrap();
+        assert!(TlsConfig::new().revocation(Revocation::CrlFile(path.clone())).build().is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(TlsConfig::new().revocation(Revocation::CrlFile(path)).build().is_err());
+        TlsConfig::new().revocation(Revocation::RequireOcspStapled).build().unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use hmac::{Hmac,Mac};
+use sha1::Sha1;
+
+use *;
+use auth::CredentialsProvider;
+use clock::{Clock,SystemClock};
+
+/// HMAC-SHA1 of `message` keyed with `key`, as used by TOTP
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// Decode RFC 4648 base32, ignoring case, spaces, dashes and padding as found in secrets
+/// copied from an enrollment page
+pub fn decode_base32(text: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    for c in text.chars().filter(|c| !c.is_whitespace() && *c != '-' && *c != '=') {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u64 - 'A' as u64,
+            c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+            _ => return Err(ClientError::new(format!("Invalid base32 character {:?}", c))),
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+/// Time-based one time password generator (RFC 6238) for accounts whose two factor secret is
+/// stored rather than held on a person's phone, such as service accounts in lab environments
+///
+/// Codes are HMAC-SHA1 based, which is what authenticator apps and most servers expect.
+#[derive(Clone)]
+pub struct Totp {
+    secret: Secret,
+    digits: u32,
+    period: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl fmt::Debug for Totp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Totp").field("digits", &self.digits).field("period", &self.period).finish()
+    }
+}
+
+impl Totp {
+    /// Generator for the base32 encoded `secret`, making six digit codes every 30 seconds
+    pub fn from_base32(secret: &str) -> Result<Self> {
+        let bytes = decode_base32(secret)?;
+        if bytes.is_empty() {
+            return Err(ClientError::new("TOTP secret is empty"));
+        }
+        // Kept encoded so that it can be held as a `Secret`
+        let secret = secret.chars().filter(|c| !c.is_whitespace() && *c != '-' && *c != '=').collect::<String>();
+        Ok(Totp { secret: Secret::from(secret), digits: 6, period: Duration::from_secs(30), clock: Arc::new(SystemClock) })
+    }
+
+    /// Make codes of `digits` digits, between 6 and 9
+    pub fn digits(mut self, digits: u32) -> Self {
+        self.digits = digits.clamp(6, 9);
+        self
+    }
+
+    /// Make a new code every `period`
+    pub fn period(mut self, period: Duration) -> Self {
+        self.period = period;
+        self
+    }
+
+    /// Read the time from `clock`
+    pub fn clock<C>(mut self, clock: C) -> Self where C: Clock + 'static {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Code for the current time
+    pub fn code(&self) -> Result<Secret> {
+        self.code_at(self.clock.system_now())
+    }
+
+    /// Code for `time`
+    pub fn code_at(&self, time: SystemTime) -> Result<Secret> {
+        let seconds = time.duration_since(UNIX_EPOCH).map_err(|_| ClientError::new("Time is before the Unix epoch"))?
+            .as_secs();
+        let period = self.period.as_secs().max(1);
+        let key = decode_base32(self.secret.expose())?;
+        let hash = hmac_sha1(&key, &(seconds / period).to_be_bytes());
+        let offset = (hash[19] & 0x0f) as usize;
+        let truncated = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+        let code = truncated % 10u32.pow(self.digits);
+        Ok(Secret::from(format!("{:0width$}", code, width = self.digits as usize)))
+    }
+
+    /// Username and password credentials with a freshly generated code
+    pub fn credentials<S>(&self, username: S, password: Secret) -> Result<ApiCredentials> where S: Into<String> {
+        Ok(ApiCredentials::UserPassTwoFactor(username.into(), password, self.code()?))
+    }
+}
+
+/// Credentials with a code generated from a stored TOTP secret each time they are requested,
+/// so logins that need two factor authentication run unattended
+#[derive(Clone,Debug)]
+pub struct TotpCredentials {
+    username: String,
+    password: Secret,
+    totp: Totp,
+}
+
+impl TotpCredentials {
+    /// Log in as `username` with `password` and codes from `totp`
+    pub fn new<S>(username: S, password: Secret, totp: Totp) -> Self where S: Into<String> {
+        TotpCredentials { username: username.into(), password, totp }
+    }
+}
+
+impl CredentialsProvider for TotpCredentials {
+    fn get(&mut self) -> Result<ApiCredentials> {
+        self.totp.credentials(self.username.clone(), self.password.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clock::MockClock;
+
+    #[test]
+    fn test_totp() {
+        // RFC 6238 test vectors for the SHA1 secret "12345678901234567890"
+        assert_eq!(decode_base32("gezd gnbv-gy3t qojq====").unwrap(), b"1234567890".to_vec());
+        let totp = Totp::from_base32("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap().digits(8);
+        let at = |secs| totp.code_at(UNIX_EPOCH + Duration::from_secs(secs)).unwrap().expose().to_string();
+        assert_eq!(at(59), "94287082");
+        assert_eq!(at(1111111109), "07081804");
+        assert_eq!(at(20000000000), "65353130");
+
+        let clock = MockClock::at(UNIX_EPOCH + Duration::from_secs(59));
+        let mut creds = TotpCredentials::new("svc", "pw".into(), totp.digits(6).clock(clock.clone()));
+        assert_eq!(creds.get().unwrap(), ApiCredentials::UserPassTwoFactor("svc".to_string(), "pw".into(), "287082".into()));
+        clock.advance(Duration::from_secs(30));
+        assert_ne!(creds.get().unwrap(), ApiCredentials::UserPassTwoFactor("svc".to_string(), "pw".into(), "287082".into()));
+
+        assert!(Totp::from_base32("not base32!").is_err());
+        assert!(Totp::from_base32("").is_err());
+    }
+}
@@ -1,27 +1,752 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
 use hyper::{Response,Method};
 use hyper::header::ContentType;
+use futures::future::join_all;
 use serde_json::{Value,Map};
 
 use *;
+use auth::{CredentialsProvider,MintedToken,SharedToken,TokenCache,TokenRotation};
+use clock::{Sleeper,SystemClock};
+use completion::{NameCache,complete};
 
 header! { #[allow(missing_docs)] (XVaultToken, "X-Vault-Token") => [String] }
 
 /// An API client for Vault
 pub struct VaultClient {
     api_uri: Uri,
-    token: Option<String>,
+    token: SharedToken<Secret>,
+    rotation: Option<TokenRotation<Secret>>,
+    request_log: Option<RequestLog>,
+    token_cache: Option<TokenCache>,
+    auth_mount: String,
+    kv_mounts: HashMap<String, Option<KvVersion>>,
+    pending_request: Option<(Method, String)>,
     http_client: SimpleHttpClient,
 }
 
+/// Version of a KV secrets engine mount, which decides where its secrets are read
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum KvVersion {
+    /// Secrets are read at `mount/path`
+    V1,
+    /// Secrets are read at `mount/data/path`, wrapped with their version metadata
+    V2,
+}
+
+impl KvVersion {
+    /// Version of the engine described by `mount`, an entry of `sys/mounts` - `None` if it is
+    /// not a KV engine
+    fn of_mount(mount: &Value) -> Option<Self> {
+        match mount.get("type").and_then(Value::as_str) {
+            Some("kv") | Some("generic") => match mount.pointer("/options/version").and_then(Value::as_str) {
+                Some("2") => Some(KvVersion::V2),
+                _ => Some(KvVersion::V1),
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Append-only record of the requests a `VaultClient` makes, one JSON object per line
+///
+/// Each line holds the time as seconds since the Unix epoch, the method, the path without any
+/// query string and the response status, or `null` if no response was received. Request and
+/// response bodies are never written, so the log shows which secrets a tool accessed without
+/// exposing them.
+pub struct RequestLog {
+    writer: Box<dyn Write + Send>,
+}
+
+impl RequestLog {
+    /// Append to the file at `path`, creating it if needed
+    pub fn open<P>(path: P) -> Result<Self> where P: AsRef<Path> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| {
+            ClientError::new(format!("Failed to open Vault request log {}: {}", path.display(), e))
+        })?;
+        Ok(RequestLog::new(file))
+    }
+
+    /// Write the log to `writer`
+    pub fn new<W>(writer: W) -> Self where W: Write + Send + 'static {
+        RequestLog { writer: Box::new(writer) }
+    }
+
+    fn record(&mut self, method: &Method, path: &str, status: Option<StatusCode>) -> Result<()> {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut entry = Map::new();
+        entry.insert("time".to_string(), Value::from(time));
+        entry.insert("method".to_string(), Value::from(method.to_string()));
+        entry.insert("path".to_string(), Value::from(path));
+        entry.insert("status".to_string(), status.map(|s| Value::from(u16::from(s))).unwrap_or(Value::Null));
+        let mut line = Value::from(entry).to_string();
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).and_then(|_| self.writer.flush()).map_err(|e| {
+            ClientError::new(format!("Failed to write Vault request log: {}", e))
+        })
+    }
+}
+
+/// Builder for a `VaultClient` - see `VaultClient::builder`
+pub struct VaultClientBuilder {
+    api_uri: String,
+    token: Option<Secret>,
+    shared_token: Option<SharedToken<Secret>>,
+    rotation: Option<TokenRotation<Secret>>,
+    request_log: Option<RequestLog>,
+    token_cache: Option<TokenCache>,
+    auth_mount: String,
+    http: SimpleHttpClientBuilder,
+}
+
+impl VaultClientBuilder {
+    /// Authenticate with `token`
+    pub fn token<T>(mut self, token: T) -> Self where T: Into<Secret> {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Use token storage shared with other clients
+    pub fn shared_token(mut self, token: SharedToken<Secret>) -> Self {
+        self.shared_token = Some(token);
+        self
+    }
+
+    /// Mint tokens with `rotation` whenever the current token is missing or about to expire
+    pub fn token_rotation(mut self, rotation: TokenRotation<Secret>) -> Self {
+        self.rotation = Some(rotation);
+        self
+    }
+
+    /// Record the method, path and status of every request in `log`
+    pub fn request_log(mut self, log: RequestLog) -> Self {
+        self.request_log = Some(log);
+        self
+    }
+
+    /// Start with the token saved in `cache` if no other is set, and save the token from
+    /// every login to it
+    pub fn token_cache(mut self, cache: TokenCache) -> Self {
+        self.token_cache = Some(cache);
+        self
+    }
+
+    /// Log in with the auth method mounted at `mount`, such as `userpass` or `okta`, rather
+    /// than `ldap`
+    pub fn auth_mount<S>(mut self, mount: S) -> Self where S: Into<String> {
+        self.auth_mount = mount.into();
+        self
+    }
+
+    /// Configure the HTTP client - headers, timeout, TLS and the other `SimpleHttpClient`
+    /// settings
+    pub fn http<F>(mut self, configure: F) -> Self
+            where F: FnOnce(SimpleHttpClientBuilder) -> SimpleHttpClientBuilder {
+        self.http = configure(self.http);
+        self
+    }
+
+    /// Check the options and create the client
+    pub fn build(self) -> Result<VaultClient> {
+        let api_uri = parse_base_uri(&self.api_uri)?;
+        let token = match (self.token, self.shared_token) {
+            (Some(_), Some(_)) => return Err(ClientError::new("Set either a token or shared token storage, not both")),
+            (Some(ref t), None) if t.is_empty() => return Err(ClientError::new("Vault token is empty")),
+            (token, None) => SharedToken::new(token),
+            (None, Some(shared)) => shared,
+        };
+        let mut client = VaultClient {
+            http_client: self.http.build_for(&api_uri)?,
+            api_uri,
+            token,
+            rotation: self.rotation,
+            request_log: self.request_log,
+            token_cache: None,
+            auth_mount: self.auth_mount,
+            kv_mounts: HashMap::new(),
+            pending_request: None,
+        };
+        if let Some(cache) = self.token_cache {
+            client.set_token_cache(cache)?;
+        }
+        Ok(client)
+    }
+}
+
 impl VaultClient {
     /// Create new client
-    pub fn new(api_uri: &str, token: Option<String>) -> Result<Self> {
+    pub fn new(api_uri: &str, token: Option<Secret>) -> Result<Self> {
+        VaultClient::with_shared_token(api_uri, SharedToken::new(token))
+    }
+
+    /// Start building a client for the API at the absolute `api_uri` with more options than
+    /// `new` offers
+    pub fn builder<S>(api_uri: S) -> VaultClientBuilder where S: Into<String> {
+        VaultClientBuilder {
+            api_uri: api_uri.into(),
+            token: None,
+            shared_token: None,
+            rotation: None,
+            request_log: None,
+            token_cache: None,
+            auth_mount: DEFAULT_AUTH_MOUNT.to_string(),
+            http: SimpleHttpClientBuilder::new(),
+        }
+    }
+
+    /// Create new client using token storage shared with other clients so that concurrent
+    /// logins are only performed once
+    pub fn with_shared_token(api_uri: &str, token: SharedToken<Secret>) -> Result<Self> {
         Ok(VaultClient {
             api_uri: api_uri.parse::<Uri>()?,
             token,
+            rotation: None,
+            request_log: None,
+            token_cache: None,
+            auth_mount: DEFAULT_AUTH_MOUNT.to_string(),
+            kv_mounts: HashMap::new(),
+            pending_request: None,
             http_client: SimpleHttpClient::new()?,
         })
     }
+
+    /// Get a handle to the token storage for sharing with other clients
+    pub fn shared_token(&self) -> SharedToken<Secret> {
+        self.token.clone()
+    }
+
+    /// Mint tokens with `rotation` whenever the current token is missing or about to expire
+    pub fn set_token_rotation(&mut self, rotation: TokenRotation<Secret>) {
+        self.rotation = Some(rotation);
+    }
+
+    /// Record the method, path and status of every request in `log`
+    ///
+    /// A request whose log entry cannot be written fails even if Vault answered it.
+    pub fn set_request_log(&mut self, log: RequestLog) {
+        self.request_log = Some(log);
+    }
+
+    /// Start with the token saved in `cache` if the client has no token, and save the token
+    /// from every login to it
+    pub fn set_token_cache(&mut self, cache: TokenCache) -> Result<()> {
+        if self.token.get().is_none() {
+            if let Some(token) = cache.load()? {
+                self.token.set(Some(token));
+            }
+        }
+        self.token_cache = Some(cache);
+        Ok(())
+    }
+
+    /// Log in with the auth method mounted at `mount` rather than `ldap`
+    pub fn set_auth_mount<S>(&mut self, mount: S) where S: Into<String> {
+        self.auth_mount = mount.into();
+    }
+
+    /// Mount the client logs in with
+    pub fn auth_mount(&self) -> &str {
+        &self.auth_mount
+    }
+
+    /// Mounts of auth methods that log in with a username and password, without the trailing
+    /// `/` - listing them may need a token with access to `sys/auth`
+    pub fn list_password_auth_mounts(&mut self) -> Result<Vec<String>> {
+        let payload = check_errors(self.request_json(Method::Get, vault_uri("sys/auth")?, None::<Value>)?,
+                                   "list", "sys/auth")?;
+        let methods = payload.get("data").filter(|d| d.is_object()).unwrap_or(&payload);
+        let mut mounts = methods.as_object().map(|m| {
+            m.iter().filter(|&(_, method)| {
+                method.get("type").and_then(Value::as_str).is_some_and(|t| PASSWORD_AUTH_TYPES.contains(&t))
+            }).map(|(mount, _)| mount.trim_end_matches('/').to_string()).collect::<Vec<_>>()
+        }).unwrap_or_default();
+        mounts.sort();
+        Ok(mounts)
+    }
+
+    /// Log in with the TLS certificate auth method mounted at `mount` (usually `cert`), as
+    /// `role` or whichever role matches the client certificate
+    ///
+    /// The certificate is the one the HTTP client presents, configured with
+    /// `SimpleHttpClientBuilder::tls_connector`.
+    pub fn cert_login(&mut self, mount: &str, role: Option<&str>) -> Result<()> {
+        let mut args = Map::new();
+        if let Some(role) = role {
+            args.insert("name".to_string(), Value::from(role));
+        }
+        let uri = vault_uri(&format!("auth/{}/login", mount.trim_matches('/')))?;
+        let shared_token = self.token.clone();
+        let seen = shared_token.generation();
+        shared_token.refresh(seen, || {
+            let payload = check_errors(self.request_json(Method::Post, uri, Some(Value::from(args)))?,
+                                       "log in with certificate at", mount)?;
+            let token = payload.get("auth").and_then(|x| x.get("client_token")).and_then(Value::as_str)
+                .ok_or(ClientError::new("Could not retrieve auth token"))?;
+            Ok(Some(Secret::from(token)))
+        })?;
+        if let (Some(cache), Some(token)) = (self.token_cache.as_ref(), self.token.get()) {
+            cache.save(&token)?;
+        }
+        Ok(())
+    }
+
+    /// Find the password auth mounts and log in with the only one, or prompt for which to use
+    /// when there are several, returning the mount chosen
+    pub fn choose_auth_mount_interactively(&mut self) -> Result<String> {
+        let mounts = self.list_password_auth_mounts()?;
+        let mount = match mounts.len() {
+            0 => return Err(ClientError::new("Vault has no username and password auth methods enabled")),
+            1 => mounts[0].clone(),
+            _ => {
+                let chosen = interactive_select("Multiple Vault auth mounts detected - pick one:", &mounts)
+                    .map_err(|e| ClientError::new(format!("Failed to read auth mount choice: {}", e)))?;
+                mounts[chosen].clone()
+            },
+        };
+        self.auth_mount = mount.clone();
+        Ok(mount)
+    }
+
+    /// Read the secret at `path` (for example `secret/gitlab/token`), returning the full
+    /// response including `data` and `lease_duration`
+    ///
+    /// Paths on KV version 2 mounts are read at their `data/` path, and the response is
+    /// unwrapped so `data` holds the secret as it would on version 1, with the version
+    /// details under `metadata`. Paths already under `data/` are read as they are. See
+    /// `kv_version` for how mounts are detected.
+    pub fn read_secret(&mut self, path: &str) -> Result<Value> {
+        let (read_path, unwrap) = self.kv_read_path(path)?;
+        let payload = self.request_json(Method::Get, vault_uri(&read_path)?, None::<Value>)?;
+        check_errors(payload, "read secret", path).map(|p| if unwrap { unwrap_kv2(p) } else { p })
+    }
+
+    /// Read the secrets at all of `paths` concurrently, returning each full response or the
+    /// error reading it by path
+    ///
+    /// The requests share the client's event loop, so reading many secrets takes about as long
+    /// as the slowest read rather than the sum of them all.
+    pub fn read_secrets(&mut self, paths: &[&str]) -> Result<HashMap<String, Result<Value>>> {
+        let mut unwraps = Vec::new();
+        let mut read_paths = Vec::new();
+        for path in paths {
+            let (read_path, unwrap) = self.kv_read_path(path)?;
+            read_paths.push(read_path);
+            unwraps.push(unwrap);
+        }
+        self.before_request()?;
+        let mut requests = Vec::new();
+        let mut log_paths = Vec::new();
+        for path in &read_paths {
+            let uri = vault_uri(path)?;
+            log_paths.push(self.full_uri(uri.clone())?.path().to_string());
+            let future = self.request_future(Method::Get, uri, None::<String>)
+                .ok_or(ClientError::new("No request made"))?;
+            let read = future.and_then(|response| {
+                let status = response.status();
+                response.body().concat2().map(move |body| (status, body))
+            });
+            requests.push(self.http_client().limit_concurrency(read).then(Ok::<_, ()>));
+        }
+        self.pending_request = None;
+        let responses = self.http_client_mut().evaluate_future(join_all(requests))
+            .map_err(|_| ClientError::new("Failed to read secrets"))?;
+
+        let mut secrets = HashMap::new();
+        for (((path, log_path), response), unwrap) in paths.iter().zip(log_paths).zip(responses).zip(unwraps) {
+            if let Some(ref mut log) = self.request_log {
+                log.record(&Method::Get, &log_path, response.as_ref().ok().map(|r| r.0))?;
+            }
+            let secret = response.map_err(ClientError::from).and_then(|(_, body)| {
+                Ok(serde_json::from_slice::<Value>(&body)?)
+            }).and_then(|payload| check_errors(payload, "read secret", path))
+                .map(|payload| if unwrap { unwrap_kv2(payload) } else { payload });
+            secrets.insert(path.to_string(), secret);
+        }
+        Ok(secrets)
+    }
+
+    /// Re-read the secret at `path` every `interval` and call `callback` with the full response
+    /// on the first read and whenever its `data` changes, until `callback` returns `false`
+    ///
+    /// Secrets with a lease, such as dynamic database credentials, are re-read once two thirds
+    /// of the lease has passed if that is sooner than `interval`, so new credentials arrive
+    /// before the old ones expire. A failed read ends the watch with its error.
+    pub fn watch_secret<F>(&mut self, path: &str, interval: Duration, callback: F) -> Result<()>
+            where F: FnMut(&Value) -> bool {
+        self.watch_secret_using(path, interval, &SystemClock, callback)
+    }
+
+    /// `watch_secret` waiting between reads with `sleeper`
+    pub fn watch_secret_using<S, F>(&mut self, path: &str, interval: Duration, sleeper: &S, mut callback: F)
+            -> Result<()> where S: Sleeper, F: FnMut(&Value) -> bool {
+        let mut last_data = None;
+        loop {
+            let payload = self.read_secret(path)?;
+            let data = payload.get("data").cloned();
+            if last_data.is_none() || last_data != Some(data.clone()) {
+                if !callback(&payload) {
+                    return Ok(());
+                }
+                last_data = Some(data);
+            }
+            let lease = payload.get("lease_duration").and_then(Value::as_u64).unwrap_or(0);
+            let wait = if lease > 0 {
+                interval.min(Duration::from_secs(lease) * 2 / 3)
+            } else {
+                interval
+            };
+            sleeper.sleep(wait);
+        }
+    }
+
+    fn write(&mut self, path: &str, args: Map<String, Value>, action: &str) -> Result<Value> {
+        let payload = self.request_json(Method::Post, vault_uri(path)?, Some(Value::from(args)))?;
+        check_errors(payload, action, path)
+    }
+}
+
+/// KV secrets engine versions
+impl VaultClient {
+    /// KV version of the mount holding `path` - `None` if it is on another secrets engine
+    ///
+    /// The mounts are listed from `sys/mounts` the first time, or looked up one at a time
+    /// from `sys/internal/ui/mounts` for tokens that may not list them, and kept for the life
+    /// of the client. A mount neither reveals has its paths read as they are given.
+    pub fn kv_version(&mut self, path: &str) -> Result<Option<KvVersion>> {
+        Ok(self.kv_mount(path)?.1)
+    }
+
+    /// Take the mount at `mount` to be KV `version`, or not KV if `None`, without asking Vault
+    pub fn set_kv_version(&mut self, mount: &str, version: Option<KvVersion>) {
+        self.kv_mounts.insert(format!("{}/", mount.trim_matches('/')), version);
+    }
+
+    /// Forget the detected mount versions, for example after a mount is upgraded to version 2
+    pub fn clear_kv_versions(&mut self) {
+        self.kv_mounts.clear();
+    }
+
+    fn cached_kv_mount(&self, path: &str) -> Option<(String, Option<KvVersion>)> {
+        self.kv_mounts.iter().filter(|&(mount, _)| path.starts_with(mount.as_str()))
+            .max_by_key(|&(mount, _)| mount.len()).map(|(mount, version)| (mount.clone(), *version))
+    }
+
+    /// Mount holding `path`, with a trailing `/`, and its KV version
+    fn kv_mount(&mut self, path: &str) -> Result<(String, Option<KvVersion>)> {
+        let path = format!("{}/", path.split('?').next().unwrap_or("").trim_matches('/'));
+        if let Some(found) = self.cached_kv_mount(&path) {
+            return Ok(found);
+        }
+
+        let response = self.request(Method::Get, vault_uri("sys/mounts")?, None::<String>)?;
+        if response.status().is_success() {
+            let payload = self.response_to_json(response)?;
+            let mounts = payload.get("data").filter(|d| d.is_object()).unwrap_or(&payload);
+            for (mount, config) in mounts.as_object().into_iter().flatten().filter(|&(_, c)| c.is_object()) {
+                self.kv_mounts.insert(mount.clone(), KvVersion::of_mount(config));
+            }
+        } else {
+            let uri = vault_uri(&format!("sys/internal/ui/mounts/{}", path.trim_end_matches('/')))?;
+            let response = self.request(Method::Get, uri, None::<String>)?;
+            if response.status().is_success() {
+                let payload = self.response_to_json(response)?;
+                if let Some(mount) = payload.pointer("/data/path").and_then(Value::as_str) {
+                    let version = KvVersion::of_mount(&payload["data"]);
+                    self.kv_mounts.insert(mount.to_string(), version);
+                }
+            }
+        }
+        Ok(self.cached_kv_mount(&path).unwrap_or_else(|| {
+            let mount = format!("{}/", path.split('/').next().unwrap_or(""));
+            self.kv_mounts.insert(mount.clone(), None);
+            (mount, None)
+        }))
+    }
+
+    /// Path to read the secret at `path` from, and whether the response is wrapped by KV
+    /// version 2
+    fn kv_read_path(&mut self, path: &str) -> Result<(String, bool)> {
+        let path = path.trim_start_matches('/');
+        match self.kv_mount(path)? {
+            (ref mount, Some(KvVersion::V2)) if path.len() > mount.len() && path.starts_with(mount.as_str()) => {
+                let rest = &path[mount.len()..];
+                if rest.starts_with("data/") {
+                    Ok((path.to_string(), false))
+                } else {
+                    Ok((format!("{}data/{}", mount, rest), true))
+                }
+            },
+            _ => Ok((path.to_string(), false)),
+        }
+    }
+}
+
+/// Move the secret of a KV version 2 response up to `data` and its version details to
+/// `metadata`
+fn unwrap_kv2(mut payload: Value) -> Value {
+    let wrapped = payload.get_mut("data").map(|d| mem::replace(d, Value::Null)).unwrap_or(Value::Null);
+    if let Value::Object(ref mut object) = payload {
+        object.insert("data".to_string(), wrapped.get("data").cloned().unwrap_or(Value::Null));
+        object.insert("metadata".to_string(), wrapped.get("metadata").cloned().unwrap_or(Value::Null));
+    }
+    payload
+}
+
+/// Token capability checks
+impl VaultClient {
+    /// Capabilities of the client's token on `path`, such as `read`, `update` or `deny`
+    pub fn capabilities(&mut self, path: &str) -> Result<Vec<String>> {
+        let path = path.trim_start_matches('/');
+        let mut args = Map::new();
+        args.insert("paths".to_string(), Value::from(vec![path]));
+        let payload = self.write("sys/capabilities-self", args, "check capabilities on")?;
+        let capabilities = payload.get(path).or_else(|| payload.get("capabilities"))
+            .and_then(Value::as_array)
+            .ok_or_else(|| ClientError::new(format!("Response has no capabilities for {}", path)))?;
+        Ok(capabilities.iter().filter_map(Value::as_str).map(str::to_string).collect())
+    }
+
+    /// Check that the client's token has every one of `required` (for example `["read",
+    /// "update"]`) on `path`, so a workflow can stop before starting rather than failing part
+    /// way through
+    ///
+    /// `root` grants every capability and `deny` overrides all others.
+    pub fn has_capabilities(&mut self, path: &str, required: &[&str]) -> Result<bool> {
+        let capabilities = self.capabilities(path)?;
+        if capabilities.iter().any(|c| c == "deny") {
+            return Ok(false);
+        }
+        if capabilities.iter().any(|c| c == "root") {
+            return Ok(true);
+        }
+        Ok(required.iter().all(|r| capabilities.iter().any(|c| c == r)))
+    }
+}
+
+/// Shell completion
+impl VaultClient {
+    /// Secret paths starting with `prefix`, found by listing the directory `prefix` ends in -
+    /// each directory is listed at most once per `cache` TTL
+    ///
+    /// A prefix without a `/` completes secrets engine mounts. Directories are returned with a
+    /// trailing `/`, and a directory that does not exist or cannot be listed has no entries.
+    pub fn complete_secret_paths(&mut self, cache: &mut NameCache, prefix: &str) -> Result<Vec<String>> {
+        let prefix = prefix.trim_start_matches('/');
+        let dir = &prefix[..prefix.rfind('/').map(|i| i + 1).unwrap_or(0)];
+        let key = format!("vault {} {}", self.api_uri, dir);
+        let paths = cache.get_or_fetch(&key, || {
+            let uri = if dir.is_empty() { vault_uri("sys/mounts")? } else { vault_uri(&format!("{}?list=true", dir))? };
+            let response = self.request(Method::Get, uri, None::<String>)?;
+            if !response.status().is_success() {
+                return Ok(Vec::new());
+            }
+            let payload = check_errors(self.response_to_json(response)?, "list", dir)?;
+            let names = if dir.is_empty() {
+                let mounts = payload.get("data").unwrap_or(&payload);
+                mounts.as_object().map(|m| m.keys().filter(|k| k.ends_with('/')).cloned().collect())
+            } else {
+                payload.get("data").and_then(|d| d.get("keys")).and_then(Value::as_array).map(|keys| {
+                    keys.iter().filter_map(Value::as_str).map(|k| format!("{}{}", dir, k)).collect()
+                })
+            };
+            Ok(names.unwrap_or_default())
+        })?;
+        Ok(complete(&paths, prefix))
+    }
+}
+
+/// SSH secrets engine
+impl VaultClient {
+    /// Sign the SSH `public_key` with `role` of the SSH engine mounted at `mount`, returning the
+    /// signed certificate
+    ///
+    /// `valid_principals` and `ttl` (for example `30m`) fall back to the role's defaults when
+    /// empty or `None`.
+    pub fn ssh_sign_key(&mut self, mount: &str, role: &str, public_key: &str, valid_principals: &[&str],
+                        ttl: Option<&str>) -> Result<String> {
+        let mut args = Map::new();
+        args.insert("public_key".to_string(), Value::from(public_key));
+        if !valid_principals.is_empty() {
+            args.insert("valid_principals".to_string(), Value::from(valid_principals.join(",")));
+        }
+        if let Some(ttl) = ttl {
+            args.insert("ttl".to_string(), Value::from(ttl));
+        }
+        let path = format!("{}/sign/{}", mount.trim_matches('/'), role);
+        let payload = self.write(&path, args, "sign SSH key")?;
+        data_field(&payload, "signed_key", &path).map(str::to_string)
+    }
+
+    /// Generate a one time SSH password for `username` (or the role's default user) on the host
+    /// at `ip` with OTP `role` of the SSH engine mounted at `mount`
+    pub fn ssh_otp_credential(&mut self, mount: &str, role: &str, ip: &str, username: Option<&str>)
+            -> Result<Secret> {
+        let mut args = Map::new();
+        args.insert("ip".to_string(), Value::from(ip));
+        if let Some(username) = username {
+            args.insert("username".to_string(), Value::from(username));
+        }
+        let path = format!("{}/creds/{}", mount.trim_matches('/'), role);
+        let payload = self.write(&path, args, "generate SSH OTP for")?;
+        data_field(&payload, "key", &path).map(Secret::from)
+    }
+}
+
+/// TOTP secrets engine
+impl VaultClient {
+    /// Generate a TOTP key `name` in the TOTP engine mounted at `mount` for `account_name` at
+    /// `issuer`, returning the `url` and QR code `barcode` to enroll an authenticator with
+    pub fn totp_create_key(&mut self, mount: &str, name: &str, issuer: &str, account_name: &str)
+            -> Result<Value> {
+        let mut args = Map::new();
+        args.insert("generate".to_string(), Value::from(true));
+        args.insert("issuer".to_string(), Value::from(issuer));
+        args.insert("account_name".to_string(), Value::from(account_name));
+        let path = format!("{}/keys/{}", mount.trim_matches('/'), name);
+        let payload = self.write(&path, args, "create TOTP key")?;
+        payload.get("data").cloned().ok_or_else(|| {
+            ClientError::new(format!("Response from {} has no data", path))
+        })
+    }
+
+    /// Generate the current code for TOTP key `name`
+    pub fn totp_generate_code(&mut self, mount: &str, name: &str) -> Result<Secret> {
+        let path = format!("{}/code/{}", mount.trim_matches('/'), name);
+        let payload = self.request_json(Method::Get, vault_uri(&path)?, None::<Value>)?;
+        let payload = check_errors(payload, "generate TOTP code for", &path)?;
+        data_field(&payload, "code", &path).map(Secret::from)
+    }
+
+    /// Check whether `code` is currently valid for TOTP key `name`
+    pub fn totp_validate_code(&mut self, mount: &str, name: &str, code: &str) -> Result<bool> {
+        let mut args = Map::new();
+        args.insert("code".to_string(), Value::from(code));
+        let path = format!("{}/code/{}", mount.trim_matches('/'), name);
+        let payload = self.write(&path, args, "validate TOTP code for")?;
+        payload.get("data").and_then(|d| d.get("valid")).and_then(Value::as_bool).ok_or_else(|| {
+            ClientError::new(format!("Response from {} has no valid field", path))
+        })
+    }
+}
+
+/// Identity entities and aliases
+impl VaultClient {
+    /// Read the identity entity with ID `id`
+    pub fn read_entity(&mut self, id: &str) -> Result<Option<Value>> {
+        self.identity_lookup(Method::Get, &format!("identity/entity/id/{}", id), None)
+    }
+
+    /// Read the identity entity named `name`
+    pub fn read_entity_by_name(&mut self, name: &str) -> Result<Option<Value>> {
+        self.identity_lookup(Method::Get, &format!("identity/entity/name/{}", name), None)
+    }
+
+    /// Find the identity entity owning the alias `alias_name` of the auth method with mount
+    /// accessor `mount_accessor`, for example the entity of an LDAP user
+    pub fn lookup_entity_by_alias(&mut self, alias_name: &str, mount_accessor: &str)
+            -> Result<Option<Value>> {
+        let mut args = Map::new();
+        args.insert("alias_name".to_string(), Value::from(alias_name));
+        args.insert("alias_mount_accessor".to_string(), Value::from(mount_accessor));
+        self.identity_lookup(Method::Post, "identity/lookup/entity", Some(args))
+    }
+
+    /// Read the entity alias with ID `id`
+    pub fn read_entity_alias(&mut self, id: &str) -> Result<Option<Value>> {
+        self.identity_lookup(Method::Get, &format!("identity/entity-alias/id/{}", id), None)
+    }
+
+    /// Request an identity object, returning its `data` or `None` if it does not exist
+    fn identity_lookup(&mut self, method: Method, path: &str, args: Option<Map<String, Value>>)
+            -> Result<Option<Value>> {
+        let body = args.map(|a| Value::from(a).to_string());
+        let response = self.request(method, vault_uri(path)?, body)?;
+        match response.status() {
+            StatusCode::NoContent | StatusCode::NotFound => return Ok(None),
+            _ => (),
+        }
+        let payload = check_errors(self.response_to_json(response)?, "look up", path)?;
+        Ok(payload.get("data").cloned())
+    }
+}
+
+/// Auth mount used for logins unless another is set
+pub const DEFAULT_AUTH_MOUNT: &str = "ldap";
+
+/// Auth method types that log in with a username and password
+const PASSWORD_AUTH_TYPES: &[&str] = &["ldap", "userpass", "okta", "radius"];
+
+fn vault_uri(path: &str) -> Result<Uri> {
+    Ok(format!("/v1/{}", path.trim_start_matches('/')).parse::<Uri>()?)
+}
+
+fn check_errors(payload: Value, action: &str, path: &str) -> Result<Value> {
+    if let Some(errors) = payload.get("errors") {
+        return Err(ClientError::new(format!("Failed to {} {}: {}", action, path, errors)));
+    }
+    Ok(payload)
+}
+
+fn data_field<'a>(payload: &'a Value, field: &str, path: &str) -> Result<&'a str> {
+    payload.get("data").and_then(|d| d.get(field)).and_then(Value::as_str).ok_or_else(|| {
+        ClientError::new(format!("Response from {} has no {} field", path, field))
+    })
+}
+
+/// Resolves credentials for other API clients from a secret stored in Vault
+///
+/// The secret is read each time credentials are requested so rotated secrets are picked up.
+/// The lease duration of the secret, if any, is used as the lifetime of minted tokens.
+pub struct VaultSecretResolver {
+    vault: VaultClient,
+    path: String,
+    field: String,
+}
+
+impl VaultSecretResolver {
+    /// Resolve the `token` field of the secret at `path`
+    pub fn new<S>(vault: VaultClient, path: S) -> Self where S: Into<String> {
+        VaultSecretResolver { vault, path: path.into(), field: "token".to_string() }
+    }
+
+    /// Use a field of the secret other than `token`
+    pub fn field<S>(mut self, field: S) -> Self where S: Into<String> {
+        self.field = field.into();
+        self
+    }
+
+    /// Read the secret and return the field value along with its lease duration
+    pub fn resolve(&mut self) -> Result<MintedToken<Secret>> {
+        let payload = self.vault.read_secret(&self.path)?;
+        let value = payload.get("data").and_then(|d| d.get(&self.field)).and_then(|v| v.as_str())
+            .ok_or(ClientError::new(format!("Secret {} has no field {}", self.path, self.field)))?;
+        let ttl = payload.get("lease_duration").and_then(|l| l.as_u64()).filter(|l| *l > 0)
+            .map(Duration::from_secs);
+        Ok(MintedToken::new(Secret::from(value), ttl))
+    }
+
+    /// Turn the resolver into a token rotation hook producing tokens of type `T`
+    pub fn into_rotation<T, F>(mut self, into_token: F) -> TokenRotation<T>
+            where T: Clone + 'static, F: Fn(Secret) -> T + 'static {
+        TokenRotation::new(move || {
+            self.resolve().map(|m| MintedToken::new(into_token(m.token), m.ttl))
+        })
+    }
+}
+
+impl CredentialsProvider for VaultSecretResolver {
+    fn get(&mut self) -> Result<ApiCredentials> {
+        self.resolve().map(|m| ApiCredentials::ApiKey(m.token))
+    }
+}
+
+impl Transport for VaultClient {
+    type Http = SimpleHttpClient;
 }
 
 impl ApiClient<SimpleHttpClient> for VaultClient {
@@ -37,50 +762,409 @@ impl ApiClient<SimpleHttpClient> for VaultClient {
         &mut self.http_client
     }
 
+    fn before_request(&mut self) -> Result<()> {
+        if let Some(ref mut rotation) = self.rotation {
+            rotation.rotate_if_needed(&self.token)?;
+        }
+        Ok(())
+    }
+
     fn request_future<B>(&mut self, method: Method, uri: Uri, body: Option<B>)
             -> Option<FutureResponse> where B: ToString {
-        let token = self.token.clone();
+        let token = self.token.get();
         let full_uri = self.full_uri(uri).ok()?;
+        if self.request_log.is_some() {
+            self.pending_request = Some((method.clone(), full_uri.path().to_string()));
+        }
         let client = self.http_client_mut();
         client.start_request(method, full_uri).add_header(ContentType::json());
         if let Some(ref t) = token {
-            client.add_header(XVaultToken(t.clone()));
+            client.add_header(XVaultToken(t.expose().to_string()));
         }
         if let Some(b) = body {
-            client.add_body(b.to_string());
+            client.add_body_bytes(b.to_string());
         }
         client.make_request().future()
     }
 
+    fn response_future(&mut self, f: FutureResponse) -> Result<Response> {
+        let response = self.http_client_mut().evaluate_response(f);
+        if let (Some(log), Some((method, path))) = (self.request_log.as_mut(), self.pending_request.take()) {
+            log.record(&method, &path, response.as_ref().ok().map(Response::status))?;
+        }
+        response
+    }
+
     fn login(&mut self, creds: &ApiCredentials) -> Result<()> {
         let username: String;
         let mut args = Map::new();
         if let ApiCredentials::UserPassTwoFactor(ref u, ref p, ref y) = *creds {
             username = u.clone();
-            args.insert("password".to_string(), Value::String(p.clone()));
-            args.insert("passcode".to_string(), Value::String(y.clone()));
+            args.insert("password".to_string(), Value::from(p.expose()));
+            args.insert("passcode".to_string(), Value::from(y.expose()));
         } else if let ApiCredentials::UserPass(ref u, ref p) = *creds {
             username = u.clone();
-            args.insert("password".to_string(), Value::String(p.clone()));
+            args.insert("password".to_string(), Value::from(p.expose()));
         } else {
             return Err(ClientError::new("Invalid credentials provided for login"));
         }
-        let uri = format!("/v1/auth/ldap/login/{}", username).parse::<Uri>()?;
-        let token_payload = self.request_json(
-            Method::Post, uri,
-            Some(Value::from(args))
-        )?;
-        let token = try!(token_payload.get("auth").and_then(|x| x.get("client_token"))
-                         .and_then(|x| x.as_str())
-                         .ok_or(ClientError::new("Could not retrieve auth token")));
-        self.token = Some(token.to_string());
+        let uri = vault_uri(&format!("auth/{}/login/{}", self.auth_mount.trim_matches('/'), username))?;
+        let shared_token = self.token.clone();
+        let seen = shared_token.generation();
+        shared_token.refresh(seen, || {
+            let token_payload = self.request_json(
+                Method::Post, uri,
+                Some(Value::from(args))
+            )?;
+            let token = token_payload.get("auth").and_then(|x| x.get("client_token"))
+                        .and_then(|x| x.as_str())
+                        .ok_or(ClientError::new("Could not retrieve auth token"))?;
+            Ok(Some(Secret::from(token)))
+        })?;
+        if let (Some(cache), Some(token)) = (self.token_cache.as_ref(), self.token.get()) {
+            cache.save(&token)?;
+        }
         Ok(())
     }
 }
 
 impl JsonApiClient<SimpleHttpClient> for VaultClient {
+    /// Vault returns every key of a `LIST` in one response, so there is never a next page
     fn next_page_uri(&mut self, _response: &Response)
                               -> Result<Option<Uri>> {
-        unimplemented!()
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{env,fs,process};
+    use std::time::Instant;
+    use clock::MockClock;
+    use testing::{Fixture,FixtureServer};
+
+    #[test]
+    fn test_login_and_resolve_secret() {
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/v1/auth/ldap/login/me",
+                   Fixture::json(200, r#"{"auth": {"client_token": "s.abc"}}"#))
+            .route(Method::Get, "/v1/secret/gitlab",
+                   Fixture::json(200, r#"{"lease_duration": 60, "data": {"token": "glpat"}}"#))
+            .start().unwrap();
+        let mut vault = VaultClient::new(&server.base_uri(), None).unwrap();
+        vault.login(&ApiCredentials::UserPass("me".to_string(), "pw".into())).unwrap();
+        assert_eq!(vault.shared_token().get(), Some(Secret::from("s.abc")));
+        vault.set_kv_version("secret", Some(KvVersion::V1));
+
+        let minted = VaultSecretResolver::new(vault, "secret/gitlab").resolve().unwrap();
+        assert_eq!(minted.token.expose(), "glpat");
+        assert_eq!(minted.ttl, Some(Duration::from_secs(60)));
+
+        server.assert_sent().count(2)
+            .post("/v1/auth/ldap/login/me")
+            .no_header("X-Vault-Token")
+            .json_body_contains(serde_json::from_str(r#"{"password": "pw"}"#).unwrap());
+        server.assert_sent().get("/v1/secret/gitlab").header("X-Vault-Token", "s.abc");
+    }
+
+    #[test]
+    fn test_dyn_paginated() {
+        use dynamic::{DynApiClient,DynApiClientExt};
+
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/v1/secret/list", Fixture::json(200, r#"{"data": {"keys": ["a", "b"]}}"#))
+            .start().unwrap();
+        let vault = VaultClient::new(&server.base_uri(), Some(Secret::from("s.abc"))).unwrap();
+        let mut vault: Box<dyn DynApiClient> = Box::new(vault);
+        let pages = vault.send_json_paginated(Method::Get, "/v1/secret/list".parse().unwrap(), None::<Value>).unwrap();
+        assert_eq!(pages[0]["data"]["keys"][1], "b");
+    }
+
+    #[test]
+    fn test_auth_mounts() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/v1/sys/auth", Fixture::json(200, r#"{"data": {
+                "token/": {"type": "token"}, "userpass/": {"type": "userpass"},
+                "corp-ldap/": {"type": "ldap"}, "github/": {"type": "github"}}}"#))
+            .route(Method::Post, "/v1/auth/userpass/login/me",
+                   Fixture::json(200, r#"{"auth": {"client_token": "s.up"}}"#))
+            .start().unwrap();
+        let mut vault = VaultClient::builder(server.base_uri()).token("s.root").auth_mount("corp-ldap").build().unwrap();
+        assert_eq!(vault.auth_mount(), "corp-ldap");
+        assert_eq!(vault.list_password_auth_mounts().unwrap(), vec!["corp-ldap", "userpass"]);
+
+        vault.set_auth_mount("userpass/");
+        vault.login(&ApiCredentials::UserPass("me".to_string(), "pw".into())).unwrap();
+        assert_eq!(vault.shared_token().get(), Some(Secret::from("s.up")));
+    }
+
+    #[test]
+    fn test_cert_login() {
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/v1/auth/cert/login", Fixture::json(200, r#"{"auth": {"client_token": "s.cert"}}"#))
+            .route(Method::Post, "/v1/auth/other/login", Fixture::json(400, r#"{"errors": ["invalid certificate"]}"#))
+            .start().unwrap();
+        let mut vault = VaultClient::new(&server.base_uri(), None).unwrap();
+        vault.cert_login("cert", Some("deploy")).unwrap();
+        assert_eq!(vault.shared_token().get(), Some(Secret::from("s.cert")));
+        server.assert_sent().post("/v1/auth/cert/login")
+            .json_body_contains(serde_json::from_str(r#"{"name": "deploy"}"#).unwrap());
+        assert!(vault.cert_login("other", None).is_err());
+    }
+
+    #[test]
+    fn test_ipv6_base_uri() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/v1/secret/app", Fixture::json(200, r#"{"data": {"password": "pw"}}"#))
+            .start_on("[::1]:0").unwrap();
+        assert!(server.base_uri().starts_with("http://[::1]:"));
+        let mut vault = VaultClient::builder(format!("{}/", server.base_uri())).token("s.root").build().unwrap();
+        assert_eq!(vault.read_secret("secret/app").unwrap()["data"]["password"], "pw");
+        let authority = server.base_uri().trim_start_matches("http://").to_string();
+        server.assert_sent().get("/v1/secret/app").header("Host", &authority);
+    }
+
+    #[test]
+    fn test_token_cache() {
+        use std::sync::Arc;
+        use state::MemoryStateStore;
+
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/v1/auth/ldap/login/me",
+                   Fixture::json(200, r#"{"auth": {"client_token": "s.new"}}"#))
+            .route(Method::Get, "/v1/secret/app", Fixture::json(200, r#"{"data": {}}"#))
+            .start().unwrap();
+        let cache = TokenCache::new(Arc::new(MemoryStateStore::new()), server.base_uri());
+        let mut vault = VaultClient::builder(server.base_uri()).token_cache(cache.clone()).build().unwrap();
+        assert_eq!(vault.shared_token().get(), None);
+        vault.login(&ApiCredentials::UserPass("me".to_string(), "pw".into())).unwrap();
+        assert_eq!(cache.load().unwrap(), Some(Secret::from("s.new")));
+
+        let mut vault = VaultClient::builder(server.base_uri()).token_cache(cache.clone()).build().unwrap();
+        vault.read_secret("secret/app").unwrap();
+        server.assert_sent().get("/v1/secret/app").header("X-Vault-Token", "s.new");
+        let vault = VaultClient::builder(server.base_uri()).token("s.given").token_cache(cache.clone())
+            .build().unwrap();
+        assert_eq!(vault.shared_token().get(), Some(Secret::from("s.given")));
+        cache.clear().unwrap();
+        assert_eq!(cache.load().unwrap(), None);
+    }
+
+    #[test]
+    fn test_complete_secret_paths() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/v1/sys/mounts", Fixture::json(200,
+                   r#"{"data": {"secret/": {"type": "kv"}, "ssh/": {"type": "ssh"}}}"#))
+            .route(Method::Get, "/v1/secret/app/?list=true", Fixture::json(200,
+                   r#"{"data": {"keys": ["db", "web/", "deploy"]}}"#))
+            .start().unwrap();
+        let mut vault = VaultClient::new(&server.base_uri(), Some("t".into())).unwrap();
+        let mut cache = NameCache::new(Duration::from_secs(30));
+        assert_eq!(vault.complete_secret_paths(&mut cache, "s").unwrap(), vec!["secret/", "ssh/"]);
+        assert_eq!(vault.complete_secret_paths(&mut cache, "secret/app/d").unwrap(),
+                   vec!["secret/app/db", "secret/app/deploy"]);
+        assert_eq!(vault.complete_secret_paths(&mut cache, "/secret/app/").unwrap().len(), 3);
+        server.assert_sent().count(2).get("/v1/secret/app/").header("X-Vault-Token", "t");
+        assert!(vault.complete_secret_paths(&mut cache, "secret/missing/").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_client_builder() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/v1/secret/gitlab", Fixture::json(200, r#"{"data": {"token": "glpat"}}"#))
+            .start().unwrap();
+        let path = env::temp_dir().join(format!("teatime-vault-builder-log-{}.jsonl", process::id()));
+        let mut vault = VaultClient::builder(server.base_uri())
+            .token("s.abc")
+            .request_log(RequestLog::open(&path).unwrap())
+            .http(|h| h.raw_header("X-Vault-Namespace", "ops"))
+            .build().unwrap();
+        vault.set_kv_version("secret", Some(KvVersion::V1));
+        vault.read_secret("secret/gitlab").unwrap();
+        server.assert_sent().get("/v1/secret/gitlab").header("X-Vault-Token", "s.abc").header("X-Vault-Namespace", "ops");
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        fs::remove_file(&path).unwrap();
+
+        assert!(VaultClient::builder("vault.example.com:8200").build().is_err());
+        assert!(VaultClient::builder("https://vault.example.com:8200").token("").build().is_err());
+    }
+
+    #[test]
+    fn test_ssh_sign_and_otp() {
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/v1/ssh-client/sign/bastion",
+                   Fixture::json(200, r#"{"data": {"signed_key": "ssh-rsa-cert-v01@openssh.com AAAA"}}"#))
+            .route(Method::Post, "/v1/ssh/creds/otp", Fixture::json(200, r#"{"data": {"key": "2f7e"}}"#))
+            .route(Method::Post, "/v1/ssh/creds/denied", Fixture::json(400, r#"{"errors": ["bad ip"]}"#))
+            .start().unwrap();
+        let mut vault = VaultClient::new(&server.base_uri(), Some("s.abc".into())).unwrap();
+        let cert = vault.ssh_sign_key("ssh-client/", "bastion", "ssh-rsa AAAA", &["ops", "deploy"], Some("30m"))
+            .unwrap();
+        assert_eq!(cert, "ssh-rsa-cert-v01@openssh.com AAAA");
+        let otp = vault.ssh_otp_credential("ssh", "otp", "10.0.0.5", Some("ops")).unwrap();
+        assert_eq!(otp.expose(), "2f7e");
+        assert!(vault.ssh_otp_credential("ssh", "denied", "10.0.0.6", None).is_err());
+
+        server.assert_sent().post("/v1/ssh-client/sign/bastion")
+            .json_body_contains(serde_json::from_str(
+                r#"{"public_key": "ssh-rsa AAAA", "valid_principals": "ops,deploy", "ttl": "30m"}"#
+            ).unwrap());
+        server.assert_sent().post("/v1/ssh/creds/otp")
+            .json_body_contains(serde_json::from_str(r#"{"ip": "10.0.0.5", "username": "ops"}"#).unwrap());
+    }
+
+    #[test]
+    fn test_totp_and_identity() {
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/v1/totp/keys/ops", Fixture::json(200,
+                   r#"{"data": {"barcode": "iVBOR", "url": "otpauth://totp/Acme:ops?secret=ABC"}}"#))
+            .route(Method::Get, "/v1/totp/code/ops", Fixture::json(200, r#"{"data": {"code": "123456"}}"#))
+            .route(Method::Post, "/v1/totp/code/ops", Fixture::json(200, r#"{"data": {"valid": false}}"#))
+            .route(Method::Post, "/v1/identity/lookup/entity", Fixture::json(200,
+                   r#"{"data": {"id": "e-1", "name": "entity_ops"}}"#))
+            .route(Method::Post, "/v1/identity/lookup/entity", Fixture::new(204))
+            .route(Method::Get, "/v1/identity/entity/id/e-1", Fixture::json(200, r#"{"data": {"id": "e-1"}}"#))
+            .start().unwrap();
+        let mut vault = VaultClient::new(&server.base_uri(), Some("s.abc".into())).unwrap();
+        let key = vault.totp_create_key("totp", "ops", "Acme", "ops@example.com").unwrap();
+        assert_eq!(key["url"], "otpauth://totp/Acme:ops?secret=ABC");
+        assert_eq!(vault.totp_generate_code("totp", "ops").unwrap().expose(), "123456");
+        assert!(!vault.totp_validate_code("totp", "ops", "000000").unwrap());
+
+        let entity = vault.lookup_entity_by_alias("ops", "auth_ldap_1234").unwrap().unwrap();
+        assert_eq!(entity["name"], "entity_ops");
+        assert_eq!(vault.lookup_entity_by_alias("nobody", "auth_ldap_1234").unwrap(), None);
+        assert_eq!(vault.read_entity("e-1").unwrap().unwrap()["id"], "e-1");
+        assert_eq!(vault.read_entity("e-2").unwrap(), None);
+
+        server.assert_sent().post("/v1/totp/keys/ops")
+            .json_body_contains(serde_json::from_str(
+                r#"{"generate": true, "issuer": "Acme", "account_name": "ops@example.com"}"#
+            ).unwrap());
+        server.assert_sent().post("/v1/identity/lookup/entity")
+            .json_body_contains(serde_json::from_str(
+                r#"{"alias_name": "ops", "alias_mount_accessor": "auth_ldap_1234"}"#
+            ).unwrap());
+    }
+
+    #[test]
+    fn test_request_log() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/v1/secret/gitlab", Fixture::json(200, r#"{"data": {"token": "glpat"}}"#))
+            .route(Method::Get, "/v1/secret/missing", Fixture::json(404, r#"{"errors": []}"#))
+            .start().unwrap();
+        let path = env::temp_dir().join(format!("teatime-vault-log-{}.jsonl", process::id()));
+        let _ = fs::remove_file(&path);
+        for secret in &["secret/gitlab?version=2", "secret/missing"] {
+            let mut vault = VaultClient::new(&server.base_uri(), Some("s.abc".into())).unwrap();
+            vault.set_request_log(RequestLog::open(&path).unwrap());
+            vault.set_kv_version("secret", Some(KvVersion::V1));
+            let _ = vault.read_secret(secret);
+        }
+        let log = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let entries: Vec<Value> = log.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((&entries[0]["method"], &entries[0]["path"], &entries[0]["status"]),
+                   (&Value::from("GET"), &Value::from("/v1/secret/gitlab"), &Value::from(200)));
+        assert_eq!(entries[1]["status"], 404);
+        assert!(entries[0]["time"].as_u64().unwrap() > 0);
+        assert!(!log.contains("glpat") && !log.contains("s.abc"));
+    }
+
+    #[test]
+    fn test_read_secrets_concurrently() {
+        let delay = Duration::from_millis(300);
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/v1/secret/a", Fixture::json(200, r#"{"data": {"v": "a"}}"#).delay(delay))
+            .route(Method::Get, "/v1/secret/b", Fixture::json(200, r#"{"data": {"v": "b"}}"#).delay(delay))
+            .route(Method::Get, "/v1/secret/c", Fixture::json(403, r#"{"errors": ["permission denied"]}"#)
+                   .delay(delay))
+            .start().unwrap();
+        let mut vault = VaultClient::new(&server.base_uri(), Some("s.abc".into())).unwrap();
+        vault.set_kv_version("secret", Some(KvVersion::V1));
+        let start = Instant::now();
+        let secrets = vault.read_secrets(&["secret/a", "secret/b", "secret/c"]).unwrap();
+        assert!(start.elapsed() < delay * 2);
+        assert_eq!(secrets["secret/a"].as_ref().unwrap()["data"]["v"], "a");
+        assert_eq!(secrets["secret/b"].as_ref().unwrap()["data"]["v"], "b");
+        assert!(secrets["secret/c"].is_err());
+        server.assert_sent().count(3).get("/v1/secret/a").header("X-Vault-Token", "s.abc");
+    }
+
+    #[test]
+    fn test_kv_version_detection() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/v1/sys/mounts", Fixture::json(200, r#"{"data": {
+                "secret/": {"type": "kv", "options": {"version": "2"}}, "legacy/": {"type": "kv", "options": null},
+                "database/": {"type": "database"}}}"#))
+            .route(Method::Get, "/v1/secret/data/gitlab", Fixture::json(200,
+                   r#"{"data": {"data": {"token": "glpat"}, "metadata": {"version": 3}}}"#))
+            .route(Method::Get, "/v1/secret/data/raw", Fixture::json(200, r#"{"data": {"data": {"v": 1}}}"#))
+            .route(Method::Get, "/v1/legacy/app", Fixture::json(200, r#"{"data": {"v": "old"}}"#))
+            .route(Method::Get, "/v1/database/creds/ro", Fixture::json(200, r#"{"data": {"username": "u"}}"#))
+            .route(Method::Get, "/v1/sys/mounts", Fixture::json(403, r#"{"errors": ["permission denied"]}"#))
+            .route(Method::Get, "/v1/sys/internal/ui/mounts/team/app", Fixture::json(200,
+                   r#"{"data": {"path": "team/", "type": "kv", "options": {"version": "2"}}}"#))
+            .route(Method::Get, "/v1/team/data/app", Fixture::json(200, r#"{"data": {"data": {"v": "team"}}}"#))
+            .start().unwrap();
+        let mut vault = VaultClient::new(&server.base_uri(), Some("s.abc".into())).unwrap();
+        let secret = vault.read_secret("/secret/gitlab").unwrap();
+        assert_eq!(secret["data"]["token"], "glpat");
+        assert_eq!(secret["metadata"]["version"], 3);
+        assert_eq!(vault.read_secret("secret/data/raw").unwrap()["data"]["data"]["v"], 1);
+        assert_eq!(vault.kv_version("legacy/app").unwrap(), Some(KvVersion::V1));
+        assert_eq!(vault.kv_version("database/creds/ro").unwrap(), None);
+        let secrets = vault.read_secrets(&["legacy/app", "database/creds/ro"]).unwrap();
+        assert_eq!(secrets["legacy/app"].as_ref().unwrap()["data"]["v"], "old");
+        assert_eq!(secrets["database/creds/ro"].as_ref().unwrap()["data"]["username"], "u");
+
+        // Tokens that may not list mounts look each one up
+        vault.clear_kv_versions();
+        assert_eq!(vault.read_secret("team/app").unwrap()["data"]["v"], "team");
+        let paths = server.requests().into_iter().map(|r| r.path).filter(|p| p.starts_with("/v1/sys/")).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/v1/sys/mounts", "/v1/sys/mounts", "/v1/sys/internal/ui/mounts/team/app"]);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let server = FixtureServer::builder()
+            .route(Method::Post, "/v1/sys/capabilities-self", Fixture::json(200,
+                   r#"{"secret/app": ["read", "list"], "capabilities": ["read", "list"]}"#))
+            .route(Method::Post, "/v1/sys/capabilities-self", Fixture::json(200,
+                   r#"{"secret/app": ["read", "list"], "capabilities": ["read", "list"]}"#))
+            .route(Method::Post, "/v1/sys/capabilities-self", Fixture::json(200, r#"{"capabilities": ["root"]}"#))
+            .route(Method::Post, "/v1/sys/capabilities-self", Fixture::json(200, r#"{"capabilities": ["deny"]}"#))
+            .start().unwrap();
+        let mut vault = VaultClient::new(&server.base_uri(), Some("s.abc".into())).unwrap();
+        assert_eq!(vault.capabilities("/secret/app").unwrap(), vec!["read", "list"]);
+        assert!(!vault.has_capabilities("secret/app", &["read", "update"]).unwrap());
+        assert!(vault.has_capabilities("secret/app", &["update"]).unwrap());
+        assert!(!vault.has_capabilities("secret/app", &["read"]).unwrap());
+        server.assert_sent().count(4).post("/v1/sys/capabilities-self")
+            .json_body_contains(serde_json::from_str(r#"{"paths": ["secret/app"]}"#).unwrap());
+    }
+
+    #[test]
+    fn test_watch_secret() {
+        let server = FixtureServer::builder()
+            .route(Method::Get, "/v1/secret/app", Fixture::json(200, r#"{"data": {"v": 1}}"#))
+            .route(Method::Get, "/v1/secret/app", Fixture::json(200, r#"{"data": {"v": 1}}"#))
+            .route(Method::Get, "/v1/secret/app", Fixture::json(200, r#"{"lease_duration": 90, "data": {"v": 2}}"#))
+            .route(Method::Get, "/v1/secret/app", Fixture::json(200, r#"{"data": {"v": 3}}"#))
+            .start().unwrap();
+        let mut vault = VaultClient::new(&server.base_uri(), Some("s.abc".into())).unwrap();
+        let clock = MockClock::new();
+        let mut seen = Vec::new();
+        vault.watch_secret_using("secret/app", Duration::from_secs(300), &clock, |payload| {
+            seen.push(payload["data"]["v"].as_u64().unwrap());
+            seen.len() < 3
+        }).unwrap();
+        assert_eq!(seen, vec![1, 2, 3]);
+        assert_eq!(clock.sleeps(), vec![Duration::from_secs(300), Duration::from_secs(300),
+                                        Duration::from_secs(60)]);
+        assert!(vault.watch_secret_using("secret/missing", Duration::from_secs(1), &clock, |_| true).is_err());
     }
 }
@@ -0,0 +1,235 @@
+use hmac::{Hmac,Mac};
+use hyper::header::Headers;
+use sha2::Sha256;
+
+use *;
+
+/// HMAC-SHA256 of `message` keyed with `key`
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// Compare two byte strings in time that depends only on their lengths, so signatures cannot be
+/// guessed a byte at a time
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// How the sender of a webhook proves it knows the shared secret
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum SignatureScheme {
+    /// The secret itself is sent in `header`, as Gitlab does with `X-Gitlab-Token`
+    Token {
+        /// Header holding the secret
+        header: String,
+    },
+    /// The hex HMAC-SHA256 of the body keyed with the secret is sent in `header` after
+    /// `prefix`, for example `X-Hub-Signature-256: sha256=...`
+    HmacSha256 {
+        /// Header holding the signature
+        header: String,
+        /// Text before the hex signature in the header value
+        prefix: String,
+    },
+}
+
+impl SignatureScheme {
+    /// The secret token scheme used by Gitlab webhooks
+    pub fn gitlab() -> Self {
+        SignatureScheme::token("X-Gitlab-Token")
+    }
+
+    /// The secret is sent in `header`
+    pub fn token<S>(header: S) -> Self where S: Into<String> {
+        SignatureScheme::Token { header: header.into() }
+    }
+
+    /// The body is signed with HMAC-SHA256 in `header` after `prefix`
+    pub fn hmac_sha256<H, P>(header: H, prefix: P) -> Self where H: Into<String>, P: Into<String> {
+        SignatureScheme::HmacSha256 { header: header.into(), prefix: prefix.into() }
+    }
+
+    fn header(&self) -> &str {
+        match *self {
+            SignatureScheme::Token { ref header } | SignatureScheme::HmacSha256 { ref header, .. } => header,
+        }
+    }
+}
+
+/// Checks that incoming webhooks were sent by a holder of the shared secret
+#[derive(Clone,Debug)]
+pub struct WebhookVerifier {
+    scheme: SignatureScheme,
+    secret: Secret,
+}
+
+impl WebhookVerifier {
+    /// Verify webhooks signed with `secret` using `scheme`
+    pub fn new(scheme: SignatureScheme, secret: Secret) -> Self {
+        WebhookVerifier { scheme, secret }
+    }
+
+    /// Value a sender would put in the signature header for `body`
+    pub fn signature(&self, body: &[u8]) -> String {
+        match self.scheme {
+            SignatureScheme::Token { .. } => self.secret.expose().to_string(),
+            SignatureScheme::HmacSha256 { ref prefix, .. } => {
+                format!("{}{}", prefix, to_hex(&hmac_sha256(self.secret.expose().as_bytes(), body)))
+            },
+        }
+    }
+
+    /// Check the signature header of a request, failing with `ErrorKind::AuthenticationFailed`
+    /// if it is missing or wrong
+    pub fn verify(&self, headers: &Headers, body: &[u8]) -> Result<()> {
+        let name = self.scheme.header();
+        let received = headers.get_raw(name).and_then(|r| r.one()).ok_or_else(|| {
+            ClientError::with_kind(ErrorKind::AuthenticationFailed, format!("Webhook has no {} header", name))
+        })?;
+        // The prefix is matched exactly and only the hex digits after it ignore case
+        let matches = match self.scheme {
+            SignatureScheme::HmacSha256 { ref prefix, .. } => received.starts_with(prefix.as_bytes()) && {
+                let hex = to_hex(&hmac_sha256(self.secret.expose().as_bytes(), body));
+                constant_time_eq(&received[prefix.len()..].to_ascii_lowercase(), hex.as_bytes())
+            },
+            SignatureScheme::Token { .. } => constant_time_eq(received, self.secret.expose().as_bytes()),
+        };
+        if matches {
+            Ok(())
+        } else {
+            Err(ClientError::with_kind(ErrorKind::AuthenticationFailed,
+                                       format!("Webhook {} header does not match", name)))
+        }
+    }
+
+    /// Verify a received webhook and wrap it in a `WebhookPayload`
+    pub fn accept(&self, headers: Headers, body: Vec<u8>) -> Result<WebhookPayload> {
+        self.verify(&headers, &body)?;
+        Ok(WebhookPayload { headers, body })
+    }
+}
+
+/// Kinds of Gitlab webhook events, from the `X-Gitlab-Event` header
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum GitlabEvent {
+    /// Branch pushes
+    Push,
+    /// Tag pushes
+    TagPush,
+    /// Issue changes
+    Issue,
+    /// Comments
+    Note,
+    /// Merge request changes
+    MergeRequest,
+    /// Pipeline status changes
+    Pipeline,
+    /// Job status changes
+    Job,
+    /// Any other event, by header value
+    Other(String),
+}
+
+impl GitlabEvent {
+    /// Parse the value of an `X-Gitlab-Event` header such as `Push Hook`
+    pub fn from_header(value: &str) -> Self {
+        match value {
+            "Push Hook" => GitlabEvent::Push,
+            "Tag Push Hook" => GitlabEvent::TagPush,
+            "Issue Hook" | "Confidential Issue Hook" => GitlabEvent::Issue,
+            "Note Hook" | "Confidential Note Hook" => GitlabEvent::Note,
+            "Merge Request Hook" => GitlabEvent::MergeRequest,
+            "Pipeline Hook" => GitlabEvent::Pipeline,
+            "Job Hook" => GitlabEvent::Job,
+            other => GitlabEvent::Other(other.to_string()),
+        }
+    }
+}
+
+/// A received webhook - its headers and body
+#[derive(Clone,Debug)]
+pub struct WebhookPayload {
+    /// Request headers
+    pub headers: Headers,
+    /// Raw request body, as signed by the sender
+    pub body: Vec<u8>,
+}
+
+impl WebhookPayload {
+    /// Wrap a webhook without verifying it
+    pub fn new(headers: Headers, body: Vec<u8>) -> Self {
+        WebhookPayload { headers, body }
+    }
+
+    /// Value of header `name` if it is present and valid UTF-8
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get_raw(name).and_then(|r| r.one()).and_then(|v| str::from_utf8(v).ok())
+    }
+
+    /// Parse the body as JSON
+    pub fn json(&self) -> Result<Value> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+
+    /// Kind of Gitlab event, if the webhook came from Gitlab
+    pub fn gitlab_event(&self) -> Option<GitlabEvent> {
+        self.header("X-Gitlab-Event").map(GitlabEvent::from_header)
+    }
+
+    /// Parse the body as a Sensu event, as sent by Sensu handlers that post events to HTTP
+    /// endpoints
+    #[cfg(feature = "sensu")]
+    pub fn sensu_event(&self) -> Result<::sensu::Event> {
+        ::sensu::Event::from_json(&self.json()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231 test cases 2 and 6
+        assert_eq!(to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+                   "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert_eq!(to_hex(&hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+                   "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+
+    #[test]
+    fn test_verify_webhooks() {
+        let body = br#"{"object_kind": "push"}"#.to_vec();
+        let gitlab = WebhookVerifier::new(SignatureScheme::gitlab(), "s3cret".into());
+        let mut headers = Headers::new();
+        headers.set_raw("X-Gitlab-Event", "Push Hook");
+        assert_eq!(gitlab.verify(&headers, &body).unwrap_err().kind(), &ErrorKind::AuthenticationFailed);
+        headers.set_raw("X-Gitlab-Token", "wrong");
+        assert!(gitlab.verify(&headers, &body).is_err());
+        headers.set_raw("X-Gitlab-Token", "s3cret");
+        let payload = gitlab.accept(headers, body.clone()).unwrap();
+        assert_eq!(payload.gitlab_event(), Some(GitlabEvent::Push));
+        assert_eq!(payload.json().unwrap()["object_kind"], "push");
+
+        let hmac = WebhookVerifier::new(SignatureScheme::hmac_sha256("X-Signature", "sha256="), "key".into());
+        let mut headers = Headers::new();
+        let hex = to_hex(&hmac_sha256(b"key", &body));
+        headers.set_raw("X-Signature", format!("sha256={}", hex.to_uppercase()));
+        hmac.verify(&headers, &body).unwrap();
+        assert!(hmac.verify(&headers, b"tampered").is_err());
+        headers.set_raw("X-Signature", format!("SHA256={}", hex));
+        assert!(hmac.verify(&headers, &body).is_err());
+
+        let upper = WebhookVerifier::new(SignatureScheme::hmac_sha256("X-Signature", "SHA256="), "key".into());
+        upper.verify(&headers, &body).unwrap();
+        headers.set_raw("X-Signature", format!("SHA256={}", hex.to_uppercase()));
+        upper.verify(&headers, &body).unwrap();
+        headers.set_raw("X-Signature", format!("sha256={}", hex));
+        assert!(upper.verify(&headers, &body).is_err());
+    }
+}
@@ -0,0 +1,321 @@
+use std::sync::Arc;
+
+use *;
+use clock::{Clock,SystemClock};
+
+type StepFn<S> = Box<dyn FnMut(&mut S, &StepContext) -> Result<()>>;
+
+struct Step<S> {
+    name: String,
+    changes: bool,
+    run: StepFn<S>,
+}
+
+/// What a running step knows about the workflow, passed to each step
+#[derive(Clone,Debug)]
+pub struct StepContext {
+    workflow: String,
+    step: String,
+    index: usize,
+    count: usize,
+    dry_run: bool,
+    retry: Option<RetryPolicy>,
+    remaining: Option<Duration>,
+}
+
+impl StepContext {
+    /// Name of the workflow
+    pub fn workflow(&self) -> &str {
+        &self.workflow
+    }
+
+    /// Name of the step
+    pub fn step(&self) -> &str {
+        &self.step
+    }
+
+    /// Number of the step, starting from 1
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Number of steps in the workflow
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// True if the workflow must not change anything
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Time left before the workflow's deadline, if it has one
+    pub fn remaining(&self) -> Option<Duration> {
+        self.remaining
+    }
+
+    /// Request options carrying the workflow's retry policy, with a timeout of the time left
+    /// before its deadline - pass to `request_with_options` and `request_json_with_options`
+    pub fn options(&self) -> RequestOptions {
+        let mut options = RequestOptions::new();
+        if let Some(ref retry) = self.retry {
+            options = options.retry_policy(retry.clone());
+        }
+        if let Some(remaining) = self.remaining {
+            options = options.timeout(remaining);
+        }
+        options
+    }
+}
+
+/// What happened to one step of a workflow run
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum StepStatus {
+    /// The step ran and succeeded
+    Completed,
+    /// The step changes things and was skipped because the run was a dry run
+    Skipped,
+}
+
+/// One step of a workflow run
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct StepOutcome {
+    /// Name of the step
+    pub name: String,
+    /// Whether the step ran
+    pub status: StepStatus,
+    /// Time the step took
+    pub elapsed: Duration,
+}
+
+/// Outcome of a workflow run that finished every step
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+pub struct WorkflowReport {
+    /// True if steps that change things were skipped
+    pub dry_run: bool,
+    /// Outcome of each step, in order
+    pub steps: Vec<StepOutcome>,
+}
+
+impl WorkflowReport {
+    /// Names of the steps skipped by a dry run
+    pub fn skipped(&self) -> Vec<&str> {
+        self.steps.iter().filter(|s| s.status == StepStatus::Skipped).map(|s| s.name.as_str()).collect()
+    }
+
+    /// Total time of all steps
+    pub fn elapsed(&self) -> Duration {
+        self.steps.iter().map(|s| s.elapsed).sum()
+    }
+}
+
+/// Dependent API steps run in order over shared state, such as log in, list and then act on
+/// what was listed
+///
+/// Each step receives the state, usually a struct holding the client and what earlier steps
+/// found, and a `StepContext` whose `options` apply the workflow's retry policy and deadline to
+/// its requests. The run stops at the first failing step, with the workflow and step names
+/// added to the error. In a dry run steps added with `action` are skipped, while steps added
+/// with `step` still run so the report shows what would have been done.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use teatime::retry::RetryPolicy;
+/// # use teatime::workflow::Workflow;
+/// let mut stale: Vec<String> = Vec::new();
+/// let report = Workflow::new("cleanup")
+///     .retry_policy(RetryPolicy::new(2))
+///     .deadline(Duration::from_secs(60))
+///     .dry_run(true)
+///     .step("list", |stale: &mut Vec<String>, _| { stale.push("web-1".to_string()); Ok(()) })
+///     .action("delete", |stale, _| { stale.clear(); Ok(()) })
+///     .run(&mut stale).unwrap();
+/// assert_eq!(report.skipped(), vec!["delete"]);
+/// assert_eq!(stale, vec!["web-1"]);
+/// ```
+pub struct Workflow<S> {
+    name: String,
+    retry: Option<RetryPolicy>,
+    deadline: Option<Duration>,
+    dry_run: bool,
+    clock: Arc<dyn Clock>,
+    steps: Vec<Step<S>>,
+}
+
+impl<S> fmt::Debug for Workflow<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Workflow")
+            .field("name", &self.name)
+            .field("retry", &self.retry)
+            .field("deadline", &self.deadline)
+            .field("dry_run", &self.dry_run)
+            .field("steps", &self.steps.iter().map(|s| s.name.as_str()).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl<S> Workflow<S> {
+    /// Create a workflow named `name` with no steps, retries or deadline
+    pub fn new<N>(name: N) -> Self where N: Into<String> {
+        Workflow {
+            name: name.into(),
+            retry: None,
+            deadline: None,
+            dry_run: false,
+            clock: Arc::new(SystemClock),
+            steps: Vec::new(),
+        }
+    }
+
+    /// Retry the requests of every step as `policy` says
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Fail the run if it has not finished within `deadline`
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Skip the steps that change things
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Measure the deadline with `clock`
+    pub fn clock<C>(mut self, clock: C) -> Self where C: Clock + 'static {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Add a step that only reads, which runs in dry runs too
+    pub fn step<N, F>(self, name: N, run: F) -> Self
+            where N: Into<String>, F: FnMut(&mut S, &StepContext) -> Result<()> + 'static {
+        self.add(name.into(), false, Box::new(run))
+    }
+
+    /// Add a step that changes things, which is skipped in dry runs
+    pub fn action<N, F>(self, name: N, run: F) -> Self
+            where N: Into<String>, F: FnMut(&mut S, &StepContext) -> Result<()> + 'static {
+        self.add(name.into(), true, Box::new(run))
+    }
+
+    fn add(mut self, name: String, changes: bool, run: StepFn<S>) -> Self {
+        self.steps.push(Step { name, changes, run });
+        self
+    }
+
+    /// Run every step in order on `state`, stopping at the first that fails
+    ///
+    /// A step's error keeps its kind, with the workflow and step named in its message. Passing
+    /// the deadline between steps fails the run with `ErrorKind::TimedOut`.
+    pub fn run(&mut self, state: &mut S) -> Result<WorkflowReport> {
+        let start = self.clock.now();
+        let count = self.steps.len();
+        let name = &self.name;
+        let mut report = WorkflowReport { dry_run: self.dry_run, steps: Vec::with_capacity(count) };
+        for (i, step) in self.steps.iter_mut().enumerate() {
+            let remaining = match self.deadline {
+                Some(deadline) => match deadline.checked_sub(self.clock.now().duration_since(start)) {
+                    Some(remaining) if remaining > Duration::from_secs(0) => Some(remaining),
+                    _ => return Err(ClientError::with_kind(ErrorKind::TimedOut, format!(
+                        "Workflow {} passed its deadline of {:?} before step {}/{} ({})",
+                        name, deadline, i + 1, count, step.name
+                    ))),
+                },
+                None => None,
+            };
+            let step_start = self.clock.now();
+            let status = if step.changes && self.dry_run {
+                StepStatus::Skipped
+            } else {
+                let context = StepContext {
+                    workflow: name.clone(),
+                    step: step.name.clone(),
+                    index: i + 1,
+                    count,
+                    dry_run: self.dry_run,
+                    retry: self.retry.clone(),
+                    remaining,
+                };
+                (step.run)(state, &context).map_err(|e| ClientError::with_kind(e.kind().clone(), format!(
+                    "Workflow {} failed at step {}/{} ({}): {}", name, i + 1, count, step.name, e
+                )))?;
+                StepStatus::Completed
+            };
+            report.steps.push(StepOutcome {
+                name: step.name.clone(),
+                status,
+                elapsed: self.clock.now().duration_since(step_start),
+            });
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clock::MockClock;
+
+    #[test]
+    fn test_workflow_steps() {
+        let mut workflow = Workflow::new("cleanup")
+            .retry_policy(RetryPolicy::new(3))
+            .step("login", |log: &mut Vec<String>, ctx| {
+                assert_eq!(ctx.options().retry().map(RetryPolicy::retry_count), Some(3));
+                log.push(format!("{} {}/{}", ctx.step(), ctx.index(), ctx.count()));
+                Ok(())
+            })
+            .step("list", |log, ctx| { log.push(ctx.step().to_string()); Ok(()) })
+            .action("delete", |log, ctx| { log.push(ctx.step().to_string()); Ok(()) });
+        let mut log = Vec::new();
+        let report = workflow.run(&mut log).unwrap();
+        assert_eq!(log, vec!["login 1/3", "list", "delete"]);
+        assert!(report.skipped().is_empty());
+
+        let mut dry_run = workflow.dry_run(true);
+        let mut log = Vec::new();
+        let report = dry_run.run(&mut log).unwrap();
+        assert_eq!(log, vec!["login 1/3", "list"]);
+        assert!(report.dry_run);
+        assert_eq!(report.skipped(), vec!["delete"]);
+
+        let mut failing = Workflow::new("remediate")
+            .step("login", |_: &mut (), _| Err(ClientError::with_kind(ErrorKind::AuthenticationFailed, "bad token")))
+            .action("act", |_, _| panic!("ran after a failed step"));
+        let error = failing.run(&mut ()).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::AuthenticationFailed);
+        assert_eq!(error.to_string(), "Workflow remediate failed at step 1/2 (login): bad token");
+    }
+
+    #[test]
+    fn test_workflow_deadline() {
+        let clock = MockClock::new();
+        let step_clock = clock.clone();
+        let mut workflow = Workflow::new("slow")
+            .deadline(Duration::from_secs(10))
+            .clock(clock.clone())
+            .step("first", move |remaining: &mut Vec<Option<Duration>>, ctx| {
+                remaining.push(ctx.remaining());
+                assert_eq!(ctx.options().overrides().timeout(), Some(Duration::from_secs(10)));
+                step_clock.advance(Duration::from_secs(4));
+                Ok(())
+            })
+            .step("second", |remaining, ctx| { remaining.push(ctx.remaining()); Ok(()) });
+        let mut remaining = Vec::new();
+        let report = workflow.run(&mut remaining).unwrap();
+        assert_eq!(remaining, vec![Some(Duration::from_secs(10)), Some(Duration::from_secs(6))]);
+        assert_eq!(report.steps[0].elapsed, Duration::from_secs(4));
+
+        let mut late = Workflow::new("late").deadline(Duration::from_secs(1)).clock(clock.clone())
+            .step("wait", move |_: &mut (), _| { clock.advance(Duration::from_secs(2)); Ok(()) })
+            .step("act", |_, _| Ok(()));
+        let error = late.run(&mut ()).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::TimedOut);
+        assert!(error.to_string().contains("before step 2/2 (act)"));
+    }
+}
@@ -0,0 +1,103 @@
+use hyper::Response;
+use hyper::header::{Accept,ContentType,qitem};
+use hyper::mime::Mime;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_yaml;
+
+use *;
+
+fn yaml_mime() -> Mime {
+    "application/yaml".parse().expect("YAML MIME type is valid")
+}
+
+/// Encode a value as a YAML document
+pub fn to_yaml<T>(value: &T) -> Result<String> where T: ?Sized + Serialize {
+    serde_yaml::to_string(value).map_err(|e| {
+        ClientError::new(format!("Failed to encode YAML: {}", e))
+    })
+}
+
+/// Decode a YAML document
+pub fn from_yaml<T>(bytes: &[u8]) -> Result<T> where T: DeserializeOwned {
+    serde_yaml::from_slice(bytes).map_err(|e| {
+        ClientError::new(format!("Failed to parse YAML: {}", e))
+    })
+}
+
+/// Request bodies accepted by the YAML flows
+///
+/// Any `Serialize` type is serialized to YAML. `RawBody` is sent as is for manifests that
+/// are already written out, preserving comments and formatting.
+pub trait YamlBody {
+    /// Encode the body as the string sent in the request
+    fn to_yaml_body(&self) -> Result<String>;
+}
+
+impl<T> YamlBody for T where T: Serialize {
+    fn to_yaml_body(&self) -> Result<String> {
+        to_yaml(self)
+    }
+}
+
+impl YamlBody for RawBody {
+    fn to_yaml_body(&self) -> Result<String> {
+        Ok(self.0.clone())
+    }
+}
+
+/// YAML flows for APIs accepting YAML manifests, mirroring the JSON flows in `JsonApiClient` -
+/// implemented for every `ApiClient`
+///
+/// Requests are sent with `Accept` and `Content-Type` set to `application/yaml`. Responses can
+/// be decoded into any `Deserialize` type, including `serde_json::Value`.
+pub trait YamlApiClient<HTTP>: ApiClient<HTTP> where HTTP: HttpClient {
+    /// Make an API request with a YAML body and decode the YAML response
+    fn request_yaml<B, T>(&mut self, method: Method, uri: Uri, body: Option<B>) -> Result<T>
+            where B: YamlBody, T: DeserializeOwned {
+        let future = self.request_future_yaml(method, uri, body)?;
+        self.response_future_yaml(future)
+    }
+
+    /// Make an API request with a YAML body and return the future
+    fn request_future_yaml<B>(&mut self, method: Method, uri: Uri, body: Option<B>)
+            -> Result<FutureResponse> where B: YamlBody {
+        let mut overrides = RequestOverrides::new();
+        overrides.set_header(Accept(vec![qitem(yaml_mime())]))
+                 .set_header(ContentType(yaml_mime()));
+        if let Some(ref b) = body {
+            overrides.set_body(b.to_yaml_body()?);
+        }
+        self.request_future_with_overrides(method, uri, overrides)
+    }
+
+    /// Resolve the future to a response and decode the YAML body
+    fn response_future_yaml<T>(&mut self, fut: FutureResponse) -> Result<T>
+            where T: DeserializeOwned {
+        let response = self.response_future(fut)?;
+        self.response_to_yaml(response)
+    }
+
+    /// Decode a response body as YAML
+    fn response_to_yaml<T>(&mut self, response: Response) -> Result<T>
+            where T: DeserializeOwned {
+        let chunk = self.http_client_mut().evaluate_future(response.body().concat2())?;
+        from_yaml(&chunk)
+    }
+}
+
+impl<HTTP, C> YamlApiClient<HTTP> for C where HTTP: HttpClient, C: ApiClient<HTTP> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_yaml_bodies() {
+        let value: Value = from_yaml(b"kind: Deployment\nreplicas: 3\n").unwrap();
+        assert_eq!(value["replicas"], Value::from(3));
+        assert_eq!(value.to_yaml_body().unwrap(), "kind: Deployment\nreplicas: 3\n");
+        let raw = RawBody("# keep me\nkind: Service\n".to_string());
+        assert_eq!(raw.to_yaml_body().unwrap(), "# keep me\nkind: Service\n");
+    }
+}